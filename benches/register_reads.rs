@@ -0,0 +1,33 @@
+//! Times Modbus reads of the meter's registers, as a busy inverter makes them.
+//! Run with `cargo bench --bench register_reads`.
+
+use std::time::Instant;
+
+use fronius_meter_emulation::{sunspec_map::DEFAULT_UNIT_ID, Readings, SmartMeterEmulator};
+use tokio_modbus::{prelude::*, server::Service};
+
+const READS: u32 = 200_000;
+
+#[tokio::main]
+async fn main() {
+    let (meter, update_handle) = SmartMeterEmulator::new();
+    update_handle
+        .send(Readings::TotalRealPower(1234.5))
+        .await
+        .unwrap();
+    // The total power alone, the whole meter model, and the common model
+    for (addr, cnt) in [(40097, 2), (40071, 124), (40000, 70)] {
+        let started = Instant::now();
+        for _ in 0..READS {
+            let request = SlaveRequest {
+                slave: DEFAULT_UNIT_ID,
+                request: Request::ReadHoldingRegisters(addr, cnt),
+            };
+            meter.call(request).await.unwrap();
+        }
+        println!(
+            "{cnt} registers from {addr}: {:?} per read",
+            started.elapsed() / READS
+        );
+    }
+}
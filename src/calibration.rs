@@ -0,0 +1,67 @@
+// Corrects a meter that reads consistently high or low, such as a CT clamp a few percent out,
+// without touching the device's own configuration.
+
+/// A linear correction applied to a source's power, `watts * gain + offset`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calibration {
+    pub gain: f32,
+    /// Watts added after the gain
+    pub offset: f32,
+}
+
+impl Default for Calibration {
+    /// Leaves the power as measured
+    fn default() -> Self {
+        Self {
+            gain: 1.0,
+            offset: 0.0,
+        }
+    }
+}
+
+impl Calibration {
+    pub fn apply(&self, watts: f32) -> f32 {
+        watts * self.gain + self.offset
+    }
+
+    /// Calibrates each phase so together they match the calibrated total, with the offset
+    /// split evenly between them
+    pub fn apply_to_phases(&self, phases: [f32; 3]) -> [f32; 3] {
+        phases.map(|watts| watts * self.gain + self.offset / 3.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_is_a_no_op() {
+        let calibration = Calibration::default();
+        for watts in [0.0, 1234.5, -800.25] {
+            assert_eq!(calibration.apply(watts), watts);
+        }
+        assert_eq!(
+            calibration.apply_to_phases([100.0, -50.0, 25.0]),
+            [100.0, -50.0, 25.0]
+        );
+    }
+
+    #[test]
+    fn test_gain_and_offset() {
+        // A clamp reading 3% high
+        let calibration = Calibration {
+            gain: 0.97,
+            offset: -10.0,
+        };
+        assert!((calibration.apply(1000.0) - 960.0).abs() < 1e-3);
+        // Export is scaled the same way, and the offset still adds
+        assert!((calibration.apply(-1000.0) - -980.0).abs() < 1e-3);
+        assert_eq!(calibration.apply(0.0), -10.0);
+
+        let phases = calibration.apply_to_phases([400.0, 500.0, 100.0]);
+        let total: f32 = phases.iter().sum();
+        assert!((total - calibration.apply(1000.0)).abs() < 1e-3);
+        assert!((phases[2] - (97.0 - 10.0 / 3.0)).abs() < 1e-3);
+    }
+}
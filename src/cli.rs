@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+
+use clap::Parser;
+
+use crate::config::{Config, ConfigError};
+
+// Command line flags, as an alternative to environment variables for service managers such as
+// systemd. Each flag stands in for the environment variable of the same name, so the two are
+// parsed and validated the same way, and anything not given as a flag still comes from the
+// environment.
+
+#[derive(Debug, Clone, Default, PartialEq, Parser)]
+#[command(
+    version,
+    about = "Emulates a Fronius smart meter fed from a Shelly and Home Assistant"
+)]
+pub struct Cli {
+    /// The Shelly's address, or several comma separated, as for SHELLY_MODBUS
+    #[arg(long, value_name = "ADDRESS")]
+    pub shelly_modbus: Option<String>,
+    /// Home Assistant's base URL, as for HA_URL
+    #[arg(long, value_name = "URL")]
+    pub ha_url: Option<String>,
+    /// As for HA_TOKEN
+    #[arg(long, value_name = "TOKEN")]
+    pub ha_token: Option<String>,
+    /// One or more comma separated sensors, as for HA_EXTRA_IMPORT
+    #[arg(long, value_name = "SENSOR")]
+    pub ha_extra_import: Option<String>,
+    /// One or more comma separated sensors, as for HA_EXTRA_EXPORT
+    #[arg(long, value_name = "SENSOR")]
+    pub ha_extra_export: Option<String>,
+    /// Smooth the HA offset, as for HA_SMOOTH=true
+    #[arg(long)]
+    pub ha_smooth: bool,
+    /// As for POLL_INTERVAL_MS
+    #[arg(long, value_name = "MS")]
+    pub poll_interval_ms: Option<String>,
+    /// Where the meter serves Modbus, as for LISTEN_ADDR
+    #[arg(long, value_name = "ADDRESS")]
+    pub listen: Option<String>,
+    /// Any other setting, by its environment variable name. May be repeated.
+    #[arg(long = "set", value_name = "NAME=VALUE", value_parser = parse_setting)]
+    pub settings: Vec<(String, String)>,
+    /// Print every setting as TOML and exit
+    #[arg(long)]
+    pub dump_config: bool,
+    /// Include HA_TOKEN in `--dump-config`
+    #[arg(long)]
+    pub dump_secrets: bool,
+    /// Print the Shelly register pair at this address decoded with every float layout and exit
+    #[arg(long, value_name = "REGISTER")]
+    pub probe_register: Option<u16>,
+    /// Read the total power from the meter at LISTEN_ADDR and exit 0 if it answers, 1 if not
+    #[arg(long)]
+    pub probe: bool,
+}
+
+impl Cli {
+    /// The settings given as flags, by environment variable name
+    pub fn overrides(&self) -> HashMap<String, String> {
+        let flags = [
+            ("SHELLY_MODBUS", &self.shelly_modbus),
+            ("HA_URL", &self.ha_url),
+            ("HA_TOKEN", &self.ha_token),
+            ("HA_EXTRA_IMPORT", &self.ha_extra_import),
+            ("HA_EXTRA_EXPORT", &self.ha_extra_export),
+            ("POLL_INTERVAL_MS", &self.poll_interval_ms),
+            ("LISTEN_ADDR", &self.listen),
+        ];
+        let mut overrides: HashMap<String, String> = self.settings.iter().cloned().collect();
+        // The dedicated flags win over the same setting given with `--set`
+        for (name, value) in flags {
+            if let Some(value) = value {
+                overrides.insert(name.to_string(), value.clone());
+            }
+        }
+        if self.ha_smooth {
+            overrides.insert("HA_SMOOTH".to_string(), "true".to_string());
+        }
+        overrides
+    }
+
+    /// Resolves the config from the flags, then from `lookup` for anything they don't set
+    pub fn config(&self, lookup: impl Fn(&str) -> Option<String>) -> Result<Config, ConfigError> {
+        let overrides = self.overrides();
+        Config::from_lookup(|name| overrides.get(name).cloned().or_else(|| lookup(name)))
+    }
+}
+
+fn parse_setting(setting: &str) -> Result<(String, String), String> {
+    match setting.split_once('=') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_ascii_uppercase(), value.to_string()))
+        }
+        _ => Err(format!("Expected NAME=VALUE, got `{setting}`")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn environment(name: &str) -> Option<String> {
+        match name {
+            "SHELLY_MODBUS" => Some("192.168.1.20:502".to_string()),
+            "HA_URL" => Some("http://homeassistant.local:8123".to_string()),
+            "POLL_INTERVAL_MS" => Some("1000".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_flags_override_the_environment() {
+        let cli = Cli::try_parse_from([
+            "fronius_meter_emulation",
+            "--shelly-modbus",
+            "10.0.0.5:502",
+            "--ha-smooth",
+            "--listen",
+            "127.0.0.1:1502",
+            "--set",
+            "ha_offset_max=2500",
+            "--set",
+            "POLL_INTERVAL_MS=250",
+        ])
+        .unwrap();
+        let config = cli.config(environment).unwrap();
+        assert_eq!(config.shelly_modbus, "10.0.0.5:502");
+        assert!(config.ha_smooth);
+        assert_eq!(config.listen_addr, "127.0.0.1:1502".parse().unwrap());
+        assert_eq!(config.ha_offset_max, 2500.0);
+        assert_eq!(config.poll_interval_ms, 250);
+        // Anything not given as a flag still comes from the environment
+        assert_eq!(config.ha_url, "http://homeassistant.local:8123");
+    }
+
+    #[test]
+    fn test_no_flags_reads_the_environment() {
+        let cli = Cli::try_parse_from(["fronius_meter_emulation"]).unwrap();
+        assert_eq!(cli, Cli::default());
+        let config = cli.config(environment).unwrap();
+        assert_eq!(config, Config::from_lookup(environment).unwrap());
+    }
+
+    #[test]
+    fn test_invalid_flags() {
+        assert!(Cli::try_parse_from(["fronius_meter_emulation", "--set", "HA_SMOOTH"]).is_err());
+        assert!(Cli::try_parse_from(["fronius_meter_emulation", "--probe-register"]).is_err());
+        let cli = Cli::try_parse_from(["fronius_meter_emulation", "--listen", "nowhere"]).unwrap();
+        assert_eq!(cli.config(environment).unwrap_err().name, "LISTEN_ADDR");
+    }
+}
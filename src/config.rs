@@ -0,0 +1,993 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fmt,
+    net::{AddrParseError, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use tracing::warn;
+
+use crate::{
+    calibration::Calibration,
+    data_fetcher::{parse_bool_safe, ExportSign, OutputWhenFull},
+    fault_injection::FaultInjection,
+    home_assistant::{self, HaConfig, HaTls, UnavailablePolicy},
+    offset_limiter::OutOfRangePolicy,
+    output_scheduler,
+    power_combiner::{parse_fallback_chain, EmitPolicy, FallbackPolicy, FallbackTier, OffsetSign},
+    power_model::{self, OffsetDistribution, PhaseTotalMode, PowerModel, Precision},
+    replay,
+    retry::BackoffPolicy,
+    rolling_average::DEFAULT_WINDOW_SIZE,
+    shelly_3em_client::{
+        self, validate_power_register, FloatLayout, ShellyConfig, ShellyProtocol, ShellyTransport,
+        DEFAULT_POWER_REGISTER,
+    },
+    shelly_http,
+    smart_meter_emulator::{
+        self, parse_scale_factors, EmulatorOptions, FramingMode, UnimplementedResponse,
+    },
+    smoothing::SmoothingMode,
+    sunspec_map::{MeterTopology, DEFAULT_UNIT_ID},
+};
+
+// All settings in one place, resolved from the environment at startup.
+// Field names match the environment variables, lowercased, so a dump maps straight back to them.
+
+/// Replaces secrets in a dump unless they are explicitly asked for
+const REDACTED: &str = "<redacted>";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub shelly_modbus: String,
+    pub shelly_protocol: ShellyProtocol,
+    pub replay_speed: f64,
+    pub poll_interval_ms: u64,
+    pub shelly_poll_ms: Option<u64>,
+    pub shelly_read_timeout_ms: u64,
+    pub ha_poll_ms: Option<u64>,
+    pub ha_timeout_ms: u64,
+    pub prime_ha_zero_ms: Option<u64>,
+    pub retry_max: u32,
+    pub retry_base_ms: u64,
+    pub retry_factor: f64,
+    pub retry_max_delay_ms: u64,
+    pub retry_jitter: f64,
+    pub shelly_phase_current: bool,
+    pub shelly_phase_voltage: bool,
+    pub shelly_voltage_frequency: bool,
+    pub shelly_float_layout: FloatLayout,
+    pub shelly_power_register: u16,
+    pub shelly_slave_id: Option<u8>,
+    pub shelly_gain: f32,
+    pub shelly_offset: f32,
+    pub phase_total_mode: PhaseTotalMode,
+    pub offset_distribution: OffsetDistribution,
+    pub nominal_voltage: f32,
+    pub nominal_frequency_hz: f32,
+    pub power_factor: f32,
+    pub ha_enabled: bool,
+    pub ha_url: String,
+    pub ha_token: String,
+    pub ha_extra_import: String,
+    pub ha_extra_export: String,
+    pub ha_export_sign: ExportSign,
+    pub ha_offset_sign: OffsetSign,
+    pub ha_on_unavailable: UnavailablePolicy,
+    pub ha_insecure_tls: bool,
+    pub ha_ca_cert: String,
+    pub ha_offset_min: f32,
+    pub ha_offset_max: f32,
+    pub ha_offset_policy: OutOfRangePolicy,
+    pub offset_file: String,
+    pub ha_smooth: bool,
+    pub ha_smooth_mode: SmoothingMode,
+    pub ha_smooth_window: usize,
+    pub ha_smooth_median_window: usize,
+    pub ha_smooth_ema_alpha: f32,
+    pub smooth_persist: bool,
+    pub smooth_persist_path: String,
+    pub smooth_persist_max_age_s: u64,
+    pub persist_path: String,
+    pub fallback_chain: Vec<FallbackTier>,
+    pub stale_after_s: u64,
+    pub shelly_stale_ms: Option<u64>,
+    pub last_good_hold_s: u64,
+    pub degraded_value_w: f32,
+    pub output_rate_hz: Option<f32>,
+    pub output_when_full: OutputWhenFull,
+    /// How many batches of readings can wait for the meter, see `DEFAULT_CHANNEL_CAPACITY`
+    pub readings_channel_cap: usize,
+    pub delay_serve_until_ready: bool,
+    pub dry_run: bool,
+    pub delay_serve_timeout_s: u64,
+    pub modbus_framing: FramingMode,
+    pub modbus_idle_timeout_s: u64,
+    pub max_modbus_clients: usize,
+    pub voltage_decimals: u32,
+    pub current_decimals: u32,
+    pub frequency_decimals: u32,
+    pub unimplemented_exception: UnimplementedResponse,
+    pub meter_unit_id: u8,
+    pub meter_filter_unit_id: bool,
+    pub meter_topology: MeterTopology,
+    pub meter_float_layout: FloatLayout,
+    pub scale_factors: BTreeMap<String, f32>,
+    pub fault_injection: bool,
+    pub response_delay_ms: u64,
+    pub response_jitter_ms: u64,
+    pub drop_prob: f32,
+    pub post_sequence: bool,
+    pub post_step_ms: u64,
+    pub emit_threshold_w: Option<f32>,
+    pub emit_heartbeat_s: u64,
+    pub listen_addr: SocketAddr,
+    pub metrics_port: Option<u16>,
+    pub health_port: Option<u16>,
+    pub control_port: Option<u16>,
+    pub mqtt_broker: String,
+    pub mqtt_power_topic: String,
+    pub mqtt_json_path: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let fallback = FallbackPolicy::default();
+        let precision = Precision::default();
+        let power_model = PowerModel::default();
+        let backoff = BackoffPolicy::default();
+        let calibration = Calibration::default();
+        Self {
+            shelly_modbus: String::new(),
+            shelly_protocol: ShellyProtocol::Tcp,
+            replay_speed: 1.0,
+            poll_interval_ms: 500,
+            shelly_poll_ms: None,
+            shelly_read_timeout_ms: shelly_3em_client::DEFAULT_READ_TIMEOUT.as_millis() as u64,
+            ha_poll_ms: None,
+            ha_timeout_ms: home_assistant::DEFAULT_TIMEOUT.as_millis() as u64,
+            prime_ha_zero_ms: None,
+            retry_max: backoff.max_retries,
+            retry_base_ms: backoff.base.as_millis() as u64,
+            retry_factor: backoff.factor,
+            retry_max_delay_ms: backoff.max_delay.as_millis() as u64,
+            retry_jitter: backoff.jitter,
+            shelly_phase_current: false,
+            shelly_phase_voltage: false,
+            shelly_voltage_frequency: false,
+            shelly_float_layout: FloatLayout::Cdab,
+            shelly_power_register: DEFAULT_POWER_REGISTER,
+            shelly_slave_id: None,
+            shelly_gain: calibration.gain,
+            shelly_offset: calibration.offset,
+            phase_total_mode: PhaseTotalMode::DerivePhasesFromTotal,
+            offset_distribution: OffsetDistribution::default(),
+            nominal_voltage: power_model.nominal_voltage,
+            nominal_frequency_hz: power_model::NOMINAL_FREQUENCY,
+            power_factor: power_model.power_factor,
+            ha_enabled: true,
+            ha_url: String::new(),
+            ha_token: String::new(),
+            ha_extra_import: String::new(),
+            ha_extra_export: String::new(),
+            ha_export_sign: ExportSign::Positive,
+            ha_offset_sign: OffsetSign::default(),
+            ha_on_unavailable: UnavailablePolicy::default(),
+            ha_insecure_tls: false,
+            ha_ca_cert: String::new(),
+            ha_offset_min: f32::NEG_INFINITY,
+            ha_offset_max: f32::INFINITY,
+            ha_offset_policy: OutOfRangePolicy::Clamp,
+            offset_file: String::new(),
+            ha_smooth: false,
+            ha_smooth_mode: SmoothingMode::Mean,
+            ha_smooth_window: DEFAULT_WINDOW_SIZE,
+            ha_smooth_median_window: 5,
+            ha_smooth_ema_alpha: 0.3,
+            smooth_persist: false,
+            smooth_persist_path: "smoothing_state.json".to_string(),
+            smooth_persist_max_age_s: 300,
+            persist_path: String::new(),
+            fallback_chain: fallback.chain,
+            stale_after_s: fallback.stale_after.as_secs(),
+            shelly_stale_ms: None,
+            last_good_hold_s: fallback.last_good_hold.as_secs(),
+            degraded_value_w: fallback.degraded_value,
+            output_rate_hz: None,
+            output_when_full: OutputWhenFull::default(),
+            readings_channel_cap: smart_meter_emulator::DEFAULT_CHANNEL_CAPACITY,
+            delay_serve_until_ready: false,
+            dry_run: false,
+            delay_serve_timeout_s: 60,
+            modbus_framing: FramingMode::Lenient,
+            modbus_idle_timeout_s: 0,
+            max_modbus_clients: 0,
+            voltage_decimals: precision.voltage_decimals,
+            current_decimals: precision.current_decimals,
+            frequency_decimals: precision.frequency_decimals,
+            unimplemented_exception: UnimplementedResponse::IllegalFunction,
+            meter_unit_id: DEFAULT_UNIT_ID,
+            meter_filter_unit_id: false,
+            meter_topology: MeterTopology::default(),
+            meter_float_layout: FloatLayout::Abcd,
+            scale_factors: BTreeMap::new(),
+            fault_injection: false,
+            response_delay_ms: 0,
+            response_jitter_ms: 0,
+            drop_prob: 0.0,
+            post_sequence: false,
+            post_step_ms: 2000,
+            emit_threshold_w: None,
+            emit_heartbeat_s: 5,
+            listen_addr: SocketAddr::from(([0, 0, 0, 0], 5502)),
+            metrics_port: None,
+            health_port: None,
+            control_port: None,
+            mqtt_broker: String::new(),
+            mqtt_power_topic: String::new(),
+            mqtt_json_path: String::new(),
+        }
+    }
+}
+
+/// A setting that can't be used, and so shouldn't be quietly replaced with its default
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    pub name: &'static str,
+    pub reason: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid {}: {}", self.name, self.reason)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Builds a config from settings given in code, named as the environment variables,
+/// so tests don't need to touch the process environment
+#[derive(Debug, Clone, Default)]
+pub struct ConfigBuilder {
+    vars: HashMap<String, String>,
+}
+
+impl ConfigBuilder {
+    pub fn var(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.vars.insert(name.to_string(), value.into());
+        self
+    }
+
+    pub fn build(self) -> Result<Config, ConfigError> {
+        Config::from_lookup(|name| self.vars.get(name).cloned())
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::from_lookup(|name| env::var(name).ok())
+    }
+
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    /// Resolves every setting through `lookup`, using the default for anything unset or invalid.
+    /// Settings that can't sensibly fall back to a default, such as lists, are an error instead.
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let defaults = Self::default();
+        let string_or = |name: &str, default: String| lookup(name).unwrap_or(default);
+        let bool_var = |name: &str| parse_bool_safe(lookup(name));
+        let shelly_modbus = string_or("SHELLY_MODBUS", defaults.shelly_modbus);
+        let shelly_protocol = parse_or(&lookup, "SHELLY_PROTOCOL", defaults.shelly_protocol);
+        // Several devices can be listed, separated by commas. Replayed traces are checked when
+        // they are loaded, and HTTP devices when they are first polled.
+        for address in shelly_modbus.split(',').filter(|a| !a.trim().is_empty()) {
+            if replay::trace_path(address).is_some() || shelly_http::device_url(address).is_some() {
+                continue;
+            }
+            ShellyTransport::parse(address, shelly_protocol).map_err(|reason| ConfigError {
+                name: "SHELLY_MODBUS",
+                reason,
+            })?;
+        }
+        let shelly_power_register = validate_power_register(parse_or(
+            &lookup,
+            "SHELLY_POWER_REGISTER",
+            defaults.shelly_power_register,
+        ))
+        .map_err(|reason| ConfigError {
+            name: "SHELLY_POWER_REGISTER",
+            reason,
+        })?;
+        let positive_ms = |name: &'static str| match lookup(name)?.trim().parse() {
+            Ok(0) => Some(Err(ConfigError {
+                name,
+                reason: "Must be more than 0".to_string(),
+            })),
+            Ok(interval_ms) => Some(Ok(interval_ms)),
+            Err(_) => None,
+        };
+        let shelly_poll_ms = positive_ms("SHELLY_POLL_MS").transpose()?;
+        let shelly_read_timeout_ms = positive_ms("SHELLY_READ_TIMEOUT_MS")
+            .transpose()?
+            .unwrap_or(defaults.shelly_read_timeout_ms);
+        let ha_poll_ms = positive_ms("HA_POLL_MS").transpose()?;
+        let ha_timeout_ms = positive_ms("HA_TIMEOUT_MS")
+            .transpose()?
+            .unwrap_or(defaults.ha_timeout_ms);
+        let prime_ha_zero_ms = positive_ms("PRIME_HA_ZERO_MS").transpose()?;
+        let shelly_stale_ms = positive_ms("SHELLY_STALE_MS").transpose()?;
+        let listen_addr = match lookup("LISTEN_ADDR") {
+            Some(addr) => addr
+                .trim()
+                .parse()
+                .map_err(|e: AddrParseError| ConfigError {
+                    name: "LISTEN_ADDR",
+                    reason: e.to_string(),
+                })?,
+            None => defaults.listen_addr,
+        };
+        let retry_factor = parse_or(&lookup, "RETRY_FACTOR", defaults.retry_factor);
+        // Shrinking delays would hammer a struggling source the hardest
+        if !(retry_factor >= 1.0 && retry_factor.is_finite()) {
+            return Err(ConfigError {
+                name: "RETRY_FACTOR",
+                reason: format!("{retry_factor} must be at least 1"),
+            });
+        }
+        let shelly_gain = parse_or(&lookup, "SHELLY_GAIN", defaults.shelly_gain);
+        let shelly_offset = parse_or(&lookup, "SHELLY_OFFSET", defaults.shelly_offset);
+        for (name, value) in [
+            ("SHELLY_GAIN", shelly_gain),
+            ("SHELLY_OFFSET", shelly_offset),
+        ] {
+            if !value.is_finite() {
+                return Err(ConfigError {
+                    name,
+                    reason: format!("{value} must be a finite number"),
+                });
+            }
+        }
+        let retry_jitter = parse_or(&lookup, "RETRY_JITTER", defaults.retry_jitter);
+        if !(0.0..=1.0).contains(&retry_jitter) {
+            return Err(ConfigError {
+                name: "RETRY_JITTER",
+                reason: format!("{retry_jitter} must be from 0 to 1"),
+            });
+        }
+        let readings_channel_cap = parse_or(
+            &lookup,
+            "READINGS_CHANNEL_CAP",
+            defaults.readings_channel_cap,
+        );
+        if readings_channel_cap == 0 {
+            return Err(ConfigError {
+                name: "READINGS_CHANNEL_CAP",
+                reason: "Must be more than 0".to_string(),
+            });
+        }
+        let replay_speed = parse_or(&lookup, "REPLAY_SPEED", defaults.replay_speed);
+        if !(replay_speed > 0.0 && replay_speed.is_finite()) {
+            return Err(ConfigError {
+                name: "REPLAY_SPEED",
+                reason: format!("{replay_speed} must be above 0"),
+            });
+        }
+        let ha_offset_min = parse_or(&lookup, "HA_OFFSET_MIN", defaults.ha_offset_min);
+        let ha_offset_max = parse_or(&lookup, "HA_OFFSET_MAX", defaults.ha_offset_max);
+        // NaN can't bound anything either
+        if ha_offset_min.is_nan() || ha_offset_max.is_nan() || ha_offset_min > ha_offset_max {
+            return Err(ConfigError {
+                name: "HA_OFFSET_MIN",
+                reason: format!("{ha_offset_min} is above HA_OFFSET_MAX of {ha_offset_max}"),
+            });
+        }
+        let output_rate_hz = match lookup("OUTPUT_RATE_HZ") {
+            Some(rate) => {
+                let rate = rate.trim();
+                let rate_hz: f32 = rate.parse().map_err(|_| ConfigError {
+                    name: "OUTPUT_RATE_HZ",
+                    reason: format!("`{rate}` is not a number"),
+                })?;
+                if output_scheduler::period_from_rate(rate_hz).is_none() {
+                    return Err(ConfigError {
+                        name: "OUTPUT_RATE_HZ",
+                        reason: format!("Can't emit at {rate_hz}Hz"),
+                    });
+                }
+                Some(rate_hz)
+            }
+            None => None,
+        };
+        let fallback_chain = match lookup("FALLBACK_CHAIN") {
+            Some(chain) => parse_fallback_chain(&chain).map_err(|reason| ConfigError {
+                name: "FALLBACK_CHAIN",
+                reason,
+            })?,
+            None => defaults.fallback_chain,
+        };
+        let scale_factors = match lookup("SCALE_FACTORS") {
+            Some(factors) => parse_scale_factors(&factors).map_err(|reason| ConfigError {
+                name: "SCALE_FACTORS",
+                reason,
+            })?,
+            None => defaults.scale_factors,
+        };
+        Ok(Self {
+            shelly_modbus,
+            shelly_protocol,
+            replay_speed,
+            poll_interval_ms: parse_or(&lookup, "POLL_INTERVAL_MS", defaults.poll_interval_ms),
+            shelly_poll_ms,
+            shelly_read_timeout_ms,
+            ha_poll_ms,
+            ha_timeout_ms,
+            prime_ha_zero_ms,
+            retry_max: parse_or(&lookup, "RETRY_MAX", defaults.retry_max),
+            retry_base_ms: parse_or(&lookup, "RETRY_BASE_MS", defaults.retry_base_ms),
+            retry_factor,
+            retry_max_delay_ms: parse_or(
+                &lookup,
+                "RETRY_MAX_DELAY_MS",
+                defaults.retry_max_delay_ms,
+            ),
+            retry_jitter,
+            shelly_phase_current: bool_var("SHELLY_PHASE_CURRENT"),
+            shelly_phase_voltage: bool_var("SHELLY_PHASE_VOLTAGE"),
+            shelly_voltage_frequency: bool_var("SHELLY_VOLTAGE_FREQUENCY"),
+            shelly_float_layout: parse_or(
+                &lookup,
+                "SHELLY_FLOAT_LAYOUT",
+                defaults.shelly_float_layout,
+            ),
+            shelly_power_register,
+            shelly_slave_id: lookup("SHELLY_SLAVE_ID").and_then(|id| id.trim().parse().ok()),
+            shelly_gain,
+            shelly_offset,
+            phase_total_mode: parse_or(&lookup, "PHASE_TOTAL_MODE", defaults.phase_total_mode),
+            offset_distribution: parse_or(
+                &lookup,
+                "OFFSET_DISTRIBUTION",
+                defaults.offset_distribution,
+            ),
+            nominal_voltage: parse_or(&lookup, "NOMINAL_VOLTAGE", defaults.nominal_voltage),
+            nominal_frequency_hz: parse_or(
+                &lookup,
+                "NOMINAL_FREQUENCY_HZ",
+                defaults.nominal_frequency_hz,
+            ),
+            power_factor: parse_or(&lookup, "POWER_FACTOR", defaults.power_factor),
+            // On unless turned off, unlike the other switches
+            ha_enabled: lookup("HA_ENABLED")
+                .and_then(|enabled| enabled.trim().to_ascii_lowercase().parse().ok())
+                .unwrap_or(defaults.ha_enabled),
+            ha_url: string_or("HA_URL", defaults.ha_url),
+            ha_token: string_or("HA_TOKEN", defaults.ha_token),
+            ha_extra_import: string_or("HA_EXTRA_IMPORT", defaults.ha_extra_import),
+            ha_extra_export: string_or("HA_EXTRA_EXPORT", defaults.ha_extra_export),
+            ha_export_sign: parse_or(&lookup, "HA_EXPORT_SIGN", defaults.ha_export_sign),
+            ha_offset_sign: parse_or(&lookup, "HA_OFFSET_SIGN", defaults.ha_offset_sign),
+            ha_on_unavailable: parse_or(&lookup, "HA_ON_UNAVAILABLE", defaults.ha_on_unavailable),
+            ha_insecure_tls: bool_var("HA_INSECURE_TLS"),
+            ha_ca_cert: string_or("HA_CA_CERT", defaults.ha_ca_cert),
+            ha_offset_min,
+            ha_offset_max,
+            ha_offset_policy: parse_or(&lookup, "HA_OFFSET_POLICY", defaults.ha_offset_policy),
+            offset_file: string_or("OFFSET_FILE", defaults.offset_file),
+            ha_smooth: bool_var("HA_SMOOTH"),
+            ha_smooth_mode: parse_or(&lookup, "HA_SMOOTH_MODE", defaults.ha_smooth_mode),
+            ha_smooth_window: parse_or(&lookup, "HA_SMOOTH_WINDOW", defaults.ha_smooth_window),
+            ha_smooth_median_window: parse_or(
+                &lookup,
+                "HA_SMOOTH_MEDIAN_WINDOW",
+                defaults.ha_smooth_median_window,
+            ),
+            ha_smooth_ema_alpha: parse_or(
+                &lookup,
+                "HA_SMOOTH_EMA_ALPHA",
+                defaults.ha_smooth_ema_alpha,
+            ),
+            smooth_persist: bool_var("SMOOTH_PERSIST"),
+            smooth_persist_path: string_or("SMOOTH_PERSIST_PATH", defaults.smooth_persist_path),
+            smooth_persist_max_age_s: parse_or(
+                &lookup,
+                "SMOOTH_PERSIST_MAX_AGE_S",
+                defaults.smooth_persist_max_age_s,
+            ),
+            persist_path: string_or("PERSIST_PATH", defaults.persist_path),
+            fallback_chain,
+            stale_after_s: parse_or(&lookup, "STALE_AFTER_S", defaults.stale_after_s),
+            shelly_stale_ms,
+            last_good_hold_s: parse_or(&lookup, "LAST_GOOD_HOLD_S", defaults.last_good_hold_s),
+            degraded_value_w: parse_or(&lookup, "DEGRADED_VALUE_W", defaults.degraded_value_w),
+            output_rate_hz,
+            output_when_full: parse_or(&lookup, "OUTPUT_WHEN_FULL", defaults.output_when_full),
+            readings_channel_cap,
+            delay_serve_until_ready: bool_var("DELAY_SERVE_UNTIL_READY"),
+            dry_run: bool_var("DRY_RUN"),
+            delay_serve_timeout_s: parse_or(
+                &lookup,
+                "DELAY_SERVE_TIMEOUT_S",
+                defaults.delay_serve_timeout_s,
+            ),
+            modbus_framing: parse_or(&lookup, "MODBUS_FRAMING", defaults.modbus_framing),
+            modbus_idle_timeout_s: parse_or(
+                &lookup,
+                "MODBUS_IDLE_TIMEOUT_S",
+                defaults.modbus_idle_timeout_s,
+            ),
+            max_modbus_clients: parse_or(
+                &lookup,
+                "MAX_MODBUS_CLIENTS",
+                defaults.max_modbus_clients,
+            ),
+            voltage_decimals: parse_or(&lookup, "VOLTAGE_DECIMALS", defaults.voltage_decimals),
+            current_decimals: parse_or(&lookup, "CURRENT_DECIMALS", defaults.current_decimals),
+            frequency_decimals: parse_or(
+                &lookup,
+                "FREQUENCY_DECIMALS",
+                defaults.frequency_decimals,
+            ),
+            unimplemented_exception: parse_or(
+                &lookup,
+                "UNIMPLEMENTED_EXCEPTION",
+                defaults.unimplemented_exception,
+            ),
+            meter_unit_id: parse_or(&lookup, "METER_UNIT_ID", defaults.meter_unit_id),
+            meter_filter_unit_id: bool_var("METER_FILTER_UNIT_ID"),
+            meter_topology: parse_or(&lookup, "METER_TOPOLOGY", defaults.meter_topology),
+            meter_float_layout: parse_or(
+                &lookup,
+                "METER_FLOAT_LAYOUT",
+                defaults.meter_float_layout,
+            ),
+            scale_factors,
+            fault_injection: bool_var("FAULT_INJECTION"),
+            response_delay_ms: parse_or(&lookup, "RESPONSE_DELAY_MS", defaults.response_delay_ms),
+            response_jitter_ms: parse_or(
+                &lookup,
+                "RESPONSE_JITTER_MS",
+                defaults.response_jitter_ms,
+            ),
+            drop_prob: parse_or(&lookup, "DROP_PROB", defaults.drop_prob),
+            post_sequence: bool_var("POST_SEQUENCE"),
+            post_step_ms: parse_or(&lookup, "POST_STEP_MS", defaults.post_step_ms),
+            emit_threshold_w: lookup("EMIT_THRESHOLD_W").and_then(|w| w.trim().parse().ok()),
+            emit_heartbeat_s: parse_or(&lookup, "EMIT_HEARTBEAT_S", defaults.emit_heartbeat_s),
+            listen_addr,
+            metrics_port: lookup("METRICS_PORT").and_then(|port| port.trim().parse().ok()),
+            health_port: lookup("HEALTH_PORT").and_then(|port| port.trim().parse().ok()),
+            control_port: lookup("CONTROL_PORT").and_then(|port| port.trim().parse().ok()),
+            mqtt_broker: string_or("MQTT_BROKER", defaults.mqtt_broker),
+            mqtt_power_topic: string_or("MQTT_POWER_TOPIC", defaults.mqtt_power_topic),
+            mqtt_json_path: string_or("MQTT_JSON_PATH", defaults.mqtt_json_path),
+        })
+    }
+
+    /// Renders the config as TOML. Secrets are replaced with a placeholder unless `include_secrets`.
+    pub fn to_toml(&self, include_secrets: bool) -> String {
+        let mut config = self.clone();
+        if !include_secrets && !config.ha_token.is_empty() {
+            config.ha_token = REDACTED.to_string();
+        }
+        toml::to_string(&config).expect("Config is always representable as TOML")
+    }
+
+    pub fn shelly(&self) -> ShellyConfig {
+        ShellyConfig {
+            address: self.shelly_modbus.clone(),
+            protocol: self.shelly_protocol,
+            float_layout: self.shelly_float_layout,
+            read_phases: self.shelly_phase_current,
+            read_voltages: self.shelly_phase_voltage,
+            read_voltage_frequency: self.shelly_voltage_frequency,
+            power_register: self.shelly_power_register,
+            slave_id: self.shelly_slave_id,
+            read_timeout: Duration::from_millis(self.shelly_read_timeout_ms),
+        }
+    }
+
+    #[cfg(feature = "mqtt")]
+    pub fn mqtt(&self) -> crate::mqtt_reader::MqttConfig {
+        crate::mqtt_reader::MqttConfig {
+            broker: self.mqtt_broker.clone(),
+            topic: self.mqtt_power_topic.clone(),
+            json_path: self.mqtt_json_path.clone(),
+            stale_after: self.fallback_policy().meter_stale_after(),
+        }
+    }
+
+    pub fn home_assistant(&self) -> HaConfig {
+        HaConfig {
+            url: self.ha_url.clone(),
+            token: self.ha_token.clone(),
+            import_sensor: self.ha_extra_import.clone(),
+            export_sensor: self.ha_extra_export.clone(),
+            export_sign: self.ha_export_sign,
+            tls: HaTls {
+                insecure: self.ha_insecure_tls,
+                ca_cert: self.ha_ca_cert.clone(),
+            },
+            timeout: Duration::from_millis(self.ha_timeout_ms),
+            on_unavailable: self.ha_on_unavailable,
+        }
+    }
+
+    /// Whether the HA sensors are read, which needs them set up and HA not turned off
+    pub fn reads_home_assistant(&self) -> bool {
+        self.ha_enabled && self.home_assistant().has_sensors()
+    }
+
+    pub fn shelly_calibration(&self) -> Calibration {
+        Calibration {
+            gain: self.shelly_gain,
+            offset: self.shelly_offset,
+        }
+    }
+
+    pub fn power_model(&self) -> PowerModel {
+        PowerModel {
+            nominal_voltage: self.nominal_voltage,
+            power_factor: self.power_factor,
+        }
+    }
+
+    pub fn retry_policy(&self) -> BackoffPolicy {
+        BackoffPolicy {
+            base: Duration::from_millis(self.retry_base_ms),
+            factor: self.retry_factor,
+            max_retries: self.retry_max,
+            max_delay: Duration::from_millis(self.retry_max_delay_ms),
+            jitter: self.retry_jitter,
+        }
+    }
+
+    pub fn fallback_policy(&self) -> FallbackPolicy {
+        FallbackPolicy {
+            chain: self.fallback_chain.clone(),
+            stale_after: Duration::from_secs(self.stale_after_s),
+            meter_stale_after: self.shelly_stale_ms.map(Duration::from_millis),
+            last_good_hold: Duration::from_secs(self.last_good_hold_s),
+            degraded_value: self.degraded_value_w,
+        }
+    }
+
+    /// None when every combined value should be emitted
+    pub fn emit_policy(&self) -> Option<EmitPolicy> {
+        self.emit_threshold_w.map(|threshold| EmitPolicy {
+            threshold,
+            heartbeat: Duration::from_secs(self.emit_heartbeat_s),
+        })
+    }
+
+    pub fn emulator_options(&self) -> EmulatorOptions {
+        EmulatorOptions {
+            precision: Precision {
+                voltage_decimals: self.voltage_decimals,
+                current_decimals: self.current_decimals,
+                frequency_decimals: self.frequency_decimals,
+            },
+            unimplemented: self.unimplemented_exception,
+            unit_id: self.meter_unit_id,
+            topology: self.meter_topology,
+            float_layout: self.meter_float_layout,
+            scale_factors: self.scale_factors.clone(),
+            nominal_voltage: self.nominal_voltage,
+            nominal_frequency: self.nominal_frequency_hz,
+            ..Default::default()
+        }
+    }
+
+    /// The response faults to inject. None unless `FAULT_INJECTION` is set, so a stray setting
+    /// can't degrade a production meter.
+    pub fn fault_injection(&self) -> Option<FaultInjection> {
+        let configured =
+            self.response_delay_ms > 0 || self.response_jitter_ms > 0 || self.drop_prob > 0.0;
+        if !self.fault_injection {
+            if configured {
+                warn!("Ignoring response delay and drop settings without FAULT_INJECTION=true");
+            }
+            return None;
+        }
+        Some(FaultInjection {
+            delay: Duration::from_millis(self.response_delay_ms),
+            jitter: Duration::from_millis(self.response_jitter_ms),
+            drop_probability: self.drop_prob.clamp(0.0, 1.0),
+        })
+    }
+
+    /// None when connections may stay idle forever
+    pub fn modbus_idle_timeout(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.modbus_idle_timeout_s)).filter(|timeout| !timeout.is_zero())
+    }
+
+    /// None when any number of clients may connect
+    pub fn max_modbus_clients(&self) -> Option<usize> {
+        Some(self.max_modbus_clients).filter(|max| *max != 0)
+    }
+}
+
+/// Parses a setting, using the default when unset or invalid
+fn parse_or<T: FromStr>(lookup: impl Fn(&str) -> Option<String>, name: &str, default: T) -> T {
+    lookup(name)
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn known_config() -> Config {
+        let vars = HashMap::from([
+            ("SHELLY_MODBUS", "192.168.1.20:502"),
+            ("SHELLY_PHASE_CURRENT", "true"),
+            ("PHASE_TOTAL_MODE", "sum_phases_to_total"),
+            ("HA_URL", "http://homeassistant.local:8123"),
+            ("HA_TOKEN", "very-secret"),
+            ("HA_EXTRA_EXPORT", "sensor.virtual_export"),
+            ("HA_EXPORT_SIGN", "negative"),
+            ("HA_OFFSET_SIGN", "subtract"),
+            ("HA_ON_UNAVAILABLE", "zero"),
+            ("HA_OFFSET_MAX", "2500.5"),
+            ("HA_OFFSET_POLICY", "drop"),
+            ("FALLBACK_CHAIN", "live,last_good,degraded"),
+            ("STALE_AFTER_S", "3"),
+            ("OUTPUT_RATE_HZ", "2.5"),
+            ("MODBUS_FRAMING", "strict"),
+            ("SCALE_FACTORS", "TotalRealPower=0.001"),
+            ("NOMINAL_FREQUENCY_HZ", "60"),
+            ("METER_FLOAT_LAYOUT", "word_swap"),
+            ("OFFSET_DISTRIBUTION", "phase_a"),
+        ]);
+        Config::from_lookup(|name| vars.get(name).map(|value| value.to_string())).unwrap()
+    }
+
+    #[test]
+    fn test_builder() {
+        let config = Config::builder()
+            .var("SHELLY_MODBUS", "10.0.0.5:502")
+            .var("HA_SMOOTH", "true")
+            .var("POLL_INTERVAL_MS", "250")
+            .build()
+            .unwrap();
+        assert_eq!(
+            config,
+            Config {
+                shelly_modbus: "10.0.0.5:502".to_string(),
+                ha_smooth: true,
+                poll_interval_ms: 250,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_unusable_settings_are_errors() {
+        let error = Config::builder()
+            .var("FALLBACK_CHAIN", "live,sometimes")
+            .build()
+            .unwrap_err();
+        assert_eq!(error.name, "FALLBACK_CHAIN");
+        let error = Config::builder()
+            .var("SCALE_FACTORS", "TotalRealPower")
+            .build()
+            .unwrap_err();
+        assert_eq!(error.name, "SCALE_FACTORS");
+        let config = Config::builder()
+            .var("SHELLY_MODBUS", "serial:///dev/ttyUSB0?baud=19200")
+            .build()
+            .unwrap();
+        assert_eq!(config.shelly_modbus, "serial:///dev/ttyUSB0?baud=19200");
+        let error = Config::builder()
+            .var("SHELLY_MODBUS", "shelly.local")
+            .build()
+            .unwrap_err();
+        assert_eq!(error.name, "SHELLY_MODBUS");
+        let error = Config::builder()
+            .var("SHELLY_MODBUS", "192.168.1.20:502,shelly.local")
+            .build()
+            .unwrap_err();
+        assert_eq!(error.name, "SHELLY_MODBUS");
+        let error = Config::builder()
+            .var("SHELLY_POWER_REGISTER", "65535")
+            .build()
+            .unwrap_err();
+        assert_eq!(error.name, "SHELLY_POWER_REGISTER");
+        let error = Config::builder()
+            .var("HA_POLL_MS", "0")
+            .build()
+            .unwrap_err();
+        assert_eq!(error.name, "HA_POLL_MS");
+        let error = Config::builder()
+            .var("HA_TIMEOUT_MS", "0")
+            .build()
+            .unwrap_err();
+        assert_eq!(error.name, "HA_TIMEOUT_MS");
+        let error = Config::builder()
+            .var("PRIME_HA_ZERO_MS", "0")
+            .build()
+            .unwrap_err();
+        assert_eq!(error.name, "PRIME_HA_ZERO_MS");
+        let error = Config::builder()
+            .var("SHELLY_READ_TIMEOUT_MS", "0")
+            .build()
+            .unwrap_err();
+        assert_eq!(error.name, "SHELLY_READ_TIMEOUT_MS");
+        let error = Config::builder()
+            .var("SHELLY_STALE_MS", "0")
+            .build()
+            .unwrap_err();
+        assert_eq!(error.name, "SHELLY_STALE_MS");
+        let error = Config::builder()
+            .var("RETRY_FACTOR", "0.5")
+            .build()
+            .unwrap_err();
+        assert_eq!(error.name, "RETRY_FACTOR");
+        let error = Config::builder()
+            .var("REPLAY_SPEED", "0")
+            .build()
+            .unwrap_err();
+        assert_eq!(error.name, "REPLAY_SPEED");
+        let error = Config::builder()
+            .var("READINGS_CHANNEL_CAP", "0")
+            .build()
+            .unwrap_err();
+        assert_eq!(error.name, "READINGS_CHANNEL_CAP");
+        let error = Config::builder()
+            .var("RETRY_JITTER", "1.5")
+            .build()
+            .unwrap_err();
+        assert_eq!(error.name, "RETRY_JITTER");
+        let error = Config::builder()
+            .var("SHELLY_GAIN", "NaN")
+            .build()
+            .unwrap_err();
+        assert_eq!(error.name, "SHELLY_GAIN");
+        let error = Config::builder()
+            .var("HA_OFFSET_MIN", "500")
+            .var("HA_OFFSET_MAX", "-500")
+            .build()
+            .unwrap_err();
+        assert_eq!(error.name, "HA_OFFSET_MIN");
+        let error = Config::builder()
+            .var("HA_OFFSET_MAX", "NaN")
+            .build()
+            .unwrap_err();
+        assert_eq!(error.name, "HA_OFFSET_MIN");
+        for rate in ["fast", "0", "1e-30", "1e10"] {
+            let error = Config::builder()
+                .var("OUTPUT_RATE_HZ", rate)
+                .build()
+                .unwrap_err();
+            assert_eq!(error.name, "OUTPUT_RATE_HZ", "{rate}");
+        }
+        // Single values still fall back to their defaults
+        let config = Config::builder()
+            .var("POLL_INTERVAL_MS", "fast")
+            .build()
+            .unwrap();
+        assert_eq!(config.poll_interval_ms, Config::default().poll_interval_ms);
+    }
+
+    #[test]
+    fn test_resolves_known_config() {
+        let config = known_config();
+        assert_eq!(config.shelly_modbus, "192.168.1.20:502");
+        assert!(config.shelly_phase_current);
+        assert!(!config.shelly_phase_voltage);
+        assert_eq!(config.phase_total_mode, PhaseTotalMode::SumPhasesToTotal);
+        assert_eq!(config.offset_distribution, OffsetDistribution::PhaseA);
+        assert_eq!(config.ha_export_sign, ExportSign::Negative);
+        assert_eq!(config.ha_offset_sign, OffsetSign::Subtract);
+        assert_eq!(config.ha_on_unavailable, UnavailablePolicy::Zero);
+        assert_eq!(config.ha_offset_min, f32::NEG_INFINITY);
+        assert_eq!(config.ha_offset_max, 2500.5);
+        assert_eq!(
+            config.fallback_chain,
+            vec![
+                FallbackTier::Live,
+                FallbackTier::LastGood,
+                FallbackTier::Degraded
+            ]
+        );
+        assert_eq!(config.stale_after_s, 3);
+        assert_eq!(config.output_rate_hz, Some(2.5));
+        assert_eq!(config.modbus_framing, FramingMode::Strict);
+        assert_eq!(config.scale_factors.get("TotalRealPower"), Some(&0.001));
+        assert_eq!(config.nominal_frequency_hz, 60.0);
+        assert_eq!(config.nominal_voltage, 230.0);
+        assert_eq!(config.emulator_options().nominal_frequency, 60.0);
+        assert_eq!(config.meter_float_layout, FloatLayout::Cdab);
+        // The Shelly keeps its own layout
+        assert_eq!(config.shelly_float_layout, FloatLayout::Cdab);
+        assert_eq!(Config::default().meter_float_layout, FloatLayout::Abcd);
+        assert!(config.ha_enabled);
+        assert!(config.reads_home_assistant());
+        let config = Config::builder()
+            .var("HA_EXTRA_IMPORT", "sensor.virtual_import")
+            .var("HA_ENABLED", "False")
+            .build()
+            .unwrap();
+        assert!(!config.ha_enabled);
+        assert!(!config.reads_home_assistant());
+        assert_eq!(
+            Config::default().home_assistant().timeout,
+            Duration::from_secs(5)
+        );
+        assert_eq!(
+            Config::default().shelly().read_timeout,
+            Duration::from_secs(2)
+        );
+        let config = Config::builder()
+            .var("SHELLY_READ_TIMEOUT_MS", "500")
+            .build()
+            .unwrap();
+        assert_eq!(config.shelly().read_timeout, Duration::from_millis(500));
+        assert_eq!(Config::default().prime_ha_zero_ms, None);
+        let config = Config::builder()
+            .var("PRIME_HA_ZERO_MS", "3000")
+            .build()
+            .unwrap();
+        assert_eq!(config.prime_ha_zero_ms, Some(3000));
+        let config = Config::builder()
+            .var("HA_TIMEOUT_MS", "1500")
+            .build()
+            .unwrap();
+        assert_eq!(config.home_assistant().timeout, Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn test_fault_injection_needs_the_flag() {
+        let config = Config {
+            response_delay_ms: 100,
+            response_jitter_ms: 20,
+            drop_prob: 0.1,
+            ..Default::default()
+        };
+        assert_eq!(config.fault_injection(), None);
+        let config = Config {
+            fault_injection: true,
+            ..config
+        };
+        assert_eq!(
+            config.fault_injection(),
+            Some(FaultInjection {
+                delay: Duration::from_millis(100),
+                jitter: Duration::from_millis(20),
+                drop_probability: 0.1,
+            })
+        );
+    }
+
+    #[test]
+    fn test_dump_round_trips() {
+        for config in [known_config(), Config::default()] {
+            let dumped = config.to_toml(true);
+            let parsed: Config = toml::from_str(&dumped).unwrap();
+            assert_eq!(parsed, config, "{dumped}");
+        }
+    }
+
+    #[test]
+    fn test_dump_redacts_secrets() {
+        let config = known_config();
+        let dumped = config.to_toml(false);
+        assert!(!dumped.contains("very-secret"));
+        let parsed: Config = toml::from_str(&dumped).unwrap();
+        assert_eq!(parsed.ha_token, REDACTED);
+        assert_eq!(
+            Config {
+                ha_token: config.ha_token.clone(),
+                ..parsed
+            },
+            config
+        );
+    }
+}
@@ -0,0 +1,133 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+// Every Modbus client gets its own connection task, so a misbehaving one opening connections in
+// a loop could otherwise exhaust a small device. Connections are counted while their stream lives.
+
+/// Caps how many Modbus clients are served at once, cheap to clone and share
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionLimit {
+    active: Arc<AtomicUsize>,
+    /// None for no limit
+    max: Option<usize>,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: Option<usize>) -> Self {
+        Self {
+            active: Arc::default(),
+            max,
+        }
+    }
+
+    /// Connections currently open
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Counts a new connection until the returned permit is dropped.
+    /// None if the limit has already been reached.
+    pub fn try_acquire(&self) -> Option<ConnectionPermit> {
+        let max = self.max.unwrap_or(usize::MAX);
+        self.active
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |active| {
+                (active < max).then_some(active + 1)
+            })
+            .ok()?;
+        Some(ConnectionPermit {
+            active: self.active.clone(),
+        })
+    }
+}
+
+/// One counted connection, released on drop
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    active: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A stream holding the permit for its connection, so the connection is counted until it closes
+pub struct PermittedStream<S> {
+    inner: S,
+    _permit: ConnectionPermit,
+}
+
+impl<S> PermittedStream<S> {
+    pub fn new(inner: S, permit: ConnectionPermit) -> Self {
+        Self {
+            inner,
+            _permit: permit,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PermittedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PermittedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permits_are_limited_and_released() {
+        let limit = ConnectionLimit::new(Some(2));
+        let first = limit.try_acquire().unwrap();
+        let second = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+        assert_eq!(limit.active(), 2);
+        drop(first);
+        assert_eq!(limit.active(), 1);
+        let _third = limit.try_acquire().unwrap();
+        drop(second);
+        assert_eq!(limit.active(), 1);
+    }
+
+    #[test]
+    fn test_no_limit() {
+        let limit = ConnectionLimit::new(None);
+        let permits: Vec<_> = (0..1000).map(|_| limit.try_acquire().unwrap()).collect();
+        assert_eq!(limit.active(), 1000);
+        drop(permits);
+        assert_eq!(limit.active(), 0);
+    }
+}
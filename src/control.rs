@@ -0,0 +1,197 @@
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    routing::{get, post},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+use crate::{power_combiner::PowerOverride, SmartMeterEmulator};
+
+// A small HTTP control plane for debugging and manual testing: read back what the inverter sees,
+// and pin the reported power for a while, e.g. to check the inverter curtails on export.
+
+/// How long an override lasts when the request doesn't say
+pub const DEFAULT_OVERRIDE_TTL: Duration = Duration::from_secs(60);
+
+/// The body of `POST /override`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct OverrideRequest {
+    pub watts: f32,
+    /// Seconds until live data resumes, `DEFAULT_OVERRIDE_TTL` if unset
+    pub ttl_s: Option<u64>,
+}
+
+#[derive(Clone)]
+struct ControlState {
+    meter: SmartMeterEmulator,
+    power_override: PowerOverride,
+}
+
+/// Serves the control API for `meter` at `socket_addr` until `shutdown` is cancelled.
+/// `GET /readings` returns the measurement fields as the inverter would decode them,
+/// `POST /override` pins the reported power and `DELETE /override` resumes live data.
+pub async fn serve(
+    socket_addr: SocketAddr,
+    meter: SmartMeterEmulator,
+    power_override: PowerOverride,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    info!("Serving the control API on {socket_addr}");
+    let listener = TcpListener::bind(socket_addr).await?;
+    serve_on(listener, meter, power_override, shutdown).await
+}
+
+async fn serve_on(
+    listener: TcpListener,
+    meter: SmartMeterEmulator,
+    power_override: PowerOverride,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    let app = Router::new()
+        .route("/readings", get(readings))
+        .route("/override", post(pin_power).delete(clear_override))
+        .with_state(ControlState {
+            meter,
+            power_override,
+        });
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move { shutdown.cancelled().await })
+        .await?;
+    Ok(())
+}
+
+async fn readings(State(state): State<ControlState>) -> Json<BTreeMap<&'static str, f32>> {
+    Json(state.meter.current_readings().await)
+}
+
+async fn pin_power(
+    State(state): State<ControlState>,
+    Json(request): Json<OverrideRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if !request.watts.is_finite() {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("{}W can't be reported", request.watts),
+        ));
+    }
+    let ttl = request
+        .ttl_s
+        .map_or(DEFAULT_OVERRIDE_TTL, Duration::from_secs);
+    let Some(until) = Instant::now().checked_add(ttl) else {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("A TTL of {ttl:?} runs past the end of time"),
+        ));
+    };
+    info!(
+        "Overriding the reported power with {}W for {ttl:?}",
+        request.watts
+    );
+    state.power_override.pin(request.watts, until);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn clear_override(State(state): State<ControlState>) -> StatusCode {
+    info!("Override cleared, resuming live data");
+    state.power_override.clear();
+    StatusCode::NO_CONTENT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Readings;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn request(socket_addr: SocketAddr, method: &str, path: &str, body: &str) -> String {
+        let mut stream = tokio::net::TcpStream::connect(socket_addr).await.unwrap();
+        let request = format!(
+            "{method} {path} HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\
+             Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    fn status_line(response: &str) -> &str {
+        response.lines().next().unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn test_override_then_resume() {
+        let (meter, update_handle) = SmartMeterEmulator::new();
+        let power_override = PowerOverride::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket_addr = listener.local_addr().unwrap();
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn(serve_on(
+            listener,
+            meter,
+            power_override.clone(),
+            shutdown.clone(),
+        ));
+
+        update_handle
+            .send(Readings::TotalRealPower(750.0))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let response = request(socket_addr, "GET", "/readings", "").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 200 OK");
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        let readings: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(readings["TotalRealPower"], 750.0);
+
+        let now = Instant::now();
+        let response = request(
+            socket_addr,
+            "POST",
+            "/override",
+            r#"{"watts": -2500, "ttl_s": 1}"#,
+        )
+        .await;
+        assert_eq!(status_line(&response), "HTTP/1.1 204 No Content");
+        assert_eq!(power_override.active(now), Some(-2500.0));
+        // Live data resumes after the TTL
+        assert_eq!(power_override.active(now + Duration::from_secs(2)), None);
+
+        request(socket_addr, "POST", "/override", r#"{"watts": 100}"#).await;
+        assert_eq!(
+            power_override.active(now + Duration::from_secs(30)),
+            Some(100.0)
+        );
+        let response = request(socket_addr, "DELETE", "/override", "").await;
+        assert_eq!(status_line(&response), "HTTP/1.1 204 No Content");
+        assert_eq!(power_override.active(now), None);
+
+        let response = request(socket_addr, "POST", "/override", r#"{"power": 1}"#).await;
+        assert!(status_line(&response).starts_with("HTTP/1.1 4"));
+        assert_eq!(power_override.active(now), None);
+
+        let response = request(
+            socket_addr,
+            "POST",
+            "/override",
+            &format!(r#"{{"watts": 100, "ttl_s": {}}}"#, u64::MAX),
+        )
+        .await;
+        assert_eq!(status_line(&response), "HTTP/1.1 422 Unprocessable Entity");
+        assert_eq!(power_override.active(now), None);
+
+        shutdown.cancel();
+        server.await.unwrap().unwrap();
+    }
+}
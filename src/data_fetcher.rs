@@ -1,132 +1,1835 @@
-use std::{env, time::Duration};
+use std::{
+    fmt,
+    path::Path,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use tracing::{debug, error, info, warn};
 
 use crate::{
-    home_assistant::HomeAssistantAPI, rolling_average::RollingAverage,
-    shelly_3em_client::Shelly3EMClient, smart_meter_emulator::Readings,
+    config::Config,
+    events::{spawn_event_logger, EventBus},
+    health::HealthStatus,
+    home_assistant::HomeAssistantReader,
+    offset_file_reader::OffsetFileReader,
+    offset_limiter::OffsetLimiter,
+    output_scheduler::{period_from_rate, OutputScheduler},
+    poll_reliability::{PollReliability, RELIABILITY_WINDOW},
+    power_combiner::{grid_flow, FallbackTier, PowerCombiner, PowerOverride},
+    power_model::{phase_powers, OffsetDistribution, PhaseTotalMode, PowerModel},
+    power_source::{Measurement, PolledEvery, PowerSource},
+    replay::{self, ReplayReader},
+    retry::Retried,
+    rolling_average::RollingAverage,
+    shelly_3em_client::{ShellyReader, ShellyTransport},
+    shelly_http::{self, ShellyHttpReader},
+    smart_meter_emulator::Readings,
+    smoothing::{ExponentialMovingAverage, Median, Smoother},
 };
-use tokio::{sync::mpsc::Sender, time};
+use tokio::{
+    sync::{
+        mpsc::{
+            error::{SendError, TrySendError},
+            Sender,
+        },
+        watch,
+    },
+    task::JoinHandle,
+    time,
+};
+use tokio_util::sync::CancellationToken;
 
 // Implements reading the Shelly unit and then adjusting power metrics
 
-pub struct DataFetcher {}
+/// Saving the combiner state at most this often keeps disk writes down on SD card installs
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Total power steps shown at startup with POST_SEQUENCE, so an installer can see the link is live
+const POST_SEQUENCE_W: [f32; 3] = [0.0, 1000.0, 0.0];
+
+/// Why the sources set up in the config can't be read
+#[derive(Debug, Clone, PartialEq)]
+pub enum FetcherError {
+    /// Nothing to read the grid power from
+    MissingShellyAddress,
+    InvalidShellyAddress {
+        address: String,
+        reason: String,
+    },
+    /// A `replay://` trace that couldn't be read
+    InvalidReplayTrace {
+        path: String,
+        reason: String,
+    },
+    /// `HA_INSECURE_TLS` or `HA_CA_CERT` couldn't be used
+    InvalidHaTls(String),
+}
+
+impl fmt::Display for FetcherError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingShellyAddress => {
+                write!(
+                    f,
+                    "SHELLY_MODBUS must be set to the Shelly's Modbus address"
+                )
+            }
+            Self::InvalidShellyAddress { address, reason } => {
+                write!(f, "Invalid SHELLY_MODBUS address `{address}`: {reason}")
+            }
+            Self::InvalidReplayTrace { path, reason } => {
+                write!(f, "Can't replay `{path}`: {reason}")
+            }
+            Self::InvalidHaTls(reason) => write!(f, "Unusable HA TLS settings: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for FetcherError {}
+
+/// What the meters last measured besides the total power
+#[derive(Debug, Clone, Copy, Default)]
+struct MeterLines {
+    phase_watts: Option<[f32; 3]>,
+    phase_voltages: Option<[f32; 3]>,
+    frequency: Option<f32>,
+}
+
+pub struct DataFetcher {
+    task: JoinHandle<()>,
+    shutdown: CancellationToken,
+    health: HealthStatus,
+    power_override: PowerOverride,
+}
 
 impl DataFetcher {
-    pub fn new(output: Sender<Readings>) -> Self {
-        tokio::spawn(async move {
-            Self::worker(output).await;
+    /// Reads from the sources set up in `config`, once it has checked they can be connected to
+    pub fn new(output: Sender<Readings>, config: Config) -> Result<Self, FetcherError> {
+        Self::check_sources(&config)?;
+        let shutdown = CancellationToken::new();
+        let worker_shutdown = shutdown.clone();
+        let health = HealthStatus::default();
+        let worker_health = health.clone();
+        let power_override = PowerOverride::default();
+        let worker_override = power_override.clone();
+        let task = tokio::spawn(async move {
+            // Connecting can take a while against an unreachable device, so allow stopping it too
+            let sources = worker_shutdown
+                .run_until_cancelled(Self::configured_sources(&config))
+                .await;
+            if let Some(sources) = sources {
+                Self::worker(
+                    output,
+                    config,
+                    sources,
+                    worker_health,
+                    worker_override,
+                    worker_shutdown,
+                )
+                .await;
+            }
         });
-        Self {}
+        Ok(Self {
+            task,
+            shutdown,
+            health,
+            power_override,
+        })
     }
 
-    async fn worker(output: Sender<Readings>) {
-        // 1. Open link to read from Shelly Unit
-        // 2. Open link to read from HA
-        let home_assistant_extra_import_sensor = env::var("HA_EXTRA_IMPORT").unwrap_or_default();
-        let home_assistant_extra_export_sensor = env::var("HA_EXTRA_EXPORT").unwrap_or_default();
-        let shelly_modbus =
-            env::var("SHELLY_MODBUS").expect("Required to add Shelly modbus connection info");
-
-        println!("Connecting to shelly `{shelly_modbus}`");
-        let mut shelly_client = Shelly3EMClient::new(shelly_modbus.parse().unwrap()).await;
-        let mut home_assistant_client = HomeAssistantAPI::new();
-
-        println!("Running");
-        let should_smooth = parse_bool_safe(env::var("HA_SMOOTH").ok());
-        let mut filtered_ha_offset = RollingAverage::default();
-        let mut interval = time::interval(Duration::from_millis(500));
-        loop {
-            // Now we read the shelly, and also read the HA offset
-            let shelly_net_power = shelly_client.read_total_power().await;
-            let ha_import = Self::read_ha_sensor_or_null(
-                &home_assistant_extra_import_sensor,
-                &mut home_assistant_client,
-            )
-            .await;
-            let ha_export = Self::read_ha_sensor_or_null(
-                &home_assistant_extra_export_sensor,
-                &mut home_assistant_client,
+    /// Catches the mistakes in setting up the sources that would otherwise stop them connecting
+    pub fn check_sources(config: &Config) -> Result<(), FetcherError> {
+        if config.offset_file.is_empty() && config.reads_home_assistant() {
+            HomeAssistantReader::new(config.home_assistant())
+                .map_err(|e| FetcherError::InvalidHaTls(format!("{e:#}")))?;
+        }
+        // MQTT replaces the Shellys when set up
+        #[cfg(feature = "mqtt")]
+        if !config.mqtt_power_topic.is_empty() {
+            return Ok(());
+        }
+        let shelly = config.shelly();
+        if shelly.address.trim().is_empty() {
+            return Err(FetcherError::MissingShellyAddress);
+        }
+        for device in shelly.devices() {
+            if let Some(path) = replay::trace_path(&device.address) {
+                replay::load_trace(path).map_err(|reason| FetcherError::InvalidReplayTrace {
+                    path: path.to_string(),
+                    reason,
+                })?;
+                continue;
+            }
+            if shelly_http::device_url(&device.address).is_some() {
+                continue;
+            }
+            ShellyTransport::parse(&device.address, device.protocol).map_err(|reason| {
+                FetcherError::InvalidShellyAddress {
+                    address: device.address.clone(),
+                    reason,
+                }
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Reads from the given sources instead of those set up in the config
+    pub fn with_sources(
+        output: Sender<Readings>,
+        config: Config,
+        sources: Vec<Box<dyn PowerSource>>,
+    ) -> Self {
+        let shutdown = CancellationToken::new();
+        let worker_shutdown = shutdown.clone();
+        let health = HealthStatus::default();
+        let worker_health = health.clone();
+        let power_override = PowerOverride::default();
+        let worker_override = power_override.clone();
+        let task = tokio::spawn(async move {
+            Self::worker(
+                output,
+                config,
+                sources,
+                worker_health,
+                worker_override,
+                worker_shutdown,
             )
             .await;
-            let ha_offset = if should_smooth {
-                filtered_ha_offset.add(ha_import - ha_export)
-            } else {
-                ha_import - ha_export
+        });
+        Self {
+            task,
+            shutdown,
+            health,
+            power_override,
+        }
+    }
+
+    /// Cancelled when the fetcher is shut down, for other tasks to stop along with it
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Readiness of the combined power, for the health checks to report
+    pub fn health(&self) -> HealthStatus {
+        self.health.clone()
+    }
+
+    /// Pins the reported power while set, for the control API
+    pub fn power_override(&self) -> PowerOverride {
+        self.power_override.clone()
+    }
+
+    /// Stops reading and waits for the fetcher's tasks to finish
+    pub async fn shutdown(mut self) {
+        self.shutdown.cancel();
+        self.stopped().await;
+    }
+
+    /// Completes if the fetcher stops, which only happens when it can no longer update the meter.
+    /// The process can't do anything useful from then on, so the caller should exit.
+    pub async fn stopped(&mut self) {
+        if let Err(e) = (&mut self.task).await {
+            error!("Data fetcher failed: {e}");
+        }
+    }
+
+    async fn configured_sources(config: &Config) -> Vec<Box<dyn PowerSource>> {
+        // 1. Open link to read from each Shelly Unit
+        // 2. Open link to read from HA
+        let mut sources: Vec<Box<dyn PowerSource>> = Vec::new();
+        // MQTT replaces the Shellys when set up
+        #[cfg(feature = "mqtt")]
+        if !config.mqtt_power_topic.is_empty() {
+            sources.push(Box::new(crate::mqtt_reader::MqttReader::connect(
+                config.mqtt(),
+            )));
+        }
+        let devices = if sources.is_empty() {
+            config.shelly().devices()
+        } else {
+            Vec::new()
+        };
+        let name_by_address = devices.len() > 1;
+        for device in devices {
+            if let Some(path) = replay::trace_path(&device.address) {
+                match replay::load_trace(path) {
+                    Ok(samples) => {
+                        sources.push(Box::new(ReplayReader::start(samples, config.replay_speed)))
+                    }
+                    Err(e) => error!("Can't replay `{path}`: {e}"),
+                }
+                continue;
+            }
+            let reader: Box<dyn PowerSource> = match shelly_http::device_url(&device.address) {
+                Some(url) => {
+                    let mut reader = ShellyHttpReader::new(url, &device);
+                    if name_by_address {
+                        reader = reader.with_name(format!("Shelly {url}"));
+                    }
+                    Box::new(reader)
+                }
+                None => {
+                    let mut reader = ShellyReader::connect(&device).await;
+                    if name_by_address {
+                        reader = reader.with_name(format!("Shelly {}", device.address));
+                    }
+                    Box::new(reader)
+                }
             };
-            let summed_power = shelly_net_power.expect("Didn't get shelly power") + ha_offset;
-            println!(
-                "Summed power {summed_power}W, shelly {:?}W, HA Import {}W Export {}W",
-                shelly_net_power, ha_import, ha_export
-            );
-            Self::send_power(summed_power, &output).await;
-            interval.tick().await; // Wait for next sample time
+            let reader = Retried::new(reader, config.retry_policy());
+            sources.push(Self::polled(Box::new(reader), config.shelly_poll_ms));
         }
+
+        // The offset file replaces the HA sensors when both are set up
+        if !config.offset_file.is_empty() {
+            if config.reads_home_assistant() {
+                info!("OFFSET_FILE is set, ignoring the HA sensors");
+            }
+            sources.push(Box::new(OffsetFileReader::new(&config.offset_file)));
+        } else if config.reads_home_assistant() {
+            match HomeAssistantReader::new(config.home_assistant()) {
+                Ok(reader) => {
+                    let reader = Retried::new(Box::new(reader), config.retry_policy());
+                    sources.push(Self::polled(Box::new(reader), config.ha_poll_ms));
+                }
+                Err(e) => error!("Unusable HA TLS settings: {e:#}"),
+            }
+        } else if config.home_assistant().has_sensors() {
+            info!("HA_ENABLED is false, ignoring the HA sensors");
+        }
+        sources
     }
-    async fn read_ha_sensor_or_null(
-        sensor_name: &str,
-        home_assistant_client: &mut HomeAssistantAPI,
-    ) -> f32 {
-        let home_assistant_offset_str = home_assistant_client.read_sensor_value(sensor_name).await;
-        let ha_offset: f32 = match home_assistant_offset_str {
-            Ok(res) => res.state.parse().unwrap_or_default(),
-            Err(e) => {
-                println!("Didn't read HA offset {e:?}");
-                0.0
+
+    /// Polls `source` every `interval_ms` if set, rather than every cycle
+    fn polled(source: Box<dyn PowerSource>, interval_ms: Option<u64>) -> Box<dyn PowerSource> {
+        match interval_ms {
+            Some(interval_ms) => {
+                Box::new(PolledEvery::new(source, Duration::from_millis(interval_ms)))
+            }
+            None => source,
+        }
+    }
+
+    /// The cycle period, fast enough for the most frequently polled source,
+    /// and how many cycles apart each source is polled.
+    /// Sources without their own interval are polled every `poll_interval`.
+    fn poll_schedule(
+        poll_interval: Duration,
+        sources: &[Box<dyn PowerSource>],
+    ) -> (Duration, Vec<usize>) {
+        let period = sources
+            .iter()
+            .filter_map(|source| source.poll_interval())
+            .fold(poll_interval, Duration::min)
+            .max(Duration::from_millis(1));
+        let cycles = sources
+            .iter()
+            .map(|source| {
+                let interval = source.poll_interval().unwrap_or(poll_interval);
+                let cycles = (interval.as_secs_f64() / period.as_secs_f64()).round() as usize;
+                let cycles = cycles.max(1);
+                info!(
+                    "Polling {} every {:?}",
+                    source.name(),
+                    period * cycles as u32
+                );
+                cycles
+            })
+            .collect();
+        (period, cycles)
+    }
+
+    async fn worker(
+        output: Sender<Readings>,
+        config: Config,
+        mut sources: Vec<Box<dyn PowerSource>>,
+        health: HealthStatus,
+        power_override: PowerOverride,
+        shutdown: CancellationToken,
+    ) {
+        info!("Running");
+        if config.post_sequence {
+            let step = Duration::from_millis(config.post_step_ms);
+            if Self::run_post_sequence(&output, step, &config.power_model())
+                .await
+                .is_err()
+            {
+                Self::report_meter_closed();
+                return;
+            }
+        }
+        // The median runs first, so spikes are rejected before they reach the average
+        let should_smooth = config.ha_smooth && config.ha_smooth_mode.uses_mean();
+        let mut median_prefilter = (config.ha_smooth && config.ha_smooth_mode.uses_median())
+            .then(|| Median::new(config.ha_smooth_median_window));
+        let mut ema = (config.ha_smooth && config.ha_smooth_mode.uses_ema())
+            .then(|| ExponentialMovingAverage::new(config.ha_smooth_ema_alpha));
+        let send_phase_currents = config.shelly_phase_current;
+        let phase_total_mode = config.phase_total_mode;
+        let offset_distribution = config.offset_distribution;
+        let power_model = config.power_model();
+        let output_when_full = config.output_when_full;
+        let events = EventBus::new();
+        spawn_event_logger(&events);
+        let smoothing_state = config
+            .smooth_persist
+            .then(|| Path::new(&config.smooth_persist_path));
+        let mut filtered_ha_offset = Self::restore_smoothing(
+            smoothing_state,
+            config.ha_smooth_window,
+            Duration::from_secs(config.smooth_persist_max_age_s),
+        );
+        let mut samples_since_save = 0;
+        let mut offset_limiter = OffsetLimiter::new(
+            config.ha_offset_min,
+            config.ha_offset_max,
+            config.ha_offset_policy,
+        )
+        .with_events(events.clone());
+        let calibration = config.shelly_calibration();
+        let mut combiner = PowerCombiner::new(config.fallback_policy())
+            .with_events(events)
+            .with_calibration(calibration)
+            .with_offset_sign(config.ha_offset_sign)
+            .with_override(power_override);
+        let needs_offset = config.reads_home_assistant() || !config.offset_file.is_empty();
+        let combiner_state =
+            (!config.persist_path.is_empty()).then(|| Path::new(&config.persist_path));
+        if let Some(path) = combiner_state {
+            match combiner.load_state(path, Instant::now(), needs_offset) {
+                Ok(true) => info!("Restored the last readings from {path:?}"),
+                Ok(false) => {}
+                Err(e) => warn!("Ignoring unreadable state {path:?}: {e}"),
+            }
+        }
+        let mut last_state_save: Option<Instant> = None;
+        #[cfg(feature = "metrics")]
+        let metrics = config.metrics_port.map(|port| {
+            let registry = crate::metrics::Registry::default();
+            let socket_addr = std::net::SocketAddr::from(([0, 0, 0, 0], port));
+            let server = crate::metrics::serve(socket_addr, registry.clone(), shutdown.clone());
+            tokio::spawn(async move {
+                if let Err(e) = server.await {
+                    error!("Metrics server failed: {e}");
+                }
+            });
+            registry
+        });
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &metrics {
+            combiner = combiner.with_metrics(metrics.clone());
+        }
+        if let Some(emit_policy) = config.emit_policy() {
+            combiner = combiner.with_emit_policy(emit_policy);
+        }
+        // With a fixed output rate the scheduler does the sending, otherwise send as we go
+        let (scheduled_output, scheduler_task) =
+            match config.output_rate_hz.and_then(period_from_rate) {
+                Some(period) => {
+                    info!("Emitting readings every {period:?}");
+                    let (latest_tx, latest_rx) = watch::channel(None);
+                    let task = OutputScheduler::spawn(latest_rx, output.clone(), period);
+                    (Some(latest_tx), Some(task))
+                }
+                None => (None, None),
+            };
+        let mut reliability = vec![PollReliability::default(); sources.len()];
+        let mut polls: usize = 0;
+        let (period, poll_every) =
+            Self::poll_schedule(Duration::from_millis(config.poll_interval_ms), &sources);
+        let mut interval = time::interval(period);
+        // The first tick is immediate, and the first cycle runs straight away anyway
+        interval.tick().await;
+        // Until an offset arrives or this passes, no source is waited on past it
+        let mut prime_deadline = config
+            .prime_ha_zero_ms
+            // One too far off to represent is never reached, so the same as none
+            .and_then(|ms| time::Instant::now().checked_add(Duration::from_millis(ms)));
+        // Kept for the cycles that only read other sources, so the phases don't flip between
+        // measured and evenly split values when the meters are polled less often
+        let mut lines = MeterLines::default();
+        loop {
+            // Now we read every source, the meters and the offsets
+            let mut grid_power: Option<f32> = None;
+            let mut phase_watts = None;
+            let mut phase_voltages = None;
+            let mut frequency = None;
+            let mut raw_offset: Option<f32> = None;
+            let sources_and_state = sources.iter_mut().zip(reliability.iter_mut());
+            for ((source, reliability), every) in sources_and_state.zip(&poll_every) {
+                if !polls.is_multiple_of(*every) {
+                    continue;
+                }
+                let result = match prime_deadline {
+                    Some(deadline) => match time::timeout_at(deadline, source.next()).await {
+                        Ok(result) => result,
+                        Err(_) => {
+                            info!("No reading from {} yet, going on without it", source.name());
+                            continue;
+                        }
+                    },
+                    None => source.next().await,
+                };
+                reliability.record(result.is_ok());
+                #[cfg(feature = "metrics")]
+                if let (Some(metrics), Some(percent)) = (&metrics, reliability.success_percent()) {
+                    metrics.set_poll_success_ratio(source.name(), percent / 100.0);
+                }
+                match result {
+                    Ok(Measurement::GridPower {
+                        watts,
+                        phase_watts: watts_per_phase,
+                        phase_voltages: voltages,
+                        frequency: measured_frequency,
+                    }) => {
+                        combiner.update_device_power(source.name(), watts, Instant::now());
+                        // Several meters add up, as do their phases while every meter has them
+                        phase_watts = match (grid_power, phase_watts, watts_per_phase) {
+                            (None, _, phases) => phases,
+                            (Some(_), Some(sum), Some(phases)) => {
+                                Some([0, 1, 2].map(|phase| sum[phase] + phases[phase]))
+                            }
+                            _ => None,
+                        };
+                        grid_power = Some(grid_power.unwrap_or_default() + watts);
+                        phase_voltages = phase_voltages.or(voltages);
+                        frequency = frequency.or(measured_frequency);
+                    }
+                    // Several offset sources add up
+                    Ok(Measurement::Offset(offset)) => {
+                        raw_offset = Some(raw_offset.unwrap_or_default() + offset)
+                    }
+                    Err(e) => {
+                        warn!("Didn't get a reading from {}: {e}", source.name());
+                        #[cfg(feature = "metrics")]
+                        if let Some(metrics) = &metrics {
+                            metrics.record_read_failure(source.name());
+                        }
+                    }
+                }
+            }
+            if grid_power.is_some() {
+                lines = MeterLines {
+                    phase_watts,
+                    phase_voltages,
+                    frequency,
+                };
+            }
+            if let Some(deadline) = prime_deadline {
+                if raw_offset.is_some() {
+                    prime_deadline = None;
+                } else if time::Instant::now() >= deadline {
+                    info!("No offset yet, counting it as 0W until one arrives");
+                    combiner.update_ha_offset(0.0, Instant::now());
+                    prime_deadline = None;
+                }
+            }
+            polls += 1;
+            if polls.is_multiple_of(RELIABILITY_WINDOW) {
+                Self::log_reliability(&sources, &reliability);
+            }
+            // Without a fresh offset, let it go stale
+            // Limit before smoothing so a bogus value can't linger in the average
+            if let Some(ha_offset) = raw_offset.and_then(|offset| offset_limiter.apply(offset)) {
+                let ha_offset = match median_prefilter.as_mut() {
+                    Some(median) => median.add(ha_offset),
+                    None => ha_offset,
+                };
+                let ha_offset = match ema.as_mut() {
+                    Some(ema) => ema.add(ha_offset),
+                    None => ha_offset,
+                };
+                let ha_offset = if should_smooth {
+                    let smoothed = filtered_ha_offset.add(ha_offset);
+                    // Saving once per window keeps disk writes down on SD card installs
+                    samples_since_save += 1;
+                    if let Some(path) = smoothing_state {
+                        if samples_since_save >= filtered_ha_offset.capacity() {
+                            samples_since_save = 0;
+                            if let Err(e) = filtered_ha_offset.save(path) {
+                                warn!("Failed to save smoothing state: {e}");
+                            }
+                        }
+                    }
+                    smoothed
+                } else {
+                    ha_offset
+                };
+                combiner.update_ha_offset(ha_offset, Instant::now());
             }
+            let combined_at = Instant::now();
+            let (summed_power, tier) = combiner.combine(combined_at);
+            // Once the meters have gone quiet what they last measured is no longer current
+            if tier != FallbackTier::Live {
+                lines = MeterLines::default();
+            }
+            health.set_ready(combiner.is_ready(needs_offset));
+            if let Some(path) = combiner_state {
+                let now = Instant::now();
+                if last_state_save.is_none_or(|at| now - at >= STATE_SAVE_INTERVAL) {
+                    last_state_save = Some(now);
+                    if let Err(e) = combiner.save_state(path) {
+                        warn!("Failed to save state: {e}");
+                    }
+                }
+            }
+            debug!("Summed power {summed_power}W ({tier:?}), grid {grid_power:?}W");
+            if should_smooth && !filtered_ha_offset.is_full() {
+                debug!(
+                    "Smoothing HA offset, {}/{} samples",
+                    filtered_ha_offset.len(),
+                    filtered_ha_offset.capacity()
+                );
+            }
+            if combiner.should_emit(summed_power, Instant::now()) {
+                // Everything from this cycle goes out as one batch, so the total and the phases
+                // are always written together
+                let mut readings = if send_phase_currents {
+                    Self::phase_readings(
+                        summed_power,
+                        // Calibrated like the total, so only the offsets are left to spread
+                        lines
+                            .phase_watts
+                            .map(|phases| calibration.apply_to_phases(phases)),
+                        lines.phase_voltages,
+                        phase_total_mode,
+                        offset_distribution,
+                        &power_model,
+                    )
+                } else {
+                    Self::power_readings(summed_power, &power_model)
+                };
+                readings.extend(Self::line_readings(lines.phase_voltages, lines.frequency));
+                // With no data left to fall back on, the meter stops answering rather than
+                // report a number nobody measured, unless a value has been pinned
+                let readings =
+                    if tier == FallbackTier::Unavailable && !combiner.is_overridden(combined_at) {
+                        Readings::Unavailable
+                    } else {
+                        Readings::Batch(readings)
+                    };
+                let meter_closed = match &scheduled_output {
+                    // The scheduler stops, dropping its receiver, once the meter is gone
+                    Some(latest) => latest.send(Some(readings)).is_err(),
+                    None => Self::send_output(&output, readings, output_when_full)
+                        .await
+                        .is_err(),
+                };
+                if meter_closed {
+                    Self::report_meter_closed();
+                    return;
+                }
+            }
+            // Wait for next sample time
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.cancelled() => break,
+            }
+        }
+        info!("Shutting down data fetcher");
+        // The latest readings may not have been saved yet
+        if let Some(path) = combiner_state {
+            if let Err(e) = combiner.save_state(path) {
+                warn!("Failed to save state: {e}");
+            }
+        }
+        // The scheduler stops once it sees its input has closed
+        drop(scheduled_output);
+        if let Some(task) = scheduler_task {
+            let _ = task.await;
+        }
+    }
+    /// Prints each source's success rate, e.g. "Shelly 97% over last 100 reads"
+    fn log_reliability(sources: &[Box<dyn PowerSource>], reliability: &[PollReliability]) {
+        for (source, reliability) in sources.iter().zip(reliability) {
+            if let Some(percent) = reliability.success_percent() {
+                info!(
+                    "{} {percent:.0}% over last {} reads",
+                    source.name(),
+                    reliability.len()
+                );
+            }
+        }
+    }
+
+    /// Steps the total power through `POST_SEQUENCE_W`, holding each value for `step`
+    async fn run_post_sequence(
+        output: &Sender<Readings>,
+        step: Duration,
+        power_model: &PowerModel,
+    ) -> Result<(), SendError<Readings>> {
+        for watts in POST_SEQUENCE_W {
+            info!("Self test, reporting {watts}W");
+            output
+                .send(Readings::Batch(Self::power_readings(watts, power_model)))
+                .await?;
+            time::sleep(step).await;
+        }
+        Ok(())
+    }
+
+    /// Sends `readings` to the meter, or drops them if it's behind and `when_full` allows.
+    /// Only fails once the meter is gone.
+    async fn send_output(
+        output: &Sender<Readings>,
+        readings: Readings,
+        when_full: OutputWhenFull,
+    ) -> Result<(), SendError<Readings>> {
+        match when_full {
+            OutputWhenFull::Block => output.send(readings).await,
+            OutputWhenFull::Drop => match output.try_send(readings) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(_)) => {
+                    warn!("The meter is falling behind, dropped an update");
+                    Ok(())
+                }
+                Err(TrySendError::Closed(readings)) => Err(SendError(readings)),
+            },
+        }
+    }
+
+    fn report_meter_closed() {
+        error!("The meter is no longer accepting readings, stopping the data fetcher");
+    }
+
+    /// Picks up the smoothing window from before a restart, if there is a recent one
+    fn restore_smoothing(path: Option<&Path>, window: usize, max_age: Duration) -> RollingAverage {
+        let Some(path) = path else {
+            return RollingAverage::with_capacity(window);
         };
-        ha_offset
+        match RollingAverage::restore(path, window, max_age) {
+            Ok(Some(restored)) => {
+                info!(
+                    "Restored {} smoothing samples from {path:?}",
+                    restored.len()
+                );
+                restored
+            }
+            Ok(None) => RollingAverage::with_capacity(window),
+            Err(e) => {
+                warn!("Ignoring unreadable smoothing state {path:?}: {e}");
+                RollingAverage::with_capacity(window)
+            }
+        }
     }
-    async fn send_power(summed_power: f32, output: &Sender<Readings>) {
-        output
-            .send(Readings::TotalRealPower(summed_power))
-            .await
-            .expect("Cant send readings to fake meter");
-        output
-            .send(Readings::ReactivePower(summed_power))
-            .await
-            .expect("Cant send readings to fake meter");
-        output
-            .send(Readings::NetACCurrent(summed_power))
-            .await
-            .expect("Cant send readings to fake meter");
+
+    /// Readings for the total power, and the per-phase powers and currents consistent with it
+    /// Without measured voltages the currents are derived from the nominal voltage
+    fn phase_readings(
+        summed_power: f32,
+        measured_phases: Option<[f32; 3]>,
+        phase_voltages: Option<[f32; 3]>,
+        mode: PhaseTotalMode,
+        distribution: OffsetDistribution,
+        power_model: &PowerModel,
+    ) -> Vec<Readings> {
+        if measured_phases.is_none() {
+            debug!("Didn't get phase powers, splitting the total evenly");
+        }
+        let (total, phase_watts) = phase_powers(summed_power, measured_phases, mode, distribution);
+        let [watts_a, watts_b, watts_c] = phase_watts;
+        let [current_a, current_b, current_c] =
+            power_model.phase_currents(phase_watts, phase_voltages);
+        let mut readings = Self::power_readings(total, power_model);
+        readings.extend([
+            Readings::PhaseAWatts(watts_a),
+            Readings::PhaseBWatts(watts_b),
+            Readings::PhaseCWatts(watts_c),
+            Readings::PhaseACurrent(current_a),
+            Readings::PhaseBCurrent(current_b),
+            Readings::PhaseCCurrent(current_c),
+            Readings::PhaseAVA(power_model.apparent_power(watts_a)),
+            Readings::PhaseBVA(power_model.apparent_power(watts_b)),
+            Readings::PhaseCVA(power_model.apparent_power(watts_c)),
+            Readings::PhaseAVAR(power_model.reactive_power(watts_a)),
+            Readings::PhaseBVAR(power_model.reactive_power(watts_b)),
+            Readings::PhaseCVAR(power_model.reactive_power(watts_c)),
+            Readings::PhaseAPF(power_model.signed_power_factor(watts_a)),
+            Readings::PhaseBPF(power_model.signed_power_factor(watts_b)),
+            Readings::PhaseCPF(power_model.signed_power_factor(watts_c)),
+        ]);
+        readings
+    }
+
+    /// Readings for the measured voltages and frequency, none for what wasn't measured
+    fn line_readings(phase_voltages: Option<[f32; 3]>, frequency: Option<f32>) -> Vec<Readings> {
+        let mut readings = Vec::new();
+        if let Some(voltages @ [voltage_a, voltage_b, voltage_c]) = phase_voltages {
+            readings.extend([
+                Readings::PhaseAVoltage(voltage_a),
+                Readings::PhaseBVoltage(voltage_b),
+                Readings::PhaseCVoltage(voltage_c),
+                Readings::AveragePhaseVoltage(voltages.iter().sum::<f32>() / 3.0),
+            ]);
+        }
+        readings.extend(frequency.map(Readings::Frequency));
+        readings
+    }
+
+    pub fn power_readings(summed_power: f32, power_model: &PowerModel) -> Vec<Readings> {
+        let (import, export) = grid_flow(summed_power);
+        vec![
+            Readings::TotalRealPower(summed_power),
+            Readings::ApparentPower(power_model.apparent_power(summed_power)),
+            Readings::ReactivePower(power_model.reactive_power(summed_power)),
+            Readings::PowerFactorTotal(power_model.signed_power_factor(summed_power)),
+            Readings::NetACCurrent(power_model.net_current(summed_power)),
+            Readings::GridImportPower(import),
+            Readings::GridExportPower(export),
+        ]
     }
 }
 
-fn parse_bool_safe(val: Option<String>) -> bool {
+pub fn parse_bool_safe(val: Option<String>) -> bool {
     val.unwrap_or_default()
         .to_ascii_lowercase()
         .parse()
         .unwrap_or_default()
 }
+
+/// How the HA export sensor reports power flowing out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportSign {
+    /// Export is a positive magnitude, to be subtracted from import
+    Positive,
+    /// Export is already negative, to be added to import
+    Negative,
+}
+
+impl ExportSign {
+    /// Nets the import and export readings into a single signed offset
+    pub fn offset(self, import: f32, export: f32) -> f32 {
+        match self {
+            Self::Positive => import - export,
+            Self::Negative => import + export,
+        }
+    }
+}
+
+impl FromStr for ExportSign {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "positive" => Ok(Self::Positive),
+            "negative" => Ok(Self::Negative),
+            other => Err(format!("Unknown export sign `{other}`")),
+        }
+    }
+}
+
+/// What to do with new readings while the meter hasn't taken the previous ones yet
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputWhenFull {
+    /// Wait for the meter, pausing the polling until it catches up
+    #[default]
+    Block,
+    /// Drop the update and keep polling, the meter gets the next one
+    Drop,
+}
+
+impl FromStr for OutputWhenFull {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "block" => Ok(Self::Block),
+            "drop" => Ok(Self::Drop),
+            other => Err(format!("Unknown output policy `{other}`")),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        power_source::{ReaderError, SourceFuture},
+        sunspec_map::{FREQUENCY, PHASE_A_WATTS, PHASE_B_VOLTAGE, PHASE_C_WATTS, TOTAL_REAL_POWER},
+    };
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use tokio::sync::mpsc;
+
+    /// A source of our own, standing in for one the crate doesn't know about
+    struct FixedSource(Measurement);
+
+    impl PowerSource for FixedSource {
+        fn name(&self) -> &str {
+            "Fixed"
+        }
+
+        fn next(&mut self) -> SourceFuture<'_> {
+            Box::pin(std::future::ready(Ok(self.0)))
+        }
+    }
+
+    /// A source whose value the test can change while the fetcher runs
+    struct WatchedSource(watch::Receiver<f32>);
+
+    impl PowerSource for WatchedSource {
+        fn name(&self) -> &str {
+            "Watched"
+        }
+
+        fn next(&mut self) -> SourceFuture<'_> {
+            Box::pin(std::future::ready(Ok(Measurement::grid_power(
+                *self.0.borrow(),
+            ))))
+        }
+    }
+
+    /// A grid source the test can silence, as if the meter dropped off the network
+    struct SilencedSource(watch::Receiver<Option<f32>>);
+
+    impl PowerSource for SilencedSource {
+        fn name(&self) -> &str {
+            "Silenced"
+        }
+
+        fn next(&mut self) -> SourceFuture<'_> {
+            let reading = match *self.0.borrow() {
+                Some(watts) => Ok(Measurement::grid_power(watts)),
+                None => Err(ReaderError::Unavailable("silenced".to_string())),
+            };
+            Box::pin(std::future::ready(reading))
+        }
+    }
+
+    /// An offset source whose value the test can change while the fetcher runs
+    struct WatchedOffset(watch::Receiver<f32>);
+
+    impl PowerSource for WatchedOffset {
+        fn name(&self) -> &str {
+            "Watched offset"
+        }
+
+        fn next(&mut self) -> SourceFuture<'_> {
+            Box::pin(std::future::ready(Ok(Measurement::Offset(
+                *self.0.borrow(),
+            ))))
+        }
+    }
+
+    /// Steps the offset from 0 to 500W under a steady 1000W grid, and returns the distinct
+    /// combined values reported from the step until the output settles
+    async fn offset_step_response(ha_smooth: bool) -> Vec<f32> {
+        let (offset_tx, offset_rx) = watch::channel(0.0);
+        let (output_tx, mut output_rx) = mpsc::channel(16);
+        let config = Config {
+            poll_interval_ms: 5,
+            ha_smooth,
+            ..Default::default()
+        };
+        let _data_fetcher = DataFetcher::with_sources(
+            output_tx,
+            config,
+            vec![
+                Box::new(FixedSource(Measurement::grid_power(1000.0))),
+                Box::new(WatchedOffset(offset_rx)),
+            ],
+        );
+        // Fill the smoothing window with the starting offset first
+        for _ in 0..RollingAverage::new().capacity() {
+            assert_eq!(total_power(&output_rx.recv().await.unwrap()), Some(1000.0));
+        }
+        offset_tx.send_replace(500.0);
+        let mut seen = Vec::new();
+        time::timeout(Duration::from_secs(2), async {
+            while seen.last() != Some(&1500.0) {
+                let power = total_power(&output_rx.recv().await.unwrap()).unwrap();
+                if power != 1000.0 && seen.last() != Some(&power) {
+                    seen.push(power);
+                }
+            }
+        })
+        .await
+        .expect("The output should settle on the new offset");
+        seen
+    }
+
+    #[tokio::test]
+    async fn test_infinite_ha_state_does_not_poison_smoothing() {
+        let mut server = mockito::Server::new_async().await;
+        let infinite = server
+            .mock("GET", "/api/states/sensor.import")
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"entity_id": "sensor.import", "state": "inf", "last_changed": "", "last_reported": "", "last_updated": ""}"#)
+            .create_async()
+            .await;
+        let (output_tx, mut output_rx) = mpsc::channel(16);
+        let config = Config {
+            poll_interval_ms: 5,
+            ha_url: server.url(),
+            ha_extra_import: "sensor.import".to_string(),
+            ha_smooth: true,
+            ..Default::default()
+        };
+        let ha = HomeAssistantReader::new(config.home_assistant()).unwrap();
+        let _data_fetcher = DataFetcher::with_sources(
+            output_tx,
+            config,
+            vec![
+                Box::new(FixedSource(Measurement::grid_power(1000.0))),
+                Box::new(ha),
+            ],
+        );
+        for _ in 0..5 {
+            assert_eq!(total_power(&output_rx.recv().await.unwrap()), Some(1000.0));
+        }
+        infinite.remove_async().await;
+        server
+            .mock("GET", "/api/states/sensor.import")
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"entity_id": "sensor.import", "state": "200", "last_changed": "", "last_reported": "", "last_updated": ""}"#)
+            .create_async()
+            .await;
+        time::timeout(Duration::from_secs(2), async {
+            while total_power(&output_rx.recv().await.unwrap()) != Some(1200.0) {}
+        })
+        .await
+        .expect("The offset should be picked up once HA reports a number");
+    }
+
+    #[tokio::test]
+    async fn test_unsmoothed_offset_steps_instantly() {
+        assert_eq!(offset_step_response(false).await, vec![1500.0]);
+    }
+
+    #[tokio::test]
+    async fn test_smoothed_offset_steps_gradually() {
+        let seen = offset_step_response(true).await;
+        // Each new sample moves the 10 sample average a tenth of the way
+        let expected: Vec<f32> = (1..=10).map(|step| 1000.0 + step as f32 * 50.0).collect();
+        assert_eq!(seen.len(), expected.len(), "{seen:?}");
+        for (seen, expected) in seen.iter().zip(expected) {
+            assert!((seen - expected).abs() < 0.01, "{seen} != {expected}");
+        }
+    }
+
+    fn total_power(readings: &Readings) -> Option<f32> {
+        readings
+            .fields()
+            .into_iter()
+            .find(|(field, _)| *field == TOTAL_REAL_POWER)
+            .map(|(_, value)| value)
+    }
+
+    #[tokio::test]
+    async fn test_phases_are_kept_between_meter_polls() {
+        let (output_tx, mut output_rx) = mpsc::channel(100);
+        let config = Config {
+            poll_interval_ms: 5,
+            shelly_phase_current: true,
+            ..Default::default()
+        };
+        let meter = Measurement::GridPower {
+            watts: 600.0,
+            phase_watts: Some([100.0, 200.0, 300.0]),
+            phase_voltages: Some([230.0, 231.0, 232.0]),
+            frequency: Some(50.1),
+        };
+        let _data_fetcher = DataFetcher::with_sources(
+            output_tx,
+            config,
+            vec![
+                DataFetcher::polled(Box::new(FixedSource(meter)), Some(50)),
+                Box::new(FixedSource(Measurement::Offset(0.0))),
+            ],
+        );
+        let field = |readings: &Readings, wanted| {
+            readings
+                .fields()
+                .into_iter()
+                .find(|(field, _)| *field == wanted)
+                .map(|(_, value)| value)
+        };
+        // Several cycles for every meter poll, each reporting what the meter last measured
+        for _ in 0..30 {
+            let readings = output_rx.recv().await.unwrap();
+            assert_eq!(field(&readings, PHASE_A_WATTS), Some(100.0));
+            assert_eq!(field(&readings, PHASE_C_WATTS), Some(300.0));
+            assert_eq!(field(&readings, PHASE_B_VOLTAGE), Some(231.0));
+            assert_eq!(field(&readings, FREQUENCY), Some(50.1));
+        }
+    }
+
+    /// Counts how often it's read
+    struct CountingSource(Arc<AtomicUsize>);
+
+    impl PowerSource for CountingSource {
+        fn name(&self) -> &str {
+            "Counting"
+        }
+
+        fn next(&mut self) -> SourceFuture<'_> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Box::pin(std::future::ready(Ok(Measurement::Offset(0.0))))
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn test_poll_success_is_exported() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        // Bound then dropped, so the fetcher can serve its metrics there
+        let port = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let (_power_tx, power_rx) = watch::channel(None);
+        let (output_tx, mut output_rx) = mpsc::channel(16);
+        let config = Config {
+            poll_interval_ms: 5,
+            metrics_port: Some(port),
+            ..Default::default()
+        };
+        let _data_fetcher = DataFetcher::with_sources(
+            output_tx,
+            config,
+            vec![
+                Box::new(FixedSource(Measurement::grid_power(1000.0))),
+                Box::new(SilencedSource(power_rx)),
+            ],
+        );
+        output_rx.recv().await.unwrap();
+        // The metrics server starts alongside the first poll
+        let mut stream = time::timeout(Duration::from_secs(2), async {
+            loop {
+                match tokio::net::TcpStream::connect(("127.0.0.1", port)).await {
+                    Ok(stream) => return stream,
+                    Err(_) => time::sleep(Duration::from_millis(5)).await,
+                }
+            }
+        })
+        .await
+        .expect("The metrics should be served");
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(
+            response.contains("\nfronius_source_poll_success_ratio{source=\"Fixed\"} 1\n"),
+            "{response}"
+        );
+        assert!(
+            response.contains("\nfronius_source_poll_success_ratio{source=\"Silenced\"} 0\n"),
+            "{response}"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sources_are_polled_at_their_interval() {
+        let every_cycle = Arc::new(AtomicUsize::new(0));
+        let slow = Arc::new(AtomicUsize::new(0));
+        let (output_tx, mut output_rx) = mpsc::channel(10);
+        let config = Config {
+            poll_interval_ms: 100,
+            ..Default::default()
+        };
+        let data_fetcher = DataFetcher::with_sources(
+            output_tx,
+            config,
+            vec![
+                Box::new(CountingSource(every_cycle.clone())),
+                DataFetcher::polled(Box::new(CountingSource(slow.clone())), Some(250)),
+                DataFetcher::polled(
+                    Box::new(FixedSource(Measurement::grid_power(0.0))),
+                    Some(50),
+                ),
+            ],
+        );
+        tokio::spawn(async move { while output_rx.recv().await.is_some() {} });
+        // The fastest source sets a 50ms cycle, so the others are polled every 2 and 5 cycles
+        time::sleep(Duration::from_millis(999)).await;
+        data_fetcher.shutdown().await;
+        assert_eq!(every_cycle.load(Ordering::Relaxed), 10);
+        assert_eq!(slow.load(Ordering::Relaxed), 4);
+    }
+
+    /// How often the sources were read while nothing took the readings off a single slot channel
+    async fn polls_with_a_stalled_meter(output_when_full: OutputWhenFull) -> usize {
+        let polls = Arc::new(AtomicUsize::new(0));
+        let (output_tx, output_rx) = mpsc::channel(1);
+        let config = Config {
+            poll_interval_ms: 10,
+            output_when_full,
+            ..Default::default()
+        };
+        let data_fetcher = DataFetcher::with_sources(
+            output_tx,
+            config,
+            vec![
+                Box::new(FixedSource(Measurement::grid_power(500.0))),
+                Box::new(CountingSource(polls.clone())),
+            ],
+        );
+        time::sleep(Duration::from_millis(195)).await;
+        let polls = polls.load(Ordering::Relaxed);
+        // A fetcher blocked on the meter only notices it's gone
+        drop(output_rx);
+        data_fetcher.shutdown().await;
+        polls
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stalled_meter_follows_output_policy() {
+        // The first readings fill the channel, and the fetcher waits to send the second
+        assert_eq!(polls_with_a_stalled_meter(OutputWhenFull::Block).await, 2);
+        // Updates are dropped instead, and polling carries on
+        assert_eq!(polls_with_a_stalled_meter(OutputWhenFull::Drop).await, 20);
+        assert_eq!(" DROP".parse(), Ok(OutputWhenFull::Drop));
+        assert!("queue".parse::<OutputWhenFull>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sums_several_shellys() {
+        let first = crate::test_utils::MockShelly::start(&[(1013, 600.0)]).await;
+        let second = crate::test_utils::MockShelly::start(&[(1013, -200.0)]).await;
+        let (output_tx, mut output_rx) = mpsc::channel(10);
+        let config = Config {
+            shelly_modbus: format!("{first},{second}"),
+            poll_interval_ms: 5,
+            ..Default::default()
+        };
+        let _data_fetcher = DataFetcher::new(output_tx, config).unwrap();
+        let readings = output_rx.recv().await.unwrap();
+        assert_eq!(total_power(&readings), Some(400.0));
+    }
+
+    /// An offset that takes a while to read, like a slow HA
+    struct SlowOffset(Duration);
+
+    impl PowerSource for SlowOffset {
+        fn name(&self) -> &str {
+            "Slow offset"
+        }
+
+        fn next(&mut self) -> SourceFuture<'_> {
+            let delay = self.0;
+            Box::pin(async move {
+                time::sleep(delay).await;
+                Ok(Measurement::Offset(500.0))
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_offset_is_primed_while_ha_is_slow() {
+        let (output_tx, mut output_rx) = mpsc::channel(100);
+        let config = Config {
+            poll_interval_ms: 5,
+            prime_ha_zero_ms: Some(50),
+            ..Default::default()
+        };
+        let _data_fetcher = DataFetcher::with_sources(
+            output_tx,
+            config,
+            vec![
+                Box::new(FixedSource(Measurement::grid_power(1000.0))),
+                Box::new(SlowOffset(Duration::from_millis(300))),
+            ],
+        );
+        // The meter alone, long before the offset could have been read
+        let readings = time::timeout(Duration::from_millis(250), output_rx.recv())
+            .await
+            .expect("The meter should be reported within the prime window")
+            .unwrap();
+        assert_eq!(total_power(&readings), Some(1000.0));
+
+        // Then corrected once the offset arrives
+        time::timeout(Duration::from_secs(2), async {
+            while total_power(&output_rx.recv().await.unwrap()) != Some(1500.0) {}
+        })
+        .await
+        .expect("The offset should be added once read");
+    }
+
+    #[tokio::test]
+    async fn test_endless_prime_waits_for_the_offset() {
+        let (output_tx, mut output_rx) = mpsc::channel(100);
+        let config = Config {
+            poll_interval_ms: 5,
+            prime_ha_zero_ms: Some(u64::MAX),
+            ..Default::default()
+        };
+        let _data_fetcher = DataFetcher::with_sources(
+            output_tx,
+            config,
+            vec![
+                Box::new(FixedSource(Measurement::grid_power(1000.0))),
+                Box::new(SlowOffset(Duration::from_millis(20))),
+            ],
+        );
+        let readings = time::timeout(Duration::from_secs(2), output_rx.recv())
+            .await
+            .expect("The first reading should wait for the offset")
+            .unwrap();
+        assert_eq!(total_power(&readings), Some(1500.0));
+    }
+
+    #[tokio::test]
+    async fn test_readings_channel_capacity() {
+        let config = Config::builder()
+            .var("READINGS_CHANNEL_CAP", "2")
+            .var("POLL_INTERVAL_MS", "5")
+            .build()
+            .unwrap();
+        let (meter, meter_update_handle) =
+            crate::smart_meter_emulator::SmartMeterEmulator::builder()
+                .channel_capacity(config.readings_channel_cap)
+                .build();
+        assert_eq!(meter_update_handle.max_capacity(), 2);
+        let _data_fetcher = DataFetcher::with_sources(
+            meter_update_handle,
+            config,
+            vec![Box::new(FixedSource(Measurement::grid_power(750.0)))],
+        );
+        // Far more cycles than the queue holds, which the meter keeps up with
+        time::timeout(Duration::from_secs(2), async {
+            while meter.snapshot().await.total_real_power != 750.0 {
+                time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("The meter should get the readings");
+        assert_eq!(
+            Config::default().readings_channel_cap,
+            crate::smart_meter_emulator::DEFAULT_CHANNEL_CAPACITY
+        );
+    }
+
+    /// The meter fed an hour of `grid` watts with `offset` added, as the fetcher combines them
+    async fn meter_after_an_hour(
+        grid: f32,
+        offset: f32,
+    ) -> crate::smart_meter_emulator::MeterSnapshot {
+        use crate::smart_meter_emulator::{EmulatorOptions, SmartMeterEmulator};
+        let (meter, meter_update_handle) = SmartMeterEmulator::builder()
+            .options(EmulatorOptions {
+                update_timeout: None,
+                ..Default::default()
+            })
+            .build();
+        let config = Config {
+            poll_interval_ms: 1000,
+            ..Default::default()
+        };
+        let data_fetcher = DataFetcher::with_sources(
+            meter_update_handle,
+            config,
+            vec![
+                Box::new(FixedSource(Measurement::grid_power(grid))),
+                Box::new(FixedSource(Measurement::Offset(offset))),
+            ],
+        );
+        time::sleep(Duration::from_secs(3600)).await;
+        data_fetcher.shutdown().await;
+        meter.snapshot().await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_energy_is_split_by_the_combined_power() {
+        // Importing, with the offset taking some of it off
+        let meter = meter_after_an_hour(1500.0, -500.0).await;
+        assert_eq!(meter.total_real_power, 1000.0);
+        assert!(
+            (meter.total_wh_imported - 1000.0).abs() < 1.0,
+            "{}",
+            meter.total_wh_imported
+        );
+        assert_eq!(meter.total_wh_exported, 0.0);
+        assert_eq!(meter.grid_import_power, 1000.0);
+        assert_eq!(meter.grid_export_power, 0.0);
+
+        // The offset turns a small import into an export, which is what gets counted
+        let meter = meter_after_an_hour(200.0, -1200.0).await;
+        assert_eq!(meter.total_real_power, -1000.0);
+        assert!(
+            (meter.total_wh_exported - 1000.0).abs() < 1.0,
+            "{}",
+            meter.total_wh_exported
+        );
+        assert_eq!(meter.total_wh_imported, 0.0);
+        assert_eq!(meter.grid_import_power, 0.0);
+        assert_eq!(meter.grid_export_power, 1000.0);
+    }
+
+    #[tokio::test]
+    async fn test_ha_can_be_turned_off() {
+        let shelly = crate::test_utils::MockShelly::start(&[(1013, 600.0)]).await;
+        let (output_tx, mut output_rx) = mpsc::channel(10);
+        // Sensors set up on an HA that never answers, which would hold back readiness
+        let config = Config {
+            shelly_modbus: shelly.to_string(),
+            ha_enabled: false,
+            ha_url: "http://127.0.0.1:1".to_string(),
+            ha_extra_import: "sensor.virtual_import".to_string(),
+            poll_interval_ms: 5,
+            ..Default::default()
+        };
+        let data_fetcher = DataFetcher::new(output_tx, config).unwrap();
+        let readings = time::timeout(Duration::from_secs(2), output_rx.recv())
+            .await
+            .expect("The Shelly alone should be reported")
+            .unwrap();
+        assert_eq!(total_power(&readings), Some(600.0));
+        // Without waiting on an offset that will never come
+        assert!(data_fetcher.health().is_ready());
+    }
+
+    #[tokio::test]
+    async fn test_reads_a_shelly_over_http() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/rpc/EM.GetStatus")
+            .match_query(mockito::Matcher::UrlEncoded("id".into(), "0".into()))
+            .with_body(r#"{"id": 0, "total_act_power": 750.5}"#)
+            .create_async()
+            .await;
+        let modbus = crate::test_utils::MockShelly::start(&[(1013, -250.0)]).await;
+        let (output_tx, mut output_rx) = mpsc::channel(10);
+        let config = Config {
+            shelly_modbus: format!("{},{modbus}", server.url()),
+            poll_interval_ms: 5,
+            ..Default::default()
+        };
+        let _data_fetcher = DataFetcher::new(output_tx, config).unwrap();
+        let readings = output_rx.recv().await.unwrap();
+        assert_eq!(total_power(&readings), Some(500.5));
+    }
+
+    #[tokio::test]
+    async fn test_unusable_shelly_address_is_an_error() {
+        let (output_tx, _output_rx) = mpsc::channel(10);
+        let error = DataFetcher::new(output_tx.clone(), Config::default()).err();
+        assert_eq!(error, Some(FetcherError::MissingShellyAddress));
+        let config = Config {
+            shelly_modbus: "192.168.1.20:502, not an address".to_string(),
+            ..Default::default()
+        };
+        let error = DataFetcher::new(output_tx.clone(), config).err();
+        assert!(
+            matches!(
+                &error,
+                Some(FetcherError::InvalidShellyAddress { address, .. }) if address == "not an address"
+            ),
+            "{error:?}"
+        );
+        let config = Config {
+            shelly_modbus: "replay:///no/such/trace.csv".to_string(),
+            ..Default::default()
+        };
+        let error = DataFetcher::new(output_tx, config).err();
+        assert!(
+            matches!(
+                &error,
+                Some(FetcherError::InvalidReplayTrace { path, .. }) if path == "/no/such/trace.csv"
+            ),
+            "{error:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unusable_ha_tls_is_an_error() {
+        let (output_tx, _output_rx) = mpsc::channel(10);
+        let config = Config {
+            shelly_modbus: "192.168.1.20:502".to_string(),
+            ha_url: "https://ha.local:8123".to_string(),
+            ha_extra_import: "sensor.import".to_string(),
+            ha_ca_cert: "/no/such/ca.pem".to_string(),
+            ..Default::default()
+        };
+        let error = DataFetcher::new(output_tx.clone(), config.clone()).err();
+        assert!(
+            matches!(&error, Some(FetcherError::InvalidHaTls(reason)) if reason.contains("/no/such/ca.pem")),
+            "{error:?}"
+        );
+        // Not checked when HA isn't read
+        let config = Config {
+            ha_enabled: false,
+            ..config
+        };
+        assert!(DataFetcher::check_sources(&config).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_changes_propagate_with_a_short_interval() {
+        let (power_tx, power_rx) = watch::channel(100.0);
+        let (output_tx, mut output_rx) = mpsc::channel(16);
+        let config = Config {
+            poll_interval_ms: 20,
+            ..Default::default()
+        };
+        let _data_fetcher =
+            DataFetcher::with_sources(output_tx, config, vec![Box::new(WatchedSource(power_rx))]);
+
+        // Wait on the output itself rather than sleeping for a guessed time
+        let started = std::time::Instant::now();
+        let first = output_rx.recv().await.unwrap();
+        assert_eq!(total_power(&first), Some(100.0));
+        power_tx.send_replace(250.0);
+        time::timeout(Duration::from_millis(500), async {
+            while total_power(&output_rx.recv().await.unwrap()) != Some(250.0) {}
+        })
+        .await
+        .expect("The new value should be picked up within a few cycles");
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn test_custom_sources_feed_the_combiner() {
+        let (output_tx, mut output_rx) = mpsc::channel(16);
+        let data_fetcher = DataFetcher::with_sources(
+            output_tx,
+            Config::default(),
+            vec![
+                Box::new(FixedSource(Measurement::grid_power(1000.0))),
+                Box::new(FixedSource(Measurement::Offset(-150.0))),
+                Box::new(FixedSource(Measurement::Offset(-50.0))),
+            ],
+        );
+        let readings = output_rx.recv().await.unwrap();
+        assert_eq!(total_power(&readings), Some(800.0));
+        assert!(data_fetcher.health().is_ready());
+    }
+
+    /// Silences the meter after its first reading and returns the first readings sent once the
+    /// watchdog has given up on it
+    async fn readings_after_meter_goes_quiet(fallback_chain: Vec<FallbackTier>) -> Readings {
+        let (power_tx, power_rx) = watch::channel(Some(1200.0));
+        let (output_tx, mut output_rx) = mpsc::channel(16);
+        let config = Config {
+            poll_interval_ms: 5,
+            shelly_stale_ms: Some(50),
+            fallback_chain,
+            ..Default::default()
+        };
+        let _data_fetcher =
+            DataFetcher::with_sources(output_tx, config, vec![Box::new(SilencedSource(power_rx))]);
+        assert_eq!(total_power(&output_rx.recv().await.unwrap()), Some(1200.0));
+        power_tx.send_replace(None);
+        time::timeout(Duration::from_secs(2), async {
+            loop {
+                let readings = output_rx.recv().await.unwrap();
+                if total_power(&readings) != Some(1200.0) {
+                    return readings;
+                }
+            }
+        })
+        .await
+        .expect("The stale reading should be replaced")
+    }
+
+    #[tokio::test]
+    async fn test_stale_meter_reports_zero() {
+        let readings =
+            readings_after_meter_goes_quiet(vec![FallbackTier::Live, FallbackTier::Degraded]).await;
+        assert_eq!(total_power(&readings), Some(0.0));
+    }
+
+    #[tokio::test]
+    async fn test_stale_meter_can_report_unavailable() {
+        let readings =
+            readings_after_meter_goes_quiet(vec![FallbackTier::Live, FallbackTier::Unavailable])
+                .await;
+        assert!(matches!(readings, Readings::Unavailable), "{readings:?}");
+    }
+
+    #[tokio::test]
+    async fn test_override_is_reported_while_unavailable() {
+        let (power_tx, power_rx) = watch::channel(Some(1200.0));
+        let (output_tx, mut output_rx) = mpsc::channel(16);
+        let config = Config {
+            poll_interval_ms: 5,
+            shelly_stale_ms: Some(50),
+            fallback_chain: vec![FallbackTier::Live, FallbackTier::Unavailable],
+            ..Default::default()
+        };
+        let data_fetcher =
+            DataFetcher::with_sources(output_tx, config, vec![Box::new(SilencedSource(power_rx))]);
+        assert_eq!(total_power(&output_rx.recv().await.unwrap()), Some(1200.0));
+        data_fetcher
+            .power_override()
+            .pin(750.0, Instant::now() + Duration::from_secs(60));
+        power_tx.send_replace(None);
+        // Well past the meter going stale, without the override dropping out
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_millis(200) {
+            let readings = output_rx.recv().await.unwrap();
+            if total_power(&readings) != Some(1200.0) {
+                assert_eq!(total_power(&readings), Some(750.0), "{readings:?}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_measured_voltage_and_frequency_are_reported() {
+        let (output_tx, mut output_rx) = mpsc::channel(16);
+        let _data_fetcher = DataFetcher::with_sources(
+            output_tx,
+            Config::default(),
+            vec![Box::new(FixedSource(Measurement::GridPower {
+                watts: 1000.0,
+                phase_watts: None,
+                phase_voltages: Some([229.0, 230.0, 234.0]),
+                frequency: Some(49.95),
+            }))],
+        );
+        let readings = output_rx.recv().await.unwrap();
+        let value = |name: &str| {
+            readings
+                .fields()
+                .into_iter()
+                .find(|(field, _)| field.name == name)
+                .map(|(_, value)| value)
+        };
+        assert_eq!(value("PhaseAVoltage"), Some(229.0));
+        assert_eq!(value("PhaseCVoltage"), Some(234.0));
+        assert_eq!(value("AveragePhaseVoltage"), Some(231.0));
+        assert_eq!(value("Frequency"), Some(49.95));
+    }
+
+    #[test]
+    fn test_reactive_power_is_zero_by_default() {
+        // Some inverters flag the meter as faulty if the reactive power follows the real power
+        let readings = DataFetcher::power_readings(5000.0, &Config::default().power_model());
+        assert!(readings
+            .iter()
+            .any(|reading| matches!(reading, Readings::ReactivePower(var) if *var == 0.0)));
+        assert!(readings
+            .iter()
+            .any(|reading| matches!(reading, Readings::TotalRealPower(watts) if *watts == 5000.0)));
+
+        // Only an assumed power factor below 1 gives any
+        let config = Config {
+            power_factor: 0.8,
+            ..Default::default()
+        };
+        let readings = DataFetcher::power_readings(5000.0, &config.power_model());
+        assert!(readings.iter().any(
+            |reading| matches!(reading, Readings::ReactivePower(var) if (*var - 3750.0).abs() < 0.1)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_apparent_power_and_power_factor_are_reported() {
+        let (output_tx, mut output_rx) = mpsc::channel(16);
+        let config = Config {
+            power_factor: 0.8,
+            shelly_phase_current: true,
+            ..Default::default()
+        };
+        let _data_fetcher = DataFetcher::with_sources(
+            output_tx,
+            config,
+            vec![Box::new(FixedSource(Measurement::GridPower {
+                watts: 1200.0,
+                phase_watts: Some([400.0, 400.0, 400.0]),
+                phase_voltages: None,
+                frequency: None,
+            }))],
+        );
+        let readings = output_rx.recv().await.unwrap();
+        let value = |name: &str| {
+            readings
+                .fields()
+                .into_iter()
+                .find(|(field, _)| field.name == name)
+                .map(|(_, value)| value)
+                .unwrap()
+        };
+        assert_eq!(value("TotalRealPower"), 1200.0);
+        assert!((value("ApparentPower") - 1500.0).abs() < 0.1);
+        assert!((value("ReactivePower") - 900.0).abs() < 0.1);
+        assert!((value("PowerFactorTotal") - 0.8).abs() < 1e-6);
+        assert!((value("PhaseBVA") - 500.0).abs() < 0.1);
+        assert!((value("PhaseCVAR") - 300.0).abs() < 0.1);
+        assert!((value("PhaseAPF") - 0.8).abs() < 1e-6);
+    }
+
+    #[tokio::test]
+    async fn test_offset_follows_the_distribution() {
+        for (distribution, expected) in [
+            (OffsetDistribution::EvenThirds, [300.0, 300.0, 300.0]),
+            (OffsetDistribution::PhaseA, [100.0, 400.0, 400.0]),
+            (OffsetDistribution::TotalOnly, [400.0, 400.0, 400.0]),
+        ] {
+            let (output_tx, mut output_rx) = mpsc::channel(16);
+            let config = Config {
+                shelly_phase_current: true,
+                offset_distribution: distribution,
+                ..Default::default()
+            };
+            let _data_fetcher = DataFetcher::with_sources(
+                output_tx,
+                config,
+                vec![
+                    Box::new(FixedSource(Measurement::GridPower {
+                        watts: 1200.0,
+                        phase_watts: Some([400.0, 400.0, 400.0]),
+                        phase_voltages: None,
+                        frequency: None,
+                    })),
+                    Box::new(FixedSource(Measurement::Offset(-300.0))),
+                ],
+            );
+            let readings = output_rx.recv().await.unwrap();
+            let value = |name: &str| {
+                readings
+                    .fields()
+                    .into_iter()
+                    .find(|(field, _)| field.name == name)
+                    .map(|(_, value)| value)
+                    .unwrap()
+            };
+            assert_eq!(value("TotalRealPower"), 900.0, "{distribution:?}");
+            assert_eq!(
+                [
+                    value("PhaseAWatts"),
+                    value("PhaseBWatts"),
+                    value("PhaseCWatts")
+                ],
+                expected,
+                "{distribution:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_post_sequence_runs_before_live_data() {
+        let (output_tx, mut output_rx) = mpsc::channel(16);
+        let config = Config {
+            post_sequence: true,
+            post_step_ms: 10,
+            ..Default::default()
+        };
+        let _data_fetcher = DataFetcher::with_sources(
+            output_tx,
+            config,
+            vec![Box::new(FixedSource(Measurement::grid_power(500.0)))],
+        );
+        let mut seen = Vec::new();
+        for _ in 0..4 {
+            seen.push(total_power(&output_rx.recv().await.unwrap()).unwrap());
+        }
+        assert_eq!(seen, vec![0.0, 1000.0, 0.0, 500.0]);
+    }
+
+    #[tokio::test]
+    async fn test_no_post_sequence_by_default() {
+        let (output_tx, mut output_rx) = mpsc::channel(16);
+        let _data_fetcher = DataFetcher::with_sources(
+            output_tx,
+            Config::default(),
+            vec![Box::new(FixedSource(Measurement::grid_power(500.0)))],
+        );
+        let readings = output_rx.recv().await.unwrap();
+        assert_eq!(total_power(&readings), Some(500.0));
+    }
+
+    #[tokio::test]
+    async fn test_stops_when_the_meter_is_gone() {
+        for output_rate_hz in [None, Some(50.0)] {
+            let (output_tx, output_rx) = mpsc::channel(16);
+            let config = Config {
+                poll_interval_ms: 10,
+                output_rate_hz,
+                ..Default::default()
+            };
+            let mut data_fetcher = DataFetcher::with_sources(
+                output_tx,
+                config,
+                vec![Box::new(FixedSource(Measurement::grid_power(500.0)))],
+            );
+            drop(output_rx);
+            time::timeout(Duration::from_secs(1), data_fetcher.stopped())
+                .await
+                .expect("The fetcher should stop rather than keep trying");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_all_tasks() {
+        for output_rate_hz in [None, Some(50.0)] {
+            let (output_tx, mut output_rx) = mpsc::channel(16);
+            let config = Config {
+                poll_interval_ms: 10,
+                output_rate_hz,
+                ..Default::default()
+            };
+            let data_fetcher = DataFetcher::with_sources(
+                output_tx,
+                config,
+                vec![Box::new(FixedSource(Measurement::grid_power(500.0)))],
+            );
+            output_rx.recv().await.unwrap();
+            time::timeout(Duration::from_secs(1), data_fetcher.shutdown())
+                .await
+                .expect("The fetcher should stop when asked");
+            // Every task holding a sender has finished once the channel closes
+            time::timeout(Duration::from_secs(1), async {
+                while output_rx.recv().await.is_some() {}
+            })
+            .await
+            .expect("All of the fetcher's tasks should have stopped");
+        }
+    }
+
+    #[test]
+    fn test_export_sign_conventions() {
+        // 1000W virtual import and 400W virtual export, reported both ways
+        assert_eq!(ExportSign::Positive.offset(1000.0, 400.0), 600.0);
+        assert_eq!(ExportSign::Negative.offset(1000.0, -400.0), 600.0);
+        // Pure export
+        assert_eq!(ExportSign::Positive.offset(0.0, 250.0), -250.0);
+        assert_eq!(ExportSign::Negative.offset(0.0, -250.0), -250.0);
+    }
+
+    #[test]
+    fn test_parse_export_sign() {
+        assert_eq!("positive".parse(), Ok(ExportSign::Positive));
+        assert_eq!(" Negative".parse(), Ok(ExportSign::Negative));
+        assert!("minus".parse::<ExportSign>().is_err());
+    }
 
     #[test]
     fn test_parse_bool_safe() {
         // Test None input
-        assert_eq!(parse_bool_safe(None), false);
+        assert!(!parse_bool_safe(None));
 
         // Test empty string
-        assert_eq!(parse_bool_safe(Some("".to_string())), false);
+        assert!(!parse_bool_safe(Some("".to_string())));
 
         // Test "true" variations
-        assert_eq!(parse_bool_safe(Some("true".to_string())), true);
-        assert_eq!(parse_bool_safe(Some("True".to_string())), true);
-        assert_eq!(parse_bool_safe(Some("TRUE".to_string())), true);
-        assert_eq!(parse_bool_safe(Some("TrUe".to_string())), true);
+        assert!(parse_bool_safe(Some("true".to_string())));
+        assert!(parse_bool_safe(Some("True".to_string())));
+        assert!(parse_bool_safe(Some("TRUE".to_string())));
+        assert!(parse_bool_safe(Some("TrUe".to_string())));
 
         // Test "false" variations
-        assert_eq!(parse_bool_safe(Some("false".to_string())), false);
-        assert_eq!(parse_bool_safe(Some("False".to_string())), false);
-        assert_eq!(parse_bool_safe(Some("FALSE".to_string())), false);
-        assert_eq!(parse_bool_safe(Some("FaLsE".to_string())), false);
+        assert!(!parse_bool_safe(Some("false".to_string())));
+        assert!(!parse_bool_safe(Some("False".to_string())));
+        assert!(!parse_bool_safe(Some("FALSE".to_string())));
+        assert!(!parse_bool_safe(Some("FaLsE".to_string())));
 
         // Test invalid strings (should default to false)
-        assert_eq!(parse_bool_safe(Some("yes".to_string())), false);
-        assert_eq!(parse_bool_safe(Some("no".to_string())), false);
-        assert_eq!(parse_bool_safe(Some("1".to_string())), false);
-        assert_eq!(parse_bool_safe(Some("0".to_string())), false);
-        assert_eq!(parse_bool_safe(Some("invalid".to_string())), false);
-        assert_eq!(parse_bool_safe(Some("random text".to_string())), false);
+        assert!(!parse_bool_safe(Some("yes".to_string())));
+        assert!(!parse_bool_safe(Some("no".to_string())));
+        assert!(!parse_bool_safe(Some("1".to_string())));
+        assert!(!parse_bool_safe(Some("0".to_string())));
+        assert!(!parse_bool_safe(Some("invalid".to_string())));
+        assert!(!parse_bool_safe(Some("random text".to_string())));
     }
 }
@@ -0,0 +1,86 @@
+use tokio::time::Instant;
+
+// Lifetime energy counters, built up from the total power as it is reported.
+// Inverters and monitoring read these from a real meter, which counts in hardware; here each
+// power reading is taken to hold until the next one, the same way the inverter sees it.
+
+/// Integrates power over time into the energy imported from and exported to the grid
+#[derive(Debug, Clone, Default)]
+pub struct EnergyAccumulator {
+    imported_wh: f64,
+    exported_wh: f64,
+    /// The power being held, and when it was reported
+    last: Option<(f32, Instant)>,
+}
+
+impl EnergyAccumulator {
+    /// Counts the previous power for the time since it was reported, then holds `watts`.
+    /// Positive power is import, negative is export.
+    pub fn add(&mut self, watts: f32, now: Instant) {
+        if let Some((last_watts, at)) = self.last {
+            let hours = now.saturating_duration_since(at).as_secs_f64() / 3600.0;
+            let wh = f64::from(last_watts) * hours;
+            if wh >= 0.0 {
+                self.imported_wh += wh;
+            } else {
+                self.exported_wh -= wh;
+            }
+        }
+        // A bogus reading would poison the totals for good
+        self.last = watts.is_finite().then_some((watts, now));
+    }
+
+    pub fn imported_wh(&self) -> f64 {
+        self.imported_wh
+    }
+
+    pub fn exported_wh(&self) -> f64 {
+        self.exported_wh
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_constant_power_over_an_hour() {
+        let start = Instant::now();
+        let mut energy = EnergyAccumulator::default();
+        energy.add(1500.0, start);
+        assert_eq!(
+            energy.imported_wh(),
+            0.0,
+            "Nothing counted until time passes"
+        );
+        energy.add(1500.0, start + Duration::from_secs(1800));
+        energy.add(1500.0, start + Duration::from_secs(3600));
+        assert!((energy.imported_wh() - 1500.0).abs() < 1e-9);
+        assert_eq!(energy.exported_wh(), 0.0);
+    }
+
+    #[test]
+    fn test_import_and_export_are_separate() {
+        let start = Instant::now();
+        let mut energy = EnergyAccumulator::default();
+        energy.add(-2000.0, start);
+        // Held for 15 minutes, then importing for 6
+        energy.add(600.0, start + Duration::from_secs(900));
+        energy.add(0.0, start + Duration::from_secs(1260));
+        assert!((energy.exported_wh() - 500.0).abs() < 1e-9);
+        assert!((energy.imported_wh() - 60.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_non_finite_power_is_not_counted() {
+        let start = Instant::now();
+        let mut energy = EnergyAccumulator::default();
+        energy.add(f32::NAN, start);
+        energy.add(100.0, start + Duration::from_secs(3600));
+        energy.add(f32::INFINITY, start + Duration::from_secs(7200));
+        energy.add(0.0, start + Duration::from_secs(10800));
+        assert!((energy.imported_wh() - 100.0).abs() < 1e-9);
+        assert_eq!(energy.exported_wh(), 0.0);
+    }
+}
@@ -0,0 +1,102 @@
+use tokio::sync::broadcast;
+
+use tracing::{info, warn};
+
+use crate::power_combiner::FallbackTier;
+
+// Notifications about state changes, broadcast so that several parts of the program
+// (logging, status, metrics) can react without polling each other's state
+
+const EVENT_BUS_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Shelly,
+    HomeAssistant,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A source hasn't produced a reading within the staleness window
+    SourceStale(Source),
+    /// A stale source produced a reading again
+    SourceRecovered(Source),
+    /// The combined power is now produced by a different fallback tier
+    FallbackTierChanged {
+        from: FallbackTier,
+        to: FallbackTier,
+    },
+    /// The HA offset was outside the configured bounds
+    OffsetOutOfRange { offset: f32 },
+}
+
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUS_CAPACITY);
+        Self { sender }
+    }
+
+    /// Publishes an event to all current subscribers
+    pub fn emit(&self, event: Event) {
+        // Having nobody listening is fine, the event is just dropped
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Prints every event on the bus, until the bus is dropped
+pub fn spawn_event_logger(events: &EventBus) {
+    let mut receiver = events.subscribe();
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => info!("Event: {event:?}"),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Event logger fell behind, missed {missed} events")
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_emit_without_subscribers() {
+        let events = EventBus::new();
+        events.emit(Event::SourceStale(Source::Shelly));
+    }
+
+    #[test]
+    fn test_all_subscribers_receive_events() {
+        let events = EventBus::new();
+        let mut first = events.subscribe();
+        let mut second = events.subscribe();
+        events.emit(Event::SourceRecovered(Source::HomeAssistant));
+        assert_eq!(
+            first.try_recv(),
+            Ok(Event::SourceRecovered(Source::HomeAssistant))
+        );
+        assert_eq!(
+            second.try_recv(),
+            Ok(Event::SourceRecovered(Source::HomeAssistant))
+        );
+    }
+}
@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use crate::rng::random_unit;
+
+// Degrades the emulated meter's responses on purpose, to check an inverter copes with a slow or
+// lossy meter. Only for testing, so it has to be explicitly enabled.
+
+/// Response delays and drops applied to every request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FaultInjection {
+    /// Average delay before answering
+    pub delay: Duration,
+    /// Each delay is picked uniformly from `delay ± jitter`, never going below zero
+    pub jitter: Duration,
+    /// Chance from 0 to 1 of not answering a request at all
+    pub drop_probability: f32,
+}
+
+impl FaultInjection {
+    /// Picks the delay for a response, and whether to drop it
+    pub fn sample(&self) -> (Duration, bool) {
+        let jitter = self.jitter.as_secs_f64();
+        let offset = (random_unit() * 2.0 - 1.0) * jitter;
+        let delay = (self.delay.as_secs_f64() + offset).max(0.0);
+        let drop = random_unit() < self.drop_probability as f64;
+        (Duration::from_secs_f64(delay), drop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delays_within_range() {
+        let faults = FaultInjection {
+            delay: Duration::from_millis(100),
+            jitter: Duration::from_millis(20),
+            drop_probability: 0.0,
+        };
+        let delays: Vec<Duration> = (0..1000).map(|_| faults.sample().0).collect();
+        for delay in &delays {
+            assert!(
+                (Duration::from_millis(80)..=Duration::from_millis(120)).contains(delay),
+                "{delay:?}"
+            );
+        }
+        // Actually jittered, rather than always the same value
+        assert!(delays
+            .iter()
+            .any(|delay| *delay < Duration::from_millis(95)));
+        assert!(delays
+            .iter()
+            .any(|delay| *delay > Duration::from_millis(105)));
+    }
+
+    #[test]
+    fn test_jitter_never_goes_negative() {
+        let faults = FaultInjection {
+            delay: Duration::from_millis(5),
+            jitter: Duration::from_millis(50),
+            drop_probability: 0.0,
+        };
+        for _ in 0..1000 {
+            assert!(faults.sample().0 <= Duration::from_millis(55));
+        }
+    }
+
+    #[test]
+    fn test_drop_probability() {
+        let mut faults = FaultInjection {
+            delay: Duration::ZERO,
+            jitter: Duration::ZERO,
+            drop_probability: 0.0,
+        };
+        assert!((0..1000).all(|_| !faults.sample().1));
+        faults.drop_probability = 1.0;
+        assert!((0..1000).all(|_| faults.sample().1));
+        faults.drop_probability = 0.25;
+        let dropped = (0..10_000).filter(|_| faults.sample().1).count();
+        assert!((2000..3000).contains(&dropped), "{dropped}");
+    }
+}
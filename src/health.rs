@@ -0,0 +1,138 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+#[cfg(feature = "health")]
+use std::net::SocketAddr;
+
+#[cfg(feature = "health")]
+use axum::{http::StatusCode, routing::get, Router};
+#[cfg(feature = "health")]
+use tokio::net::TcpListener;
+#[cfg(feature = "health")]
+use tokio_util::sync::CancellationToken;
+#[cfg(feature = "health")]
+use tracing::info;
+
+// Liveness and readiness for container orchestration. The flags are always tracked,
+// the HTTP server reporting them needs the `health` feature.
+
+/// Whether the meter is up and has something worth reporting, cheap to clone and share
+#[derive(Debug, Clone, Default)]
+pub struct HealthStatus {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    listening: AtomicBool,
+    ready: AtomicBool,
+}
+
+#[cfg_attr(not(feature = "health"), allow(dead_code))]
+impl HealthStatus {
+    /// Marks the Modbus server as accepting connections
+    pub fn set_listening(&self) {
+        self.inner.listening.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_listening(&self) -> bool {
+        self.inner.listening.load(Ordering::Relaxed)
+    }
+
+    /// Whether the combined power is built from real readings
+    pub fn set_ready(&self, ready: bool) {
+        self.inner.ready.store(ready, Ordering::Relaxed);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.inner.ready.load(Ordering::Relaxed)
+    }
+}
+
+/// Serves `/health` and `/ready` for `status` at `socket_addr` until `shutdown` is cancelled.
+/// Each answers 200 once its flag is set, and 503 until then.
+#[cfg(feature = "health")]
+pub async fn serve(
+    socket_addr: SocketAddr,
+    status: HealthStatus,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    info!("Serving health checks on {socket_addr}");
+    let listener = TcpListener::bind(socket_addr).await?;
+    serve_on(listener, status, shutdown).await
+}
+
+#[cfg(feature = "health")]
+async fn serve_on(
+    listener: TcpListener,
+    status: HealthStatus,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    let respond = |ok: bool| {
+        if ok {
+            (StatusCode::OK, "OK")
+        } else {
+            (StatusCode::SERVICE_UNAVAILABLE, "Not yet")
+        }
+    };
+    let live = status.clone();
+    let app = Router::new()
+        .route(
+            "/health",
+            get(move || async move { respond(live.is_listening()) }),
+        )
+        .route(
+            "/ready",
+            get(move || async move { respond(status.is_ready()) }),
+        );
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move { shutdown.cancelled().await })
+        .await?;
+    Ok(())
+}
+
+#[cfg(all(test, feature = "health"))]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn get_status_line(socket_addr: SocketAddr, path: &str) -> String {
+        let mut stream = tokio::net::TcpStream::connect(socket_addr).await.unwrap();
+        let request = format!("GET {path} HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_endpoints_follow_status() {
+        let status = HealthStatus::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket_addr = listener.local_addr().unwrap();
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn(serve_on(listener, status.clone(), shutdown.clone()));
+
+        let unavailable = "HTTP/1.1 503 Service Unavailable";
+        assert_eq!(get_status_line(socket_addr, "/health").await, unavailable);
+        assert_eq!(get_status_line(socket_addr, "/ready").await, unavailable);
+
+        status.set_listening();
+        assert_eq!(
+            get_status_line(socket_addr, "/health").await,
+            "HTTP/1.1 200 OK"
+        );
+        assert_eq!(get_status_line(socket_addr, "/ready").await, unavailable);
+
+        status.set_ready(true);
+        assert_eq!(
+            get_status_line(socket_addr, "/ready").await,
+            "HTTP/1.1 200 OK"
+        );
+
+        shutdown.cancel();
+        server.await.unwrap().unwrap();
+    }
+}
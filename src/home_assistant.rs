@@ -1,37 +1,345 @@
+use crate::{
+    data_fetcher::ExportSign,
+    power_source::{Measurement, PowerSource, ReaderError, SourceFuture},
+};
+use reqwest::StatusCode;
 use serde_derive::{Deserialize, Serialize};
-use std::env;
+use std::{fmt, str::FromStr, time::Duration};
+use tracing::{debug, warn};
+
+/// The states HA reports for a sensor it has no value for
+const NO_VALUE_STATES: [&str; 2] = ["unavailable", "unknown"];
+/// How long a read may take before it counts as failed
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct HomeAssistantAPI {
-    endpoint_url: String,
-    auth_token: String,
+    /// Base URL and token of each instance, in the order they are tried
+    endpoints: Vec<(String, String)>,
     client: reqwest::Client,
+    /// For each instance, so a hung one doesn't hold up trying the next
+    timeout: Duration,
 }
 
 impl HomeAssistantAPI {
-    pub fn new() -> Self {
+    /// `endpoint_url` may list several comma separated instances, which are tried in order.
+    /// `auth_token` is either one token shared by all of them, or a comma separated token per URL.
+    pub fn with_credentials(endpoint_url: String, auth_token: String) -> Self {
         Self {
-            endpoint_url: env::var("HA_URL").unwrap_or_default(),
-            auth_token: env::var("HA_TOKEN").unwrap_or_default(),
+            endpoints: parse_endpoints(&endpoint_url, &auth_token),
             client: reqwest::Client::new(),
+            timeout: DEFAULT_TIMEOUT,
         }
     }
 
-    pub async fn read_sensor_value(
-        &mut self,
-        sensor_path: &str,
-    ) -> Result<HASensor, anyhow::Error> {
-        if self.endpoint_url.is_empty() {
-            anyhow::bail!("No HA connection");
+    /// Gives up on an instance that hasn't answered within `timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Checks certificates for https URLs as set up in `tls`.
+    /// Whether TLS is used at all follows each URL's scheme.
+    pub fn with_tls(mut self, tls: &HaTls) -> anyhow::Result<Self> {
+        self.client = tls.client()?;
+        Ok(self)
+    }
+
+    /// Reads the sensor from the first instance that answers
+    pub async fn read_sensor_value(&mut self, sensor_path: &str) -> Result<HASensor, HaError> {
+        if self.endpoints.is_empty() {
+            return Err(HaError::NotConfigured);
+        }
+        let mut errors = Vec::new();
+        for (endpoint_url, auth_token) in &self.endpoints {
+            match self.read_from(endpoint_url, auth_token, sensor_path).await {
+                Ok(result) => return Ok(result),
+                Err(e) => errors.push((endpoint_url.clone(), e)),
+            }
+        }
+        // With a single instance its own error says it all
+        if errors.len() == 1 {
+            return Err(errors.remove(0).1);
         }
-        let result = self
+        Err(HaError::NoInstanceAnswered(errors))
+    }
+
+    async fn read_from(
+        &self,
+        endpoint_url: &str,
+        auth_token: &str,
+        sensor_path: &str,
+    ) -> Result<HASensor, HaError> {
+        let response = self
             .client
-            .get(format!("{}/api/states/{}", self.endpoint_url, sensor_path))
-            .bearer_auth(&self.auth_token)
+            .get(format!("{endpoint_url}/api/states/{sensor_path}"))
+            .bearer_auth(auth_token)
+            .timeout(self.timeout)
             .send()
-            .await?
-            .json()
             .await?;
-        Ok(result)
+        let response = response
+            .error_for_status()
+            .map_err(|e| e.status().map_or(HaError::Http(e), HaError::Status))?;
+        let body = response.bytes().await?;
+        serde_json::from_slice(&body).map_err(HaError::Deserialize)
+    }
+}
+
+/// Why a sensor couldn't be read from Home Assistant
+#[derive(Debug)]
+pub enum HaError {
+    /// No HA URL was given
+    NotConfigured,
+    /// HA couldn't be reached, or the connection failed part way
+    Http(reqwest::Error),
+    /// HA answered with an error, e.g. 404 for a sensor it doesn't have
+    Status(StatusCode),
+    /// The body isn't a sensor state
+    Deserialize(serde_json::Error),
+    /// Every one of several instances failed, with each one's error
+    NoInstanceAnswered(Vec<(String, HaError)>),
+}
+
+impl fmt::Display for HaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotConfigured => write!(f, "No HA connection"),
+            Self::Http(e) => write!(f, "{e}"),
+            Self::Status(status) => write!(f, "HA answered {status}"),
+            Self::Deserialize(e) => write!(f, "Unexpected sensor state: {e}"),
+            Self::NoInstanceAnswered(errors) => {
+                write!(f, "No HA instance answered (")?;
+                for (index, (endpoint_url, e)) in errors.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{endpoint_url}: {e}")?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl std::error::Error for HaError {}
+
+impl From<reqwest::Error> for HaError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Http(e)
+    }
+}
+
+impl HaError {
+    /// Whether asking again soon could work, as for a dropped connection or a restarting HA
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::Http(_) | Self::NoInstanceAnswered(_) => true,
+            Self::Status(status) => status.is_server_error(),
+            Self::NotConfigured | Self::Deserialize(_) => false,
+        }
+    }
+}
+
+/// Pairs each comma separated URL with its token, reusing the last token for URLs without one
+fn parse_endpoints(urls: &str, tokens: &str) -> Vec<(String, String)> {
+    let tokens: Vec<&str> = tokens.split(',').map(str::trim).collect();
+    urls.split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .enumerate()
+        .map(|(index, url)| {
+            let token = tokens.get(index).or(tokens.last()).unwrap_or(&"");
+            (url.to_string(), token.to_string())
+        })
+        .collect()
+}
+
+/// The entity ids in a comma separated list, each optionally with an attribute path
+fn sensor_list(sensors: &str) -> Vec<String> {
+    sensors
+        .split(',')
+        .map(str::trim)
+        .filter(|sensor| !sensor.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// How certificates are checked when HA is reached over https
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HaTls {
+    /// Accept any certificate, for self-signed ones that can't be added as a root
+    pub insecure: bool,
+    /// Path to extra PEM root certificates to trust, e.g. a self-signed HA's own. Empty for none.
+    pub ca_cert: String,
+}
+
+impl HaTls {
+    fn client(&self) -> anyhow::Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder();
+        if self.insecure {
+            warn!("HA_INSECURE_TLS is set, HA's certificate won't be checked");
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        if !self.ca_cert.is_empty() {
+            let pem = std::fs::read(&self.ca_cert)
+                .map_err(|e| anyhow::anyhow!("Can't read HA_CA_CERT {:?}: {e}", self.ca_cert))?;
+            let certs = reqwest::Certificate::from_pem_bundle(&pem)?;
+            if certs.is_empty() {
+                anyhow::bail!("No PEM certificates found in HA_CA_CERT {:?}", self.ca_cert);
+            }
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// What an `unavailable` or `unknown` sensor counts as
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnavailablePolicy {
+    /// No reading, so the last offset carries on
+    #[default]
+    Hold,
+    /// 0W, as if the sensor read nothing
+    Zero,
+}
+
+impl FromStr for UnavailablePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "hold" => Ok(Self::Hold),
+            "zero" => Ok(Self::Zero),
+            other => Err(format!("Unknown unavailable policy `{other}`")),
+        }
+    }
+}
+
+/// Everything needed to read the offset from Home Assistant
+#[derive(Debug, Clone, PartialEq)]
+pub struct HaConfig {
+    /// One or more comma separated base URLs, see `HomeAssistantAPI::with_credentials`
+    pub url: String,
+    pub token: String,
+    /// One or more comma separated sensors, summed
+    pub import_sensor: String,
+    /// One or more comma separated sensors, summed
+    pub export_sensor: String,
+    pub export_sign: ExportSign,
+    pub tls: HaTls,
+    /// How long each read may take, see `HomeAssistantAPI::with_timeout`
+    pub timeout: Duration,
+    pub on_unavailable: UnavailablePolicy,
+}
+
+impl HaConfig {
+    /// Whether there is anything to read
+    pub fn has_sensors(&self) -> bool {
+        !sensor_list(&self.import_sensor).is_empty() || !sensor_list(&self.export_sensor).is_empty()
+    }
+}
+
+/// Reads the virtual import and export sensors, and reports their net as an offset
+pub struct HomeAssistantReader {
+    api: HomeAssistantAPI,
+    import_sensors: Vec<String>,
+    export_sensors: Vec<String>,
+    export_sign: ExportSign,
+    on_unavailable: UnavailablePolicy,
+}
+
+impl HomeAssistantReader {
+    /// No import or export sensors read as 0W.
+    /// Fails if the TLS settings can't be used, such as an unreadable `HA_CA_CERT`.
+    pub fn new(config: HaConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            api: HomeAssistantAPI::with_credentials(config.url, config.token)
+                .with_tls(&config.tls)?
+                .with_timeout(config.timeout),
+            import_sensors: sensor_list(&config.import_sensor),
+            export_sensors: sensor_list(&config.export_sensor),
+            export_sign: config.export_sign,
+            on_unavailable: config.on_unavailable,
+        })
+    }
+
+    /// The sum of every sensor, failing if any one of them does
+    async fn read_sensors(
+        api: &mut HomeAssistantAPI,
+        sensor_names: &[String],
+        on_unavailable: UnavailablePolicy,
+    ) -> Result<f32, ReaderError> {
+        let mut total = 0.0;
+        for sensor_name in sensor_names {
+            total += Self::read_sensor(api, sensor_name, on_unavailable).await?;
+        }
+        Ok(total)
+    }
+
+    async fn read_sensor(
+        api: &mut HomeAssistantAPI,
+        sensor_name: &str,
+        on_unavailable: UnavailablePolicy,
+    ) -> Result<f32, ReaderError> {
+        // `sensor.foo:attributes.power` reads the number from an attribute
+        let (entity_id, value_path) = match sensor_name.split_once(':') {
+            Some((entity_id, path)) => (entity_id.trim(), Some(path.trim())),
+            None => (sensor_name, None),
+        };
+        let sensor = api.read_sensor_value(entity_id).await.map_err(|e| {
+            let reason = format!("{sensor_name}: {e}");
+            if e.is_transient() {
+                ReaderError::Unavailable(reason)
+            } else {
+                ReaderError::Invalid(reason)
+            }
+        })?;
+        if let Some(value) = value_path.and_then(|path| sensor.value_at(path)) {
+            let watts = match value {
+                serde_json::Value::Number(number) => number.as_f64().map(|watts| watts as f32),
+                serde_json::Value::String(text) => text.trim().parse().ok(),
+                _ => None,
+            };
+            // `as f32` turns a number too big for it into inf
+            let watts = watts.filter(|watts: &f32| watts.is_finite());
+            return watts.ok_or_else(|| ReaderError::Invalid(format!("{sensor_name} is {value}")));
+        }
+        let state = sensor.state.trim();
+        if NO_VALUE_STATES.contains(&state) {
+            return match on_unavailable {
+                UnavailablePolicy::Hold => {
+                    Err(ReaderError::Invalid(format!("{sensor_name} is {state}")))
+                }
+                UnavailablePolicy::Zero => Ok(0.0),
+            };
+        }
+        // "inf" and "NaN" parse, but would poison any smoothing they reach
+        state
+            .parse()
+            .ok()
+            .filter(|watts: &f32| watts.is_finite())
+            .ok_or_else(|| ReaderError::Invalid(format!("{sensor_name} state `{state}`")))
+    }
+}
+
+impl PowerSource for HomeAssistantReader {
+    fn name(&self) -> &str {
+        "Home Assistant"
+    }
+
+    fn next(&mut self) -> SourceFuture<'_> {
+        Box::pin(async move {
+            // Only a complete set is a usable offset, an unavailable sensor is left to the policy
+            let on_unavailable = self.on_unavailable;
+            let import =
+                Self::read_sensors(&mut self.api, &self.import_sensors, on_unavailable).await?;
+            let export =
+                Self::read_sensors(&mut self.api, &self.export_sensors, on_unavailable).await?;
+            debug!("HA Import {import}W Export {export}W");
+            Ok(Measurement::Offset(self.export_sign.offset(import, export)))
+        })
     }
 }
 
@@ -47,12 +355,24 @@ pub struct HASensor {
     pub last_reported: String,
     #[serde(rename = "last_updated")]
     pub last_updated: String,
+    #[serde(default)]
+    pub attributes: serde_json::Value,
+}
+
+impl HASensor {
+    /// The value at a dotted path such as `attributes.power`, if the sensor has one there
+    pub fn value_at(&self, path: &str) -> Option<&serde_json::Value> {
+        let mut keys = path.split('.');
+        if keys.next()? != "attributes" {
+            return None;
+        }
+        keys.try_fold(&self.attributes, |value, key| value.get(key))
+    }
 }
 
 #[cfg(test)]
 mod test_ha_wrapper {
     use super::*;
-    use std::env;
 
     #[tokio::test]
     async fn test_home_assistant_api() {
@@ -77,13 +397,8 @@ mod test_ha_wrapper {
             )
             .create();
 
-        // Set environment variables for the test
-        env::set_var("HA_URL", server.url());
-        env::set_var("HA_TOKEN", "test_token");
-
         // Create API instance and perform request
-
-        let mut api = HomeAssistantAPI::new();
+        let mut api = HomeAssistantAPI::with_credentials(server.url(), "test_token".to_string());
         let result = api.read_sensor_value("sensor.temperature").await.unwrap();
 
         // Verify result
@@ -99,13 +414,447 @@ mod test_ha_wrapper {
 
     #[tokio::test]
     async fn test_home_assistant_api_no_connection() {
-        // Clear environment variables
-        env::remove_var("HA_URL");
-
-        let mut api = HomeAssistantAPI::new();
+        let mut api = HomeAssistantAPI::with_credentials(String::new(), String::new());
         let result = api.read_sensor_value("sensor.temperature").await;
 
-        assert!(result.is_err());
-        assert_eq!(result.unwrap_err().to_string(), "No HA connection");
+        assert!(matches!(result, Err(HaError::NotConfigured)));
+    }
+
+    /// Accepts connections and never answers, like a hung HA
+    async fn hung_server() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn test_hung_instance_times_out() {
+        let hung = hung_server().await;
+        let timeout = Duration::from_millis(100);
+        let mut api = HomeAssistantAPI::with_credentials(hung.clone(), "token".to_string())
+            .with_timeout(timeout);
+        let started = std::time::Instant::now();
+        let result = api.read_sensor_value("sensor.import").await;
+        assert!(
+            matches!(&result, Err(HaError::Http(e)) if e.is_timeout()),
+            "{result:?}"
+        );
+        assert!(result.unwrap_err().is_transient());
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        // The next instance is tried in good time
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/api/states/sensor.import")
+            .with_header("content-type", "application/json")
+            .with_body(sensor_body("250"))
+            .create_async()
+            .await;
+        let mut api = HomeAssistantAPI::with_credentials(
+            format!("{hung},{}", server.url()),
+            "token".to_string(),
+        )
+        .with_timeout(timeout);
+        let started = std::time::Instant::now();
+        assert_eq!(
+            api.read_sensor_value("sensor.import").await.unwrap().state,
+            "250"
+        );
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    fn sensor_body(state: &str) -> String {
+        format!(
+            r#"{{"entity_id": "sensor.grid", "state": "{state}", "last_changed": "", "last_reported": "", "last_updated": ""}}"#
+        )
+    }
+
+    #[test]
+    fn test_parse_endpoints() {
+        assert_eq!(
+            parse_endpoints("http://a, http://b", "shared"),
+            vec![
+                ("http://a".to_string(), "shared".to_string()),
+                ("http://b".to_string(), "shared".to_string())
+            ]
+        );
+        assert_eq!(
+            parse_endpoints("http://a,http://b", "first,second"),
+            vec![
+                ("http://a".to_string(), "first".to_string()),
+                ("http://b".to_string(), "second".to_string())
+            ]
+        );
+        assert!(parse_endpoints("", "token").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fails_over_to_next_instance() {
+        let mut primary = mockito::Server::new_async().await;
+        let mut secondary = mockito::Server::new_async().await;
+        let primary_mock = primary
+            .mock("GET", "/api/states/sensor.grid")
+            .with_status(500)
+            .expect(2)
+            .create_async()
+            .await;
+        let secondary_mock = secondary
+            .mock("GET", "/api/states/sensor.grid")
+            .match_header("Authorization", "Bearer second_token")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(sensor_body("42"))
+            .expect(2)
+            .create_async()
+            .await;
+
+        let mut api = HomeAssistantAPI::with_credentials(
+            format!("{},{}", primary.url(), secondary.url()),
+            "first_token,second_token".to_string(),
+        );
+        for _ in 0..2 {
+            // The primary is retried every read, so it's used again as soon as it recovers
+            let result = api.read_sensor_value("sensor.grid").await.unwrap();
+            assert_eq!(result.state, "42");
+        }
+        primary_mock.assert_async().await;
+        secondary_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_reports_all_instances_failing() {
+        let mut api = HomeAssistantAPI::with_credentials(
+            "http://127.0.0.1:1,http://127.0.0.1:2".to_string(),
+            "token".to_string(),
+        );
+        let error = api.read_sensor_value("sensor.grid").await.unwrap_err();
+        assert!(matches!(&error, HaError::NoInstanceAnswered(errors) if errors.len() == 2));
+        let message = error.to_string();
+        assert!(message.contains("127.0.0.1:1"), "{message}");
+        assert!(message.contains("127.0.0.1:2"), "{message}");
+    }
+
+    #[tokio::test]
+    async fn test_reader_from_explicit_config() {
+        let mut server = mockito::Server::new_async().await;
+        for (sensor, state) in [("sensor.import", "1000"), ("sensor.export", "-400")] {
+            server
+                .mock("GET", format!("/api/states/{sensor}").as_str())
+                .match_header("Authorization", "Bearer reader_token")
+                .with_status(200)
+                .with_header("content-type", "application/json")
+                .with_body(sensor_body(state))
+                .create_async()
+                .await;
+        }
+        let config = HaConfig {
+            url: server.url(),
+            token: "reader_token".to_string(),
+            import_sensor: "sensor.import".to_string(),
+            export_sensor: "sensor.export".to_string(),
+            export_sign: ExportSign::Negative,
+            tls: HaTls::default(),
+            timeout: DEFAULT_TIMEOUT,
+            on_unavailable: UnavailablePolicy::Hold,
+        };
+        assert!(config.has_sensors());
+        let mut reader = HomeAssistantReader::new(config).unwrap();
+        assert_eq!(reader.next().await, Ok(Measurement::Offset(600.0)));
+    }
+
+    #[tokio::test]
+    async fn test_sensor_lists_are_summed() {
+        let mut server = mockito::Server::new_async().await;
+        for (sensor, state) in [
+            ("sensor.import_a", "400"),
+            ("sensor.import_b", "150.5"),
+            ("sensor.export_a", "100"),
+            ("sensor.export_b", "50"),
+            ("sensor.import_down", "unavailable"),
+        ] {
+            server
+                .mock("GET", format!("/api/states/{sensor}").as_str())
+                .with_status(200)
+                .with_header("content-type", "application/json")
+                .with_body(sensor_body(state))
+                .create_async()
+                .await;
+        }
+        let config = |on_unavailable, import_sensor: &str| HaConfig {
+            url: server.url(),
+            token: "token".to_string(),
+            import_sensor: import_sensor.to_string(),
+            export_sensor: "sensor.export_a, sensor.export_b".to_string(),
+            export_sign: ExportSign::Positive,
+            tls: HaTls::default(),
+            timeout: DEFAULT_TIMEOUT,
+            on_unavailable,
+        };
+
+        let mut reader = HomeAssistantReader::new(config(
+            UnavailablePolicy::Hold,
+            "sensor.import_a,sensor.import_b,",
+        ))
+        .unwrap();
+        assert_eq!(reader.next().await, Ok(Measurement::Offset(400.5)));
+
+        // One sensor down is handled as it would be on its own
+        let sensors = "sensor.import_a,sensor.import_b,sensor.import_down";
+        let mut hold = HomeAssistantReader::new(config(UnavailablePolicy::Hold, sensors)).unwrap();
+        assert!(matches!(hold.next().await, Err(ReaderError::Invalid(_))));
+        let mut zero = HomeAssistantReader::new(config(UnavailablePolicy::Zero, sensors)).unwrap();
+        assert_eq!(zero.next().await, Ok(Measurement::Offset(400.5)));
+
+        let separators_only = HaConfig {
+            export_sensor: ",".to_string(),
+            ..config(UnavailablePolicy::Hold, " , ")
+        };
+        assert!(!separators_only.has_sensors());
+        assert_eq!(
+            sensor_list(" sensor.a ,, sensor.b:attributes.power "),
+            vec!["sensor.a", "sensor.b:attributes.power"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_sensor_follows_policy() {
+        let mut server = mockito::Server::new_async().await;
+        for (sensor, state) in [
+            ("sensor.import", "unavailable"),
+            ("sensor.export", "250"),
+            ("sensor.garbage", "on"),
+        ] {
+            server
+                .mock("GET", format!("/api/states/{sensor}").as_str())
+                .with_status(200)
+                .with_header("content-type", "application/json")
+                .with_body(sensor_body(state))
+                .create_async()
+                .await;
+        }
+        let config = |on_unavailable, import_sensor: &str| HaConfig {
+            url: server.url(),
+            token: "token".to_string(),
+            import_sensor: import_sensor.to_string(),
+            export_sensor: "sensor.export".to_string(),
+            export_sign: ExportSign::Positive,
+            tls: HaTls::default(),
+            timeout: DEFAULT_TIMEOUT,
+            on_unavailable,
+        };
+
+        // No offset at all, so the combiner keeps the last one
+        let mut hold =
+            HomeAssistantReader::new(config(UnavailablePolicy::Hold, "sensor.import")).unwrap();
+        assert!(matches!(hold.next().await, Err(ReaderError::Invalid(_))));
+        let mut zero =
+            HomeAssistantReader::new(config(UnavailablePolicy::Zero, "sensor.import")).unwrap();
+        assert_eq!(zero.next().await, Ok(Measurement::Offset(-250.0)));
+        // Other states that aren't numbers are never read as 0
+        let mut garbage =
+            HomeAssistantReader::new(config(UnavailablePolicy::Zero, "sensor.garbage")).unwrap();
+        assert!(matches!(garbage.next().await, Err(ReaderError::Invalid(_))));
+    }
+
+    #[tokio::test]
+    async fn test_non_finite_values_are_rejected() {
+        let mut server = mockito::Server::new_async().await;
+        for (sensor, state) in [("sensor.inf", "inf"), ("sensor.nan", "NaN")] {
+            server
+                .mock("GET", format!("/api/states/{sensor}").as_str())
+                .with_header("content-type", "application/json")
+                .with_body(sensor_body(state))
+                .create_async()
+                .await;
+        }
+        server
+            .mock("GET", "/api/states/sensor.plug")
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"entity_id": "sensor.plug", "state": "on", "last_changed": "",
+                    "last_reported": "", "last_updated": "",
+                    "attributes": {"power": 1e300, "text": "-inf"}}"#,
+            )
+            .create_async()
+            .await;
+        for sensor in [
+            "sensor.inf",
+            "sensor.nan",
+            "sensor.plug:attributes.power",
+            "sensor.plug:attributes.text",
+        ] {
+            let mut reader = HomeAssistantReader::new(HaConfig {
+                url: server.url(),
+                token: "token".to_string(),
+                import_sensor: sensor.to_string(),
+                export_sensor: String::new(),
+                export_sign: ExportSign::Positive,
+                tls: HaTls::default(),
+                timeout: DEFAULT_TIMEOUT,
+                on_unavailable: UnavailablePolicy::Zero,
+            })
+            .unwrap();
+            let result = reader.next().await;
+            assert!(
+                matches!(result, Err(ReaderError::Invalid(_))),
+                "{sensor}: {result:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_value_from_an_attribute() {
+        let mut server = mockito::Server::new_async().await;
+        server
+            .mock("GET", "/api/states/sensor.plug")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"{"entity_id": "sensor.plug", "state": "Measuring", "last_changed": "",
+                    "last_reported": "", "last_updated": "",
+                    "attributes": {"power": 320.5, "phases": {"l1": "110"}, "mode": "eco"}}"#,
+            )
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/api/states/sensor.export")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(sensor_body("20"))
+            .create_async()
+            .await;
+        let config = |import_sensor: &str| HaConfig {
+            url: server.url(),
+            token: "token".to_string(),
+            import_sensor: import_sensor.to_string(),
+            export_sensor: "sensor.export".to_string(),
+            export_sign: ExportSign::Positive,
+            tls: HaTls::default(),
+            timeout: DEFAULT_TIMEOUT,
+            on_unavailable: UnavailablePolicy::Hold,
+        };
+
+        let mut reader = HomeAssistantReader::new(config("sensor.plug:attributes.power")).unwrap();
+        assert_eq!(reader.next().await, Ok(Measurement::Offset(300.5)));
+        let mut nested =
+            HomeAssistantReader::new(config("sensor.plug:attributes.phases.l1")).unwrap();
+        assert_eq!(nested.next().await, Ok(Measurement::Offset(90.0)));
+        let mut text = HomeAssistantReader::new(config("sensor.plug:attributes.mode")).unwrap();
+        assert!(matches!(text.next().await, Err(ReaderError::Invalid(_))));
+        // Without the attribute it falls back to the state, which isn't a number here
+        let mut missing =
+            HomeAssistantReader::new(config("sensor.plug:attributes.energy")).unwrap();
+        assert!(matches!(missing.next().await, Err(ReaderError::Invalid(_))));
+
+        // Sensors without attributes still read their state
+        let sensor: HASensor = serde_json::from_str(&sensor_body("5")).unwrap();
+        assert_eq!(sensor.value_at("attributes.power"), None);
+    }
+
+    #[tokio::test]
+    async fn test_error_statuses_are_reported() {
+        let mut server = mockito::Server::new_async().await;
+        for (sensor, status) in [("sensor.missing", 404), ("sensor.broken", 500)] {
+            server
+                .mock("GET", format!("/api/states/{sensor}").as_str())
+                .with_status(status)
+                .with_body("{}")
+                .create_async()
+                .await;
+        }
+        server
+            .mock("GET", "/api/states/sensor.garbled")
+            .with_status(200)
+            .with_body("<html>")
+            .create_async()
+            .await;
+        let mut api = HomeAssistantAPI::with_credentials(server.url(), "token".to_string());
+
+        let missing = api.read_sensor_value("sensor.missing").await;
+        assert!(
+            matches!(missing, Err(HaError::Status(StatusCode::NOT_FOUND))),
+            "{missing:?}"
+        );
+        let broken = api.read_sensor_value("sensor.broken").await;
+        assert!(
+            matches!(
+                broken,
+                Err(HaError::Status(StatusCode::INTERNAL_SERVER_ERROR))
+            ),
+            "{broken:?}"
+        );
+        let garbled = api.read_sensor_value("sensor.garbled").await;
+        assert!(
+            matches!(garbled, Err(HaError::Deserialize(_))),
+            "{garbled:?}"
+        );
+
+        // A restarting HA is worth retrying, a sensor that isn't there isn't
+        let config = |import_sensor: &str| HaConfig {
+            url: server.url(),
+            token: "token".to_string(),
+            import_sensor: import_sensor.to_string(),
+            export_sensor: String::new(),
+            export_sign: ExportSign::Positive,
+            tls: HaTls::default(),
+            timeout: DEFAULT_TIMEOUT,
+            on_unavailable: UnavailablePolicy::Hold,
+        };
+        let mut reader = HomeAssistantReader::new(config("sensor.broken")).unwrap();
+        assert!(matches!(
+            reader.next().await,
+            Err(ReaderError::Unavailable(_))
+        ));
+        let mut reader = HomeAssistantReader::new(config("sensor.missing")).unwrap();
+        assert!(matches!(reader.next().await, Err(ReaderError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_parse_unavailable_policy() {
+        assert_eq!("HOLD".parse(), Ok(UnavailablePolicy::Hold));
+        assert_eq!(" zero".parse(), Ok(UnavailablePolicy::Zero));
+        assert!("skip".parse::<UnavailablePolicy>().is_err());
+    }
+
+    const SELF_SIGNED_CERT: &str = "-----BEGIN CERTIFICATE-----\n\
+MIIBkjCCATmgAwIBAgIUH+jPowXNalb/ophigL22UIuCidUwCgYIKoZIzj0EAwIw\n\
+HjEcMBoGA1UEAwwTaG9tZWFzc2lzdGFudC5sb2NhbDAgFw0yNjEwMTYxODMxMzda\n\
+GA8yMTI2MDkyMjE4MzEzN1owHjEcMBoGA1UEAwwTaG9tZWFzc2lzdGFudC5sb2Nh\n\
+bDBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABAU6C+OnzJxuiAKRwW36BjaWYq0/\n\
+JkFpFeng8lD/6qv1+Wbz84Dbr1w9omUduMJYcTVd7YCvoBNTycloFweEO+6jUzBR\n\
+MB0GA1UdDgQWBBRfPSjwY4eHgMqyeDqpTd/bacUeYDAfBgNVHSMEGDAWgBRfPSjw\n\
+Y4eHgMqyeDqpTd/bacUeYDAPBgNVHRMBAf8EBTADAQH/MAoGCCqGSM49BAMCA0cA\n\
+MEQCIA+zO+wZnDD7FMUST1emXYSudA6SChg8L/zQfRWqdtEdAiAOECKCihkN+aJJ\n\
+OGanzWH9lopbeY6lVqr1WbzSMo/dsg==\n\
+-----END CERTIFICATE-----";
+
+    #[test]
+    fn test_tls_client_settings() {
+        assert!(HaTls::default().client().is_ok());
+        let insecure = HaTls {
+            insecure: true,
+            ..Default::default()
+        };
+        assert!(insecure.client().is_ok());
+
+        let path = std::env::temp_dir().join(format!("ha-ca-{}.pem", std::process::id()));
+        let with_ca = HaTls {
+            ca_cert: path.to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+        assert!(with_ca.client().is_err(), "The file doesn't exist yet");
+        std::fs::write(&path, "not a certificate").unwrap();
+        let garbage = with_ca.client();
+        std::fs::write(&path, SELF_SIGNED_CERT).unwrap();
+        let valid = with_ca.client();
+        std::fs::remove_file(&path).unwrap();
+        assert!(garbage.is_err());
+        assert!(valid.is_ok());
     }
 }
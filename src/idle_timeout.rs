@@ -0,0 +1,132 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{self, Instant, Sleep},
+};
+
+// Inverters that go away without closing their connection would otherwise hold it open forever
+
+/// Wraps a stream so that reads fail with `TimedOut` once nothing has been received for `timeout`.
+/// This covers both connections that never send anything and ones that go quiet.
+/// With no timeout, or one too long to have a deadline, the stream is passed through untouched.
+pub struct IdleTimeoutStream<S> {
+    inner: S,
+    timeout: Option<(Duration, Pin<Box<Sleep>>)>,
+}
+
+impl<S> IdleTimeoutStream<S> {
+    pub fn new(inner: S, timeout: Option<Duration>) -> Self {
+        let timeout = timeout.and_then(|timeout| {
+            let deadline = Instant::now().checked_add(timeout)?;
+            Some((timeout, Box::pin(time::sleep_until(deadline))))
+        });
+        Self { inner, timeout }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for IdleTimeoutStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        let Some((timeout, deadline)) = &mut this.timeout else {
+            return result;
+        };
+        match result {
+            Poll::Ready(result) => {
+                match Instant::now().checked_add(*timeout) {
+                    Some(next) => deadline.as_mut().reset(next),
+                    // Only reachable with a clock that has run for ages, so stop timing out
+                    None => this.timeout = None,
+                }
+                Poll::Ready(result)
+            }
+            Poll::Pending => match deadline.as_mut().poll(cx) {
+                Poll::Ready(()) => Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("Connection idle for more than {timeout:?}"),
+                ))),
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for IdleTimeoutStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_activity_resets_the_timeout() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut server = IdleTimeoutStream::new(server, Some(Duration::from_secs(10)));
+        let writer = tokio::spawn(async move {
+            let mut client = client;
+            for byte in 0..3 {
+                time::sleep(Duration::from_secs(8)).await;
+                client.write_all(&[byte]).await.unwrap();
+            }
+            // Keep the connection open but quiet
+            time::sleep(Duration::from_secs(60)).await;
+        });
+
+        let mut byte = [0];
+        for expected in 0..3 {
+            server.read_exact(&mut byte).await.unwrap();
+            assert_eq!(byte[0], expected);
+        }
+        let start = Instant::now();
+        let err = server.read(&mut byte).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+        writer.abort();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_no_timeout() {
+        // A timeout too long for a deadline is the same as none
+        for timeout in [None, Some(Duration::from_secs(u64::MAX))] {
+            let (mut client, server) = tokio::io::duplex(64);
+            let mut server = IdleTimeoutStream::new(server, timeout);
+            let reader = tokio::spawn(async move {
+                let mut bytes = [0; 2];
+                server.read_exact(&mut bytes[..1]).await?;
+                server.read_exact(&mut bytes[1..]).await.map(|_| bytes)
+            });
+            time::sleep(Duration::from_secs(24 * 60 * 60)).await;
+            client.write_all(&[7]).await.unwrap();
+            time::sleep(Duration::from_secs(24 * 60 * 60)).await;
+            client.write_all(&[8]).await.unwrap();
+            assert_eq!(reader.await.unwrap().unwrap(), [7, 8], "{timeout:?}");
+        }
+    }
+}
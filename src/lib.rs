@@ -0,0 +1,44 @@
+//! Emulates a Fronius Smart Meter over Modbus TCP, so a Fronius inverter can be fed the grid
+//! power from another meter, optionally shifted by an offset from Home Assistant.
+//!
+//! The binary wires everything up from environment variables, or the matching flags. The meter can also be embedded and
+//! fed directly, see [`MeterHandle`].
+
+pub mod calibration;
+pub mod cli;
+pub mod config;
+pub mod connection_limit;
+#[cfg(feature = "control")]
+pub mod control;
+pub mod data_fetcher;
+pub mod energy;
+pub mod events;
+pub mod fault_injection;
+pub mod health;
+pub mod home_assistant;
+pub mod idle_timeout;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "mqtt")]
+pub mod mqtt_reader;
+pub mod offset_file_reader;
+pub mod offset_limiter;
+pub mod output_scheduler;
+pub mod poll_reliability;
+pub mod power_combiner;
+pub mod power_model;
+pub mod power_source;
+pub mod register_bank;
+pub mod replay;
+pub mod retry;
+pub mod rng;
+pub mod rolling_average;
+pub mod shelly_3em_client;
+pub mod shelly_http;
+pub mod smart_meter_emulator;
+pub mod smoothing;
+pub mod sunspec_map;
+#[cfg(test)]
+mod test_utils;
+
+pub use smart_meter_emulator::{MeterHandle, Phase, Readings, SmartMeterEmulator};
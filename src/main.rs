@@ -1,46 +1,737 @@
-use data_fetcher::DataFetcher;
-use smart_meter_emulator::SmartMeterEmulator;
-use std::net::SocketAddr;
+use clap::Parser;
+use fronius_meter_emulation::{
+    cli::Cli,
+    config::Config,
+    connection_limit::{ConnectionLimit, PermittedStream},
+    data_fetcher::DataFetcher,
+    health::HealthStatus,
+    idle_timeout::IdleTimeoutStream,
+    replay,
+    shelly_3em_client::{FloatLayout, Shelly3EMClient},
+    shelly_http,
+    smart_meter_emulator::SmartMeterEmulator,
+    sunspec_map::TOTAL_REAL_POWER,
+};
+use std::{
+    env,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
 use tokio::net::TcpListener;
-use tokio_modbus::server::tcp::{accept_tcp_connection, Server};
-mod data_fetcher;
-mod home_assistant;
-mod rolling_average;
-mod shelly_3em_client;
-mod smart_meter_emulator;
+use tokio_modbus::{
+    client::Reader,
+    server::{
+        tcp::{accept_tcp_connection, Server},
+        Terminated,
+    },
+    Slave,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt::init();
+    let cli = Cli::parse();
+    // Flags override the environment variables of the same name
+    let config = cli.config(|name| env::var(name).ok())?;
+    if cli.dump_config {
+        print!("{}", config.to_toml(cli.dump_secrets));
+        return Ok(());
+    }
+    if let Some(register) = cli.probe_register {
+        probe_register(&config, register).await;
+        return Ok(());
+    }
+    if cli.probe {
+        match probe_meter(&config).await {
+            Ok(watts) => println!("The meter answered with {watts}W"),
+            Err(e) => {
+                println!("The meter didn't answer: {e}");
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
 
-    println!("Starting Fronius modbus bridge");
-    let socket_addr = "0.0.0.0:5502".parse().unwrap();
+    // Info and up by default, RUST_LOG=debug adds every register read and reading
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
 
-    let (emulated_meter, meter_update_handle) = SmartMeterEmulator::new();
-    let _data_fetcher = DataFetcher::new(meter_update_handle);
+    info!("Starting Fronius modbus bridge");
+    // Before the meter starts, as it exits the process as soon as nothing can update it
+    if !config.dry_run {
+        if let Err(e) = DataFetcher::check_sources(&config) {
+            error!("{e}");
+            std::process::exit(1);
+        }
+    }
+    let socket_addr = config.listen_addr;
 
-    //Start fake meter
-    server_context(socket_addr, emulated_meter)
-        .await
-        .expect("Should never exit fake meter");
+    let mut emulator_options = config.emulator_options();
+    if config.dry_run {
+        // Nothing will update it
+        emulator_options.update_timeout = None;
+    }
+    let (mut emulated_meter, meter_update_handle) = SmartMeterEmulator::builder()
+        .options(emulator_options)
+        .channel_capacity(config.readings_channel_cap)
+        .build();
+    // An inverter can't make sense of a broken map, so don't serve one
+    if let Err(e) = emulated_meter.validate_map().await {
+        error!("The meter's register map is broken: {e}");
+        std::process::exit(1);
+    }
+    if config.delay_serve_until_ready {
+        let max_wait = config.delay_serve_timeout_s;
+        info!("Delaying serving requests until data is ready, for up to {max_wait}s");
+        emulated_meter = emulated_meter.delay_serving_until_ready(Duration::from_secs(max_wait));
+    }
+    emulated_meter = emulated_meter.with_framing(config.modbus_framing);
+    if config.meter_filter_unit_id {
+        emulated_meter = emulated_meter.answer_only_unit_id(config.meter_unit_id);
+    }
+    if let Some(faults) = config.fault_injection() {
+        warn!("FAULT INJECTION ENABLED, the meter will answer slowly or not at all: {faults:?}");
+        emulated_meter = emulated_meter.with_fault_injection(faults);
+    }
+    #[cfg(not(feature = "mqtt"))]
+    if !config.mqtt_power_topic.is_empty() {
+        warn!("MQTT_POWER_TOPIC is set, but MQTT needs building with the `mqtt` feature");
+    }
+    #[cfg(not(feature = "metrics"))]
+    if config.metrics_port.is_some() {
+        warn!("METRICS_PORT is set, but metrics need building with the `metrics` feature");
+    }
+    #[cfg(not(feature = "health"))]
+    if config.health_port.is_some() {
+        warn!("HEALTH_PORT is set, but health checks need building with the `health` feature");
+    }
+    #[cfg(not(feature = "control"))]
+    if config.control_port.is_some() {
+        warn!("CONTROL_PORT is set, but the control API needs building with the `control` feature");
+    }
+    #[cfg(feature = "health")]
+    let health_port = config.health_port;
+    #[cfg(feature = "control")]
+    let control_port = config.control_port;
+    let idle_timeout = config.modbus_idle_timeout();
+    let connection_limit = ConnectionLimit::new(config.max_modbus_clients());
+    if config.dry_run {
+        warn!("DRY_RUN is set, the meter isn't fed any readings and logs every read instead");
+        drop(meter_update_handle);
+        let emulated_meter = emulated_meter.with_read_logging();
+        let shutdown = CancellationToken::new();
+        let health = HealthStatus::default();
+        return Ok(server_context(
+            socket_addr,
+            emulated_meter,
+            idle_timeout,
+            connection_limit,
+            health,
+            shutdown,
+        )
+        .await?);
+    }
+    let mut data_fetcher = DataFetcher::new(meter_update_handle, config)?;
+    let shutdown = data_fetcher.shutdown_token();
+    let health = data_fetcher.health();
+    #[cfg(feature = "health")]
+    if let Some(port) = health_port {
+        let socket_addr = SocketAddr::from(([0, 0, 0, 0], port));
+        let server =
+            fronius_meter_emulation::health::serve(socket_addr, health.clone(), shutdown.clone());
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                error!("Health check server failed: {e}");
+            }
+        });
+    }
+    #[cfg(feature = "control")]
+    if let Some(port) = control_port {
+        let socket_addr = SocketAddr::from(([0, 0, 0, 0], port));
+        let server = fronius_meter_emulation::control::serve(
+            socket_addr,
+            emulated_meter.clone(),
+            data_fetcher.power_override(),
+            shutdown.clone(),
+        );
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                error!("Control API server failed: {e}");
+            }
+        });
+    }
+
+    //Start fake meter, until the fetcher is shut down
+    tokio::select! {
+        result = server_context(socket_addr, emulated_meter, idle_timeout, connection_limit, health, shutdown.clone()) => {
+            result?;
+        }
+        // Exit so that whatever supervises the process can restart it
+        _ = data_fetcher.stopped() => {
+            shutdown.cancel();
+            return Err("Data fetcher stopped".into());
+        }
+    }
 
     Ok(())
 }
 
+/// Prints the Shelly register pair at `register` decoded with every float layout,
+/// to help pick `SHELLY_FLOAT_LAYOUT` for a device
+async fn probe_register(config: &Config, register: u16) {
+    let words = match read_shelly_register(config, register).await {
+        Ok(words) => words,
+        Err(e) => {
+            println!("Couldn't read register {register}: {e}");
+            return;
+        }
+    };
+    println!("Register {register}: {:#06x} {:#06x}", words[0], words[1]);
+    for layout in FloatLayout::ALL {
+        println!("{layout:?}: {}", layout.decode(words));
+    }
+}
+
+/// Reads the register pair at `register` from the first Shelly set up in `config`
+async fn read_shelly_register(config: &Config, register: u16) -> Result<[u16; 2], String> {
+    // With several devices, probe the first
+    let device = &config.shelly().devices()[0];
+    let address = device.address.trim();
+    if address.is_empty() {
+        return Err("SHELLY_MODBUS isn't set, so there is no Shelly to probe".to_string());
+    }
+    if shelly_http::device_url(address).is_some() || replay::trace_path(address).is_some() {
+        return Err(format!(
+            "`{address}` isn't read over Modbus, so it has no registers to probe"
+        ));
+    }
+    let mut client = Shelly3EMClient::try_connect(device)
+        .await
+        .map_err(|e| format!("can't connect to `{address}`, {e}"))?;
+    client.read_words(register).await.map_err(|e| e.to_string())
+}
+
+/// How long `--probe` waits for the meter, inside Docker's default healthcheck timeout
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reads the total power from the meter this config serves, as an inverter would,
+/// for a healthcheck that needs nothing but the binary
+async fn probe_meter(config: &Config) -> Result<f32, String> {
+    // Listening on every interface can be reached on loopback
+    let mut socket_addr = config.listen_addr;
+    match socket_addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => socket_addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+        IpAddr::V6(ip) if ip.is_unspecified() => socket_addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+        _ => {}
+    }
+    let read = async {
+        let mut client =
+            tokio_modbus::client::tcp::connect_slave(socket_addr, Slave(config.meter_unit_id))
+                .await
+                .map_err(|e| format!("can't connect to {socket_addr}, {e}"))?;
+        client
+            .read_holding_registers(TOTAL_REAL_POWER.address, TOTAL_REAL_POWER.len)
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| format!("Modbus exception {e}"))
+    };
+    let words = tokio::time::timeout(PROBE_TIMEOUT, read)
+        .await
+        .map_err(|_| format!("no answer from {socket_addr} within {PROBE_TIMEOUT:?}"))??;
+    let [first, second] = words[..] else {
+        return Err(format!("{} registers came back rather than 2", words.len()));
+    };
+    let watts = config.meter_float_layout.decode([first, second]);
+    if watts.is_finite() {
+        Ok(watts)
+    } else {
+        Err(format!("the total power decoded to {watts}"))
+    }
+}
+
 async fn server_context(
     socket_addr: SocketAddr,
     emulated_meter: SmartMeterEmulator,
+    idle_timeout: Option<Duration>,
+    connection_limit: ConnectionLimit,
+    health: HealthStatus,
+    shutdown: CancellationToken,
 ) -> anyhow::Result<()> {
-    println!("Starting up server on {socket_addr}");
+    info!("Starting up server on {socket_addr}");
     let listener = TcpListener::bind(socket_addr).await?;
+    health.set_listening();
+    serve(
+        listener,
+        emulated_meter,
+        idle_timeout,
+        connection_limit,
+        shutdown,
+    )
+    .await
+}
+
+/// Serves the meter on `listener` until `shutdown` is cancelled,
+/// closing connections that receive nothing for `idle_timeout`,
+/// and refusing those past the `connection_limit`
+async fn serve(
+    listener: TcpListener,
+    emulated_meter: SmartMeterEmulator,
+    idle_timeout: Option<Duration>,
+    connection_limit: ConnectionLimit,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
     let server = Server::new(listener);
     let new_service = |_socket_addr| Ok(Some(emulated_meter.clone()));
+    let connection_limit = &connection_limit;
     let on_connected = |stream, socket_addr| async move {
-        accept_tcp_connection(stream, socket_addr, new_service)
+        let Some(permit) = connection_limit.try_acquire() else {
+            // Dropping the stream closes the connection
+            warn!(
+                "Refusing {socket_addr}, already serving {} clients",
+                connection_limit.active()
+            );
+            return Ok(None);
+        };
+        let accepted = accept_tcp_connection(stream, socket_addr, new_service)?;
+        Ok(accepted.map(|(service, stream)| {
+            let stream = PermittedStream::new(stream, permit);
+            (service, IdleTimeoutStream::new(stream, idle_timeout))
+        }))
     };
     let on_process_error = |err| {
-        eprintln!("{err}");
+        error!("{err}");
     };
-    server.serve(&on_connected, on_process_error).await?;
+    let shutdown = async move { shutdown.cancelled().await };
+    if let Terminated::Aborted = server
+        .serve_until(&on_connected, on_process_error, shutdown)
+        .await?
+    {
+        info!("Meter server shut down");
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fronius_meter_emulation::{
+        smart_meter_emulator::{self, FramingMode, Readings},
+        sunspec_map,
+    };
+    use tokio_modbus::{client::Context, prelude::*};
+
+    async fn start_server(emulated_meter: SmartMeterEmulator) -> Context {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket_addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(
+            listener,
+            emulated_meter,
+            None,
+            ConnectionLimit::default(),
+            CancellationToken::new(),
+        ));
+        tcp::connect(socket_addr).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_busy_until_first_reading() {
+        let (emulated_meter, meter_update_handle) = SmartMeterEmulator::new();
+        let emulated_meter = emulated_meter.delay_serving_until_ready(Duration::from_secs(60));
+        let mut client = start_server(emulated_meter).await;
+
+        let response = client.read_holding_registers(40097, 2).await.unwrap();
+        assert_eq!(response, Err(ExceptionCode::ServerDeviceBusy));
+
+        meter_update_handle
+            .send(Readings::TotalRealPower(1.0))
+            .await
+            .unwrap();
+        // The reading is applied by a background task, so give it a moment to land
+        let registers = tokio::time::timeout(Duration::from_secs(1), async {
+            loop {
+                if let Ok(registers) = client.read_holding_registers(40097, 2).await.unwrap() {
+                    break registers;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("Meter should serve once a reading arrived");
+        assert_eq!(registers, vec![0x3F80, 0x0000]);
+    }
+
+    #[tokio::test]
+    async fn test_serves_after_ready_timeout() {
+        let (emulated_meter, _meter_update_handle) = SmartMeterEmulator::new();
+        let emulated_meter = emulated_meter.delay_serving_until_ready(Duration::ZERO);
+        let mut client = start_server(emulated_meter).await;
+
+        let response = client.read_holding_registers(40097, 2).await.unwrap();
+        assert_eq!(response, Ok(vec![0, 0]));
+    }
+
+    #[tokio::test]
+    async fn test_serves_immediately_by_default() {
+        let (emulated_meter, _meter_update_handle) = SmartMeterEmulator::new();
+        let mut client = start_server(emulated_meter).await;
+
+        let response = client.read_holding_registers(40000, 2).await.unwrap();
+        assert_eq!(response, Ok(vec![0x5375, 0x6e53]));
+    }
+
+    #[tokio::test]
+    async fn test_sunspec_address_follows_unit_id() {
+        let (emulated_meter, _meter_update_handle) =
+            SmartMeterEmulator::with_options(smart_meter_emulator::EmulatorOptions {
+                unit_id: 17,
+                ..Default::default()
+            });
+        let mut client = start_server(emulated_meter).await;
+
+        let response = client.read_holding_registers(40068, 1).await.unwrap();
+        assert_eq!(response, Ok(vec![17]));
+    }
+
+    #[tokio::test]
+    async fn test_other_unit_ids_are_ignored_when_filtering() {
+        let (emulated_meter, _meter_update_handle) = SmartMeterEmulator::new();
+        let emulated_meter = emulated_meter.answer_only_unit_id(240);
+        let mut client = start_server(emulated_meter).await;
+
+        client.set_slave(Slave(1));
+        let response = tokio::time::timeout(
+            Duration::from_millis(200),
+            client.read_holding_registers(40000, 1),
+        )
+        .await;
+        assert!(response.is_err(), "No answer for another unit");
+
+        // A fresh connection, as the ignored request may still be in flight on the first
+        let (emulated_meter, _meter_update_handle) = SmartMeterEmulator::new();
+        let mut client = start_server(emulated_meter.answer_only_unit_id(240)).await;
+        client.set_slave(Slave(240));
+        let response = client.read_holding_registers(40000, 1).await.unwrap();
+        assert_eq!(response, Ok(vec![0x5375]));
+    }
+
+    #[tokio::test]
+    async fn test_model_follows_topology() {
+        // The meter exits the process once its update handle is dropped, so keep them all
+        let mut update_handles = Vec::new();
+        for topology in sunspec_map::MeterTopology::ALL {
+            let (emulated_meter, meter_update_handle) =
+                SmartMeterEmulator::with_options(smart_meter_emulator::EmulatorOptions {
+                    topology,
+                    ..Default::default()
+                });
+            update_handles.push(meter_update_handle);
+            let mut client = start_server(emulated_meter).await;
+
+            let response = client.read_holding_registers(40069, 2).await.unwrap();
+            assert_eq!(response, Ok(vec![topology.model_id(), 124]), "{topology:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_probe_reads_a_live_meter() {
+        let (emulated_meter, meter_update_handle) = SmartMeterEmulator::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = Config {
+            listen_addr: listener.local_addr().unwrap(),
+            ..Config::default()
+        };
+        tokio::spawn(serve(
+            listener,
+            emulated_meter,
+            None,
+            ConnectionLimit::default(),
+            CancellationToken::new(),
+        ));
+        assert_eq!(probe_meter(&config).await, Ok(0.0));
+
+        meter_update_handle
+            .send(Readings::TotalRealPower(-1234.5))
+            .await
+            .unwrap();
+        let watts = tokio::time::timeout(Duration::from_secs(1), async {
+            loop {
+                match probe_meter(&config).await {
+                    Ok(watts) if watts != 0.0 => break watts,
+                    _ => tokio::time::sleep(Duration::from_millis(5)).await,
+                }
+            }
+        })
+        .await
+        .expect("The probe should see the reading");
+        assert_eq!(watts, -1234.5);
+    }
+
+    #[tokio::test]
+    async fn test_probe_fails_without_a_meter() {
+        // Bound then dropped, so nothing is listening there
+        let listen_addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let config = Config {
+            listen_addr,
+            ..Config::default()
+        };
+        assert!(probe_meter(&config).await.is_err());
+
+        // A meter that doesn't answer the probe's unit id
+        let (emulated_meter, _meter_update_handle) = SmartMeterEmulator::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = Config {
+            listen_addr: listener.local_addr().unwrap(),
+            meter_unit_id: 1,
+            ..Config::default()
+        };
+        tokio::spawn(serve(
+            listener,
+            emulated_meter.answer_only_unit_id(240),
+            None,
+            ConnectionLimit::default(),
+            CancellationToken::new(),
+        ));
+        tokio::time::pause();
+        assert!(probe_meter(&config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_register_probe_reports_unusable_devices() {
+        // Bound then dropped, so nothing is listening there
+        let unreachable = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        for (address, expected) in [
+            ("", "isn't set"),
+            ("http://192.168.1.20", "no registers"),
+            ("replay:///data/trace.csv", "no registers"),
+            ("shelly.local", "can't connect"),
+            (&unreachable.to_string(), "can't connect"),
+        ] {
+            let config = Config {
+                shelly_modbus: address.to_string(),
+                ..Config::default()
+            };
+            let error = read_shelly_register(&config, 1013).await.unwrap_err();
+            assert!(error.contains(expected), "{address}: {error}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_then_read_back() {
+        let (emulated_meter, _meter_update_handle) = SmartMeterEmulator::new();
+        let mut client = start_server(emulated_meter).await;
+
+        let response = client.write_single_register(50000, 0x1234).await.unwrap();
+        assert_eq!(response, Ok(()));
+        let response = client.read_holding_registers(50000, 1).await.unwrap();
+        assert_eq!(response, Ok(vec![0x1234]));
+
+        let response = client.write_single_register(40000, 0).await.unwrap();
+        assert_eq!(response, Err(ExceptionCode::IllegalDataAddress));
+    }
+
+    // Requests and the expected responses as raw Modbus TCP frames, so the exact response
+    // layout is checked rather than what the client library makes of it
+    async fn raw_exchange(emulated_meter: SmartMeterEmulator, request: &[u8]) -> Vec<u8> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket_addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(
+            listener,
+            emulated_meter,
+            None,
+            ConnectionLimit::default(),
+            CancellationToken::new(),
+        ));
+        let mut stream = tokio::net::TcpStream::connect(socket_addr).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        let mut header = [0; 6];
+        stream.read_exact(&mut header).await.unwrap();
+        let mut body = vec![0; u16::from_be_bytes([header[4], header[5]]).into()];
+        stream.read_exact(&mut body).await.unwrap();
+        [header.to_vec(), body].concat()
+    }
+
+    #[tokio::test]
+    async fn test_raw_frame_sunspec_marker() {
+        let (emulated_meter, _meter_update_handle) = SmartMeterEmulator::new();
+        let request = [0, 1, 0, 0, 0, 6, 0xF0, 0x03, 0x9C, 0x40, 0, 2];
+        assert_eq!(
+            raw_exchange(emulated_meter, &request).await,
+            [0, 1, 0, 0, 0, 7, 0xF0, 0x03, 4, 0x53, 0x75, 0x6E, 0x53]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_raw_frame_max_length_read() {
+        let (emulated_meter, _meter_update_handle) = SmartMeterEmulator::new();
+        let request = [0, 2, 0, 0, 0, 6, 0xF0, 0x03, 0x9C, 0x40, 0, 125];
+        let response = raw_exchange(emulated_meter, &request).await;
+        assert_eq!(response[..9], [0, 2, 0, 0, 0, 253, 0xF0, 0x03, 250]);
+        assert_eq!(response.len(), 6 + 253);
+    }
+
+    #[tokio::test]
+    async fn test_raw_frame_over_length_read() {
+        let (emulated_meter, _meter_update_handle) = SmartMeterEmulator::new();
+        let request = [0, 3, 0, 0, 0, 6, 0xF0, 0x03, 0x9C, 0x40, 0, 126];
+        assert_eq!(
+            raw_exchange(emulated_meter, &request).await,
+            [0, 3, 0, 0, 0, 3, 0xF0, 0x83, 0x03]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_raw_frame_zero_length_read() {
+        let request = [0, 4, 0, 0, 0, 6, 0xF0, 0x03, 0x9C, 0x40, 0, 0];
+        let (emulated_meter, _meter_update_handle) = SmartMeterEmulator::new();
+        assert_eq!(
+            raw_exchange(emulated_meter, &request).await,
+            [0, 4, 0, 0, 0, 3, 0xF0, 0x03, 0]
+        );
+        let (emulated_meter, _meter_update_handle) = SmartMeterEmulator::new();
+        let emulated_meter = emulated_meter.with_framing(FramingMode::Strict);
+        assert_eq!(
+            raw_exchange(emulated_meter, &request).await,
+            [0, 4, 0, 0, 0, 3, 0xF0, 0x83, 0x03]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_server_stops_on_shutdown() {
+        let (emulated_meter, _meter_update_handle) = SmartMeterEmulator::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn(serve(
+            listener,
+            emulated_meter,
+            None,
+            ConnectionLimit::default(),
+            shutdown.clone(),
+        ));
+        shutdown.cancel();
+        let result = tokio::time::timeout(Duration::from_secs(1), server)
+            .await
+            .expect("Server should stop once shut down");
+        assert!(result.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_silent_connection_is_closed() {
+        use tokio::io::AsyncReadExt;
+        let (emulated_meter, _meter_update_handle) = SmartMeterEmulator::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket_addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(
+            listener,
+            emulated_meter,
+            Some(Duration::from_millis(100)),
+            ConnectionLimit::default(),
+            CancellationToken::new(),
+        ));
+
+        let mut stream = tokio::net::TcpStream::connect(socket_addr).await.unwrap();
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf))
+            .await
+            .expect("Server should have closed the idle connection");
+        // Either a clean close or a reset, but never any data
+        assert!(matches!(read, Ok(0) | Err(_)));
+    }
+
+    #[tokio::test]
+    async fn test_connections_past_the_limit_are_refused() {
+        use tokio::io::AsyncReadExt;
+        let (emulated_meter, _meter_update_handle) = SmartMeterEmulator::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket_addr = listener.local_addr().unwrap();
+        let limit = ConnectionLimit::new(Some(2));
+        tokio::spawn(serve(
+            listener,
+            emulated_meter,
+            None,
+            limit.clone(),
+            CancellationToken::new(),
+        ));
+
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let mut client = tcp::connect(socket_addr).await.unwrap();
+            let response = client.read_holding_registers(40000, 2).await.unwrap();
+            assert_eq!(response, Ok(vec![0x5375, 0x6e53]));
+            clients.push(client);
+        }
+        let mut refused = tokio::net::TcpStream::connect(socket_addr).await.unwrap();
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), refused.read(&mut buf))
+            .await
+            .expect("Server should have closed the connection past the limit");
+        assert!(matches!(read, Ok(0) | Err(_)));
+        assert_eq!(limit.active(), 2);
+
+        // Closing a connection makes room for another
+        drop(clients.pop());
+        let response = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let mut client = tcp::connect(socket_addr).await.unwrap();
+                if let Ok(response) = client.read_holding_registers(40000, 2).await {
+                    break response;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("A connection should be accepted once one has closed");
+        assert_eq!(response, Ok(vec![0x5375, 0x6e53]));
+    }
+
+    #[tokio::test]
+    async fn test_total_matches_phases_under_rapid_updates() {
+        use fronius_meter_emulation::power_model::{consistent_phase_powers, PhaseTotalMode};
+        let (emulated_meter, meter_update_handle) = SmartMeterEmulator::new();
+        let mut client = start_server(emulated_meter).await;
+
+        let writer = tokio::spawn(async move {
+            for step in 0..2000 {
+                let measured = [step as f32 * 1.7, 400.0 - step as f32, 33.3];
+                let (total, [a, b, c]) = consistent_phase_powers(
+                    step as f32 * 3.1 - 500.0,
+                    Some(measured),
+                    PhaseTotalMode::SumPhasesToTotal,
+                );
+                let batch = Readings::Batch(vec![
+                    Readings::TotalRealPower(total),
+                    Readings::PhaseAWatts(a),
+                    Readings::PhaseBWatts(b),
+                    Readings::PhaseCWatts(c),
+                ]);
+                meter_update_handle.send(batch).await.unwrap();
+            }
+        });
+
+        let decode = |words: &[u16]| f32::from_bits((words[0] as u32) << 16 | words[1] as u32);
+        while !writer.is_finished() {
+            // Total and the three phase watts are contiguous from 40097
+            let words = client
+                .read_holding_registers(40097, 8)
+                .await
+                .unwrap()
+                .unwrap();
+            let [total, a, b, c] = [0, 2, 4, 6].map(|offset| decode(&words[offset..]));
+            assert_eq!(total, a + b + c, "Read a mix of updates");
+        }
+        writer.await.unwrap();
+    }
+}
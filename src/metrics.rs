@@ -0,0 +1,187 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use axum::{routing::get, Router};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tracing::info;
+
+// The latest readings in the Prometheus text format, for monitoring what the meter is reporting
+
+/// Shared gauges and counters, cheap to clone and update from the polling loop
+#[derive(Debug, Clone, Default)]
+pub struct Registry {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    // f32s stored as their bits, as there are no atomic floats
+    combined_power: AtomicU32,
+    shelly_power: AtomicU32,
+    ha_offset: AtomicU32,
+    read_failures: Mutex<BTreeMap<String, u64>>,
+    poll_success: Mutex<BTreeMap<String, f32>>,
+}
+
+impl Registry {
+    pub fn set_combined_power(&self, watts: f32) {
+        self.inner
+            .combined_power
+            .store(watts.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn set_shelly_power(&self, watts: f32) {
+        self.inner
+            .shelly_power
+            .store(watts.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn set_ha_offset(&self, watts: f32) {
+        self.inner
+            .ha_offset
+            .store(watts.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Counts a failed read from the source named `source`
+    pub fn record_read_failure(&self, source: &str) {
+        let mut failures = self.inner.read_failures.lock().unwrap();
+        *failures.entry(source.to_string()).or_default() += 1;
+    }
+
+    /// Sets the share of recent polls of the source named `source` that succeeded, from 0 to 1
+    pub fn set_poll_success_ratio(&self, source: &str, ratio: f32) {
+        let mut poll_success = self.inner.poll_success.lock().unwrap();
+        poll_success.insert(source.to_string(), ratio);
+    }
+
+    /// Renders every metric in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, help, value) in [
+            (
+                "fronius_combined_power_watts",
+                "Power reported to the inverter",
+                &self.inner.combined_power,
+            ),
+            (
+                "fronius_shelly_power_watts",
+                "Latest grid power read from the Shelly",
+                &self.inner.shelly_power,
+            ),
+            (
+                "fronius_ha_offset_watts",
+                "Latest offset read from Home Assistant, after limiting and smoothing",
+                &self.inner.ha_offset,
+            ),
+        ] {
+            let value = f32::from_bits(value.load(Ordering::Relaxed));
+            let _ = writeln!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}"
+            );
+        }
+        let name = "fronius_source_read_failures_total";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Reads from each source that failed\n# TYPE {name} counter"
+        );
+        for (source, count) in self.inner.read_failures.lock().unwrap().iter() {
+            let _ = writeln!(out, "{name}{{source=\"{}\"}} {count}", escape(source));
+        }
+        let name = "fronius_source_poll_success_ratio";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Share of each source's recent polls that succeeded\n# TYPE {name} gauge"
+        );
+        for (source, ratio) in self.inner.poll_success.lock().unwrap().iter() {
+            let _ = writeln!(out, "{name}{{source=\"{}\"}} {ratio}", escape(source));
+        }
+        out
+    }
+}
+
+/// Escapes a label value for the text format
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Serves `registry` on `/metrics` at `socket_addr` until `shutdown` is cancelled
+pub async fn serve(
+    socket_addr: SocketAddr,
+    registry: Registry,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    info!("Serving metrics on {socket_addr}");
+    let listener = TcpListener::bind(socket_addr).await?;
+    serve_on(listener, registry, shutdown).await
+}
+
+async fn serve_on(
+    listener: TcpListener,
+    registry: Registry,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    let app = Router::new().route("/metrics", get(move || async move { registry.render() }));
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move { shutdown.cancelled().await })
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_render() {
+        let registry = Registry::default();
+        registry.set_combined_power(-250.5);
+        registry.set_shelly_power(-300.0);
+        registry.set_ha_offset(49.5);
+        registry.record_read_failure("Shelly");
+        registry.record_read_failure("Shelly");
+        registry.record_read_failure("Home Assistant");
+        registry.set_poll_success_ratio("Shelly", 0.97);
+
+        let rendered = registry.render();
+        assert!(rendered.contains("\nfronius_combined_power_watts -250.5\n"));
+        assert!(rendered.contains("\nfronius_shelly_power_watts -300\n"));
+        assert!(rendered.contains("\nfronius_ha_offset_watts 49.5\n"));
+        assert!(rendered.contains("\nfronius_source_read_failures_total{source=\"Shelly\"} 2\n"));
+        assert!(rendered
+            .contains("\nfronius_source_read_failures_total{source=\"Home Assistant\"} 1\n"));
+        assert!(rendered.contains("\nfronius_source_poll_success_ratio{source=\"Shelly\"} 0.97\n"));
+    }
+
+    #[tokio::test]
+    async fn test_scrape() {
+        let registry = Registry::default();
+        registry.set_combined_power(1000.0);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket_addr = listener.local_addr().unwrap();
+        let shutdown = CancellationToken::new();
+        let server = tokio::spawn(serve_on(listener, registry, shutdown.clone()));
+
+        let mut stream = tokio::net::TcpStream::connect(socket_addr).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("\nfronius_combined_power_watts 1000\n"));
+
+        shutdown.cancel();
+        server.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,214 @@
+use std::time::Duration;
+
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use tokio::{
+    sync::watch,
+    task::JoinHandle,
+    time::{self, Instant},
+};
+
+use tracing::{info, warn};
+
+use crate::power_source::{Measurement, PowerSource, ReaderError, SourceFuture};
+
+// Reads the grid power from an MQTT topic, for setups that already publish it rather than
+// exposing a meter over Modbus. The broker connection runs in the background and the latest
+// value is handed out each poll, so a slow broker doesn't hold up the other sources.
+
+const DEFAULT_PORT: u16 = 1883;
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Everything needed to connect to the broker and read the power
+#[derive(Debug, Clone, PartialEq)]
+pub struct MqttConfig {
+    /// `host` or `host:port` of the broker
+    pub broker: String,
+    pub topic: String,
+    /// Dot separated path to the power within a JSON payload, e.g. `power.total`.
+    /// Empty for payloads that are just the number.
+    pub json_path: String,
+    /// A value not republished within this is no longer reported
+    pub stale_after: Duration,
+}
+
+pub struct MqttReader {
+    /// The latest power and when it arrived
+    latest: watch::Receiver<Option<(f32, Instant)>>,
+    stale_after: Duration,
+    task: Option<JoinHandle<()>>,
+}
+
+impl MqttReader {
+    /// Starts following `config.topic`, reconnecting with a backoff whenever the broker drops
+    pub fn connect(config: MqttConfig) -> Self {
+        let (latest_tx, latest_rx) = watch::channel(None);
+        let stale_after = config.stale_after;
+        let task = tokio::spawn(Self::run(config, latest_tx));
+        Self {
+            latest: latest_rx,
+            stale_after,
+            task: Some(task),
+        }
+    }
+
+    async fn run(config: MqttConfig, latest: watch::Sender<Option<(f32, Instant)>>) {
+        let (host, port) = match config.broker.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().unwrap_or(DEFAULT_PORT)),
+            None => (config.broker.as_str(), DEFAULT_PORT),
+        };
+        info!(
+            "Connecting to MQTT broker `{host}:{port}` for `{}`",
+            config.topic
+        );
+        let client_id = format!("fronius_meter_emulation-{}", std::process::id());
+        let mut options = MqttOptions::new(client_id, host, port);
+        options.set_keep_alive(Duration::from_secs(10));
+        let (client, mut event_loop) = AsyncClient::new(options, 10);
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            match event_loop.poll().await {
+                // Subscriptions don't survive a reconnect, so subscribe on every connect
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    backoff = MIN_BACKOFF;
+                    if let Err(e) = client.try_subscribe(&config.topic, QoS::AtMostOnce) {
+                        warn!("Failed to subscribe to `{}`: {e}", config.topic);
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    match parse_payload(&publish.payload, &config.json_path) {
+                        Some(watts) => {
+                            latest.send_replace(Some((watts, Instant::now())));
+                        }
+                        None => warn!("Ignoring MQTT payload {:?}", publish.payload),
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    // A value from before the disconnect is no longer live
+                    latest.send_replace(None);
+                    warn!("MQTT connection failed: {e}, retrying in {backoff:?}");
+                    time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for MqttReader {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
+
+impl PowerSource for MqttReader {
+    fn name(&self) -> &str {
+        "MQTT"
+    }
+
+    fn next(&mut self) -> SourceFuture<'_> {
+        let latest = *self.latest.borrow();
+        let result = match latest {
+            // The connection can stay up long after the publisher has stopped
+            Some((_, at)) if at.elapsed() > self.stale_after => Err(ReaderError::Unavailable(
+                format!("nothing published in {:?}", at.elapsed()),
+            )),
+            Some((watts, _)) => Ok(Measurement::grid_power(watts)),
+            None => Err(ReaderError::Unavailable(
+                "no power received from the broker".to_string(),
+            )),
+        };
+        Box::pin(std::future::ready(result))
+    }
+}
+
+/// Reads the power from a payload that is either just a number, or JSON holding it at `json_path`.
+/// Numbers sent as JSON strings are accepted too, as some publishers send everything as text.
+pub fn parse_payload(payload: &[u8], json_path: &str) -> Option<f32> {
+    let payload = std::str::from_utf8(payload).ok()?.trim();
+    let watts = if json_path.is_empty() {
+        payload.parse().ok()?
+    } else {
+        let json: serde_json::Value = serde_json::from_str(payload).ok()?;
+        let value = json_path
+            .split('.')
+            .try_fold(&json, |value, key| value.get(key))?;
+        match value {
+            serde_json::Value::Number(number) => number.as_f64()? as f32,
+            serde_json::Value::String(text) => text.trim().parse().ok()?,
+            _ => return None,
+        }
+    };
+    Some(watts).filter(|watts: &f32| watts.is_finite())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_plain_payload() {
+        assert_eq!(parse_payload(b"1234.5", ""), Some(1234.5));
+        assert_eq!(parse_payload(b" -250\n", ""), Some(-250.0));
+        assert_eq!(parse_payload(b"NaN", ""), None);
+        assert_eq!(parse_payload(b"unavailable", ""), None);
+        assert_eq!(parse_payload(&[0xFF, 0xFE], ""), None);
+    }
+
+    #[test]
+    fn test_parse_json_payload() {
+        let payload = br#"{"power": {"total": -812.25, "text": "42"}, "ok": true}"#;
+        assert_eq!(parse_payload(payload, "power.total"), Some(-812.25));
+        assert_eq!(parse_payload(payload, "power.text"), Some(42.0));
+        assert_eq!(parse_payload(payload, "ok"), None);
+        assert_eq!(parse_payload(payload, "power.missing"), None);
+        assert_eq!(parse_payload(payload, ""), None);
+        assert_eq!(parse_payload(b"123", "power"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reports_latest_value() {
+        let (latest_tx, latest_rx) = watch::channel(None);
+        let mut reader = MqttReader {
+            latest: latest_rx,
+            stale_after: Duration::from_secs(5),
+            task: None,
+        };
+        assert!(matches!(
+            reader.next().await,
+            Err(ReaderError::Unavailable(_))
+        ));
+        latest_tx.send_replace(Some((300.0, Instant::now())));
+        assert_eq!(reader.next().await, Ok(Measurement::grid_power(300.0)));
+        // Dropped after a disconnect
+        latest_tx.send_replace(None);
+        assert!(reader.next().await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_value_goes_stale() {
+        let (latest_tx, latest_rx) = watch::channel(None);
+        let mut reader = MqttReader {
+            latest: latest_rx,
+            stale_after: Duration::from_secs(5),
+            task: None,
+        };
+        latest_tx.send_replace(Some((300.0, Instant::now())));
+        time::advance(Duration::from_secs(4)).await;
+        assert_eq!(reader.next().await, Ok(Measurement::grid_power(300.0)));
+
+        // The publisher stopped while the connection stayed up
+        time::advance(Duration::from_secs(2)).await;
+        assert!(matches!(
+            reader.next().await,
+            Err(ReaderError::Unavailable(_))
+        ));
+
+        // Live again once something is published
+        latest_tx.send_replace(Some((250.0, Instant::now())));
+        assert_eq!(reader.next().await, Ok(Measurement::grid_power(250.0)));
+    }
+}
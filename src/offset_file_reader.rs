@@ -0,0 +1,92 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use tracing::warn;
+
+use crate::power_source::{Measurement, PowerSource, ReaderError, SourceFuture};
+
+// Reads the offset from a local file, for setups that don't run HA.
+// The file is re-read every cycle rather than watched for changes: it's tiny, the loop only
+// samples it every 500ms anyway, and watches are unreliable on bind mounted or replaced files.
+
+pub struct OffsetFileReader {
+    path: PathBuf,
+    last_valid: Option<f32>,
+}
+
+impl OffsetFileReader {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            last_valid: None,
+        }
+    }
+
+    /// Returns the offset in the file, as a single number in W.
+    /// An empty or unparsable file (e.g. caught mid-write) keeps the last valid offset.
+    /// Writers should write to a temporary file and rename it over the target to avoid this.
+    pub fn read_offset(&mut self) -> Option<f32> {
+        match fs::read_to_string(&self.path) {
+            Ok(contents) => match contents.trim().parse::<f32>() {
+                Ok(offset) if offset.is_finite() => self.last_valid = Some(offset),
+                _ => warn!(
+                    "Ignoring offset file contents `{}`, keeping {:?}",
+                    contents.trim(),
+                    self.last_valid
+                ),
+            },
+            Err(e) => {
+                warn!("Didn't read offset file {:?}: {e}", self.path);
+                return None;
+            }
+        }
+        self.last_valid
+    }
+}
+
+impl PowerSource for OffsetFileReader {
+    fn name(&self) -> &str {
+        "Offset file"
+    }
+
+    fn next(&mut self) -> SourceFuture<'_> {
+        let offset = self
+            .read_offset()
+            .map(Measurement::Offset)
+            .ok_or_else(|| ReaderError::Unavailable(format!("{:?}", self.path)));
+        Box::pin(std::future::ready(offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_values_propagate() {
+        let path = std::env::temp_dir().join(format!("offset-file-{}.txt", std::process::id()));
+        let mut reader = OffsetFileReader::new(&path);
+        assert_eq!(reader.read_offset(), None, "No file yet");
+
+        fs::write(&path, "250.5\n").unwrap();
+        assert_eq!(reader.read_offset(), Some(250.5));
+        fs::write(&path, "-1000").unwrap();
+        assert_eq!(reader.read_offset(), Some(-1000.0));
+
+        // Partial writes keep the previous value
+        fs::write(&path, "").unwrap();
+        assert_eq!(reader.read_offset(), Some(-1000.0));
+        fs::write(&path, "12.").unwrap();
+        assert_eq!(reader.read_offset(), Some(12.0));
+        fs::write(&path, "-").unwrap();
+        assert_eq!(reader.read_offset(), Some(12.0));
+        fs::write(&path, "NaN").unwrap();
+        assert_eq!(reader.read_offset(), Some(12.0));
+
+        // A missing file means the source is down, so let the offset go stale
+        fs::remove_file(&path).unwrap();
+        assert_eq!(reader.read_offset(), None);
+    }
+}
@@ -0,0 +1,149 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use tracing::warn;
+
+use crate::events::{Event, EventBus};
+
+// Guards against a misbehaving HA entity reporting an absurd offset that would dominate the sum
+
+/// What to do with an offset outside the configured bounds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutOfRangePolicy {
+    /// Limit the offset to the nearest bound
+    Clamp,
+    /// Discard the offset entirely, as if the read had failed
+    Drop,
+}
+
+impl FromStr for OutOfRangePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "clamp" => Ok(Self::Clamp),
+            "drop" => Ok(Self::Drop),
+            other => Err(format!("Unknown out of range policy `{other}`")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OffsetLimiter {
+    min: f32,
+    max: f32,
+    policy: OutOfRangePolicy,
+    out_of_range: u64,
+    events: EventBus,
+}
+
+impl OffsetLimiter {
+    pub fn new(min: f32, max: f32, policy: OutOfRangePolicy) -> Self {
+        Self {
+            min,
+            max,
+            policy,
+            out_of_range: 0,
+            events: EventBus::new(),
+        }
+    }
+
+    /// Publish out of range offsets on the given bus
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Applies the limits to an offset.
+    /// Returns None if the offset was out of range and the policy is to drop it.
+    pub fn apply(&mut self, offset: f32) -> Option<f32> {
+        if (self.min..=self.max).contains(&offset) {
+            return Some(offset);
+        }
+        self.out_of_range += 1;
+        self.events.emit(Event::OffsetOutOfRange { offset });
+        warn!(
+            "HA offset {offset}W outside of {}W..{}W ({} times so far)",
+            self.min, self.max, self.out_of_range
+        );
+        match self.policy {
+            // There is no sensible bound to clamp NaN to
+            OutOfRangePolicy::Clamp if !offset.is_nan() => Some(offset.clamp(self.min, self.max)),
+            _ => None,
+        }
+    }
+
+    /// Number of offsets that have been outside the bounds
+    pub fn out_of_range_count(&self) -> u64 {
+        self.out_of_range
+    }
+}
+
+impl Default for OffsetLimiter {
+    fn default() -> Self {
+        Self::new(f32::NEG_INFINITY, f32::INFINITY, OutOfRangePolicy::Clamp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_range_passes_through() {
+        let mut limiter = OffsetLimiter::new(-5000.0, 5000.0, OutOfRangePolicy::Clamp);
+        assert_eq!(limiter.apply(0.0), Some(0.0));
+        assert_eq!(limiter.apply(-5000.0), Some(-5000.0));
+        assert_eq!(limiter.apply(4999.5), Some(4999.5));
+        assert_eq!(limiter.out_of_range_count(), 0);
+    }
+
+    #[test]
+    fn test_absurd_offset_is_clamped() {
+        let mut limiter = OffsetLimiter::new(-5000.0, 3000.0, OutOfRangePolicy::Clamp);
+        assert_eq!(limiter.apply(50_000.0), Some(3000.0));
+        assert_eq!(limiter.apply(-50_000.0), Some(-5000.0));
+        assert_eq!(limiter.out_of_range_count(), 2);
+    }
+
+    #[test]
+    fn test_out_of_range_event() {
+        let events = EventBus::new();
+        let mut receiver = events.subscribe();
+        let mut limiter =
+            OffsetLimiter::new(-100.0, 100.0, OutOfRangePolicy::Clamp).with_events(events);
+        limiter.apply(50.0);
+        limiter.apply(500.0);
+        assert_eq!(
+            receiver.try_recv(),
+            Ok(Event::OffsetOutOfRange { offset: 500.0 })
+        );
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_absurd_offset_is_dropped() {
+        let mut limiter = OffsetLimiter::new(-5000.0, 5000.0, OutOfRangePolicy::Drop);
+        assert_eq!(limiter.apply(50_000.0), None);
+        assert_eq!(limiter.apply(100.0), Some(100.0));
+        assert_eq!(limiter.out_of_range_count(), 1);
+    }
+
+    #[test]
+    fn test_default_is_unlimited() {
+        let mut limiter = OffsetLimiter::default();
+        assert_eq!(limiter.apply(1e9), Some(1e9));
+        assert_eq!(limiter.apply(-1e9), Some(-1e9));
+        // NaN can never be in range, and is dropped rather than clamped to a bound
+        assert_eq!(limiter.apply(f32::NAN), None);
+    }
+
+    #[test]
+    fn test_parse_policy() {
+        assert_eq!("clamp".parse(), Ok(OutOfRangePolicy::Clamp));
+        assert_eq!("DROP".parse(), Ok(OutOfRangePolicy::Drop));
+        assert!("other".parse::<OutOfRangePolicy>().is_err());
+    }
+}
@@ -0,0 +1,117 @@
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use crate::smart_meter_emulator::Readings;
+use tokio::{
+    sync::{mpsc::Sender, watch},
+    task::JoinHandle,
+    time::{self, MissedTickBehavior},
+};
+
+// Some inverters expect the meter to update at a steady rate, while the inputs arrive whenever
+// the sources respond. This re-emits the latest combined readings on its own fixed timer.
+
+pub struct OutputScheduler {}
+
+impl OutputScheduler {
+    /// Emits the most recent value from `latest` every `period` until either side is dropped.
+    /// Nothing is emitted until the first value has been published.
+    pub fn spawn(
+        latest: watch::Receiver<Option<Readings>>,
+        output: Sender<Readings>,
+        period: Duration,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            Self::worker(latest, output, period).await;
+        })
+    }
+
+    async fn worker(
+        latest: watch::Receiver<Option<Readings>>,
+        output: Sender<Readings>,
+        period: Duration,
+    ) {
+        let mut interval = time::interval(period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
+            if latest.has_changed().is_err() {
+                info!("Combined power source closed, stopping output scheduler");
+                return;
+            }
+            let value = latest.borrow().clone();
+            if let Some(readings) = value {
+                if output.send(readings).await.is_err() {
+                    warn!("The meter is no longer accepting readings, stopping output scheduler");
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Converts a rate in Hz into a tick period, rejecting rates that can't be scheduled
+pub fn period_from_rate(rate_hz: f32) -> Option<Duration> {
+    // Negative, NaN and overflowing periods are all errors here
+    let period = Duration::try_from_secs_f32(1.0 / rate_hz).ok()?;
+    // A rate too fast for the clock rounds down to nothing, which an interval can't tick at
+    (!period.is_zero()).then_some(period)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sunspec_map::TOTAL_REAL_POWER;
+    use tokio::{sync::mpsc, time::Instant};
+
+    #[test]
+    fn test_period_from_rate() {
+        assert_eq!(period_from_rate(1.0), Some(Duration::from_secs(1)));
+        assert_eq!(period_from_rate(4.0), Some(Duration::from_millis(250)));
+        assert_eq!(period_from_rate(0.0), None);
+        assert_eq!(period_from_rate(-1.0), None);
+        assert_eq!(period_from_rate(f32::NAN), None);
+        assert_eq!(period_from_rate(f32::INFINITY), None);
+        // Too slow to fit in a Duration, and too fast to be more than 0
+        assert_eq!(period_from_rate(1e-30), None);
+        assert_eq!(period_from_rate(1e10), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_steady_output_with_irregular_inputs() {
+        let (latest_tx, latest_rx) = watch::channel(None);
+        let (output_tx, mut output_rx) = mpsc::channel(128);
+        let period = Duration::from_millis(100);
+        let _scheduler = OutputScheduler::spawn(latest_rx, output_tx, period);
+
+        // Inputs arrive in a burst, then pause for a long time, then change again
+        let inputs = tokio::spawn(async move {
+            for value in [1.0, 2.0, 3.0] {
+                latest_tx.send_replace(Some(Readings::TotalRealPower(value)));
+                time::sleep(Duration::from_millis(5)).await;
+            }
+            time::sleep(Duration::from_millis(700)).await;
+            latest_tx.send_replace(Some(Readings::TotalRealPower(4.0)));
+            time::sleep(Duration::from_millis(300)).await;
+        });
+
+        let mut emissions = Vec::new();
+        while let Some(reading) = output_rx.recv().await {
+            for (field, value) in reading.fields() {
+                if field == TOTAL_REAL_POWER {
+                    emissions.push((Instant::now(), value));
+                }
+            }
+        }
+        inputs.await.unwrap();
+
+        // Emissions keep coming during the pause, each exactly one period apart
+        assert!(emissions.len() >= 9, "Only {} emissions", emissions.len());
+        for pair in emissions.windows(2) {
+            assert_eq!(pair[1].0 - pair[0].0, period);
+        }
+        assert_eq!(emissions.first().unwrap().1, 3.0);
+        assert_eq!(emissions.last().unwrap().1, 4.0);
+    }
+}
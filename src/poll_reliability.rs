@@ -0,0 +1,95 @@
+use std::collections::VecDeque;
+
+/// Number of polls the success rate is worked out over
+pub const RELIABILITY_WINDOW: usize = 100;
+
+/// Tracks whether each of the last few polls of a source succeeded, to report link quality.
+/// The boolean counterpart of the rolling average.
+#[derive(Debug, Clone)]
+pub struct PollReliability {
+    outcomes: VecDeque<bool>,
+    capacity: usize,
+    successes: usize,
+}
+
+impl PollReliability {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            outcomes: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+            successes: 0,
+        }
+    }
+
+    /// Records a poll, dropping the oldest once the window is full
+    pub fn record(&mut self, success: bool) {
+        if self.outcomes.len() == self.capacity {
+            if let Some(true) = self.outcomes.pop_front() {
+                self.successes -= 1;
+            }
+        }
+        self.outcomes.push_back(success);
+        if success {
+            self.successes += 1;
+        }
+    }
+
+    /// Percentage of the recorded polls that succeeded, None before the first poll
+    pub fn success_percent(&self) -> Option<f32> {
+        if self.outcomes.is_empty() {
+            return None;
+        }
+        Some(self.successes as f32 * 100.0 / self.outcomes.len() as f32)
+    }
+
+    /// Number of polls currently in the window
+    pub fn len(&self) -> usize {
+        self.outcomes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outcomes.is_empty()
+    }
+}
+
+impl Default for PollReliability {
+    fn default() -> Self {
+        Self::new(RELIABILITY_WINDOW)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_has_no_rate() {
+        let reliability = PollReliability::default();
+        assert_eq!(reliability.success_percent(), None);
+        assert_eq!(reliability.len(), 0);
+    }
+
+    #[test]
+    fn test_mixed_outcomes() {
+        let mut reliability = PollReliability::new(4);
+        reliability.record(true);
+        reliability.record(false);
+        reliability.record(true);
+        assert_eq!(reliability.len(), 3);
+        assert!((reliability.success_percent().unwrap() - 66.666_67).abs() < 1e-3);
+
+        reliability.record(true);
+        assert_eq!(reliability.success_percent(), Some(75.0));
+
+        // The early failure drops out of the window
+        reliability.record(true);
+        reliability.record(true);
+        assert_eq!(reliability.len(), 4);
+        assert_eq!(reliability.success_percent(), Some(100.0));
+
+        for _ in 0..4 {
+            reliability.record(false);
+        }
+        assert_eq!(reliability.success_percent(), Some(0.0));
+    }
+}
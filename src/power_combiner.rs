@@ -0,0 +1,905 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+#[cfg(feature = "metrics")]
+use crate::metrics::Registry;
+use crate::{
+    calibration::Calibration,
+    events::{Event, EventBus, Source},
+};
+
+// Merges the Shelly power and the HA offset into the value reported by the meter,
+// falling back through a configurable chain of strategies when the inputs go stale
+
+/// One strategy for producing the combined power, tried in the configured order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FallbackTier {
+    /// Fresh Shelly power plus the latest HA offset
+    Live,
+    /// Only the HA offset, used when the Shelly reading is stale
+    HaOnly,
+    /// The last live value, held for a limited time
+    LastGood,
+    /// The configured sentinel value, always available
+    Degraded,
+    /// Flag the readings as out of date, so the meter answers with an exception rather than a
+    /// value the inverter would trust. Always available, like `Degraded`.
+    Unavailable,
+}
+
+impl FromStr for FallbackTier {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "live" => Ok(Self::Live),
+            "ha_only" => Ok(Self::HaOnly),
+            "last_good" => Ok(Self::LastGood),
+            "degraded" => Ok(Self::Degraded),
+            "unavailable" => Ok(Self::Unavailable),
+            other => Err(format!("Unknown fallback tier `{other}`")),
+        }
+    }
+}
+
+/// Parses a comma separated chain such as `live,ha_only,last_good,degraded`
+pub fn parse_fallback_chain(chain: &str) -> Result<Vec<FallbackTier>, String> {
+    chain.split(',').map(FallbackTier::from_str).collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct FallbackPolicy {
+    pub chain: Vec<FallbackTier>,
+    /// Inputs older than this are considered stale
+    pub stale_after: Duration,
+    /// Meter readings go stale after this instead, when set
+    pub meter_stale_after: Option<Duration>,
+    /// How long the last live value may be reported once all inputs are stale
+    pub last_good_hold: Duration,
+    /// Reported when nothing else in the chain applies
+    pub degraded_value: f32,
+}
+
+impl Default for FallbackPolicy {
+    fn default() -> Self {
+        Self {
+            chain: vec![
+                FallbackTier::Live,
+                FallbackTier::HaOnly,
+                FallbackTier::LastGood,
+                FallbackTier::Degraded,
+            ],
+            stale_after: Duration::from_secs(5),
+            meter_stale_after: None,
+            last_good_hold: Duration::from_secs(30),
+            degraded_value: 0.0,
+        }
+    }
+}
+
+impl FallbackPolicy {
+    /// How long a meter reading stays fresh
+    pub fn meter_stale_after(&self) -> Duration {
+        self.meter_stale_after.unwrap_or(self.stale_after)
+    }
+}
+
+/// How the HA offset is combined with the meters' power
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OffsetSign {
+    /// `shelly + offset`, for an offset that is positive when it adds load
+    #[default]
+    Add,
+    /// `shelly - offset`, for an offset with the opposite convention
+    Subtract,
+}
+
+impl OffsetSign {
+    fn apply(self, offset: f32) -> f32 {
+        match self {
+            Self::Add => offset,
+            Self::Subtract => -offset,
+        }
+    }
+}
+
+impl FromStr for OffsetSign {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "add" => Ok(Self::Add),
+            "subtract" => Ok(Self::Subtract),
+            other => Err(format!("Unknown offset sign `{other}`")),
+        }
+    }
+}
+
+/// Limits emission to significant changes, plus a periodic heartbeat while steady
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EmitPolicy {
+    /// Emit as soon as the value moves by more than this from the last emitted value (W)
+    pub threshold: f32,
+    /// Emit at least this often, even when the value is steady
+    pub heartbeat: Duration,
+}
+
+/// Pins the combined power to a fixed value for a while, e.g. to test how an inverter curtails.
+/// Cheap to clone and share, so it can be set from outside the polling loop.
+#[derive(Debug, Clone, Default)]
+pub struct PowerOverride {
+    /// The pinned power and when it expires
+    pinned: Arc<Mutex<Option<(f32, Instant)>>>,
+}
+
+impl PowerOverride {
+    /// Reports `watts` in place of the combined power until `until`, replacing any earlier override
+    pub fn pin(&self, watts: f32, until: Instant) {
+        *self.pinned.lock().unwrap() = Some((watts, until));
+    }
+
+    /// Resumes reporting the combined power straight away
+    pub fn clear(&self) {
+        *self.pinned.lock().unwrap() = None;
+    }
+
+    /// The pinned power, if the override hasn't expired by `now`
+    pub fn active(&self, now: Instant) -> Option<f32> {
+        let mut pinned = self.pinned.lock().unwrap();
+        match *pinned {
+            Some((watts, until)) if now < until => Some(watts),
+            Some(_) => {
+                info!("Power override expired, resuming live data");
+                *pinned = None;
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+/// The on-disk form of the combiner, so a restart picks up where it left off
+#[derive(Debug, Serialize, Deserialize)]
+struct PersistedState {
+    /// Seconds since the unix epoch when the state was saved
+    saved_at: u64,
+    last_good: Option<f32>,
+    ha_offset: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    value: f32,
+    at: Instant,
+}
+
+impl Sample {
+    fn is_fresh(&self, now: Instant, max_age: Duration) -> bool {
+        now.saturating_duration_since(self.at) <= max_age
+    }
+}
+
+pub struct PowerCombiner {
+    policy: FallbackPolicy,
+    /// The latest reading from each meter, by name
+    shelly_power: BTreeMap<String, Sample>,
+    ha_offset: Option<Sample>,
+    last_good: Option<Sample>,
+    events: EventBus,
+    // Freshness as of the last combine, to notice transitions. None until first seen.
+    shelly_fresh: Option<bool>,
+    ha_fresh: Option<bool>,
+    last_tier: FallbackTier,
+    emit_policy: Option<EmitPolicy>,
+    last_emitted: Option<Sample>,
+    /// Applied to the meters' summed power, before the HA offset is added
+    calibration: Calibration,
+    offset_sign: OffsetSign,
+    power_override: PowerOverride,
+    #[cfg(feature = "metrics")]
+    metrics: Option<Registry>,
+}
+
+impl PowerCombiner {
+    pub fn new(policy: FallbackPolicy) -> Self {
+        Self {
+            policy,
+            shelly_power: BTreeMap::new(),
+            ha_offset: None,
+            last_good: None,
+            events: EventBus::new(),
+            shelly_fresh: None,
+            ha_fresh: None,
+            last_tier: FallbackTier::Live,
+            emit_policy: None,
+            last_emitted: None,
+            calibration: Calibration::default(),
+            offset_sign: OffsetSign::default(),
+            power_override: PowerOverride::default(),
+            #[cfg(feature = "metrics")]
+            metrics: None,
+        }
+    }
+
+    /// Publish staleness and fallback transitions on the given bus
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Only emit significant changes and heartbeats, rather than every combined value
+    pub fn with_emit_policy(mut self, emit_policy: EmitPolicy) -> Self {
+        self.emit_policy = Some(emit_policy);
+        self
+    }
+
+    /// Correct the meters' power with `calibration`
+    pub fn with_calibration(mut self, calibration: Calibration) -> Self {
+        self.calibration = calibration;
+        self
+    }
+
+    /// Add or subtract the HA offset as `offset_sign` says
+    pub fn with_offset_sign(mut self, offset_sign: OffsetSign) -> Self {
+        self.offset_sign = offset_sign;
+        self
+    }
+
+    /// Report the power pinned by `power_override` while it is active
+    pub fn with_override(mut self, power_override: PowerOverride) -> Self {
+        self.power_override = power_override;
+        self
+    }
+
+    /// Keep the power gauges in `metrics` up to date
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics(mut self, metrics: Registry) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Records the power from the only meter
+    pub fn update_shelly_power(&mut self, watts: f32, now: Instant) {
+        self.update_device_power("Shelly", watts, now);
+    }
+
+    /// Records the power from the meter named `device`, the meters' powers add up.
+    /// NaN and infinite readings are dropped, so the meter's last reading goes stale instead.
+    pub fn update_device_power(&mut self, device: &str, watts: f32, now: Instant) {
+        if !watts.is_finite() {
+            warn!("Ignoring a power of {watts} from {device}");
+            return;
+        }
+        self.shelly_power.insert(
+            device.to_string(),
+            Sample {
+                value: watts,
+                at: now,
+            },
+        );
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.set_shelly_power(self.shelly_power.values().map(|sample| sample.value).sum());
+        }
+    }
+
+    /// Records the offset, dropping NaN and infinite values as for the meters
+    pub fn update_ha_offset(&mut self, watts: f32, now: Instant) {
+        if !watts.is_finite() {
+            warn!("Ignoring an HA offset of {watts}");
+            return;
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.set_ha_offset(watts);
+        }
+        self.ha_offset = Some(Sample {
+            value: watts,
+            at: now,
+        });
+    }
+
+    /// Writes the last live value and the HA offset to `path`, replacing any previous state
+    pub fn save_state(&self, path: &Path) -> anyhow::Result<()> {
+        let state = PersistedState {
+            saved_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            last_good: self.last_good.map(|sample| sample.value),
+            ha_offset: self.ha_offset.map(|sample| sample.value),
+        };
+        // Write then rename, so a crash mid-write can't leave a truncated file behind
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, serde_json::to_vec(&state)?)?;
+        fs::rename(temp_path, path)?;
+        Ok(())
+    }
+
+    /// Restores the state saved by `save_state`, aged by the time since it was saved, so the
+    /// last live value is only held for what is left of the last good hold time.
+    /// The offset is only restored while it would still be fresh, and only when `needs_offset`,
+    /// as it is added to every live value until a new one replaces it.
+    /// Returns whether there was any state to restore.
+    pub fn load_state(
+        &mut self,
+        path: &Path,
+        now: Instant,
+        needs_offset: bool,
+    ) -> anyhow::Result<bool> {
+        self.load_state_at(path, now, SystemTime::now(), needs_offset)
+    }
+
+    fn load_state_at(
+        &mut self,
+        path: &Path,
+        now: Instant,
+        wall_clock: SystemTime,
+        needs_offset: bool,
+    ) -> anyhow::Result<bool> {
+        if !path.exists() {
+            return Ok(false);
+        }
+        let state: PersistedState = serde_json::from_slice(&fs::read(path)?)?;
+        let saved_at = UNIX_EPOCH + Duration::from_secs(state.saved_at);
+        let age = wall_clock.duration_since(saved_at).unwrap_or_default();
+        // Saved before this machine's monotonic clock started, far too old to be of use
+        let Some(at) = now.checked_sub(age) else {
+            return Ok(false);
+        };
+        let sample = |value| Sample { value, at };
+        if age <= self.policy.last_good_hold {
+            self.last_good = state.last_good.map(sample).or(self.last_good);
+        }
+        if needs_offset && age <= self.policy.stale_after {
+            self.ha_offset = state.ha_offset.map(sample).or(self.ha_offset);
+        }
+        Ok(true)
+    }
+
+    /// Whether a meter has reported, and an offset too when `needs_offset`.
+    /// Until then the combined value is only a fallback.
+    pub fn is_ready(&self, needs_offset: bool) -> bool {
+        !self.shelly_power.is_empty() && (self.ha_offset.is_some() || !needs_offset)
+    }
+
+    /// Works down the fallback chain and returns the first value that can be produced,
+    /// along with the tier that produced it.
+    /// While an override is active its value is returned instead, but the chain still runs so
+    /// staleness is tracked and the last good value stays a real reading.
+    pub fn combine(&mut self, now: Instant) -> (f32, FallbackTier) {
+        self.track_freshness(now);
+        let (mut value, tier) = self
+            .policy
+            .chain
+            .iter()
+            .find_map(|&tier| {
+                // A sum can still overflow, and inverters can choke on a non-finite register
+                let value = self.evaluate(tier, now)?;
+                if !value.is_finite() {
+                    warn!("Skipping the {tier:?} power of {value}");
+                    return None;
+                }
+                Some((value, tier))
+            })
+            .unwrap_or((self.policy.degraded_value, FallbackTier::Degraded));
+        if tier == FallbackTier::Live {
+            self.last_good = Some(Sample { value, at: now });
+        }
+        if tier != self.last_tier {
+            self.events.emit(Event::FallbackTierChanged {
+                from: self.last_tier,
+                to: tier,
+            });
+            self.last_tier = tier;
+        }
+        if let Some(pinned) = self.power_override.active(now) {
+            debug!("Reporting the overridden {pinned}W in place of {value}W");
+            value = pinned;
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.set_combined_power(value);
+        }
+        (value, tier)
+    }
+
+    /// Whether the reported power is pinned by an override at `now`
+    pub fn is_overridden(&self, now: Instant) -> bool {
+        self.power_override.active(now).is_some()
+    }
+
+    /// Whether a combined `value` should be sent on to the meter, recording it if so.
+    /// Without an emit policy every value is emitted.
+    pub fn should_emit(&mut self, value: f32, now: Instant) -> bool {
+        let emit = match (self.emit_policy, self.last_emitted) {
+            (Some(policy), Some(last)) => {
+                (value - last.value).abs() > policy.threshold
+                    || now.saturating_duration_since(last.at) >= policy.heartbeat
+            }
+            _ => true,
+        };
+        if emit {
+            self.last_emitted = Some(Sample { value, at: now });
+        }
+        emit
+    }
+
+    fn track_freshness(&mut self, now: Instant) {
+        let stale_after = self.policy.stale_after;
+        let shelly_fresh = self.shelly_fresh_at(now);
+        let ha_fresh = self
+            .ha_offset
+            .map(|sample| sample.is_fresh(now, stale_after));
+        Self::emit_transition(
+            &self.events,
+            Source::Shelly,
+            self.shelly_fresh,
+            shelly_fresh,
+        );
+        Self::emit_transition(&self.events, Source::HomeAssistant, self.ha_fresh, ha_fresh);
+        if self.shelly_fresh == Some(true) && shelly_fresh == Some(false) {
+            warn!(
+                "No meter reading in {:?}, falling back",
+                self.policy.meter_stale_after()
+            );
+        }
+        self.shelly_fresh = shelly_fresh;
+        self.ha_fresh = ha_fresh;
+    }
+
+    fn emit_transition(events: &EventBus, source: Source, was: Option<bool>, now: Option<bool>) {
+        match (was, now) {
+            (Some(true), Some(false)) => events.emit(Event::SourceStale(source)),
+            (Some(false), Some(true)) => events.emit(Event::SourceRecovered(source)),
+            _ => {}
+        }
+    }
+
+    /// Whether any meter is fresh, None before the first reading
+    fn shelly_fresh_at(&self, now: Instant) -> Option<bool> {
+        if self.shelly_power.is_empty() {
+            return None;
+        }
+        let stale_after = self.policy.meter_stale_after();
+        Some(
+            self.shelly_power
+                .values()
+                .any(|sample| sample.is_fresh(now, stale_after)),
+        )
+    }
+
+    /// The calibrated sum over the meters, while at least one is fresh.
+    /// A meter that has gone quiet counts with its last reading for the last good hold time,
+    /// and as 0 after that.
+    fn live_shelly_power(&self, now: Instant) -> Option<f32> {
+        if self.shelly_fresh_at(now) != Some(true) {
+            return None;
+        }
+        let hold = self.policy.last_good_hold;
+        let measured = self
+            .shelly_power
+            .values()
+            .filter(|sample| sample.is_fresh(now, hold))
+            .map(|sample| sample.value)
+            .sum();
+        Some(self.calibration.apply(measured))
+    }
+
+    fn evaluate(&self, tier: FallbackTier, now: Instant) -> Option<f32> {
+        let stale_after = self.policy.stale_after;
+        match tier {
+            FallbackTier::Live => {
+                let shelly_power = self.live_shelly_power(now)?;
+                // A stale offset is still a better guess than none at all
+                let ha_offset = self
+                    .ha_offset
+                    .map(|sample| self.offset_sign.apply(sample.value))
+                    .unwrap_or_default();
+                Some(shelly_power + ha_offset)
+            }
+            FallbackTier::HaOnly => self
+                .ha_offset
+                .filter(|sample| sample.is_fresh(now, stale_after))
+                .map(|sample| self.offset_sign.apply(sample.value)),
+            FallbackTier::LastGood => self
+                .last_good
+                .filter(|sample| sample.is_fresh(now, self.policy.last_good_hold))
+                .map(|sample| sample.value),
+            // The value is only for logs and metrics, the meter reports none
+            FallbackTier::Degraded | FallbackTier::Unavailable => Some(self.policy.degraded_value),
+        }
+    }
+}
+
+/// Splits a combined power into what is drawn from the grid and what is fed into it, by its
+/// sign. Both are positive and at most one is non-zero.
+pub fn grid_flow(watts: f32) -> (f32, f32) {
+    if watts < 0.0 {
+        (0.0, -watts)
+    } else {
+        (watts, 0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_policy() -> FallbackPolicy {
+        FallbackPolicy {
+            stale_after: Duration::from_secs(5),
+            last_good_hold: Duration::from_secs(30),
+            degraded_value: -1.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_fallback_chain() {
+        assert_eq!(
+            parse_fallback_chain("live, HA_ONLY,last_good,degraded"),
+            Ok(vec![
+                FallbackTier::Live,
+                FallbackTier::HaOnly,
+                FallbackTier::LastGood,
+                FallbackTier::Degraded
+            ])
+        );
+        assert_eq!(
+            parse_fallback_chain("live,degraded"),
+            Ok(vec![FallbackTier::Live, FallbackTier::Degraded])
+        );
+        assert!(parse_fallback_chain("live,bogus").is_err());
+    }
+
+    #[test]
+    fn test_grid_flow_follows_the_sign() {
+        assert_eq!(grid_flow(1500.0), (1500.0, 0.0));
+        assert_eq!(grid_flow(-800.0), (0.0, 800.0));
+        assert_eq!(grid_flow(0.0), (0.0, 0.0));
+    }
+
+    #[test]
+    fn test_walks_through_each_tier() {
+        let start = Instant::now();
+        let mut combiner = PowerCombiner::new(test_policy());
+
+        // Nothing received yet
+        assert_eq!(combiner.combine(start), (-1.0, FallbackTier::Degraded));
+
+        combiner.update_shelly_power(1000.0, start);
+        combiner.update_ha_offset(200.0, start);
+        assert_eq!(combiner.combine(start), (1200.0, FallbackTier::Live));
+
+        // Shelly goes quiet but HA keeps reporting
+        let t = start + Duration::from_secs(10);
+        combiner.update_ha_offset(300.0, t);
+        assert_eq!(combiner.combine(t), (300.0, FallbackTier::HaOnly));
+
+        // Both stale, the last live value is held
+        let t = start + Duration::from_secs(20);
+        assert_eq!(combiner.combine(t), (1200.0, FallbackTier::LastGood));
+
+        // Held for too long, report the sentinel
+        let t = start + Duration::from_secs(31);
+        assert_eq!(combiner.combine(t), (-1.0, FallbackTier::Degraded));
+
+        // Shelly comes back, live again using the latest offset
+        combiner.update_shelly_power(500.0, t);
+        assert_eq!(combiner.combine(t), (800.0, FallbackTier::Live));
+    }
+
+    #[test]
+    fn test_non_finite_inputs_are_ignored() {
+        let start = Instant::now();
+        let mut combiner = PowerCombiner::new(test_policy());
+        combiner.update_shelly_power(1000.0, start);
+        combiner.update_ha_offset(200.0, start);
+        assert_eq!(combiner.combine(start), (1200.0, FallbackTier::Live));
+
+        // The previous readings carry on
+        let t = start + Duration::from_secs(1);
+        combiner.update_shelly_power(f32::NAN, t);
+        combiner.update_ha_offset(f32::INFINITY, t);
+        assert_eq!(combiner.combine(t), (1200.0, FallbackTier::Live));
+
+        // Until they go stale, when the last good value takes over
+        let t = start + Duration::from_secs(10);
+        combiner.update_shelly_power(f32::NEG_INFINITY, t);
+        assert_eq!(combiner.combine(t), (1200.0, FallbackTier::LastGood));
+    }
+
+    #[test]
+    fn test_calibration_applies_before_the_offset() {
+        let start = Instant::now();
+        let mut combiner = PowerCombiner::new(test_policy()).with_calibration(Calibration {
+            gain: 0.5,
+            offset: 10.0,
+        });
+        combiner.update_device_power("A", 1200.0, start);
+        combiner.update_device_power("B", -400.0, start);
+        combiner.update_ha_offset(-100.0, start);
+        // (800 * 0.5 + 10) - 100
+        assert_eq!(combiner.combine(start), (310.0, FallbackTier::Live));
+
+        // Exporting, (-1600 * 0.5 + 10) - 100
+        combiner.update_device_power("A", -1200.0, start);
+        assert_eq!(combiner.combine(start), (-890.0, FallbackTier::Live));
+
+        // The offset alone isn't calibrated
+        let t = start + Duration::from_secs(10);
+        combiner.update_ha_offset(-100.0, t);
+        assert_eq!(combiner.combine(t), (-100.0, FallbackTier::HaOnly));
+    }
+
+    #[test]
+    fn test_offset_sign() {
+        let start = Instant::now();
+        let combined = |offset_sign| {
+            let mut combiner = PowerCombiner::new(test_policy()).with_offset_sign(offset_sign);
+            combiner.update_shelly_power(1000.0, start);
+            combiner.update_ha_offset(300.0, start);
+            let live = combiner.combine(start);
+            let t = start + Duration::from_secs(10);
+            combiner.update_ha_offset(300.0, t);
+            (live, combiner.combine(t))
+        };
+        assert_eq!(
+            combined(OffsetSign::Add),
+            ((1300.0, FallbackTier::Live), (300.0, FallbackTier::HaOnly))
+        );
+        assert_eq!(
+            combined(OffsetSign::Subtract),
+            ((700.0, FallbackTier::Live), (-300.0, FallbackTier::HaOnly))
+        );
+        assert_eq!(" Subtract".parse(), Ok(OffsetSign::Subtract));
+        assert!("negate".parse::<OffsetSign>().is_err());
+    }
+
+    #[test]
+    fn test_override_then_resume() {
+        let start = Instant::now();
+        let power_override = PowerOverride::default();
+        let mut combiner = PowerCombiner::new(test_policy()).with_override(power_override.clone());
+        combiner.update_shelly_power(1000.0, start);
+        assert_eq!(combiner.combine(start), (1000.0, FallbackTier::Live));
+
+        power_override.pin(-3000.0, start + Duration::from_secs(2));
+        assert_eq!(combiner.combine(start), (-3000.0, FallbackTier::Live));
+        let t = start + Duration::from_secs(1);
+        combiner.update_shelly_power(1100.0, t);
+        assert_eq!(combiner.combine(t), (-3000.0, FallbackTier::Live));
+
+        // Live data resumes once it expires, and the pinned value was never taken as a good one
+        let t = start + Duration::from_secs(2);
+        assert_eq!(combiner.combine(t), (1100.0, FallbackTier::Live));
+        assert_eq!(power_override.active(t), None);
+        power_override.pin(500.0, t + Duration::from_secs(60));
+        let t = start + Duration::from_secs(20);
+        assert_eq!(combiner.combine(t), (500.0, FallbackTier::LastGood));
+        power_override.clear();
+        assert_eq!(combiner.combine(t), (1100.0, FallbackTier::LastGood));
+    }
+
+    #[test]
+    fn test_overflowing_sum_falls_back() {
+        let start = Instant::now();
+        let mut combiner = PowerCombiner::new(test_policy());
+        combiner.update_shelly_power(500.0, start);
+        assert_eq!(combiner.combine(start), (500.0, FallbackTier::Live));
+        combiner.update_device_power("Other", f32::MAX, start);
+        combiner.update_ha_offset(f32::MAX, start);
+        let (value, tier) = combiner.combine(start);
+        assert!(value.is_finite());
+        assert_eq!(tier, FallbackTier::HaOnly);
+    }
+
+    #[test]
+    fn test_ready_once_both_have_reported() {
+        let start = Instant::now();
+        let mut combiner = PowerCombiner::new(test_policy());
+        assert!(!combiner.is_ready(true));
+        assert!(!combiner.is_ready(false));
+        combiner.update_shelly_power(1000.0, start);
+        assert!(!combiner.is_ready(true));
+        assert!(combiner.is_ready(false));
+        combiner.update_ha_offset(200.0, start);
+        assert!(combiner.is_ready(true));
+    }
+
+    #[test]
+    fn test_state_round_trips() {
+        let path = std::env::temp_dir().join(format!("combiner-{}.json", std::process::id()));
+        let start = Instant::now();
+        let mut combiner = PowerCombiner::new(test_policy());
+        combiner.update_shelly_power(1000.0, start);
+        combiner.update_ha_offset(-200.0, start);
+        combiner.combine(start);
+        combiner.save_state(&path).unwrap();
+
+        // Restarted shortly after, with nothing read yet
+        let t = start + Duration::from_secs(60);
+        let saved = SystemTime::now();
+        let mut restarted = PowerCombiner::new(test_policy());
+        let loaded = restarted.load_state_at(&path, t, saved + Duration::from_secs(2), true);
+        assert!(loaded.unwrap());
+        // The offset is still fresh, so it comes ahead of the last value
+        assert_eq!(restarted.combine(t), (-200.0, FallbackTier::HaOnly));
+        // The offset carries on into the first live value
+        restarted.update_shelly_power(500.0, t);
+        assert_eq!(restarted.combine(t), (300.0, FallbackTier::Live));
+
+        // Without an offset source the saved offset would never be replaced
+        let mut no_offset = PowerCombiner::new(test_policy());
+        let loaded = no_offset.load_state_at(&path, t, saved + Duration::from_secs(2), false);
+        assert!(loaded.unwrap());
+        assert_eq!(no_offset.combine(t), (800.0, FallbackTier::LastGood));
+        no_offset.update_shelly_power(500.0, t);
+        assert_eq!(no_offset.combine(t), (500.0, FallbackTier::Live));
+
+        // Older than the offset stays fresh, only the last value is held
+        let mut stale = PowerCombiner::new(test_policy());
+        let loaded = stale.load_state_at(&path, t, saved + Duration::from_secs(10), true);
+        assert!(loaded.unwrap());
+        assert_eq!(stale.combine(t), (800.0, FallbackTier::LastGood));
+        stale.update_shelly_power(500.0, t);
+        assert_eq!(stale.combine(t), (500.0, FallbackTier::Live));
+
+        // Restarted after longer than the hold, the last value has expired too
+        let mut late = PowerCombiner::new(test_policy());
+        let loaded = late.load_state_at(&path, t, saved + Duration::from_secs(40), true);
+        std::fs::remove_file(&path).unwrap();
+        assert!(loaded.unwrap());
+        assert_eq!(late.combine(t), (-1.0, FallbackTier::Degraded));
+        late.update_shelly_power(500.0, t);
+        assert_eq!(late.combine(t), (500.0, FallbackTier::Live));
+
+        let mut missing = PowerCombiner::new(test_policy());
+        assert!(!missing.load_state(&path, t, true).unwrap());
+    }
+
+    #[test]
+    fn test_live_uses_stale_offset() {
+        let start = Instant::now();
+        let mut combiner = PowerCombiner::new(test_policy());
+        combiner.update_ha_offset(-250.0, start);
+        let t = start + Duration::from_secs(60);
+        combiner.update_shelly_power(1000.0, t);
+        assert_eq!(combiner.combine(t), (750.0, FallbackTier::Live));
+    }
+
+    #[test]
+    fn test_events_during_reconnect() {
+        let start = Instant::now();
+        let events = EventBus::new();
+        let mut receiver = events.subscribe();
+        let mut combiner = PowerCombiner::new(test_policy()).with_events(events);
+
+        combiner.update_shelly_power(1000.0, start);
+        combiner.update_ha_offset(0.0, start);
+        combiner.combine(start);
+        assert!(receiver.try_recv().is_err(), "No events while healthy");
+
+        // Shelly drops off while HA keeps going
+        let t = start + Duration::from_secs(10);
+        combiner.update_ha_offset(0.0, t);
+        combiner.combine(t);
+        assert_eq!(receiver.try_recv(), Ok(Event::SourceStale(Source::Shelly)));
+        assert_eq!(
+            receiver.try_recv(),
+            Ok(Event::FallbackTierChanged {
+                from: FallbackTier::Live,
+                to: FallbackTier::HaOnly
+            })
+        );
+        // Still disconnected, nothing new to report
+        combiner.combine(t);
+        assert!(receiver.try_recv().is_err());
+
+        // Shelly reconnects
+        let t = start + Duration::from_secs(12);
+        combiner.update_shelly_power(900.0, t);
+        combiner.combine(t);
+        assert_eq!(
+            receiver.try_recv(),
+            Ok(Event::SourceRecovered(Source::Shelly))
+        );
+        assert_eq!(
+            receiver.try_recv(),
+            Ok(Event::FallbackTierChanged {
+                from: FallbackTier::HaOnly,
+                to: FallbackTier::Live
+            })
+        );
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_several_meters_add_up() {
+        let mut combiner = PowerCombiner::new(test_policy());
+        let start = Instant::now();
+        combiner.update_device_power("first", 1000.0, start);
+        combiner.update_device_power("second", -300.0, start);
+        combiner.update_ha_offset(50.0, start);
+        assert_eq!(combiner.combine(start), (750.0, FallbackTier::Live));
+
+        // The second goes quiet, so counts with its last reading for a while, then not at all
+        let t = start + Duration::from_secs(10);
+        combiner.update_device_power("first", 1100.0, t);
+        assert_eq!(combiner.combine(t), (850.0, FallbackTier::Live));
+        let t = start + Duration::from_secs(31);
+        combiner.update_device_power("first", 1100.0, t);
+        assert_eq!(combiner.combine(t), (1150.0, FallbackTier::Live));
+
+        // It's only a fallback once every meter is stale
+        let t = t + Duration::from_secs(6);
+        assert_ne!(combiner.combine(t).1, FallbackTier::Live);
+    }
+
+    #[test]
+    fn test_chain_order_is_respected() {
+        let start = Instant::now();
+        let mut combiner = PowerCombiner::new(FallbackPolicy {
+            chain: vec![FallbackTier::Live, FallbackTier::Degraded],
+            ..test_policy()
+        });
+        combiner.update_shelly_power(1000.0, start);
+        combiner.update_ha_offset(100.0, start);
+        assert_eq!(combiner.combine(start), (1100.0, FallbackTier::Live));
+
+        // HaOnly and LastGood are not in the chain, so a stale Shelly goes straight to degraded
+        let t = start + Duration::from_secs(10);
+        combiner.update_ha_offset(100.0, t);
+        assert_eq!(combiner.combine(t), (-1.0, FallbackTier::Degraded));
+    }
+
+    #[test]
+    fn test_steady_input_only_emits_heartbeats() {
+        let start = Instant::now();
+        let mut combiner = PowerCombiner::new(test_policy()).with_emit_policy(EmitPolicy {
+            threshold: 20.0,
+            heartbeat: Duration::from_secs(5),
+        });
+        let emitted: Vec<u64> = (0..=12)
+            .filter(|&second| {
+                // Jitter well inside the threshold
+                let value = 500.0 + (second % 3) as f32 * 5.0;
+                combiner.should_emit(value, start + Duration::from_secs(second))
+            })
+            .collect();
+        assert_eq!(emitted, vec![0, 5, 10]);
+    }
+
+    #[test]
+    fn test_changing_input_emits_immediately() {
+        let start = Instant::now();
+        let mut combiner = PowerCombiner::new(test_policy()).with_emit_policy(EmitPolicy {
+            threshold: 20.0,
+            heartbeat: Duration::from_secs(5),
+        });
+        assert!(combiner.should_emit(500.0, start));
+        assert!(!combiner.should_emit(515.0, start + Duration::from_millis(500)));
+        assert!(combiner.should_emit(530.0, start + Duration::from_secs(1)));
+        // Compared against the last emitted value, so slow drift is still caught
+        assert!(!combiner.should_emit(545.0, start + Duration::from_millis(1500)));
+        assert!(combiner.should_emit(551.0, start + Duration::from_secs(2)));
+        assert!(combiner.should_emit(100.0, start + Duration::from_millis(2500)));
+    }
+
+    #[test]
+    fn test_emits_everything_by_default() {
+        let now = Instant::now();
+        let mut combiner = PowerCombiner::new(test_policy());
+        assert!(combiner.should_emit(500.0, now));
+        assert!(combiner.should_emit(500.0, now));
+    }
+}
@@ -0,0 +1,490 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::sunspec_map::Quantity;
+
+// Derives the electrical values the inverter expects from the measured power
+
+/// Voltage assumed for a phase when no measurement is available
+pub const NOMINAL_VOLTAGE: f32 = 230.0;
+/// Line frequency reported until a measured one arrives
+pub const NOMINAL_FREQUENCY: f32 = 50.0;
+/// Anything below this is treated as a missing reading (e.g. a phase that isn't wired up)
+const MIN_VALID_VOLTAGE: f32 = 50.0;
+
+/// Derives the current for a phase from its power.
+/// Falls back to the nominal voltage when the measured voltage is missing or implausible.
+pub fn derive_current(watts: f32, voltage: Option<f32>) -> f32 {
+    current_at(watts, voltage, NOMINAL_VOLTAGE)
+}
+
+/// Derives the current of each phase, using the measured voltages when provided
+pub fn derive_phase_currents(watts: [f32; 3], voltages: Option<[f32; 3]>) -> [f32; 3] {
+    PowerModel::default().phase_currents(watts, voltages)
+}
+
+fn is_valid_voltage(voltage: f32) -> bool {
+    voltage.is_finite() && voltage >= MIN_VALID_VOLTAGE
+}
+
+fn current_at(watts: f32, voltage: Option<f32>, fallback_voltage: f32) -> f32 {
+    let voltage = match voltage {
+        Some(voltage) if is_valid_voltage(voltage) => voltage,
+        _ => fallback_voltage,
+    };
+    watts / voltage
+}
+
+/// Derives the readings the meter doesn't measure directly from the real power
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PowerModel {
+    /// Voltage assumed when no measurement is available
+    pub nominal_voltage: f32,
+    /// Assumed ratio of real to apparent power, 1 for a purely resistive load
+    pub power_factor: f32,
+}
+
+impl Default for PowerModel {
+    fn default() -> Self {
+        Self {
+            nominal_voltage: NOMINAL_VOLTAGE,
+            power_factor: 1.0,
+        }
+    }
+}
+
+impl PowerModel {
+    /// The net current at the nominal voltage
+    pub fn net_current(&self, watts: f32) -> f32 {
+        self.phase_current(watts, None)
+    }
+
+    /// The current for a phase, at its measured voltage if it's plausible, otherwise the nominal one
+    pub fn phase_current(&self, watts: f32, voltage: Option<f32>) -> f32 {
+        let fallback = if is_valid_voltage(self.nominal_voltage) {
+            self.nominal_voltage
+        } else {
+            NOMINAL_VOLTAGE
+        };
+        current_at(watts, voltage, fallback)
+    }
+
+    pub fn phase_currents(&self, watts: [f32; 3], voltages: Option<[f32; 3]>) -> [f32; 3] {
+        [0, 1, 2].map(|phase| self.phase_current(watts[phase], voltages.map(|v| v[phase])))
+    }
+
+    /// The reactive power of a load drawing `watts` at the assumed power factor.
+    /// Reported as a positive (inductive) magnitude whichever way the power flows.
+    /// Power factors outside (0, 1] can't be modelled, so are treated as 1.
+    pub fn reactive_power(&self, watts: f32) -> f32 {
+        let power_factor = self.power_factor;
+        if !(power_factor > 0.0 && power_factor < 1.0) {
+            return 0.0;
+        }
+        watts.abs() * (1.0 / (power_factor * power_factor) - 1.0).sqrt()
+    }
+
+    /// The apparent power of a load drawing `watts` at the assumed power factor
+    pub fn apparent_power(&self, watts: f32) -> f32 {
+        apparent_power(watts, self.reactive_power(watts))
+    }
+
+    /// The power factor reported for `watts`, signed with the direction of the power
+    pub fn signed_power_factor(&self, watts: f32) -> f32 {
+        power_factor(watts, self.reactive_power(watts))
+    }
+}
+
+/// Apparent power from real and reactive power, always a positive magnitude
+pub fn apparent_power(real_watts: f32, reactive_var: f32) -> f32 {
+    real_watts.hypot(reactive_var)
+}
+
+/// Power factor in the SunSpec convention, where the sign follows the real power:
+/// positive when importing, negative when exporting.
+/// With no power flowing at all there is nothing to be out of phase, so it reads as unity.
+pub fn power_factor(real_watts: f32, reactive_var: f32) -> f32 {
+    let apparent = apparent_power(real_watts, reactive_var);
+    if apparent == 0.0 || !apparent.is_finite() {
+        return 1.0;
+    }
+    // Rounding can push the ratio a hair past 1
+    (real_watts / apparent).clamp(-1.0, 1.0)
+}
+
+/// Which value wins when the total and per-phase powers are reported together
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PhaseTotalMode {
+    /// The combined power is reported as is, and the phases are adjusted to add up to it
+    DerivePhasesFromTotal,
+    /// The phases are adjusted as above, and their sum is reported as the total
+    SumPhasesToTotal,
+}
+
+impl FromStr for PhaseTotalMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "derive_phases_from_total" => Ok(Self::DerivePhasesFromTotal),
+            "sum_phases_to_total" => Ok(Self::SumPhasesToTotal),
+            other => Err(format!("Unknown phase total mode `{other}`")),
+        }
+    }
+}
+
+/// Where the difference between the total and the measured phases, e.g. the HA offset, is put
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OffsetDistribution {
+    /// Split evenly across the phases, so they add up to the total
+    #[default]
+    EvenThirds,
+    /// All on phase A, e.g. where the offsetting load is on that phase, so they add up to the total
+    PhaseA,
+    /// Only in the total, leaving the phases as measured. They then don't add up to the total,
+    /// so the total is reported as is whatever the `PhaseTotalMode`.
+    TotalOnly,
+}
+
+impl FromStr for OffsetDistribution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "even_thirds" => Ok(Self::EvenThirds),
+            "phase_a" => Ok(Self::PhaseA),
+            "total_only" => Ok(Self::TotalOnly),
+            other => Err(format!("Unknown offset distribution `{other}`")),
+        }
+    }
+}
+
+/// Returns a total and per-phase powers, with the difference between `total` and the measured
+/// phases put where `distribution` says. Without measured phases the total is split evenly.
+pub fn phase_powers(
+    total: f32,
+    measured_phases: Option<[f32; 3]>,
+    mode: PhaseTotalMode,
+    distribution: OffsetDistribution,
+) -> (f32, [f32; 3]) {
+    let Some(measured) = measured_phases else {
+        return consistent_phase_powers(total, None, mode);
+    };
+    match distribution {
+        OffsetDistribution::EvenThirds => consistent_phase_powers(total, measured_phases, mode),
+        OffsetDistribution::PhaseA => {
+            let [_, b, c] = measured;
+            let a = total - b - c;
+            let phases = [a, b, c];
+            match mode {
+                PhaseTotalMode::DerivePhasesFromTotal => (total, phases),
+                PhaseTotalMode::SumPhasesToTotal => (a + b + c, phases),
+            }
+        }
+        OffsetDistribution::TotalOnly => (total, measured),
+    }
+}
+
+/// Returns a total and per-phase powers that agree with each other.
+/// The difference between `total` and the measured phases (e.g. the HA offset) is spread evenly
+/// across the phases. Without measured phases the total is split evenly.
+pub fn consistent_phase_powers(
+    total: f32,
+    measured_phases: Option<[f32; 3]>,
+    mode: PhaseTotalMode,
+) -> (f32, [f32; 3]) {
+    let phases = match measured_phases {
+        Some(measured) => {
+            let adjustment = (total - measured.iter().sum::<f32>()) / 3.0;
+            measured.map(|watts| watts + adjustment)
+        }
+        None => [total / 3.0; 3],
+    };
+    match mode {
+        PhaseTotalMode::DerivePhasesFromTotal => (total, phases),
+        PhaseTotalMode::SumPhasesToTotal => (phases[0] + phases[1] + phases[2], phases),
+    }
+}
+
+/// Decimal places reported for the derived quantities, so they don't carry noisy trailing digits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Precision {
+    pub voltage_decimals: u32,
+    pub current_decimals: u32,
+    pub frequency_decimals: u32,
+}
+
+impl Default for Precision {
+    /// As shown by a real Fronius Smart Meter
+    fn default() -> Self {
+        Self {
+            voltage_decimals: 1,
+            current_decimals: 2,
+            frequency_decimals: 2,
+        }
+    }
+}
+
+impl Precision {
+    /// Rounds a value of the given quantity. Powers, power factors and energies are left untouched.
+    pub fn round(&self, quantity: Quantity, value: f32) -> f32 {
+        let decimals = match quantity {
+            Quantity::Voltage => self.voltage_decimals,
+            Quantity::Current => self.current_decimals,
+            Quantity::Frequency => self.frequency_decimals,
+            Quantity::Power | Quantity::PowerFactor | Quantity::Energy => return value,
+        };
+        // In f64, so the scaling doesn't add error of its own
+        let scale = 10f64.powi(decimals.min(f64::DIGITS) as i32);
+        ((value as f64 * scale).round() / scale) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{shelly_3em_client::Shelly3EMClient, test_utils::MockShelly};
+
+    #[test]
+    fn test_derive_current_nominal_fallback() {
+        assert_eq!(derive_current(2300.0, None), 10.0);
+        assert_eq!(derive_current(-460.0, None), -2.0);
+        // Unwired phases read as ~0V and should not blow up the division
+        assert_eq!(derive_current(2300.0, Some(0.0)), 10.0);
+        assert_eq!(derive_current(2300.0, Some(f32::NAN)), 10.0);
+    }
+
+    #[test]
+    fn test_derive_current_measured_voltage() {
+        assert_eq!(derive_current(2400.0, Some(240.0)), 10.0);
+        assert_eq!(
+            derive_phase_currents([1150.0, 2400.0, -500.0], Some([230.0, 240.0, 250.0])),
+            [5.0, 10.0, -2.0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_derive_currents_from_shelly_voltages() {
+        let shelly = MockShelly::start(&[
+            (1020, 220.0),
+            (1024, 1100.0),
+            (1040, 240.0),
+            (1044, 480.0),
+            (1060, 250.0),
+            (1064, -750.0),
+        ])
+        .await;
+        let mut client = Shelly3EMClient::new(shelly).await;
+
+        let watts = client.read_phase_powers().await.unwrap();
+        let voltages = client.read_phase_voltages().await.ok();
+        assert_eq!(derive_phase_currents(watts, voltages), [5.0, 2.0, -3.0]);
+        // Without the voltage reading, nominal voltage is used instead
+        assert_eq!(
+            derive_phase_currents(watts, None),
+            [1100.0 / 230.0, 480.0 / 230.0, -750.0 / 230.0]
+        );
+    }
+
+    #[test]
+    fn test_power_model_net_current() {
+        let model = PowerModel::default();
+        assert_eq!(model.net_current(2300.0), 10.0);
+        assert_eq!(model.net_current(-460.0), -2.0);
+
+        let model = PowerModel {
+            nominal_voltage: 240.0,
+            ..Default::default()
+        };
+        assert_eq!(model.net_current(2400.0), 10.0);
+        assert_eq!(
+            model.phase_currents([480.0, 2300.0, 0.0], Some([0.0, 230.0, 230.0])),
+            [2.0, 10.0, 0.0]
+        );
+        // A nonsense nominal voltage can't be divided by either
+        let model = PowerModel {
+            nominal_voltage: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(model.net_current(2300.0), 10.0);
+    }
+
+    #[test]
+    fn test_power_model_reactive_power() {
+        assert_eq!(PowerModel::default().reactive_power(2000.0), 0.0);
+
+        let model = PowerModel {
+            power_factor: 0.6,
+            ..Default::default()
+        };
+        // 3kW at 0.6 is 5kVA, leaving 4kvar
+        assert!((model.reactive_power(3000.0) - 4000.0).abs() < 0.1);
+        assert!((model.reactive_power(-3000.0) - 4000.0).abs() < 0.1);
+        assert_eq!(model.reactive_power(0.0), 0.0);
+        let var = model.reactive_power(3000.0);
+        assert!((power_factor(3000.0, var) - 0.6).abs() < 1e-6);
+
+        for power_factor in [0.0, -0.5, 1.5, f32::NAN] {
+            let model = PowerModel {
+                power_factor,
+                ..Default::default()
+            };
+            assert_eq!(model.reactive_power(3000.0), 0.0, "PF {power_factor}");
+        }
+    }
+
+    #[test]
+    fn test_power_model_apparent_power_and_power_factor() {
+        let unity = PowerModel::default();
+        assert_eq!(unity.apparent_power(2000.0), 2000.0);
+        assert_eq!(unity.apparent_power(-2000.0), 2000.0);
+        assert_eq!(unity.signed_power_factor(2000.0), 1.0);
+        assert_eq!(unity.signed_power_factor(-2000.0), -1.0);
+        assert_eq!(unity.signed_power_factor(0.0), 1.0);
+
+        let model = PowerModel {
+            power_factor: 0.8,
+            ..Default::default()
+        };
+        assert!((model.apparent_power(2000.0) - 2500.0).abs() < 0.1);
+        assert!(model.apparent_power(-2000.0) > 2000.0);
+        assert!((model.signed_power_factor(2000.0) - 0.8).abs() < 1e-6);
+        assert!((model.signed_power_factor(-2000.0) + 0.8).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_consistent_phase_powers() {
+        // The 300W HA offset is shared across the phases
+        let (total, phases) = consistent_phase_powers(
+            1300.0,
+            Some([500.0, 300.0, 200.0]),
+            PhaseTotalMode::DerivePhasesFromTotal,
+        );
+        assert_eq!(total, 1300.0);
+        assert_eq!(phases, [600.0, 400.0, 300.0]);
+
+        let (total, phases) =
+            consistent_phase_powers(900.0, None, PhaseTotalMode::DerivePhasesFromTotal);
+        assert_eq!(total, 900.0);
+        assert_eq!(phases, [300.0; 3]);
+    }
+
+    #[test]
+    fn test_offset_distribution() {
+        let measured = Some([500.0, 300.0, 200.0]);
+        let mode = PhaseTotalMode::DerivePhasesFromTotal;
+        // The even split sums back to the total, offset included
+        let (total, phases) = phase_powers(1300.0, measured, mode, OffsetDistribution::EvenThirds);
+        assert_eq!(total, 1300.0);
+        assert_eq!(phases, [600.0, 400.0, 300.0]);
+        assert_eq!(phases.iter().sum::<f32>(), total);
+
+        let (total, phases) = phase_powers(700.0, measured, mode, OffsetDistribution::PhaseA);
+        assert_eq!((total, phases), (700.0, [200.0, 300.0, 200.0]));
+
+        for mode in [
+            PhaseTotalMode::DerivePhasesFromTotal,
+            PhaseTotalMode::SumPhasesToTotal,
+        ] {
+            let (total, phases) =
+                phase_powers(1300.0, measured, mode, OffsetDistribution::TotalOnly);
+            assert_eq!((total, phases), (1300.0, [500.0, 300.0, 200.0]), "{mode:?}");
+            // Without measured phases there's nothing to keep apart from the offset
+            let (total, phases) = phase_powers(900.0, None, mode, OffsetDistribution::TotalOnly);
+            assert_eq!((total, phases), (900.0, [300.0; 3]), "{mode:?}");
+        }
+
+        assert_eq!("Even_Thirds".parse(), Ok(OffsetDistribution::EvenThirds));
+        assert_eq!("total_only".parse(), Ok(OffsetDistribution::TotalOnly));
+        assert!("phase_b".parse::<OffsetDistribution>().is_err());
+    }
+
+    #[test]
+    fn test_sum_phases_is_exact() {
+        for total in [1.0, 1234.567, -98.7, 0.1] {
+            let (reported, [a, b, c]) = consistent_phase_powers(
+                total,
+                Some([0.3, 17.1, -5.9]),
+                PhaseTotalMode::SumPhasesToTotal,
+            );
+            assert_eq!(reported, a + b + c);
+            assert!((reported - total).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_parse_phase_total_mode() {
+        assert_eq!(
+            "derive_phases_from_total".parse(),
+            Ok(PhaseTotalMode::DerivePhasesFromTotal)
+        );
+        assert_eq!(
+            "SUM_PHASES_TO_TOTAL".parse(),
+            Ok(PhaseTotalMode::SumPhasesToTotal)
+        );
+        assert!("phases".parse::<PhaseTotalMode>().is_err());
+    }
+
+    #[test]
+    fn test_precision_per_quantity() {
+        let precision = Precision {
+            voltage_decimals: 1,
+            current_decimals: 3,
+            frequency_decimals: 0,
+        };
+        assert_eq!(precision.round(Quantity::Voltage, 231.4567), 231.5);
+        assert_eq!(precision.round(Quantity::Current, 4.123456), 4.123);
+        assert_eq!(precision.round(Quantity::Current, -0.0004), -0.0);
+        assert_eq!(precision.round(Quantity::Frequency, 49.97), 50.0);
+        assert_eq!(precision.round(Quantity::Power, 1234.5678), 1234.5678);
+        assert_eq!(precision.round(Quantity::PowerFactor, 0.98765), 0.98765);
+    }
+
+    #[test]
+    fn test_default_precision() {
+        let precision = Precision::default();
+        assert_eq!(precision.round(Quantity::Voltage, 229.96), 230.0);
+        assert_eq!(precision.round(Quantity::Current, 10.0049), 10.0);
+        assert_eq!(precision.round(Quantity::Frequency, 50.014), 50.01);
+    }
+
+    #[test]
+    fn test_apparent_power_is_a_magnitude() {
+        assert_eq!(apparent_power(3000.0, 4000.0), 5000.0);
+        assert_eq!(apparent_power(-3000.0, 4000.0), 5000.0);
+        assert_eq!(apparent_power(-3000.0, -4000.0), 5000.0);
+        assert_eq!(apparent_power(-1500.0, 0.0), 1500.0);
+        assert_eq!(apparent_power(0.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn test_power_factor_import_and_export() {
+        assert_eq!(power_factor(3000.0, 4000.0), 0.6);
+        assert_eq!(power_factor(3000.0, -4000.0), 0.6);
+        // Exporting flips the sign, but not the magnitude
+        assert_eq!(power_factor(-3000.0, 4000.0), -0.6);
+        assert_eq!(power_factor(-3000.0, -4000.0), -0.6);
+        assert_eq!(power_factor(2000.0, 0.0), 1.0);
+        assert_eq!(power_factor(-2000.0, 0.0), -1.0);
+        assert_eq!(power_factor(0.0, 0.0), 1.0);
+        assert_eq!(power_factor(0.0, 500.0), 0.0);
+    }
+
+    #[test]
+    fn test_power_factor_stays_in_range() {
+        for real in [-1e6, -12345.6, -0.001, 0.001, 7.7, 1e6, f32::MAX] {
+            for reactive in [-1e6, -3.3, 0.0, 1e-6, 42.0, 1e6] {
+                let pf = power_factor(real, reactive);
+                assert!(
+                    (-1.0..=1.0).contains(&pf),
+                    "PF {pf} for {real}W {reactive}var"
+                );
+                assert_eq!(pf.is_sign_negative(), real < 0.0, "{real}W {reactive}var");
+                assert!(apparent_power(real, reactive) >= 0.0);
+            }
+        }
+    }
+}
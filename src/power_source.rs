@@ -0,0 +1,95 @@
+use std::{fmt, future::Future, pin::Pin, time::Duration};
+
+// The common shape of everything that feeds the meter, so new kinds of source can be added
+// without touching the loop that combines them
+
+/// A single reading from a source
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Measurement {
+    /// The net grid power measured by a meter, positive is import
+    GridPower {
+        watts: f32,
+        /// Per-phase powers, when the source measures them
+        phase_watts: Option<[f32; 3]>,
+        /// Per-phase voltages, when the source measures them
+        phase_voltages: Option<[f32; 3]>,
+        /// Line frequency, when the source measures it
+        frequency: Option<f32>,
+    },
+    /// A shift added on top of the grid power, positive shifts towards import
+    Offset(f32),
+}
+
+impl Measurement {
+    /// A grid power reading without any per-phase detail
+    pub fn grid_power(watts: f32) -> Self {
+        Self::GridPower {
+            watts,
+            phase_watts: None,
+            phase_voltages: None,
+            frequency: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReaderError {
+    /// The source couldn't be reached, or didn't answer
+    Unavailable(String),
+    /// The source answered with something that isn't a usable reading
+    Invalid(String),
+}
+
+impl fmt::Display for ReaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unavailable(reason) => write!(f, "source unavailable: {reason}"),
+            Self::Invalid(reason) => write!(f, "invalid reading: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ReaderError {}
+
+pub type SourceFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Measurement, ReaderError>> + Send + 'a>>;
+
+/// Something that produces measurements, polled once per cycle
+pub trait PowerSource: Send {
+    /// Used in logs to tell sources apart
+    fn name(&self) -> &str;
+
+    /// Takes the next measurement from the source
+    fn next(&mut self) -> SourceFuture<'_>;
+
+    /// How often the source wants polling, None for every cycle
+    fn poll_interval(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Polls `source` every `interval` rather than every cycle
+pub struct PolledEvery {
+    source: Box<dyn PowerSource>,
+    interval: Duration,
+}
+
+impl PolledEvery {
+    pub fn new(source: Box<dyn PowerSource>, interval: Duration) -> Self {
+        Self { source, interval }
+    }
+}
+
+impl PowerSource for PolledEvery {
+    fn name(&self) -> &str {
+        self.source.name()
+    }
+
+    fn next(&mut self) -> SourceFuture<'_> {
+        self.source.next()
+    }
+
+    fn poll_interval(&self) -> Option<Duration> {
+        Some(self.interval)
+    }
+}
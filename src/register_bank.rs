@@ -0,0 +1,154 @@
+use std::fmt;
+
+// The meter's registers are a few contiguous SunSpec blocks, so they're stored as one array
+// over the whole address space. A multi-register read is then a slice copy, with an occupancy
+// bitmap saying which registers actually hold something.
+
+const ADDRESS_SPACE: usize = u16::MAX as usize + 1;
+const WORD_BITS: usize = u64::BITS as usize;
+
+/// The value of every register, with which ones have been set
+#[derive(Clone, PartialEq, Eq)]
+pub struct RegisterBank {
+    /// Indexed by address, 0 where nothing has been set
+    values: Box<[u16]>,
+    /// One bit per address, set once the register has been
+    occupied: Box<[u64]>,
+}
+
+impl Default for RegisterBank {
+    fn default() -> Self {
+        Self {
+            values: vec![0; ADDRESS_SPACE].into_boxed_slice(),
+            occupied: vec![0; ADDRESS_SPACE / WORD_BITS].into_boxed_slice(),
+        }
+    }
+}
+
+impl RegisterBank {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, address: u16) -> Option<u16> {
+        self.contains(address)
+            .then(|| self.values[usize::from(address)])
+    }
+
+    /// The `count` registers from `address`, with 0 for any that haven't been set.
+    /// None if they'd run past the end of the address space.
+    pub fn get_range(&self, address: u16, count: u16) -> Option<&[u16]> {
+        let start = usize::from(address);
+        self.values.get(start..start + usize::from(count))
+    }
+
+    pub fn set(&mut self, address: u16, value: u16) {
+        let (word, bit) = Self::bit(address);
+        self.occupied[word] |= bit;
+        self.values[usize::from(address)] = value;
+    }
+
+    pub fn remove(&mut self, address: u16) -> Option<u16> {
+        let value = self.get(address);
+        let (word, bit) = Self::bit(address);
+        self.occupied[word] &= !bit;
+        self.values[usize::from(address)] = 0;
+        value
+    }
+
+    pub fn contains(&self, address: u16) -> bool {
+        let (word, bit) = Self::bit(address);
+        self.occupied[word] & bit != 0
+    }
+
+    /// The addresses from `address` on that haven't been set, stopping at the end of the
+    /// address space
+    pub fn unset_in(&self, address: u16, count: u16) -> impl Iterator<Item = u16> + '_ {
+        (0..count)
+            .map_while(move |offset| address.checked_add(offset))
+            .filter(|address| !self.contains(*address))
+    }
+
+    /// Every register that has been set, in address order
+    pub fn iter(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        (0..=u16::MAX)
+            .filter(|address| self.contains(*address))
+            .map(|address| (address, self.values[usize::from(address)]))
+    }
+
+    pub fn len(&self) -> usize {
+        self.occupied
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.occupied.iter().all(|word| *word == 0)
+    }
+
+    fn bit(address: u16) -> (usize, u64) {
+        let address = usize::from(address);
+        (address / WORD_BITS, 1 << (address % WORD_BITS))
+    }
+}
+
+impl FromIterator<(u16, u16)> for RegisterBank {
+    fn from_iter<I: IntoIterator<Item = (u16, u16)>>(registers: I) -> Self {
+        let mut bank = Self::new();
+        for (address, value) in registers {
+            bank.set(address, value);
+        }
+        bank
+    }
+}
+
+impl fmt::Debug for RegisterBank {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_get_and_remove() {
+        let mut bank = RegisterBank::new();
+        assert!(bank.is_empty());
+        assert_eq!(bank.get(40000), None);
+        bank.set(40000, 0x5375);
+        bank.set(u16::MAX, 7);
+        bank.set(0, 0);
+        assert_eq!(bank.get(40000), Some(0x5375));
+        assert_eq!(bank.get(u16::MAX), Some(7));
+        // Set to 0 is still set
+        assert_eq!(bank.get(0), Some(0));
+        assert_eq!(bank.len(), 3);
+        assert_eq!(
+            bank.iter().collect::<Vec<_>>(),
+            vec![(0, 0), (40000, 0x5375), (u16::MAX, 7)]
+        );
+
+        assert_eq!(bank.remove(40000), Some(0x5375));
+        assert!(!bank.contains(40000));
+        assert_eq!(bank.remove(40000), None);
+        assert_eq!(bank.len(), 2);
+    }
+
+    #[test]
+    fn test_ranges() {
+        let bank: RegisterBank = [(100, 1), (101, 2), (103, 4)].into_iter().collect();
+        assert_eq!(bank.get_range(100, 4), Some(&[1, 2, 0, 4][..]));
+        assert_eq!(bank.get_range(100, 0), Some(&[][..]));
+        assert_eq!(bank.unset_in(99, 6).collect::<Vec<_>>(), vec![99, 102, 104]);
+
+        assert_eq!(bank.get_range(u16::MAX, 1), Some(&[0][..]));
+        assert_eq!(bank.get_range(u16::MAX, 2), None);
+        assert_eq!(
+            bank.unset_in(u16::MAX - 1, 5).collect::<Vec<_>>(),
+            vec![u16::MAX - 1, u16::MAX]
+        );
+    }
+}
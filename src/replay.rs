@@ -0,0 +1,207 @@
+use std::{fs, path::Path, time::Duration};
+
+use tokio::{sync::watch, task::JoinHandle, time};
+
+use tracing::info;
+
+use crate::power_source::{Measurement, PowerSource, ReaderError, SourceFuture};
+
+// Plays back a recorded power trace in place of the Shelly, to see how an inverter reacts to a
+// known load profile without any hardware. Like MQTT, the trace plays in the background and the
+// latest value is handed out each poll, so the pacing follows the trace rather than the poll loop.
+
+/// `SHELLY_MODBUS` addresses starting with this replay the file at the rest of the address
+pub const REPLAY_SCHEME: &str = "replay://";
+
+/// The trace file named by `address`, if it is a replay URL such as `replay:///data/trace.csv`
+pub fn trace_path(address: &str) -> Option<&str> {
+    address.trim().strip_prefix(REPLAY_SCHEME)
+}
+
+/// A recorded power value, `at` after the first sample of the trace
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    pub at: Duration,
+    pub watts: f32,
+}
+
+/// Parses a `timestamp,power_watts` CSV, with the timestamps in seconds.
+/// Only the gaps between timestamps matter, so they can be relative or Unix times.
+/// A header line and blank lines are skipped.
+pub fn parse_trace(csv: &str) -> Result<Vec<Sample>, String> {
+    let mut samples: Vec<Sample> = Vec::new();
+    let mut first_timestamp = None;
+    for (index, line) in csv.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let parsed = line.split_once(',').and_then(|(timestamp, watts)| {
+            let timestamp: f64 = timestamp.trim().parse().ok()?;
+            let watts: f32 = watts.trim().parse().ok()?;
+            (timestamp.is_finite() && watts.is_finite()).then_some((timestamp, watts))
+        });
+        let Some((timestamp, watts)) = parsed else {
+            if index == 0 {
+                // The header
+                continue;
+            }
+            return Err(format!(
+                "line {}: `{line}` isn't `timestamp,power_watts`",
+                index + 1
+            ));
+        };
+        let first = *first_timestamp.get_or_insert(timestamp);
+        let at = Duration::try_from_secs_f64(timestamp - first).map_err(|_| {
+            format!(
+                "line {}: timestamp {timestamp} is before the first",
+                index + 1
+            )
+        })?;
+        if samples.last().is_some_and(|last| at < last.at) {
+            return Err(format!(
+                "line {}: timestamp {timestamp} goes backwards",
+                index + 1
+            ));
+        }
+        samples.push(Sample { at, watts });
+    }
+    if samples.is_empty() {
+        return Err("no samples".to_string());
+    }
+    Ok(samples)
+}
+
+/// Reads and parses the trace at `path`
+pub fn load_trace(path: impl AsRef<Path>) -> Result<Vec<Sample>, String> {
+    let path = path.as_ref();
+    let csv = fs::read_to_string(path).map_err(|e| format!("{path:?}: {e}"))?;
+    parse_trace(&csv).map_err(|e| format!("{path:?} {e}"))
+}
+
+pub struct ReplayReader {
+    latest: watch::Receiver<Option<f32>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl ReplayReader {
+    /// Starts playing `samples`, `speed` times faster than they were recorded.
+    /// The last sample holds once the trace has finished.
+    pub fn start(samples: Vec<Sample>, speed: f64) -> Self {
+        let (latest_tx, latest_rx) = watch::channel(None);
+        let task = tokio::spawn(Self::play(samples, speed, latest_tx));
+        Self {
+            latest: latest_rx,
+            task: Some(task),
+        }
+    }
+
+    async fn play(samples: Vec<Sample>, speed: f64, latest: watch::Sender<Option<f32>>) {
+        info!("Replaying {} samples at {speed}x", samples.len());
+        let start = time::Instant::now();
+        for sample in &samples {
+            time::sleep_until(start + sample.at.div_f64(speed)).await;
+            latest.send_replace(Some(sample.watts));
+        }
+        info!(
+            "Replay finished, holding {}W",
+            samples[samples.len() - 1].watts
+        );
+    }
+}
+
+impl Drop for ReplayReader {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
+
+impl PowerSource for ReplayReader {
+    fn name(&self) -> &str {
+        "Replay"
+    }
+
+    fn next(&mut self) -> SourceFuture<'_> {
+        let result = match *self.latest.borrow() {
+            Some(watts) => Ok(Measurement::grid_power(watts)),
+            None => Err(ReaderError::Unavailable(
+                "the replay hasn't started".to_string(),
+            )),
+        };
+        Box::pin(std::future::ready(result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE: &str = "timestamp,power_watts\n\
+        1700000000,500\n\
+        1700000001.5,-250.5\n\
+        \n\
+        1700000004,1200\n";
+
+    #[test]
+    fn test_parse_trace() {
+        assert_eq!(
+            parse_trace(TRACE),
+            Ok(vec![
+                Sample {
+                    at: Duration::ZERO,
+                    watts: 500.0
+                },
+                Sample {
+                    at: Duration::from_millis(1500),
+                    watts: -250.5
+                },
+                Sample {
+                    at: Duration::from_secs(4),
+                    watts: 1200.0
+                },
+            ])
+        );
+        assert!(parse_trace("").is_err());
+        assert!(parse_trace("timestamp,power_watts\n").is_err());
+        assert!(parse_trace("0,100\n1,oops\n").is_err());
+        assert!(parse_trace("5,100\n2,100\n").is_err());
+        assert!(parse_trace("0,100\n1,NaN\n").is_err());
+    }
+
+    #[test]
+    fn test_trace_path() {
+        assert_eq!(
+            trace_path("replay:///data/trace.csv"),
+            Some("/data/trace.csv")
+        );
+        assert_eq!(trace_path("192.168.1.20:502"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_samples_arrive_in_order_and_paced() {
+        let samples = parse_trace(TRACE).unwrap();
+        let mut reader = ReplayReader::start(samples, 2.0);
+        let mut latest = reader.latest.clone();
+        let start = time::Instant::now();
+        let mut arrivals = Vec::new();
+        for _ in 0..3 {
+            latest.changed().await.unwrap();
+            let watts = latest.borrow_and_update().unwrap();
+            arrivals.push((start.elapsed(), watts));
+        }
+        // At twice the recorded speed
+        assert_eq!(
+            arrivals,
+            [
+                (Duration::ZERO, 500.0),
+                (Duration::from_millis(750), -250.5),
+                (Duration::from_secs(2), 1200.0),
+            ]
+        );
+        // The last sample holds
+        time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(reader.next().await, Ok(Measurement::grid_power(1200.0)));
+    }
+}
@@ -0,0 +1,266 @@
+use std::time::Duration;
+
+use tracing::debug;
+
+use crate::{
+    power_source::{PowerSource, ReaderError, SourceFuture},
+    rng::SplitMix64,
+};
+
+// Retrying a source that couldn't be reached within the same poll, so a single dropped request
+// doesn't cost the whole cycle. Shared by the Shelly and HA readers so both back off the same way.
+
+/// How long to wait between retries, growing by `factor` each time up to `max_delay`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BackoffPolicy {
+    /// The wait before the first retry
+    pub base: Duration,
+    /// What each wait is multiplied by for the next
+    pub factor: f64,
+    /// Retries after the first attempt, 0 to never retry
+    pub max_retries: u32,
+    pub max_delay: Duration,
+    /// Each wait is spread by up to this fraction either way, so emulators restarted together
+    /// don't all retry at once
+    pub jitter: f64,
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        Self {
+            base: Duration::from_millis(100),
+            factor: 2.0,
+            max_retries: 2,
+            max_delay: Duration::from_secs(1),
+            jitter: 0.2,
+        }
+    }
+}
+
+impl BackoffPolicy {
+    /// The wait before retry number `attempt`, counting from 1
+    pub fn delay_for(&self, attempt: u32) -> Duration {
+        if attempt == 0 {
+            return Duration::ZERO;
+        }
+        let exponent = i32::try_from(attempt - 1).unwrap_or(i32::MAX);
+        let delay = self.base.as_secs_f64() * self.factor.powi(exponent);
+        // Anything too large to be a Duration is past the cap anyway
+        Duration::try_from_secs_f64(delay).map_or(self.max_delay, |delay| delay.min(self.max_delay))
+    }
+
+    /// `delay_for(attempt)` spread by the jitter, still never past `max_delay`
+    pub fn jittered_delay_for(&self, attempt: u32, rng: &mut SplitMix64) -> Duration {
+        let spread = (rng.next_unit() * 2.0 - 1.0) * self.jitter.clamp(0.0, 1.0);
+        self.delay_for(attempt)
+            .mul_f64(1.0 + spread)
+            .min(self.max_delay)
+    }
+}
+
+/// Retries `source` as set out in `policy` while it is unavailable.
+/// Invalid readings aren't retried, as asking again straight away is unlikely to fix them.
+pub struct Retried {
+    source: Box<dyn PowerSource>,
+    policy: BackoffPolicy,
+    rng: SplitMix64,
+}
+
+impl Retried {
+    pub fn new(source: Box<dyn PowerSource>, policy: BackoffPolicy) -> Self {
+        Self {
+            source,
+            policy,
+            rng: SplitMix64::from_clock(),
+        }
+    }
+
+    /// Draws the jitter from `seed` rather than the clock, for repeatable delays
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = SplitMix64::new(seed);
+        self
+    }
+}
+
+impl PowerSource for Retried {
+    fn name(&self) -> &str {
+        self.source.name()
+    }
+
+    fn next(&mut self) -> SourceFuture<'_> {
+        Box::pin(async move {
+            let mut attempt = 0;
+            loop {
+                match self.source.next().await {
+                    Err(ReaderError::Unavailable(reason)) if attempt < self.policy.max_retries => {
+                        attempt += 1;
+                        let delay = self.policy.jittered_delay_for(attempt, &mut self.rng);
+                        debug!(
+                            "{} unavailable ({reason}), retry {attempt} in {delay:?}",
+                            self.source.name()
+                        );
+                        tokio::time::sleep(delay).await;
+                    }
+                    result => return result,
+                }
+            }
+        })
+    }
+
+    fn poll_interval(&self) -> Option<Duration> {
+        self.source.poll_interval()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::power_source::Measurement;
+    use std::collections::VecDeque;
+    use tokio::time::Instant;
+
+    #[test]
+    fn test_delay_grows_by_factor() {
+        let policy = BackoffPolicy {
+            base: Duration::from_millis(100),
+            factor: 3.0,
+            max_retries: 5,
+            max_delay: Duration::from_secs(60),
+            jitter: 0.0,
+        };
+        assert_eq!(policy.delay_for(0), Duration::ZERO);
+        assert_eq!(policy.delay_for(1), Duration::from_millis(100));
+        assert_eq!(policy.delay_for(2), Duration::from_millis(300));
+        assert_eq!(policy.delay_for(3), Duration::from_millis(900));
+    }
+
+    #[test]
+    fn test_delay_is_clamped() {
+        let policy = BackoffPolicy {
+            max_delay: Duration::from_millis(250),
+            ..Default::default()
+        };
+        assert_eq!(policy.delay_for(2), Duration::from_millis(200));
+        assert_eq!(policy.delay_for(3), Duration::from_millis(250));
+        // Far past where the delay overflows a Duration
+        assert_eq!(policy.delay_for(u32::MAX), Duration::from_millis(250));
+        let constant = BackoffPolicy {
+            factor: 1.0,
+            ..Default::default()
+        };
+        assert_eq!(constant.delay_for(10), constant.base);
+    }
+
+    #[test]
+    fn test_jitter_stays_within_bounds_and_cap() {
+        let policy = BackoffPolicy {
+            base: Duration::from_millis(100),
+            factor: 2.0,
+            max_retries: 10,
+            max_delay: Duration::from_millis(1000),
+            jitter: 0.2,
+        };
+        let mut rng = SplitMix64::new(7);
+        for attempt in 1..=6 {
+            let unjittered = policy.delay_for(attempt);
+            let delays: Vec<Duration> = (0..500)
+                .map(|_| policy.jittered_delay_for(attempt, &mut rng))
+                .collect();
+            for delay in &delays {
+                assert!(
+                    *delay >= unjittered.mul_f64(0.8) && *delay <= unjittered.mul_f64(1.2),
+                    "{delay:?} for attempt {attempt}"
+                );
+                assert!(*delay <= policy.max_delay, "{delay:?} past the cap");
+            }
+            // Actually jittered, rather than always the same value
+            assert!(delays.iter().any(|delay| *delay != delays[0]));
+        }
+
+        let no_jitter = BackoffPolicy {
+            jitter: 0.0,
+            ..policy
+        };
+        assert_eq!(
+            no_jitter.jittered_delay_for(3, &mut rng),
+            Duration::from_millis(400)
+        );
+    }
+
+    /// Hands out `results` in order, then stays unavailable
+    struct ScriptedSource(VecDeque<Result<Measurement, ReaderError>>);
+
+    impl PowerSource for ScriptedSource {
+        fn name(&self) -> &str {
+            "Scripted"
+        }
+
+        fn next(&mut self) -> SourceFuture<'_> {
+            let result = self
+                .0
+                .pop_front()
+                .unwrap_or_else(|| Err(ReaderError::Unavailable("done".to_string())));
+            Box::pin(std::future::ready(result))
+        }
+    }
+
+    fn unavailable() -> Result<Measurement, ReaderError> {
+        Err(ReaderError::Unavailable("timed out".to_string()))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retries_until_available() {
+        let script = [unavailable(), unavailable(), Ok(Measurement::Offset(5.0))];
+        let policy = BackoffPolicy {
+            jitter: 0.0,
+            ..Default::default()
+        };
+        let mut source = Retried::new(Box::new(ScriptedSource(script.into())), policy);
+        let start = Instant::now();
+        assert_eq!(source.next().await, Ok(Measurement::Offset(5.0)));
+        assert_eq!(start.elapsed(), Duration::from_millis(300));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_seeded_retries_are_repeatable() {
+        let mut elapsed = Vec::new();
+        for _ in 0..2 {
+            let script = [unavailable(), unavailable(), Ok(Measurement::Offset(5.0))];
+            let mut source = Retried::new(
+                Box::new(ScriptedSource(script.into())),
+                BackoffPolicy::default(),
+            )
+            .with_seed(3);
+            let start = Instant::now();
+            assert_eq!(source.next().await, Ok(Measurement::Offset(5.0)));
+            elapsed.push(start.elapsed());
+        }
+        assert_eq!(elapsed[0], elapsed[1]);
+        // 100ms then 200ms, each within 20%
+        assert!((Duration::from_millis(240)..=Duration::from_millis(360)).contains(&elapsed[0]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_gives_up_after_max_retries() {
+        let script = [unavailable(), unavailable(), Ok(Measurement::Offset(5.0))];
+        let policy = BackoffPolicy {
+            max_retries: 1,
+            ..Default::default()
+        };
+        let mut source = Retried::new(Box::new(ScriptedSource(script.into())), policy);
+        assert_eq!(source.next().await, unavailable());
+        // The next poll starts afresh
+        assert_eq!(source.next().await, Ok(Measurement::Offset(5.0)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_invalid_readings_are_not_retried() {
+        let invalid = Err(ReaderError::Invalid("NaN".to_string()));
+        let script = [invalid.clone(), Ok(Measurement::Offset(5.0))];
+        let mut source = Retried::new(
+            Box::new(ScriptedSource(script.into())),
+            BackoffPolicy::default(),
+        );
+        assert_eq!(source.next().await, invalid);
+    }
+}
@@ -0,0 +1,73 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Randomness for jittering delays. Statistical quality doesn't matter here, so this avoids
+// pulling in a dependency: splitmix64, either seeded for repeatable tests or from the clock.
+
+const GOLDEN_GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+
+/// A small seedable generator of numbers in [0, 1)
+#[derive(Debug, Clone)]
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    /// The same `seed` always gives the same sequence
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Seeded from the clock, so processes started together still draw different numbers
+    pub fn from_clock() -> Self {
+        Self::new(clock_seed())
+    }
+
+    pub fn next_unit(&mut self) -> f64 {
+        self.state = self.state.wrapping_add(GOLDEN_GAMMA);
+        to_unit(self.state)
+    }
+}
+
+/// A number in [0, 1) from a generator shared by the whole process
+pub fn random_unit() -> f64 {
+    static STATE: AtomicU64 = AtomicU64::new(0);
+    if STATE.load(Ordering::Relaxed) == 0 {
+        let _ = STATE.compare_exchange(0, clock_seed() | 1, Ordering::Relaxed, Ordering::Relaxed);
+    }
+    to_unit(STATE.fetch_add(GOLDEN_GAMMA, Ordering::Relaxed))
+}
+
+fn clock_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_nanos() as u64)
+        .unwrap_or(1)
+}
+
+fn to_unit(state: u64) -> f64 {
+    let mut z = state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    // The top 53 bits fill an f64 mantissa exactly
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded_sequences_repeat() {
+        let draw = |seed| {
+            let mut rng = SplitMix64::new(seed);
+            (0..100).map(|_| rng.next_unit()).collect::<Vec<_>>()
+        };
+        assert_eq!(draw(42), draw(42));
+        assert_ne!(draw(42), draw(43));
+        assert!(draw(42).iter().all(|unit| (0.0..1.0).contains(unit)));
+    }
+}
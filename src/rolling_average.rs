@@ -1,21 +1,44 @@
-const WINDOW_SIZE: usize = 10;
+use std::{
+    collections::VecDeque,
+    fs,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Window used by `new` and `default`
+pub const DEFAULT_WINDOW_SIZE: usize = 10;
+
+/// The on-disk form of the window, so smoothing can carry on across restarts
+#[derive(Debug, Serialize, Deserialize)]
+struct PersistedWindow {
+    /// Seconds since the unix epoch when the state was saved
+    saved_at: u64,
+    /// Samples oldest first
+    samples: Vec<f32>,
+}
 
 /// A rolling average calculator that maintains a fixed-size window of f32 values.
 #[derive(Debug, Clone)]
 pub struct RollingAverage {
-    buffer: [f32; WINDOW_SIZE],
-    index: usize,
-    count: usize,
+    buffer: VecDeque<f32>,
+    capacity: usize,
     sum: f32,
 }
 
 impl RollingAverage {
-    /// Creates a new RollingAverage with all values initialized to 0.0
+    /// Creates a new, empty RollingAverage over the default window
     pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_WINDOW_SIZE)
+    }
+
+    /// Creates a new, empty RollingAverage over the last `capacity` values, at least 1
+    pub fn with_capacity(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
         Self {
-            buffer: [0.0; WINDOW_SIZE],
-            index: 0,
-            count: 0,
+            buffer: VecDeque::with_capacity(capacity),
+            capacity,
             sum: 0.0,
         }
     }
@@ -25,31 +48,99 @@ impl RollingAverage {
     /// Returns the current average after adding the value.
     pub fn add(&mut self, value: f32) -> f32 {
         // Remove the old value from sum if buffer is full
-        if self.count == WINDOW_SIZE {
-            self.sum -= self.buffer[self.index];
-        } else {
-            self.count += 1;
+        if self.is_full() {
+            if let Some(oldest) = self.buffer.pop_front() {
+                self.sum -= oldest;
+            }
         }
 
         // Add new value
-        self.buffer[self.index] = value;
+        self.buffer.push_back(value);
         self.sum += value;
 
-        // Advance index in circular fashion
-        self.index = (self.index + 1) % WINDOW_SIZE;
-
         // Return current average
         self.average()
     }
 
     /// Returns the current average without adding a new value.
+    /// Until the window fills this is the average of the values so far.
     /// Returns 0.0 if no values have been added yet.
     pub fn average(&self) -> f32 {
-        if self.count != WINDOW_SIZE {
+        if self.is_empty() {
             0.0
         } else {
-            self.sum / self.count as f32
+            self.sum / self.len() as f32
+        }
+    }
+
+    /// Returns the number of samples currently held in the window.
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Returns true if no samples have been added yet.
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Returns true once the window holds `capacity()` samples.
+    pub fn is_full(&self) -> bool {
+        self.buffer.len() == self.capacity
+    }
+
+    /// Returns the number of samples the window holds when full.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Iterates over the samples currently in the window, oldest first.
+    pub fn samples(&self) -> impl Iterator<Item = f32> + '_ {
+        self.buffer.iter().copied()
+    }
+
+    /// Writes the current window to `path`, replacing any previous state.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let state = PersistedWindow {
+            saved_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            samples: self.samples().collect(),
+        };
+        // Write then rename, so a crash mid-write can't leave a truncated file behind
+        let temp_path = path.with_extension("tmp");
+        fs::write(&temp_path, serde_json::to_vec(&state)?)?;
+        fs::rename(temp_path, path)?;
+        Ok(())
+    }
+
+    /// Restores a window saved by `save` into a window of `capacity` values.
+    /// Returns None if there is no saved state, or it is older than `max_age`.
+    pub fn restore(
+        path: &Path,
+        capacity: usize,
+        max_age: Duration,
+    ) -> anyhow::Result<Option<Self>> {
+        Self::restore_at(path, capacity, max_age, SystemTime::now())
+    }
+
+    fn restore_at(
+        path: &Path,
+        capacity: usize,
+        max_age: Duration,
+        now: SystemTime,
+    ) -> anyhow::Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let state: PersistedWindow = serde_json::from_slice(&fs::read(path)?)?;
+        let saved_at = UNIX_EPOCH + Duration::from_secs(state.saved_at);
+        if now.duration_since(saved_at).unwrap_or_default() > max_age {
+            return Ok(None);
+        }
+        // Saved with a larger window, only the newest samples fit
+        let mut restored = Self::with_capacity(capacity);
+        for sample in state.samples {
+            restored.add(sample);
         }
+        Ok(Some(restored))
     }
 }
 
@@ -63,6 +154,8 @@ impl Default for RollingAverage {
 mod tests {
     use super::*;
 
+    const WINDOW_SIZE: usize = DEFAULT_WINDOW_SIZE;
+
     #[test]
     fn test_new_rolling_average() {
         let avg = RollingAverage::new();
@@ -72,10 +165,10 @@ mod tests {
     #[test]
     fn test_add_single_value() {
         let mut avg = RollingAverage::new();
-        // Before window is full, average returns 0.0
+        // A single value is its own average
         let result = avg.add(5.0);
-        assert_eq!(result, 0.0);
-        assert_eq!(avg.average(), 0.0);
+        assert_eq!(result, 5.0);
+        assert_eq!(avg.average(), 5.0);
 
         // Fill the window completely with 5.0s
         for _ in 1..WINDOW_SIZE {
@@ -90,9 +183,9 @@ mod tests {
         avg.add(1.0);
         avg.add(2.0);
         let result = avg.add(3.0);
-        // Window not full yet, so average returns 0.0
-        assert_eq!(result, 0.0);
-        assert_eq!(avg.average(), 0.0);
+        // Window not full yet, so it's the average of what's there
+        assert_eq!(result, 2.0);
+        assert_eq!(avg.average(), 2.0);
     }
 
     #[test]
@@ -125,14 +218,22 @@ mod tests {
     }
 
     #[test]
-    fn test_partial_window_returns_zero() {
+    fn test_partial_window_returns_partial_average() {
         let mut avg = RollingAverage::new();
         avg.add(1.0);
         avg.add(2.0);
-        avg.add(3.0);
+        avg.add(6.0);
 
-        // Window not full, so average returns 0.0
-        assert_eq!(avg.average(), 0.0);
+        assert_eq!(avg.average(), 3.0);
+    }
+
+    #[test]
+    fn test_single_value_is_the_average() {
+        for value in [-250.0, 0.0, 1234.5] {
+            let mut avg = RollingAverage::new();
+            assert_eq!(avg.add(value), value);
+            assert_eq!(avg.average(), value);
+        }
     }
 
     #[test]
@@ -191,6 +292,132 @@ mod tests {
         assert_eq!(avg.average(), expected);
     }
 
+    #[test]
+    fn test_accessors_partial_window() {
+        let mut avg = RollingAverage::new();
+        assert!(avg.is_empty());
+        assert_eq!(avg.len(), 0);
+        assert_eq!(avg.samples().count(), 0);
+
+        avg.add(1.0);
+        avg.add(2.0);
+        avg.add(3.0);
+        assert!(!avg.is_empty());
+        assert!(!avg.is_full());
+        assert_eq!(avg.len(), 3);
+        assert_eq!(avg.capacity(), WINDOW_SIZE);
+        assert_eq!(avg.samples().collect::<Vec<_>>(), vec![1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn test_accessors_full_window() {
+        let mut avg = RollingAverage::new();
+        for i in 0..WINDOW_SIZE {
+            avg.add(i as f32);
+        }
+        assert!(avg.is_full());
+        assert_eq!(avg.len(), WINDOW_SIZE);
+        let expected: Vec<f32> = (0..WINDOW_SIZE).map(|i| i as f32).collect();
+        assert_eq!(avg.samples().collect::<Vec<_>>(), expected);
+
+        // Once wrapped, the oldest samples drop out and order is preserved
+        avg.add(100.0);
+        avg.add(101.0);
+        assert_eq!(avg.len(), WINDOW_SIZE);
+        let expected: Vec<f32> = (2..WINDOW_SIZE)
+            .map(|i| i as f32)
+            .chain([100.0, 101.0])
+            .collect();
+        assert_eq!(avg.samples().collect::<Vec<_>>(), expected);
+    }
+
+    fn temp_state_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("{name}-{}.json", std::process::id()))
+    }
+
+    #[test]
+    fn test_restored_window_is_immediately_active() {
+        let path = temp_state_path("rolling-average-restore");
+        let mut avg = RollingAverage::new();
+        for i in 0..WINDOW_SIZE + 3 {
+            avg.add(i as f32);
+        }
+        avg.save(&path).unwrap();
+
+        // As if the process restarted
+        let mut restored = RollingAverage::restore(&path, WINDOW_SIZE, Duration::from_secs(60))
+            .unwrap()
+            .expect("State was just saved");
+        fs::remove_file(&path).unwrap();
+        assert!(restored.is_full());
+        assert_eq!(restored.average(), avg.average());
+        assert_eq!(
+            restored.samples().collect::<Vec<_>>(),
+            avg.samples().collect::<Vec<_>>()
+        );
+        // Carries on rolling from where it left off
+        assert_eq!(restored.add(50.0), avg.add(50.0));
+    }
+
+    #[test]
+    fn test_stale_state_is_discarded() {
+        let path = temp_state_path("rolling-average-stale");
+        let mut avg = RollingAverage::new();
+        for _ in 0..WINDOW_SIZE {
+            avg.add(1.0);
+        }
+        avg.save(&path).unwrap();
+        let later = SystemTime::now() + Duration::from_secs(600);
+        let restored =
+            RollingAverage::restore_at(&path, WINDOW_SIZE, Duration::from_secs(300), later)
+                .unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(restored.is_none());
+    }
+
+    #[test]
+    fn test_restore_without_state() {
+        let path = temp_state_path("rolling-average-missing");
+        assert!(
+            RollingAverage::restore(&path, WINDOW_SIZE, Duration::from_secs(60))
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_with_capacity() {
+        let mut avg = RollingAverage::with_capacity(3);
+        assert_eq!(avg.capacity(), 3);
+        avg.add(1.0);
+        avg.add(2.0);
+        assert_eq!(avg.add(3.0), 2.0);
+        assert_eq!(avg.add(7.0), 4.0);
+        assert_eq!(avg.samples().collect::<Vec<_>>(), vec![2.0, 3.0, 7.0]);
+
+        // An empty window can't average anything, so holds at least one value
+        let mut avg = RollingAverage::with_capacity(0);
+        assert_eq!(avg.capacity(), 1);
+        assert_eq!(avg.add(4.0), 4.0);
+        assert_eq!(avg.add(6.0), 6.0);
+    }
+
+    #[test]
+    fn test_restore_into_smaller_window() {
+        let path = temp_state_path("rolling-average-resize");
+        let mut avg = RollingAverage::new();
+        for i in 0..WINDOW_SIZE {
+            avg.add(i as f32);
+        }
+        avg.save(&path).unwrap();
+        let restored = RollingAverage::restore(&path, 2, Duration::from_secs(60))
+            .unwrap()
+            .expect("State was just saved");
+        fs::remove_file(&path).unwrap();
+        let newest = [WINDOW_SIZE - 2, WINDOW_SIZE - 1].map(|i| i as f32);
+        assert_eq!(restored.samples().collect::<Vec<_>>(), newest);
+    }
+
     #[test]
     fn test_zero_values() {
         let mut avg = RollingAverage::new();
@@ -1,33 +1,973 @@
-use std::net::SocketAddr;
+use std::{fmt, io, net::SocketAddr, str::FromStr, time::Duration};
 
 use client::Context;
+use serde::{Deserialize, Serialize};
 use tokio_modbus::prelude::*;
 
+use tracing::{debug, info, warn};
+
+use crate::power_source::{Measurement, PowerSource, ReaderError, SourceFuture};
+
 pub struct Shelly3EMClient {
     connection: Context,
+    layout: FloatLayout,
+    read_timeout: Duration,
 }
 // Registers are documented here
 // https://shelly-api-docs.shelly.cloud/gen2/ComponentsAndServices/EM/#modbus-registers
+/// Where the Shelly 3EM reports its total active power, other models and firmware may differ
+pub const DEFAULT_POWER_REGISTER: u16 = 1013;
+/// Registers holding each f32 reading
+const F32_REGISTER_COUNT: u16 = 2;
+const _: () = assert!(F32_REGISTER_COUNT as usize * 2 == std::mem::size_of::<f32>());
+// Each phase has its own block of readings, 20 registers apart
+const PHASE_VOLTAGE_REGISTERS: [u16; 3] = [1020, 1040, 1060];
+const PHASE_ACTIVE_POWER_REGISTERS: [u16; 3] = [1024, 1044, 1064];
+/// Phase A's frequency, which all phases share
+const FREQUENCY_REGISTER: u16 = 1033;
+
+/// How long a read waits for the Shelly to answer before giving up on the connection
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Unit id used for RTU framing, where requests must be addressed to a specific device
+const RTU_UNIT_ID: u8 = 1;
+
+/// How requests to the Shelly are framed on the TCP connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShellyProtocol {
+    /// Standard Modbus/TCP, with an MBAP header on each frame
+    #[default]
+    Tcp,
+    /// Modbus RTU frames (unit id and CRC, no MBAP header) sent over TCP, as used by
+    /// serial gateways
+    RtuOverTcp,
+}
+
+impl FromStr for ShellyProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "tcp" => Ok(Self::Tcp),
+            "rtu_over_tcp" => Ok(Self::RtuOverTcp),
+            other => Err(format!("Unknown Shelly protocol `{other}`")),
+        }
+    }
+}
+
+/// Default serial settings, the usual for Modbus RTU devices
+const DEFAULT_BAUD: u32 = 9600;
+
+/// Where the Shelly is, and how to talk to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShellyTransport {
+    Tcp(SocketAddr),
+    RtuOverTcp(SocketAddr),
+    /// A local serial port, only available when built with the `rtu` feature
+    Rtu {
+        path: String,
+        baud: u32,
+        slave: u8,
+    },
+}
+
+impl ShellyTransport {
+    /// Parses a `host:port` address, framed according to `protocol`,
+    /// or a `serial:///dev/ttyUSB0?baud=9600&slave=1` style URL for a serial port
+    pub fn parse(address: &str, protocol: ShellyProtocol) -> Result<Self, String> {
+        let address = address.trim();
+        if let Some(serial) = address.strip_prefix("serial://") {
+            return Self::parse_serial(serial);
+        }
+        let socket_addr = address
+            .parse()
+            .map_err(|_| format!("`{address}` is not an ip:port address or serial:// URL"))?;
+        Ok(match protocol {
+            ShellyProtocol::Tcp => Self::Tcp(socket_addr),
+            ShellyProtocol::RtuOverTcp => Self::RtuOverTcp(socket_addr),
+        })
+    }
+
+    fn parse_serial(serial: &str) -> Result<Self, String> {
+        let (path, query) = serial.split_once('?').unwrap_or((serial, ""));
+        if path.is_empty() {
+            return Err("Missing serial port path".to_string());
+        }
+        let mut baud = DEFAULT_BAUD;
+        let mut slave = RTU_UNIT_ID;
+        for parameter in query.split('&').filter(|parameter| !parameter.is_empty()) {
+            let invalid = || format!("Invalid serial parameter `{parameter}`");
+            match parameter.split_once('=').ok_or_else(invalid)? {
+                ("baud", value) => baud = value.parse().map_err(|_| invalid())?,
+                ("slave", value) => slave = value.parse().map_err(|_| invalid())?,
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(Self::Rtu {
+            path: path.to_string(),
+            baud,
+            slave,
+        })
+    }
+}
+
+/// The total and per-phase active powers, read together
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShellyPowerSample {
+    pub total: f32,
+    pub phase_a: f32,
+    pub phase_b: f32,
+    pub phase_c: f32,
+}
+
+impl ShellyPowerSample {
+    pub fn phases(&self) -> [f32; 3] {
+        [self.phase_a, self.phase_b, self.phase_c]
+    }
+}
+
+/// Everything needed to connect to and read the Shelly
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShellyConfig {
+    /// `host:port` of the Shelly's Modbus server, or a `serial://` URL, see `ShellyTransport::parse`.
+    /// An `http://` URL reads a Gen2 device over its RPC API instead, see `shelly_http`.
+    /// Several devices can be listed separated by commas, see `devices`.
+    pub address: String,
+    pub protocol: ShellyProtocol,
+    pub float_layout: FloatLayout,
+    /// Also read the per-phase powers
+    pub read_phases: bool,
+    /// Also read the per-phase voltages. Only read along with the phases.
+    pub read_voltages: bool,
+    /// Read the per-phase voltages and the frequency, to report them rather than 0
+    pub read_voltage_frequency: bool,
+    /// The first of the two input registers holding the total active power
+    pub power_register: u16,
+    /// The unit id requests are addressed to, replacing the transport's default
+    pub slave_id: Option<u8>,
+    /// How long each read waits for an answer, over Modbus or HTTP
+    pub read_timeout: Duration,
+}
+
+impl ShellyConfig {
+    /// A config for each device in a comma separated `address`, sharing the other settings
+    pub fn devices(&self) -> Vec<ShellyConfig> {
+        let devices: Vec<ShellyConfig> = self
+            .address
+            .split(',')
+            .map(str::trim)
+            .filter(|address| !address.is_empty())
+            .map(|address| ShellyConfig {
+                address: address.to_string(),
+                ..self.clone()
+            })
+            .collect();
+        // Always at least the one, so an unset address is still reported when connecting
+        if devices.is_empty() {
+            vec![self.clone()]
+        } else {
+            devices
+        }
+    }
+}
+
+/// Why a read from the Shelly failed
+#[derive(Debug)]
+pub enum ShellyError {
+    /// The connection failed, or what came back couldn't be understood
+    Io(io::Error),
+    /// The device answered with an exception, e.g. for a register it doesn't have
+    ModbusException(ExceptionCode),
+    /// Fewer registers came back than were asked for
+    ShortRead { register: u16, received: usize },
+}
+
+impl fmt::Display for ShellyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{e}"),
+            Self::ModbusException(code) => write!(f, "Modbus exception {code}"),
+            Self::ShortRead { register, received } => write!(
+                f,
+                "only {received} of {F32_REGISTER_COUNT} registers came back from {register}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ShellyError {}
+
+impl From<tokio_modbus::Error> for ShellyError {
+    fn from(e: tokio_modbus::Error) -> Self {
+        match e {
+            tokio_modbus::Error::Transport(e) => Self::Io(e),
+            tokio_modbus::Error::Protocol(e) => {
+                Self::Io(io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+        }
+    }
+}
+
+impl From<ShellyError> for ReaderError {
+    /// Only a failed connection is worth asking again straight away
+    fn from(e: ShellyError) -> Self {
+        match e {
+            ShellyError::Io(_) => Self::Unavailable(e.to_string()),
+            ShellyError::ModbusException(_) | ShellyError::ShortRead { .. } => {
+                Self::Invalid(e.to_string())
+            }
+        }
+    }
+}
+
+/// Checks a whole f32 can be read starting at `register`
+pub fn validate_power_register(register: u16) -> Result<u16, String> {
+    register
+        .checked_add(F32_REGISTER_COUNT - 1)
+        .map(|_| register)
+        .ok_or_else(|| {
+            format!("Register {register} leaves no room for a {F32_REGISTER_COUNT} register f32")
+        })
+}
 
 impl Shelly3EMClient {
+    /// Connects as set up in `config`
+    pub async fn connect(config: &ShellyConfig) -> Self {
+        assert!(
+            !config.address.is_empty(),
+            "Required to add Shelly modbus connection info"
+        );
+        info!("Connecting to shelly `{}`", config.address);
+        let transport = ShellyTransport::parse(&config.address, config.protocol)
+            .expect("Invalid Shelly address");
+        Self::with_transport(&transport).await.configured(config)
+    }
+
+    /// Connects as set up in `config`, reporting rather than panicking if it can't
+    pub async fn try_connect(config: &ShellyConfig) -> io::Result<Self> {
+        let transport = ShellyTransport::parse(&config.address, config.protocol)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(Self::try_with_transport(&transport)
+            .await?
+            .configured(config))
+    }
+
+    fn configured(self, config: &ShellyConfig) -> Self {
+        let client = self
+            .with_float_layout(config.float_layout)
+            .with_read_timeout(config.read_timeout);
+        match config.slave_id {
+            Some(slave_id) => client.with_slave_id(slave_id),
+            None => client,
+        }
+    }
+
     pub async fn new(target_device: SocketAddr) -> Self {
-        let connection = tcp::connect(target_device)
+        Self::with_protocol(target_device, ShellyProtocol::Tcp).await
+    }
+
+    pub async fn with_protocol(target_device: SocketAddr, protocol: ShellyProtocol) -> Self {
+        let transport = match protocol {
+            ShellyProtocol::Tcp => ShellyTransport::Tcp(target_device),
+            ShellyProtocol::RtuOverTcp => ShellyTransport::RtuOverTcp(target_device),
+        };
+        Self::with_transport(&transport).await
+    }
+
+    pub async fn with_transport(transport: &ShellyTransport) -> Self {
+        Self::try_with_transport(transport)
             .await
-            .expect("Cant Connect to Shelly 3EM");
+            .expect("Cant Connect to Shelly 3EM")
+    }
+
+    pub async fn try_with_transport(transport: &ShellyTransport) -> io::Result<Self> {
+        let connection = match transport {
+            ShellyTransport::Tcp(target_device) => tcp::connect(*target_device).await,
+            ShellyTransport::RtuOverTcp(target_device) => {
+                tokio::net::TcpStream::connect(target_device)
+                    .await
+                    .map(|stream| rtu::attach_slave(stream, Slave(RTU_UNIT_ID)))
+            }
+            ShellyTransport::Rtu { path, baud, slave } => Self::open_serial(path, *baud, *slave),
+        }?;
+
+        Ok(Self {
+            connection,
+            layout: FloatLayout::default(),
+            read_timeout: DEFAULT_READ_TIMEOUT,
+        })
+    }
 
-        Self { connection }
+    #[cfg(feature = "rtu")]
+    fn open_serial(path: &str, baud: u32, slave: u8) -> std::io::Result<Context> {
+        let port = tokio_serial::SerialStream::open(&tokio_serial::new(path, baud))?;
+        Ok(rtu::attach_slave(port, Slave(slave)))
+    }
+
+    #[cfg(not(feature = "rtu"))]
+    fn open_serial(path: &str, _baud: u32, _slave: u8) -> std::io::Result<Context> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("Can't open {path}, serial ports need building with the `rtu` feature"),
+        ))
+    }
+
+    /// Decode floats from the device with `layout` rather than the Shelly's own
+    pub fn with_float_layout(mut self, layout: FloatLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+    /// Give up on a read after `timeout` rather than waiting on a half-open connection forever
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+    /// Address requests to `slave_id`, for devices not answering on the default unit id
+    pub fn with_slave_id(mut self, slave_id: u8) -> Self {
+        self.connection.set_slave(Slave(slave_id));
+        self
+    }
+    /// The total active power, from where the Shelly 3EM reports it
+    pub async fn read_total_power(&mut self) -> Result<f32, ShellyError> {
+        self.read_f32(DEFAULT_POWER_REGISTER).await
+    }
+    /// The total active power, from a device that reports it at `register`
+    pub async fn read_total_power_at(&mut self, register: u16) -> Result<f32, ShellyError> {
+        self.read_f32(register).await
+    }
+    /// The total active power from `power_register` along with each phase's.
+    /// Fails unless all four could be read.
+    pub async fn read_all_power(
+        &mut self,
+        power_register: u16,
+    ) -> Result<ShellyPowerSample, ShellyError> {
+        let total = self.read_total_power_at(power_register).await?;
+        let [phase_a, phase_b, phase_c] = self.read_phase_powers().await?;
+        Ok(ShellyPowerSample {
+            total,
+            phase_a,
+            phase_b,
+            phase_c,
+        })
+    }
+    pub async fn read_phase_powers(&mut self) -> Result<[f32; 3], ShellyError> {
+        self.read_phases(PHASE_ACTIVE_POWER_REGISTERS).await
+    }
+    pub async fn read_phase_voltages(&mut self) -> Result<[f32; 3], ShellyError> {
+        self.read_phases(PHASE_VOLTAGE_REGISTERS).await
+    }
+    pub async fn read_frequency(&mut self) -> Result<f32, ShellyError> {
+        self.read_f32(FREQUENCY_REGISTER).await
+    }
+    async fn read_phases(&mut self, registers: [u16; 3]) -> Result<[f32; 3], ShellyError> {
+        let mut readings = [0.0; 3];
+        for (reading, register) in readings.iter_mut().zip(registers) {
+            *reading = self.read_f32(register).await?;
+        }
+        Ok(readings)
+    }
+    async fn read_f32(&mut self, register: u16) -> Result<f32, ShellyError> {
+        // Convert the bytes of the reading into a float and send onwards
+        let words = self.read_words(register).await?;
+        Ok(self.layout.decode(words))
     }
-    pub async fn read_total_power(&mut self) -> Option<f32> {
-        if let Ok(total_readings) = self.connection.read_input_registers(1013, 2).await.unwrap() {
-            // Convert the bytes of the totals into floats and send onwards
-            let total_active_power = merge_u16_f32(total_readings[0], total_readings[1]);
-            Some(total_active_power)
+    /// The two raw registers starting at `register`, as sent by the device
+    pub async fn read_words(&mut self, register: u16) -> Result<[u16; 2], ShellyError> {
+        // Called directly rather than through read_input_registers, which asserts on the length
+        // instead of reporting a short response
+        let request = self
+            .connection
+            .call(Request::ReadInputRegisters(register, F32_REGISTER_COUNT));
+        let response = tokio::time::timeout(self.read_timeout, request)
+            .await
+            .map_err(|_| {
+                ShellyError::Io(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "no answer for register {register} within {:?}",
+                        self.read_timeout
+                    ),
+                ))
+            })??
+            .map_err(ShellyError::ModbusException)?;
+        match response {
+            Response::ReadInputRegisters(words) => match words[..] {
+                [first, second] => Ok([first, second]),
+                _ => Err(ShellyError::ShortRead {
+                    register,
+                    received: words.len(),
+                }),
+            },
+            other => Err(ShellyError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected response {other:?}"),
+            ))),
+        }
+    }
+}
+/// Reads the grid power from the Shelly, and optionally the per-phase values, as a `PowerSource`
+pub struct ShellyReader {
+    name: String,
+    /// None after the connection failed, until it is reopened on the next read
+    client: Option<Shelly3EMClient>,
+    config: ShellyConfig,
+    read_phases: bool,
+    read_voltages: bool,
+    read_voltage_frequency: bool,
+    power_register: u16,
+}
+
+impl ShellyReader {
+    /// Voltages are only read along with the phases, as they are only used for the phase currents
+    pub fn new(client: Shelly3EMClient, config: &ShellyConfig) -> Self {
+        Self {
+            name: "Shelly".to_string(),
+            client: Some(client),
+            config: config.clone(),
+            read_phases: config.read_phases,
+            read_voltages: config.read_voltages,
+            read_voltage_frequency: config.read_voltage_frequency,
+            power_register: config.power_register,
+        }
+    }
+
+    pub async fn connect(config: &ShellyConfig) -> Self {
+        Self::new(Shelly3EMClient::connect(config).await, config)
+    }
+
+    /// Names the reader in logs and metrics, to tell several devices apart
+    pub fn with_name(mut self, name: String) -> Self {
+        self.name = name;
+        self
+    }
+
+    /// Reads everything configured. An I/O error from any of the reads is returned, as the
+    /// connection can't be trusted after it, while other errors only lose the optional values.
+    async fn read(&mut self, client: &mut Shelly3EMClient) -> Result<Measurement, ShellyError> {
+        let sample = if self.read_phases {
+            match client.read_all_power(self.power_register).await {
+                Ok(sample) => Some(sample),
+                Err(e @ ShellyError::Io(_)) => return Err(e),
+                Err(e) => {
+                    debug!("{}: no phase powers, {e}", self.name);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        // Without the phases the total alone is still worth reporting
+        let (watts, phase_watts) = match sample {
+            Some(sample) => (sample.total, Some(sample.phases())),
+            None => (client.read_total_power_at(self.power_register).await?, None),
+        };
+        let phase_voltages =
+            if self.read_voltage_frequency || (self.read_phases && self.read_voltages) {
+                optional(client.read_phase_voltages().await)?
+            } else {
+                None
+            };
+        let frequency = if self.read_voltage_frequency {
+            optional(client.read_frequency().await)?
         } else {
             None
+        };
+        Ok(Measurement::GridPower {
+            watts,
+            phase_watts,
+            phase_voltages,
+            frequency,
+        })
+    }
+}
+
+/// A value that is fine to go without, unless the connection itself failed
+fn optional<T>(result: Result<T, ShellyError>) -> Result<Option<T>, ShellyError> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e @ ShellyError::Io(_)) => Err(e),
+        Err(_) => Ok(None),
+    }
+}
+
+impl PowerSource for ShellyReader {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn next(&mut self) -> SourceFuture<'_> {
+        Box::pin(async move {
+            let mut client = match self.client.take() {
+                Some(client) => client,
+                None => {
+                    info!("{}: reconnecting to `{}`", self.name, self.config.address);
+                    Shelly3EMClient::try_connect(&self.config)
+                        .await
+                        .map_err(|e| ReaderError::Unavailable(format!("can't connect, {e}")))?
+                }
+            };
+            let measurement = match self.read(&mut client).await {
+                // A late answer to a timed out request would be taken as the answer to the next,
+                // so the connection is dropped and a fresh one opened on the next read
+                Err(e @ ShellyError::Io(_)) => {
+                    warn!("{}: {e}, dropping the connection", self.name);
+                    return Err(e.into());
+                }
+                measurement => {
+                    self.client = Some(client);
+                    measurement?
+                }
+            };
+            if let Measurement::GridPower { watts, .. } = measurement {
+                if !watts.is_finite() {
+                    return Err(ReaderError::Invalid(format!("total power of {watts}W")));
+                }
+            }
+            Ok(measurement)
+        })
+    }
+}
+
+/// Order of the float's bytes across the two registers, as sent on the wire.
+/// `a` is the most significant byte, so `abcd` is plain big endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FloatLayout {
+    Abcd,
+    /// Bytes swapped within each register
+    Badc,
+    /// Registers swapped, low word first. This is what the Shelly sends.
+    #[default]
+    Cdab,
+    /// Both swapped, plain little endian
+    Dcba,
+}
+
+impl FloatLayout {
+    pub const ALL: [Self; 4] = [Self::Abcd, Self::Badc, Self::Cdab, Self::Dcba];
+
+    pub fn decode(self, [first, second]: [u16; 2]) -> f32 {
+        let [w0, w1] = first.to_be_bytes();
+        let [w2, w3] = second.to_be_bytes();
+        let bytes = match self {
+            Self::Abcd => [w0, w1, w2, w3],
+            Self::Badc => [w1, w0, w3, w2],
+            Self::Cdab => [w2, w3, w0, w1],
+            Self::Dcba => [w3, w2, w1, w0],
+        };
+        f32::from_be_bytes(bytes)
+    }
+
+    /// The two registers holding `value`, the inverse of `decode`
+    pub fn encode(self, value: f32) -> [u16; 2] {
+        let [a, b, c, d] = value.to_be_bytes();
+        let [w0, w1, w2, w3] = match self {
+            Self::Abcd => [a, b, c, d],
+            Self::Badc => [b, a, d, c],
+            Self::Cdab => [c, d, a, b],
+            Self::Dcba => [d, c, b, a],
+        };
+        [u16::from_be_bytes([w0, w1]), u16::from_be_bytes([w2, w3])]
+    }
+}
+
+impl FromStr for FloatLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "abcd" => Ok(Self::Abcd),
+            "badc" => Ok(Self::Badc),
+            "cdab" => Ok(Self::Cdab),
+            "dcba" => Ok(Self::Dcba),
+            // The names for the word orders, with the bytes of each word big endian
+            "big_endian" => Ok(Self::Abcd),
+            "word_swap" => Ok(Self::Cdab),
+            other => Err(format!("Unknown float layout `{other}`")),
         }
     }
 }
-fn merge_u16_f32(a: u16, b: u16) -> f32 {
-    let x: u32 = a as u32 | (b as u32) << 16;
-    f32::from_bits(x)
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::MockShelly;
+
+    #[tokio::test]
+    async fn test_read_phase_readings() {
+        let shelly = MockShelly::start(&[
+            (1013, 600.0),
+            (1020, 230.0),
+            (1024, 100.0),
+            (1040, 240.0),
+            (1044, 200.0),
+            (1060, 250.0),
+            (1064, 300.0),
+        ])
+        .await;
+        let mut client = Shelly3EMClient::new(shelly).await;
+
+        assert_eq!(client.read_total_power().await.ok(), Some(600.0));
+        assert_eq!(
+            client.read_phase_voltages().await.ok(),
+            Some([230.0, 240.0, 250.0])
+        );
+        assert_eq!(
+            client.read_phase_powers().await.ok(),
+            Some([100.0, 200.0, 300.0])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_all_power() {
+        let shelly =
+            MockShelly::start(&[(1013, 590.0), (1024, 90.0), (1044, 200.0), (1064, 300.0)]).await;
+        let mut client = Shelly3EMClient::new(shelly).await;
+        assert_eq!(
+            client.read_all_power(DEFAULT_POWER_REGISTER).await.ok(),
+            Some(ShellyPowerSample {
+                total: 590.0,
+                phase_a: 90.0,
+                phase_b: 200.0,
+                phase_c: 300.0,
+            })
+        );
+
+        // Missing any of the phases loses the whole sample
+        let shelly = MockShelly::start(&[(1013, 590.0), (1024, 90.0), (1044, 200.0)]).await;
+        let mut client = Shelly3EMClient::new(shelly).await;
+        assert!(client.read_all_power(DEFAULT_POWER_REGISTER).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reader_without_phase_registers() {
+        let shelly = MockShelly::start(&[(1013, 600.0)]).await;
+        let config = ShellyConfig {
+            address: shelly.to_string(),
+            protocol: ShellyProtocol::Tcp,
+            float_layout: FloatLayout::Cdab,
+            read_phases: true,
+            read_voltages: false,
+            read_voltage_frequency: false,
+            power_register: DEFAULT_POWER_REGISTER,
+            slave_id: None,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+        };
+        let mut reader = ShellyReader::connect(&config).await;
+        assert_eq!(reader.next().await, Ok(Measurement::grid_power(600.0)));
+    }
+
+    #[tokio::test]
+    async fn test_read_errors() {
+        // The mock answers registers it doesn't have with IllegalDataAddress
+        let shelly = MockShelly::start(&[]).await;
+        let mut client = Shelly3EMClient::new(shelly).await;
+        let error = client.read_total_power().await.unwrap_err();
+        assert!(
+            matches!(
+                error,
+                ShellyError::ModbusException(ExceptionCode::IllegalDataAddress)
+            ),
+            "{error:?}"
+        );
+        assert!(matches!(ReaderError::from(error), ReaderError::Invalid(_)));
+
+        let shelly = MockShelly::start_with_short_reads(&[(1013, 600.0)]).await;
+        let mut client = Shelly3EMClient::new(shelly).await;
+        let error = client.read_total_power().await.unwrap_err();
+        assert!(
+            matches!(
+                error,
+                ShellyError::ShortRead {
+                    register: 1013,
+                    received: 1
+                }
+            ),
+            "{error:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reader_reconnects_after_a_timeout() {
+        let shelly = MockShelly::start_hanging_first(&[(1013, 600.0)], 1).await;
+        let config = ShellyConfig {
+            address: shelly.to_string(),
+            protocol: ShellyProtocol::Tcp,
+            float_layout: FloatLayout::Cdab,
+            read_phases: false,
+            read_voltages: false,
+            read_voltage_frequency: false,
+            power_register: DEFAULT_POWER_REGISTER,
+            slave_id: None,
+            read_timeout: Duration::from_millis(100),
+        };
+        let mut reader = ShellyReader::connect(&config).await;
+        let started = std::time::Instant::now();
+        let error = reader.next().await.unwrap_err();
+        assert!(matches!(error, ReaderError::Unavailable(_)), "{error:?}");
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(reader.client.is_none());
+
+        // The next read opens a fresh connection, which the device answers
+        assert_eq!(reader.next().await, Ok(Measurement::grid_power(600.0)));
+        assert_eq!(reader.next().await, Ok(Measurement::grid_power(600.0)));
+    }
+
+    #[tokio::test]
+    async fn test_reader_reports_typed_errors() {
+        let shelly = MockShelly::start(&[]).await;
+        let config = ShellyConfig {
+            address: shelly.to_string(),
+            protocol: ShellyProtocol::Tcp,
+            float_layout: FloatLayout::Cdab,
+            read_phases: true,
+            read_voltages: false,
+            read_voltage_frequency: false,
+            power_register: DEFAULT_POWER_REGISTER,
+            slave_id: None,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+        };
+        let mut reader = ShellyReader::connect(&config).await;
+        assert!(matches!(reader.next().await, Err(ReaderError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_decode_each_layout() {
+        // 1234.5678 is 0x449A522B
+        let expected = 1234.5678;
+        assert_eq!(FloatLayout::Abcd.decode([0x449A, 0x522B]), expected);
+        assert_eq!(FloatLayout::Badc.decode([0x9A44, 0x2B52]), expected);
+        assert_eq!(FloatLayout::Cdab.decode([0x522B, 0x449A]), expected);
+        assert_eq!(FloatLayout::Dcba.decode([0x2B52, 0x9A44]), expected);
+    }
+
+    #[test]
+    fn test_encode_is_the_inverse_of_decode() {
+        assert_eq!(FloatLayout::Abcd.encode(1234.5678), [0x449A, 0x522B]);
+        assert_eq!(FloatLayout::Cdab.encode(1234.5678), [0x522B, 0x449A]);
+        for layout in FloatLayout::ALL {
+            for value in [1234.5678, -250.0, 0.0, f32::MIN_POSITIVE, 49.987] {
+                assert_eq!(layout.decode(layout.encode(value)), value, "{layout:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_float_layout() {
+        assert_eq!("CDAB".parse(), Ok(FloatLayout::Cdab));
+        assert_eq!(" dcba".parse(), Ok(FloatLayout::Dcba));
+        assert!("abdc".parse::<FloatLayout>().is_err());
+        assert_eq!("big_endian".parse(), Ok(FloatLayout::Abcd));
+        assert_eq!("Word_Swap".parse(), Ok(FloatLayout::Cdab));
+        assert_eq!(FloatLayout::default(), FloatLayout::Cdab);
+    }
+
+    #[tokio::test]
+    async fn test_read_over_rtu_framing() {
+        let shelly = MockShelly::start_rtu_over_tcp(&[(1013, -250.0)]).await;
+        let mut client = Shelly3EMClient::with_protocol(shelly, ShellyProtocol::RtuOverTcp).await;
+        assert_eq!(client.read_total_power().await.ok(), Some(-250.0));
+    }
+
+    #[test]
+    fn test_parse_transport() {
+        let socket_addr: SocketAddr = "192.168.1.20:502".parse().unwrap();
+        assert_eq!(
+            ShellyTransport::parse("192.168.1.20:502", ShellyProtocol::Tcp),
+            Ok(ShellyTransport::Tcp(socket_addr))
+        );
+        assert_eq!(
+            ShellyTransport::parse("192.168.1.20:502", ShellyProtocol::RtuOverTcp),
+            Ok(ShellyTransport::RtuOverTcp(socket_addr))
+        );
+        assert_eq!(
+            ShellyTransport::parse("serial:///dev/ttyUSB0", ShellyProtocol::Tcp),
+            Ok(ShellyTransport::Rtu {
+                path: "/dev/ttyUSB0".to_string(),
+                baud: 9600,
+                slave: 1
+            })
+        );
+        assert_eq!(
+            ShellyTransport::parse(
+                "serial:///dev/ttyAMA0?baud=19200&slave=3",
+                ShellyProtocol::Tcp
+            ),
+            Ok(ShellyTransport::Rtu {
+                path: "/dev/ttyAMA0".to_string(),
+                baud: 19200,
+                slave: 3
+            })
+        );
+        assert!(ShellyTransport::parse("serial://", ShellyProtocol::Tcp).is_err());
+        assert!(
+            ShellyTransport::parse("serial:///dev/ttyUSB0?parity=even", ShellyProtocol::Tcp)
+                .is_err()
+        );
+        assert!(
+            ShellyTransport::parse("serial:///dev/ttyUSB0?baud=fast", ShellyProtocol::Tcp).is_err()
+        );
+        assert!(ShellyTransport::parse("shelly.local", ShellyProtocol::Tcp).is_err());
+    }
+
+    #[test]
+    fn test_parse_shelly_protocol() {
+        assert_eq!("tcp".parse(), Ok(ShellyProtocol::Tcp));
+        assert_eq!(" RTU_over_TCP".parse(), Ok(ShellyProtocol::RtuOverTcp));
+        assert!("rtu".parse::<ShellyProtocol>().is_err());
+        assert_eq!(ShellyProtocol::default(), ShellyProtocol::Tcp);
+    }
+
+    #[tokio::test]
+    async fn test_reader_from_explicit_config() {
+        let shelly =
+            MockShelly::start(&[(1013, 600.0), (1024, 100.0), (1044, 200.0), (1064, 300.0)]).await;
+        let mut config = ShellyConfig {
+            address: shelly.to_string(),
+            protocol: ShellyProtocol::Tcp,
+            float_layout: FloatLayout::Cdab,
+            read_phases: false,
+            read_voltages: false,
+            read_voltage_frequency: false,
+            power_register: DEFAULT_POWER_REGISTER,
+            slave_id: None,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+        };
+        let mut reader = ShellyReader::connect(&config).await;
+        assert_eq!(reader.next().await, Ok(Measurement::grid_power(600.0)));
+
+        config.read_phases = true;
+        let mut reader = ShellyReader::connect(&config).await;
+        assert_eq!(
+            reader.next().await,
+            Ok(Measurement::GridPower {
+                watts: 600.0,
+                phase_watts: Some([100.0, 200.0, 300.0]),
+                phase_voltages: None,
+                frequency: None,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reader_voltage_and_frequency() {
+        let shelly = MockShelly::start(&[
+            (1013, 600.0),
+            (1020, 230.0),
+            (1033, 50.02),
+            (1040, 231.0),
+            (1060, 232.0),
+        ])
+        .await;
+        let config = ShellyConfig {
+            address: shelly.to_string(),
+            protocol: ShellyProtocol::Tcp,
+            float_layout: FloatLayout::Cdab,
+            read_phases: false,
+            read_voltages: false,
+            read_voltage_frequency: true,
+            power_register: DEFAULT_POWER_REGISTER,
+            slave_id: None,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+        };
+        let mut reader = ShellyReader::connect(&config).await;
+        assert_eq!(
+            reader.next().await,
+            Ok(Measurement::GridPower {
+                watts: 600.0,
+                phase_watts: None,
+                phase_voltages: Some([230.0, 231.0, 232.0]),
+                frequency: Some(50.02),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reader_with_power_register() {
+        // The default register holds something else, so only the configured one gives 750W
+        let shelly = MockShelly::start(&[(1013, 1.0), (5000, 750.0)]).await;
+        let config = ShellyConfig {
+            address: shelly.to_string(),
+            protocol: ShellyProtocol::Tcp,
+            float_layout: FloatLayout::Cdab,
+            read_phases: false,
+            read_voltages: false,
+            read_voltage_frequency: false,
+            power_register: 5000,
+            slave_id: None,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+        };
+        let mut reader = ShellyReader::connect(&config).await;
+        assert_eq!(reader.next().await, Ok(Measurement::grid_power(750.0)));
+    }
+
+    #[tokio::test]
+    async fn test_reader_with_slave_id() {
+        let shelly = MockShelly::start_as_unit(&[(1013, 600.0)], 3).await;
+        let mut config = ShellyConfig {
+            address: shelly.to_string(),
+            protocol: ShellyProtocol::Tcp,
+            float_layout: FloatLayout::Cdab,
+            read_phases: false,
+            read_voltages: false,
+            read_voltage_frequency: false,
+            power_register: DEFAULT_POWER_REGISTER,
+            slave_id: Some(3),
+            read_timeout: DEFAULT_READ_TIMEOUT,
+        };
+        let mut reader = ShellyReader::connect(&config).await;
+        assert_eq!(reader.next().await, Ok(Measurement::grid_power(600.0)));
+
+        // A gateway answers for units it doesn't have with an exception, which retrying won't fix
+        config.slave_id = Some(4);
+        let mut client = Shelly3EMClient::connect(&config).await;
+        let error = client.read_total_power().await.unwrap_err();
+        assert!(
+            matches!(
+                error,
+                ShellyError::ModbusException(ExceptionCode::GatewayTargetDevice)
+            ),
+            "{error:?}"
+        );
+        let mut reader = ShellyReader::new(client, &config);
+        assert!(matches!(reader.next().await, Err(ReaderError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_devices() {
+        let config = ShellyConfig {
+            address: "192.168.1.20:502, 192.168.1.21:502,".to_string(),
+            protocol: ShellyProtocol::Tcp,
+            float_layout: FloatLayout::Cdab,
+            read_phases: true,
+            read_voltages: false,
+            read_voltage_frequency: false,
+            power_register: DEFAULT_POWER_REGISTER,
+            slave_id: None,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+        };
+        let devices = config.devices();
+        assert_eq!(
+            devices
+                .iter()
+                .map(|d| d.address.as_str())
+                .collect::<Vec<_>>(),
+            ["192.168.1.20:502", "192.168.1.21:502"]
+        );
+        assert!(devices.iter().all(|d| d.read_phases));
+
+        let unset = ShellyConfig {
+            address: String::new(),
+            ..config
+        };
+        assert_eq!(unset.devices(), vec![unset.clone()]);
+    }
+
+    #[test]
+    fn test_validate_power_register() {
+        assert_eq!(validate_power_register(1013), Ok(1013));
+        assert_eq!(validate_power_register(65534), Ok(65534));
+        assert!(validate_power_register(65535).is_err());
+    }
 }
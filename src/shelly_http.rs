@@ -0,0 +1,295 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::{
+    power_source::{Measurement, PowerSource, ReaderError, SourceFuture},
+    shelly_3em_client::ShellyConfig,
+};
+
+// Reads a Gen2 Shelly Pro 3EM over its HTTP RPC API, for devices where Modbus is disabled or
+// can't be reached. Each poll is a single `EM.GetStatus` call carrying the totals and the phases.
+
+/// `SHELLY_MODBUS` addresses starting with one of these are read over HTTP RPC rather than Modbus
+pub const HTTP_SCHEMES: [&str; 2] = ["http://", "https://"];
+
+/// The device's base URL, if `address` is an HTTP one such as `http://192.168.1.20`
+pub fn device_url(address: &str) -> Option<&str> {
+    let address = address.trim();
+    HTTP_SCHEMES
+        .iter()
+        .any(|scheme| address.starts_with(scheme))
+        .then(|| address.trim_end_matches('/'))
+}
+
+/// The part of the `EM.GetStatus` response that is used
+#[derive(Debug, Clone, Deserialize)]
+struct EmStatus {
+    total_act_power: f32,
+    a_act_power: Option<f32>,
+    b_act_power: Option<f32>,
+    c_act_power: Option<f32>,
+    a_voltage: Option<f32>,
+    b_voltage: Option<f32>,
+    c_voltage: Option<f32>,
+    a_freq: Option<f32>,
+}
+
+impl EmStatus {
+    fn phases(&self) -> Option<[f32; 3]> {
+        Some([self.a_act_power?, self.b_act_power?, self.c_act_power?])
+    }
+
+    fn voltages(&self) -> Option<[f32; 3]> {
+        Some([self.a_voltage?, self.b_voltage?, self.c_voltage?])
+    }
+}
+
+/// Reads the grid power from a Shelly's `EM.GetStatus` RPC
+pub struct ShellyHttpReader {
+    name: String,
+    client: reqwest::Client,
+    status_url: String,
+    timeout: Duration,
+    read_phases: bool,
+    read_voltages: bool,
+    read_voltage_frequency: bool,
+}
+
+impl ShellyHttpReader {
+    /// `url` is the device's base URL, see `device_url`.
+    /// Which optional values are reported follows the same settings as the Modbus reader.
+    pub fn new(url: &str, config: &ShellyConfig) -> Self {
+        Self {
+            name: "Shelly HTTP".to_string(),
+            client: reqwest::Client::new(),
+            status_url: format!("{}/rpc/EM.GetStatus?id=0", url.trim_end_matches('/')),
+            timeout: config.read_timeout,
+            read_phases: config.read_phases,
+            read_voltages: config.read_voltages,
+            read_voltage_frequency: config.read_voltage_frequency,
+        }
+    }
+
+    /// Names the reader in logs and metrics, to tell several devices apart
+    pub fn with_name(mut self, name: String) -> Self {
+        self.name = name;
+        self
+    }
+
+    async fn status(&self) -> Result<EmStatus, ReaderError> {
+        let response = self
+            .client
+            .get(&self.status_url)
+            // A half-open connection would otherwise hold up every reading behind it
+            .timeout(self.timeout)
+            .send()
+            .await
+            .map_err(|e| ReaderError::Unavailable(e.to_string()))?;
+        let status = response.status();
+        if !status.is_success() {
+            let reason = format!("{} answered {status}", self.status_url);
+            // A busy or rebooting device is worth retrying, anything else won't change
+            return Err(if status.is_server_error() {
+                ReaderError::Unavailable(reason)
+            } else {
+                ReaderError::Invalid(reason)
+            });
+        }
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| ReaderError::Unavailable(e.to_string()))?;
+        serde_json::from_slice(&body)
+            .map_err(|e| ReaderError::Invalid(format!("not an EM status: {e}")))
+    }
+}
+
+impl PowerSource for ShellyHttpReader {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn next(&mut self) -> SourceFuture<'_> {
+        Box::pin(async move {
+            let status = self.status().await?;
+            let watts = status.total_act_power;
+            if !watts.is_finite() {
+                return Err(ReaderError::Invalid(format!("total power of {watts}W")));
+            }
+            let phase_watts = if self.read_phases {
+                status.phases()
+            } else {
+                None
+            };
+            let phase_voltages =
+                if self.read_voltage_frequency || (self.read_phases && self.read_voltages) {
+                    status.voltages()
+                } else {
+                    None
+                };
+            let frequency = if self.read_voltage_frequency {
+                status.a_freq
+            } else {
+                None
+            };
+            Ok(Measurement::GridPower {
+                watts,
+                phase_watts,
+                phase_voltages,
+                frequency,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Matcher;
+
+    const STATUS: &str = r#"{
+        "id": 0,
+        "a_current": 2.1, "a_voltage": 231.2, "a_act_power": 420.5, "a_aprt_power": 480.0,
+        "a_pf": 0.87, "a_freq": 50.01,
+        "b_current": 0.5, "b_voltage": 229.8, "b_act_power": -110.0, "b_aprt_power": 115.0,
+        "b_pf": 0.95, "b_freq": 50.01,
+        "c_current": 1.0, "c_voltage": 230.4, "c_act_power": 205.0, "c_aprt_power": 230.0,
+        "c_pf": 0.89, "c_freq": 50.01,
+        "n_current": null,
+        "total_current": 3.6, "total_act_power": 515.5, "total_aprt_power": 825.0,
+        "user_calibrated_phase": []
+    }"#;
+
+    fn config() -> ShellyConfig {
+        ShellyConfig {
+            read_phases: true,
+            ..crate::config::Config::default().shelly()
+        }
+    }
+
+    #[test]
+    fn test_device_urls() {
+        assert_eq!(
+            device_url(" http://192.168.1.20/ "),
+            Some("http://192.168.1.20")
+        );
+        assert_eq!(
+            device_url("https://shelly.local"),
+            Some("https://shelly.local")
+        );
+        assert_eq!(device_url("192.168.1.20:502"), None);
+        assert_eq!(device_url("replay:///data/trace.csv"), None);
+    }
+
+    #[tokio::test]
+    async fn test_reads_the_em_status() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/rpc/EM.GetStatus")
+            .match_query(Matcher::UrlEncoded("id".into(), "0".into()))
+            .with_header("content-type", "application/json")
+            .with_body(STATUS)
+            .expect(2)
+            .create_async()
+            .await;
+
+        let mut reader = ShellyHttpReader::new(&server.url(), &config());
+        assert_eq!(
+            reader.next().await.unwrap(),
+            Measurement::GridPower {
+                watts: 515.5,
+                phase_watts: Some([420.5, -110.0, 205.0]),
+                phase_voltages: None,
+                frequency: None,
+            }
+        );
+
+        // Without the phases only the total is reported
+        let mut reader =
+            ShellyHttpReader::new(&server.url(), &crate::config::Config::default().shelly());
+        assert_eq!(
+            reader.next().await.unwrap(),
+            Measurement::GridPower {
+                watts: 515.5,
+                phase_watts: None,
+                phase_voltages: None,
+                frequency: None,
+            }
+        );
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_errors_are_classified() {
+        let mut server = mockito::Server::new_async().await;
+        let mut reader = ShellyHttpReader::new(&server.url(), &config());
+
+        let busy = server
+            .mock("GET", "/rpc/EM.GetStatus")
+            .match_query(Matcher::Any)
+            .with_status(503)
+            .create_async()
+            .await;
+        assert!(matches!(
+            reader.next().await,
+            Err(ReaderError::Unavailable(_))
+        ));
+        busy.remove_async().await;
+
+        // A Gen1 device or a typo in the address
+        let missing = server
+            .mock("GET", "/rpc/EM.GetStatus")
+            .match_query(Matcher::Any)
+            .with_status(404)
+            .create_async()
+            .await;
+        assert!(matches!(reader.next().await, Err(ReaderError::Invalid(_))));
+        missing.remove_async().await;
+
+        server
+            .mock("GET", "/rpc/EM.GetStatus")
+            .match_query(Matcher::Any)
+            .with_body(r#"{"id": 0, "a_act_power": 1.0}"#)
+            .create_async()
+            .await;
+        assert!(matches!(reader.next().await, Err(ReaderError::Invalid(_))));
+
+        // Nothing listening
+        let mut reader = ShellyHttpReader::new("http://127.0.0.1:1", &config());
+        assert!(matches!(
+            reader.next().await,
+            Err(ReaderError::Unavailable(_))
+        ));
+    }
+
+    /// Accepts connections but never answers
+    async fn hung_server() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                connections.push(stream);
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn test_hung_device_times_out() {
+        let hung = hung_server().await;
+        let config = ShellyConfig {
+            read_timeout: Duration::from_millis(100),
+            ..config()
+        };
+        let mut reader = ShellyHttpReader::new(&hung, &config);
+        let started = std::time::Instant::now();
+        let result = reader.next().await;
+        assert!(
+            matches!(&result, Err(ReaderError::Unavailable(_))),
+            "{result:?}"
+        );
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
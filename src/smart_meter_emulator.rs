@@ -1,17 +1,268 @@
-use std::{collections::HashMap, future, pin::Pin, process, sync::Arc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    future,
+    pin::Pin,
+    process,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 use tokio::{
-    sync::mpsc::{self, Receiver, Sender},
-    time::timeout,
+    sync::{
+        mpsc::{self, error::SendError, Receiver, Sender},
+        RwLock,
+    },
+    time::{sleep, timeout, Duration, Instant},
 };
 use tokio_modbus::prelude::*;
 
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    energy::EnergyAccumulator,
+    fault_injection::FaultInjection,
+    power_model::{Precision, NOMINAL_FREQUENCY, NOMINAL_VOLTAGE},
+    register_bank::RegisterBank,
+    shelly_3em_client::FloatLayout,
+    sunspec_map::{self, FieldInfo, MapError, MeterIdentity, MeterTopology},
+};
+
+/// The most registers a single read may return, as a response is limited to 250 data bytes
+pub const MAX_READ_REGISTERS: u16 = 125;
+/// The most registers a single write may hold, as a request is limited to 246 data bytes
+pub const MAX_WRITE_REGISTERS: u16 = 123;
+
+/// How closely register reads are checked against the Modbus spec
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FramingMode {
+    /// Zero length reads are rejected with IllegalDataValue, as the spec requires
+    Strict,
+    /// Zero length reads are answered with an empty response
+    Lenient,
+}
+
+impl FromStr for FramingMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "strict" => Ok(Self::Strict),
+            "lenient" => Ok(Self::Lenient),
+            other => Err(format!("Unknown framing mode `{other}`")),
+        }
+    }
+}
+
+/// How requests for Modbus functions the meter doesn't implement are answered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnimplementedResponse {
+    IllegalFunction,
+    IllegalDataAddress,
+    /// Don't answer at all, as if the request never arrived
+    #[serde(rename = "none")]
+    NoResponse,
+}
+
+impl FromStr for UnimplementedResponse {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "illegal_function" => Ok(Self::IllegalFunction),
+            "illegal_data_address" => Ok(Self::IllegalDataAddress),
+            "none" => Ok(Self::NoResponse),
+            other => Err(format!("Unknown unimplemented function response `{other}`")),
+        }
+    }
+}
+
+/// Parses a comma separated list of `<field>=<factor>`, such as `TotalRealPower=0.001`.
+/// Fields are named as in `Readings`.
+pub fn parse_scale_factors(factors: &str) -> Result<BTreeMap<String, f32>, String> {
+    factors
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (name, factor) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("Expected `<field>=<factor>`, got `{entry}`"))?;
+            let name = name.trim();
+            if !sunspec_map::MEASUREMENT_FIELDS
+                .iter()
+                .any(|field| field.name == name)
+            {
+                return Err(format!("Unknown field `{name}`"));
+            }
+            let factor = factor
+                .trim()
+                .parse::<f32>()
+                .ok()
+                .filter(|factor| factor.is_finite())
+                .ok_or_else(|| format!("Invalid scale factor `{factor}` for {name}"))?;
+            Ok((name.to_string(), factor))
+        })
+        .collect()
+}
+
+/// Settings for how readings are written into the registers, and requests are answered
+#[derive(Debug, Clone)]
+pub struct EmulatorOptions {
+    pub precision: Precision,
+    pub unimplemented: UnimplementedResponse,
+    /// Reported as the meter's Modbus address in the SunSpec common model
+    pub unit_id: u8,
+    /// Picks the meter model presented to the inverter
+    pub topology: MeterTopology,
+    /// Multipliers applied to readings before they are written, by field name. Unlisted fields are
+    /// written as is.
+    pub scale_factors: BTreeMap<String, f32>,
+    /// The process exits if no reading arrives within this long, so a stalled source restarts
+    /// the container rather than leaving stale values. None waits forever, for embedders that
+    /// update rarely.
+    pub update_timeout: Option<Duration>,
+    /// How each float is laid out across its two registers. Big endian, high word first, unless
+    /// the inverter expects otherwise.
+    pub float_layout: FloatLayout,
+    /// Reported as each phase voltage until measured voltages arrive, so a freshly started
+    /// meter doesn't report 0V
+    pub nominal_voltage: f32,
+    /// Reported as the frequency until a measured one arrives
+    pub nominal_frequency: f32,
+}
+
+impl Default for EmulatorOptions {
+    fn default() -> Self {
+        Self {
+            precision: Precision::default(),
+            unimplemented: UnimplementedResponse::IllegalFunction,
+            unit_id: sunspec_map::DEFAULT_UNIT_ID,
+            topology: MeterTopology::default(),
+            scale_factors: BTreeMap::new(),
+            update_timeout: Some(Duration::from_secs(30)),
+            float_layout: FloatLayout::Abcd,
+            nominal_voltage: NOMINAL_VOLTAGE,
+            nominal_frequency: NOMINAL_FREQUENCY,
+        }
+    }
+}
+
+/// The meter's measurements at one moment, as decoded from its registers.
+/// Per phase values are in A, B, C order, and the line voltages are AB, BC, CA.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct MeterSnapshot {
+    pub total_real_power: f32,
+    pub phase_watts: [f32; 3],
+    pub apparent_power: f32,
+    pub phase_va: [f32; 3],
+    pub reactive_power: f32,
+    pub phase_var: [f32; 3],
+    pub power_factor: f32,
+    pub phase_power_factors: [f32; 3],
+    pub net_ac_current: f32,
+    pub phase_currents: [f32; 3],
+    pub average_phase_voltage: f32,
+    pub phase_voltages: [f32; 3],
+    pub average_ll_voltage: f32,
+    pub line_voltages: [f32; 3],
+    pub frequency: f32,
+    pub total_wh_exported: f32,
+    pub total_wh_imported: f32,
+    pub grid_import_power: f32,
+    pub grid_export_power: f32,
+}
+
+/// How many readings can wait for the emulator to write them before senders wait.
+/// Writing a batch only takes the register lock, so the queue is normally empty; this is room
+/// for the emulator to be starved for a while, e.g. by a burst of requests, without holding up
+/// polling. `OUTPUT_WHEN_FULL` says what happens once it runs out.
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 128;
+
+/// Sets up a `SmartMeterEmulator`, such as to present as a meter other than the Fronius one,
+/// see `SmartMeterEmulator::builder`
+#[derive(Debug, Clone)]
+pub struct SmartMeterEmulatorBuilder {
+    options: EmulatorOptions,
+    identity: MeterIdentity,
+    channel_capacity: usize,
+}
+
+impl Default for SmartMeterEmulatorBuilder {
+    fn default() -> Self {
+        Self {
+            options: EmulatorOptions::default(),
+            identity: MeterIdentity::default(),
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+        }
+    }
+}
+
+impl SmartMeterEmulatorBuilder {
+    pub fn options(mut self, options: EmulatorOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Reported in the SunSpec common model. Each of the manufacturer, model and serial number
+    /// holds up to `sunspec_map::TEXT_FIELD_LEN` ASCII characters, anything longer is cut.
+    pub fn manufacturer(mut self, manufacturer: impl Into<String>) -> Self {
+        self.identity.manufacturer = manufacturer.into();
+        self
+    }
+
+    pub fn model(mut self, model: impl Into<String>) -> Self {
+        self.identity.model = model.into();
+        self
+    }
+
+    pub fn serial_number(mut self, serial_number: impl Into<String>) -> Self {
+        self.identity.serial_number = serial_number.into();
+        self
+    }
+
+    /// How many readings can be queued before sending waits, at least 1
+    pub fn channel_capacity(mut self, channel_capacity: usize) -> Self {
+        self.channel_capacity = channel_capacity.max(1);
+        self
+    }
+
+    pub fn build(self) -> (SmartMeterEmulator, Sender<Readings>) {
+        for field in self.identity.overlong_fields() {
+            warn!(
+                "The meter's {field} is longer than {} characters and will be cut",
+                sunspec_map::TEXT_FIELD_LEN
+            );
+        }
+        SmartMeterEmulator::start(self.options, &self.identity, self.channel_capacity)
+    }
+}
+
 #[derive(Clone)]
 pub struct SmartMeterEmulator {
-    holding_registers: Arc<tokio::sync::Mutex<HashMap<u16, u16>>>,
+    // Reads share it, so a chatty inverter's reads only ever wait on a batch being written
+    holding_registers: Arc<RwLock<RegisterBank>>,
+    // Set once the first real reading has been written into the registers
+    has_readings: Arc<AtomicBool>,
+    // Set while the last update said the readings are out of date
+    unavailable: Arc<AtomicBool>,
+    // When set, reads are answered with ServerDeviceBusy until data is ready or the deadline, if
+    // there is one, passes
+    serve_deadline: Option<Option<Instant>>,
+    framing: FramingMode,
+    unimplemented: UnimplementedResponse,
+    faults: Option<FaultInjection>,
+    log_reads: bool,
+    float_layout: FloatLayout,
+    // When set, requests addressed to any other unit are left unanswered
+    only_unit_id: Option<u8>,
 }
 // Turns out you only need to implement total power here, but they are all supported for future hacks
-#[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Readings {
     NetACCurrent(f32),
     AveragePhaseVoltage(f32),
@@ -42,33 +293,298 @@ pub enum Readings {
     PhaseAPF(f32),
     PhaseBPF(f32),
     PhaseCPF(f32),
+    /// The power drawn from the grid, 0 while exporting
+    GridImportPower(f32),
+    /// The power fed into the grid as a positive value, 0 while importing
+    GridExportPower(f32),
+    /// Readings that must land together, so a client never sees a mix of old and new values
+    Batch(Vec<Readings>),
+    /// The readings are out of date. Until the next reading arrives, requests are answered with
+    /// ServerDeviceFailure rather than the stale values.
+    Unavailable,
+}
+
+impl Readings {
+    /// Every reading of a single value, to check where each of them is stored
+    const SINGLE_VALUES: [fn(f32) -> Readings; 31] = [
+        Readings::NetACCurrent,
+        Readings::AveragePhaseVoltage,
+        Readings::AverageLLVoltage,
+        Readings::PhaseACurrent,
+        Readings::PhaseBCurrent,
+        Readings::PhaseCCurrent,
+        Readings::PhaseAVoltage,
+        Readings::PhaseBVoltage,
+        Readings::PhaseCVoltage,
+        Readings::PhaseAWatts,
+        Readings::PhaseBWatts,
+        Readings::PhaseCWatts,
+        Readings::PhaseABVoltage,
+        Readings::PhaseBCVoltage,
+        Readings::PhaseCAVoltage,
+        Readings::Frequency,
+        Readings::TotalRealPower,
+        Readings::ApparentPower,
+        Readings::PhaseAVA,
+        Readings::PhaseBVA,
+        Readings::PhaseCVA,
+        Readings::ReactivePower,
+        Readings::PhaseAVAR,
+        Readings::PhaseBVAR,
+        Readings::PhaseCVAR,
+        Readings::PowerFactorTotal,
+        Readings::PhaseAPF,
+        Readings::PhaseBPF,
+        Readings::PhaseCPF,
+        Readings::GridImportPower,
+        Readings::GridExportPower,
+    ];
+
+    /// Returns where in the meter model each value is stored, along with the value
+    pub fn fields(&self) -> Vec<(FieldInfo, f32)> {
+        match self {
+            Readings::Batch(readings) => readings.iter().flat_map(Readings::fields).collect(),
+            Readings::Unavailable => Vec::new(),
+            single => vec![single.field()],
+        }
+    }
+
+    fn field(&self) -> (FieldInfo, f32) {
+        match *self {
+            Readings::NetACCurrent(value) => (sunspec_map::NET_AC_CURRENT, value),
+            Readings::AveragePhaseVoltage(value) => (sunspec_map::AVERAGE_PHASE_VOLTAGE, value),
+            Readings::AverageLLVoltage(value) => (sunspec_map::AVERAGE_LL_VOLTAGE, value),
+            Readings::PhaseACurrent(value) => (sunspec_map::PHASE_A_CURRENT, value),
+            Readings::PhaseBCurrent(value) => (sunspec_map::PHASE_B_CURRENT, value),
+            Readings::PhaseCCurrent(value) => (sunspec_map::PHASE_C_CURRENT, value),
+            Readings::PhaseAVoltage(value) => (sunspec_map::PHASE_A_VOLTAGE, value),
+            Readings::PhaseBVoltage(value) => (sunspec_map::PHASE_B_VOLTAGE, value),
+            Readings::PhaseCVoltage(value) => (sunspec_map::PHASE_C_VOLTAGE, value),
+            Readings::PhaseAWatts(value) => (sunspec_map::PHASE_A_WATTS, value),
+            Readings::PhaseBWatts(value) => (sunspec_map::PHASE_B_WATTS, value),
+            Readings::PhaseCWatts(value) => (sunspec_map::PHASE_C_WATTS, value),
+            Readings::PhaseABVoltage(value) => (sunspec_map::PHASE_AB_VOLTAGE, value),
+            Readings::PhaseBCVoltage(value) => (sunspec_map::PHASE_BC_VOLTAGE, value),
+            Readings::PhaseCAVoltage(value) => (sunspec_map::PHASE_CA_VOLTAGE, value),
+            Readings::Frequency(value) => (sunspec_map::FREQUENCY, value),
+            Readings::TotalRealPower(value) => (sunspec_map::TOTAL_REAL_POWER, value),
+            Readings::ApparentPower(value) => (sunspec_map::APPARENT_POWER, value),
+            Readings::PhaseAVA(value) => (sunspec_map::PHASE_A_VA, value),
+            Readings::PhaseBVA(value) => (sunspec_map::PHASE_B_VA, value),
+            Readings::PhaseCVA(value) => (sunspec_map::PHASE_C_VA, value),
+            Readings::ReactivePower(value) => (sunspec_map::REACTIVE_POWER, value),
+            Readings::PhaseAVAR(value) => (sunspec_map::PHASE_A_VAR, value),
+            Readings::PhaseBVAR(value) => (sunspec_map::PHASE_B_VAR, value),
+            Readings::PhaseCVAR(value) => (sunspec_map::PHASE_C_VAR, value),
+            Readings::PowerFactorTotal(value) => (sunspec_map::POWER_FACTOR_TOTAL, value),
+            Readings::PhaseAPF(value) => (sunspec_map::PHASE_A_PF, value),
+            Readings::PhaseBPF(value) => (sunspec_map::PHASE_B_PF, value),
+            Readings::PhaseCPF(value) => (sunspec_map::PHASE_C_PF, value),
+            Readings::GridImportPower(value) => (sunspec_map::GRID_IMPORT_POWER, value),
+            Readings::GridExportPower(value) => (sunspec_map::GRID_EXPORT_POWER, value),
+            Readings::Batch(_) | Readings::Unavailable => {
+                unreachable!("Only single values are stored in a field")
+            }
+        }
+    }
+}
+
+/// One leg of a three phase supply
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    A,
+    B,
+    C,
+}
+
+/// Feeds readings straight into a [`SmartMeterEmulator`], for embedding the meter in another
+/// application without going through the Shelly or Home Assistant readers.
+///
+/// Each call is applied as soon as the meter's update task picks it up. Use [`MeterHandle::send`]
+/// with a [`Readings::Batch`] when values must change together.
+///
+/// ```
+/// use fronius_meter_emulation::{MeterHandle, Phase, SmartMeterEmulator};
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let (meter, sender) = SmartMeterEmulator::new();
+/// let handle = MeterHandle::from(sender);
+/// // Negative is exporting to the grid
+/// handle.set_total_power(-1200.0).await?;
+/// handle.set_phase_voltage(Phase::A, 231.5).await?;
+/// handle.set_frequency(50.02).await?;
+/// // `meter` is then served to the inverter, e.g. with tokio_modbus::server::tcp::Server
+/// # drop(meter);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The meter exits the process if it hears nothing for 30s, as the binary relies on that to be
+/// restarted. Sources that update less often should turn that off:
+///
+/// ```
+/// use fronius_meter_emulation::{
+///     smart_meter_emulator::EmulatorOptions, MeterHandle, Readings, SmartMeterEmulator,
+/// };
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let (meter, sender) = SmartMeterEmulator::with_options(EmulatorOptions {
+///     update_timeout: None,
+///     ..Default::default()
+/// });
+/// let handle = MeterHandle::from(sender);
+/// handle
+///     .send(Readings::Batch(vec![
+///         Readings::TotalRealPower(450.0),
+///         Readings::NetACCurrent(1.9),
+///     ]))
+///     .await?;
+/// # drop(meter);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct MeterHandle {
+    sender: Sender<Readings>,
+}
+
+impl From<Sender<Readings>> for MeterHandle {
+    fn from(sender: Sender<Readings>) -> Self {
+        Self { sender }
+    }
+}
+
+impl MeterHandle {
+    /// Queues `reading` for the meter, failing only once the meter has been dropped
+    pub async fn send(&self, reading: Readings) -> Result<(), SendError<Readings>> {
+        self.sender.send(reading).await
+    }
+
+    /// Total real power in watts, positive when importing from the grid
+    pub async fn set_total_power(&self, watts: f32) -> Result<(), SendError<Readings>> {
+        self.send(Readings::TotalRealPower(watts)).await
+    }
+
+    /// Line frequency in Hz
+    pub async fn set_frequency(&self, hertz: f32) -> Result<(), SendError<Readings>> {
+        self.send(Readings::Frequency(hertz)).await
+    }
+
+    /// Phase to neutral voltage of `phase`
+    pub async fn set_phase_voltage(
+        &self,
+        phase: Phase,
+        volts: f32,
+    ) -> Result<(), SendError<Readings>> {
+        self.send(match phase {
+            Phase::A => Readings::PhaseAVoltage(volts),
+            Phase::B => Readings::PhaseBVoltage(volts),
+            Phase::C => Readings::PhaseCVoltage(volts),
+        })
+        .await
+    }
+
+    /// Real power of `phase` in watts
+    pub async fn set_phase_power(
+        &self,
+        phase: Phase,
+        watts: f32,
+    ) -> Result<(), SendError<Readings>> {
+        self.send(match phase {
+            Phase::A => Readings::PhaseAWatts(watts),
+            Phase::B => Readings::PhaseBWatts(watts),
+            Phase::C => Readings::PhaseCWatts(watts),
+        })
+        .await
+    }
 }
 
 impl tokio_modbus::server::Service for SmartMeterEmulator {
-    type Request = Request<'static>;
-    type Response = Response;
+    type Request = SlaveRequest<'static>;
+    // None leaves the request unanswered
+    type Response = Option<Response>;
     type Exception = tokio_modbus::ExceptionCode;
     type Future =
         Pin<Box<dyn future::Future<Output = Result<Self::Response, Self::Exception>> + Send>>;
 
     fn call(&self, req: Self::Request) -> Self::Future {
+        let SlaveRequest {
+            slave: unit_id,
+            request: req,
+        } = req;
+        if self.only_unit_id.is_some_and(|only| only != unit_id) {
+            debug!("Ignoring {req:?} addressed to unit {unit_id}");
+            return Box::pin(future::ready(Ok(None)));
+        }
+        if !self.is_ready_to_serve() {
+            debug!("Not serving {req:?} until the first reading arrives");
+            return Box::pin(future::ready(Err(
+                tokio_modbus::ExceptionCode::ServerDeviceBusy,
+            )));
+        }
+        if self.unavailable.load(Ordering::Relaxed) {
+            debug!("Not serving {req:?} while the readings are out of date");
+            return Box::pin(future::ready(Err(
+                tokio_modbus::ExceptionCode::ServerDeviceFailure,
+            )));
+        }
         let holding_registers = self.holding_registers.clone();
+        let framing = self.framing;
+        let unimplemented = self.unimplemented;
+        let log_reads = self.log_reads;
+        let float_layout = self.float_layout;
+        let fault = self.faults.map(|faults| faults.sample());
         Box::pin(async move {
+            if let Some((delay, drop)) = fault {
+                sleep(delay).await;
+                if drop {
+                    debug!("Fault injection: dropping the response to {req:?}");
+                    return Ok(None);
+                }
+            }
             match req {
                 Request::ReadInputRegisters(addr, cnt) => {
-                    println!("Register Read for {addr}/{cnt}");
-                    let registers = holding_registers.lock().await;
-                    register_read(&registers, addr, cnt).map(Response::ReadInputRegisters)
+                    debug!("Register Read for {addr}/{cnt}");
+                    log_partial_field_reads(addr, cnt);
+                    let registers = holding_registers.read().await;
+                    register_read(&registers, addr, cnt, framing)
+                        .inspect(|values| log_read(log_reads, float_layout, addr, values))
+                        .map(|values| Some(Response::ReadInputRegisters(values)))
                 }
                 Request::ReadHoldingRegisters(addr, cnt) => {
-                    println!("Holding register Read for {addr}/{cnt}");
-                    let registers = holding_registers.lock().await;
-                    register_read(&registers, addr, cnt).map(Response::ReadHoldingRegisters)
+                    debug!("Holding register Read for {addr}/{cnt}");
+                    log_partial_field_reads(addr, cnt);
+                    let registers = holding_registers.read().await;
+                    register_read(&registers, addr, cnt, framing)
+                        .inspect(|values| log_read(log_reads, float_layout, addr, values))
+                        .map(|values| Some(Response::ReadHoldingRegisters(values)))
+                }
+                Request::WriteSingleRegister(addr, value) => {
+                    debug!("Register Write of {value} to {addr}");
+                    let mut registers = holding_registers.write().await;
+                    register_write(&mut registers, addr, &[value])
+                        .map(|()| Some(Response::WriteSingleRegister(addr, value)))
+                }
+                Request::WriteMultipleRegisters(addr, values) => {
+                    debug!("Register Write of {values:?} to {addr}");
+                    let mut registers = holding_registers.write().await;
+                    register_write(&mut registers, addr, &values)
+                        .map(|()| Some(Response::WriteMultipleRegisters(addr, values.len() as u16)))
                 }
 
                 _ => {
-                    println!("SERVER: Exception::IllegalFunction - Unimplemented function code in request: {req:?}");
-                    Err(tokio_modbus::ExceptionCode::IllegalFunction)
+                    warn!("SERVER: {unimplemented:?} - Unimplemented function code in request: {req:?}");
+                    match unimplemented {
+                        UnimplementedResponse::IllegalFunction => {
+                            Err(tokio_modbus::ExceptionCode::IllegalFunction)
+                        }
+                        UnimplementedResponse::IllegalDataAddress => {
+                            Err(tokio_modbus::ExceptionCode::IllegalDataAddress)
+                        }
+                        UnimplementedResponse::NoResponse => Ok(None),
+                    }
                 }
             }
         })
@@ -76,217 +592,1118 @@ impl tokio_modbus::server::Service for SmartMeterEmulator {
 }
 
 impl SmartMeterEmulator {
+    /// A Fronius meter with the default options
     pub fn new() -> (Self, Sender<Readings>) {
-        // Insert some test data as register values.
-        let mut input_registers = HashMap::new();
-        input_registers.insert(0, 1234);
-        input_registers.insert(1, 5678);
-        let mut holding_registers = HashMap::new();
+        Self::builder().build()
+    }
+
+    pub fn with_options(options: EmulatorOptions) -> (Self, Sender<Readings>) {
+        Self::builder().options(options).build()
+    }
+
+    /// Sets the meter's identity and channel capacity as well as its options
+    pub fn builder() -> SmartMeterEmulatorBuilder {
+        SmartMeterEmulatorBuilder::default()
+    }
+
+    fn start(
+        options: EmulatorOptions,
+        identity: &MeterIdentity,
+        channel_capacity: usize,
+    ) -> (Self, Sender<Readings>) {
+        let unimplemented = options.unimplemented;
+        let float_layout = options.float_layout;
         // Seed in all the constant values that are used for the device
-        // Well-known value. Uniquely identifies this as a SunSpec Modbus Map
-        let sun_spec_values: [u16; 101] = [
-            0x5375, 0x6e53, // Sun Spec marker
-            1, 65, // Num registers
-            70, 114, 111, 110, 105, 117, 115, 0, 0, 0, 0, 0, 0, 0, 0, 0, 83, 109, 97, 114, 116, 32,
-            77, 101, 116, 101, 114, 32, 54, 51, 65, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-            0, 48, 48, 48, 48, 48, 48, 48, 49, 0, 0, 0, 0, 0, 0, 0, 0,   //Block2
-            240, // Modbus address
-            213, // Y connected 3 phase (ABCN)
-            124, //End of static values
-            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-            0,
-        ];
-        for (index, item) in sun_spec_values.iter().enumerate() {
-            holding_registers.insert(40000 + index as u16, *item);
-        }
-        // Second set of filler readings
-        let sun_spec_values_2: [u16; 16] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
-        for (index, item) in sun_spec_values_2.iter().enumerate() {
-            holding_registers.insert(40129 + index as u16, *item);
-        }
-        // 0 fill the "readings" address sapce
-        for index in 40071..40161 {
-            holding_registers.insert(index as u16, 0);
-        }
-
-        //Misc filler
-
-        holding_registers.insert(40193, 0);
-        holding_registers.insert(40194, 0);
-        holding_registers.insert(40195, 0xFFFF); // Terminates the readings blocks
-        holding_registers.insert(40196, 0);
-
-        holding_registers.insert(0, 1); // Sunspec model common
-        holding_registers.insert(1, 0); // Length of registers
-        holding_registers.insert(11, 0);
-        holding_registers.insert(12, 0);
-        // Not SunSpec, so return 0 to mark us as SunSpec
-        holding_registers.insert(768, 0);
-        holding_registers.insert(1706, 0);
-        holding_registers.insert(50000, 0);
-        holding_registers.insert(50001, 0);
+        let mut holding_registers = sunspec_map::seed_registers(options.unit_id, options.topology);
+        identity.write_to(&mut holding_registers);
+        Self::write_nominal_values(&mut holding_registers, &options);
 
         // To handle incoming data updates, we use an MPSC channel for comms
-        let (tx, rx) = mpsc::channel(128);
-        let holding_registers = Arc::new(tokio::sync::Mutex::new(holding_registers));
+        let (tx, rx) = mpsc::channel(channel_capacity);
+        let holding_registers = Arc::new(RwLock::new(holding_registers));
+        let has_readings = Arc::new(AtomicBool::new(false));
+        let unavailable = Arc::new(AtomicBool::new(false));
         let handler_holding_registers = holding_registers.clone();
+        let handler_has_readings = has_readings.clone();
+        let handler_unavailable = unavailable.clone();
         tokio::spawn(async move {
-            Self::handle_incoming_register_events(rx, handler_holding_registers).await;
+            Self::handle_incoming_register_events(
+                rx,
+                handler_holding_registers,
+                handler_has_readings,
+                handler_unavailable,
+                options,
+            )
+            .await;
         });
 
         //Return server & channel for readings
-        (Self { holding_registers }, tx)
+        (
+            Self {
+                holding_registers,
+                has_readings,
+                unavailable,
+                serve_deadline: None,
+                framing: FramingMode::Lenient,
+                unimplemented,
+                faults: None,
+                log_reads: false,
+                float_layout,
+                only_unit_id: None,
+            },
+            tx,
+        )
+    }
+
+    /// Answer all requests with ServerDeviceBusy until the first reading has been received.
+    /// Some inverters latch onto the all-zero seed values if they are served too early.
+    /// After `max_wait` the meter serves whatever it has, so a dead source can't block it forever.
+    /// A `max_wait` too long to represent waits for the first reading however long it takes.
+    pub fn delay_serving_until_ready(mut self, max_wait: Duration) -> Self {
+        self.serve_deadline = Some(Instant::now().checked_add(max_wait));
+        self
+    }
+
+    /// Sets how strictly the quantity of register reads is checked
+    pub fn with_framing(mut self, framing: FramingMode) -> Self {
+        self.framing = framing;
+        self
+    }
+
+    /// Only answer requests addressed to `unit_id`, like a meter sharing a bus with other devices.
+    /// By default every request is answered, whatever unit it is addressed to.
+    pub fn answer_only_unit_id(mut self, unit_id: u8) -> Self {
+        self.only_unit_id = Some(unit_id);
+        self
+    }
+
+    /// Delays and drops responses, to test how an inverter copes with a poor meter
+    pub fn with_fault_injection(mut self, faults: FaultInjection) -> Self {
+        self.faults = Some(faults);
+        self
+    }
+
+    /// Logs every register read at info, naming and decoding what it covers
+    pub fn with_read_logging(mut self) -> Self {
+        self.log_reads = true;
+        self
+    }
+
+    /// Every measurement field as the inverter would currently decode it, by name.
+    /// Fields that haven't been written read as 0.
+    pub async fn current_readings(&self) -> BTreeMap<&'static str, f32> {
+        let registers = self.holding_registers.read().await;
+        sunspec_map::MEASUREMENT_FIELDS
+            .iter()
+            .map(|field| {
+                let value = read_field(&registers, *field, self.float_layout);
+                (field.name, value)
+            })
+            .collect()
+    }
+
+    /// Checks the registers are a SunSpec map the inverter can walk, with every reading and
+    /// energy counter stored inside the meter model, see `sunspec_map::validate`
+    pub async fn validate_map(&self) -> Result<(), MapError> {
+        let mut fields: Vec<FieldInfo> = Readings::SINGLE_VALUES
+            .iter()
+            .map(|reading| reading(0.0).field().0)
+            .collect();
+        fields.extend_from_slice(sunspec_map::MEASUREMENT_FIELDS);
+        let registers = self.holding_registers.read().await;
+        sunspec_map::validate(&registers, &fields)
+    }
+
+    /// The measurements as the inverter would currently decode them, all from the same update.
+    /// Fields that haven't been written read as 0.
+    pub async fn snapshot(&self) -> MeterSnapshot {
+        let registers = self.holding_registers.read().await;
+        let field = |field| read_field(&registers, field, self.float_layout);
+        use sunspec_map::*;
+        MeterSnapshot {
+            total_real_power: field(TOTAL_REAL_POWER),
+            phase_watts: [PHASE_A_WATTS, PHASE_B_WATTS, PHASE_C_WATTS].map(field),
+            apparent_power: field(APPARENT_POWER),
+            phase_va: [PHASE_A_VA, PHASE_B_VA, PHASE_C_VA].map(field),
+            reactive_power: field(REACTIVE_POWER),
+            phase_var: [PHASE_A_VAR, PHASE_B_VAR, PHASE_C_VAR].map(field),
+            power_factor: field(POWER_FACTOR_TOTAL),
+            phase_power_factors: [PHASE_A_PF, PHASE_B_PF, PHASE_C_PF].map(field),
+            net_ac_current: field(NET_AC_CURRENT),
+            phase_currents: [PHASE_A_CURRENT, PHASE_B_CURRENT, PHASE_C_CURRENT].map(field),
+            average_phase_voltage: field(AVERAGE_PHASE_VOLTAGE),
+            phase_voltages: [PHASE_A_VOLTAGE, PHASE_B_VOLTAGE, PHASE_C_VOLTAGE].map(field),
+            average_ll_voltage: field(AVERAGE_LL_VOLTAGE),
+            line_voltages: [PHASE_AB_VOLTAGE, PHASE_BC_VOLTAGE, PHASE_CA_VOLTAGE].map(field),
+            frequency: field(FREQUENCY),
+            total_wh_exported: field(TOTAL_WH_EXPORTED),
+            total_wh_imported: field(TOTAL_WH_IMPORTED),
+            grid_import_power: field(GRID_IMPORT_POWER),
+            grid_export_power: field(GRID_EXPORT_POWER),
+        }
+    }
+
+    fn is_ready_to_serve(&self) -> bool {
+        match self.serve_deadline {
+            Some(deadline) => {
+                self.has_readings.load(Ordering::Relaxed)
+                    || deadline.is_some_and(|deadline| Instant::now() >= deadline)
+            }
+            None => true,
+        }
     }
 
     async fn handle_incoming_register_events(
         mut events: Receiver<Readings>,
-        holding_registers: Arc<tokio::sync::Mutex<HashMap<u16, u16>>>,
+        holding_registers: Arc<RwLock<RegisterBank>>,
+        has_readings: Arc<AtomicBool>,
+        unavailable: Arc<AtomicBool>,
+        options: EmulatorOptions,
     ) {
-        println!("Starting readinger updates handler task");
-
-        let data_update_timeout = tokio::time::Duration::from_secs(30);
-        while let Ok(Some(reading)) = timeout(data_update_timeout, events.recv()).await {
-            // println!("New Reading of {reading:?}");
-            match reading {
-                Readings::NetACCurrent(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40071, reading).await
-                }
-                Readings::AveragePhaseVoltage(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40079, reading).await
-                }
-                Readings::AverageLLVoltage(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40087, reading).await
-                }
-                Readings::PhaseACurrent(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40073, reading).await
-                }
-                Readings::PhaseBCurrent(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40075, reading).await
-                }
-                Readings::PhaseCCurrent(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40077, reading).await
-                }
-                Readings::PhaseAVoltage(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40081, reading).await
-                }
-                Readings::PhaseBVoltage(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40083, reading).await
-                }
-                Readings::PhaseCVoltage(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40085, reading).await
-                }
-                Readings::PhaseAWatts(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40099, reading).await
-                }
-                Readings::PhaseBWatts(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40101, reading).await
-                }
-                Readings::PhaseCWatts(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40103, reading).await
-                }
-                Readings::PhaseABVoltage(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40089, reading).await
-                }
-                Readings::PhaseBCVoltage(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40091, reading).await
-                }
-                Readings::PhaseCAVoltage(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40093, reading).await
-                }
-                Readings::Frequency(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40095, reading).await
-                }
-                Readings::TotalRealPower(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40097, reading).await
-                }
-                Readings::ApparentPower(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40105, reading).await
-                }
-                Readings::PhaseAVA(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40107, reading).await
-                }
-                Readings::PhaseBVA(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40109, reading).await
-                }
-                Readings::PhaseCVA(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 4011, reading).await
-                }
-                Readings::ReactivePower(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40113, reading).await
-                }
-                Readings::PhaseAVAR(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40115, reading).await
-                }
-                Readings::PhaseBVAR(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40117, reading).await
-                }
-                Readings::PhaseCVAR(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40119, reading).await
-                }
-                Readings::PowerFactorTotal(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40121, reading).await
-                }
-                Readings::PhaseAPF(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40123, reading).await
+        info!("Starting readinger updates handler task");
+        // Counts from 0 on each start
+        let mut energy = EnergyAccumulator::default();
+
+        loop {
+            let next = match options.update_timeout {
+                Some(limit) => match timeout(limit, events.recv()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        error!("No Raw reading updates in {limit:?}, exiting");
+                        process::exit(1);
+                    }
+                },
+                None => events.recv().await,
+            };
+            let Some(reading) = next else {
+                // Every sender is gone, the registers keep their last values
+                info!("Reading updates closed, stopping the updates handler task");
+                return;
+            };
+            // debug!("New Reading of {reading:?}");
+            if let Readings::Unavailable = reading {
+                if !unavailable.swap(true, Ordering::Relaxed) {
+                    warn!("Readings are out of date, answering with ServerDeviceFailure");
                 }
-                Readings::PhaseBPF(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40125, reading).await
+                // Nothing is counted until real readings resume
+                energy.add(0.0, Instant::now());
+                continue;
+            }
+            // Hold the lock for the whole reading, so a batch is applied atomically
+            let mut registers = holding_registers.write().await;
+            let mut total_power = None;
+            for (field, value) in reading.fields() {
+                if field == sunspec_map::TOTAL_REAL_POWER {
+                    total_power = Some(value);
                 }
-                Readings::PhaseCPF(reading) => {
-                    Self::set_holding_reg_f32(&holding_registers, 40127, reading).await
+                Self::write_field(&mut registers, &options, field, value);
+            }
+            if let Some(watts) = total_power {
+                energy.add(watts, Instant::now());
+                let counters = [
+                    (sunspec_map::TOTAL_WH_IMPORTED, energy.imported_wh()),
+                    (sunspec_map::TOTAL_WH_EXPORTED, energy.exported_wh()),
+                ];
+                for (field, wh) in counters {
+                    Self::write_field(&mut registers, &options, field, wh as f32);
                 }
             }
+            drop(registers);
+            has_readings.store(true, Ordering::Relaxed);
+            if unavailable.swap(false, Ordering::Relaxed) {
+                info!("Readings are up to date again");
+            }
+        }
+    }
+    /// The voltages and frequency the meter reports before any are measured
+    fn write_nominal_values(registers: &mut RegisterBank, options: &EmulatorOptions) {
+        for field in [
+            sunspec_map::AVERAGE_PHASE_VOLTAGE,
+            sunspec_map::PHASE_A_VOLTAGE,
+            sunspec_map::PHASE_B_VOLTAGE,
+            sunspec_map::PHASE_C_VOLTAGE,
+        ] {
+            Self::write_field(registers, options, field, options.nominal_voltage);
         }
-        println!("No Raw reading updates in 30s, exiting");
-        process::exit(1);
+        Self::write_field(
+            registers,
+            options,
+            sunspec_map::FREQUENCY,
+            options.nominal_frequency,
+        );
     }
-    async fn set_holding_reg(
-        holding_registers: &Arc<tokio::sync::Mutex<HashMap<u16, u16>>>,
-        register: u16,
-        value: u16,
+
+    /// Writes `value` into `field`, scaled and rounded as set out in `options`
+    fn write_field(
+        registers: &mut RegisterBank,
+        options: &EmulatorOptions,
+        field: FieldInfo,
+        value: f32,
     ) {
-        let mut regs = holding_registers.lock().await;
-        regs.entry(register).and_modify(|entry| *entry = value);
+        let scale = options.scale_factors.get(field.name).copied();
+        let value = value * scale.unwrap_or(1.0);
+        let value = options.precision.round(field.quantity, value);
+        Self::set_holding_reg_f32(registers, field.address, value, options.float_layout);
+    }
+
+    /// Creates the register if it wasn't seeded, so every field a reading targets can be read back
+    fn set_holding_reg(holding_registers: &mut RegisterBank, register: u16, value: u16) {
+        holding_registers.set(register, value);
     }
-    async fn set_holding_reg_f32(
-        holding_registers: &Arc<tokio::sync::Mutex<HashMap<u16, u16>>>,
+    fn set_holding_reg_f32(
+        holding_registers: &mut RegisterBank,
         register_base_number: u16,
         value: f32,
+        layout: FloatLayout,
     ) {
-        let int_encoding: u32 = value.to_bits();
-        Self::set_holding_reg(
-            holding_registers,
-            register_base_number,
-            (int_encoding >> 16) as u16,
-        )
-        .await;
-        Self::set_holding_reg(
-            holding_registers,
-            register_base_number + 1,
-            (int_encoding & 0xFFFF) as u16,
-        )
-        .await;
+        let [first, second] = layout.encode(value);
+        Self::set_holding_reg(holding_registers, register_base_number, first);
+        Self::set_holding_reg(holding_registers, register_base_number + 1, second);
+    }
+}
+
+/// Splitting an f32 across two reads can pair words from different readings, so call it out
+fn log_partial_field_reads(addr: u16, cnt: u16) {
+    for field in sunspec_map::sunspec_fields_in_range(addr, cnt) {
+        if !field.is_within(addr, cnt) {
+            debug!(
+                "Read of {addr}/{cnt} only covers part of {} at {}",
+                field.name, field.address
+            );
+        }
+    }
+}
+
+fn log_read(enabled: bool, layout: FloatLayout, addr: u16, values: &[u16]) {
+    if enabled {
+        info!(
+            "Read of {addr}/{}: {}",
+            values.len(),
+            describe_read(addr, values, layout)
+        );
+    }
+}
+
+/// The measurement `field` as the inverter would decode it, 0 if it hasn't been written
+fn read_field(registers: &RegisterBank, field: FieldInfo, layout: FloatLayout) -> f32 {
+    let word = |offset| registers.get(field.address + offset).unwrap_or_default();
+    layout.decode([word(0), word(1)])
+}
+
+/// Names what a read of `values` from `addr` covers, decoding the measurement fields it
+/// fully covers and naming the seeded block of every other register
+fn describe_read(addr: u16, values: &[u16], layout: FloatLayout) -> String {
+    let cnt = values.len() as u16;
+    let mut parts: Vec<String> = Vec::new();
+    let mut offset = 0;
+    while offset < cnt {
+        // Can't overflow, as the read succeeded
+        let address = addr + offset;
+        let field = sunspec_map::MEASUREMENT_FIELDS
+            .iter()
+            .find(|field| field.address == address && field.is_within(addr, cnt));
+        if let Some(field) = field {
+            let index = offset as usize;
+            let value = layout.decode([values[index], values[index + 1]]);
+            parts.push(format!("{}={value}", field.name));
+            offset += field.len;
+            continue;
+        }
+        let name = sunspec_map::block_at(address).map_or("unmapped", |block| block.name);
+        // A run of registers from the same block is named once
+        if parts.last().map(String::as_str) != Some(name) {
+            parts.push(name.to_string());
+        }
+        offset += 1;
     }
+    parts.join(", ")
 }
 
-/// Helper function implementing reading registers from a HashMap.
+/// Helper function implementing reading registers from the bank.
+/// Reads of more than 125 registers can't be framed, so are always rejected with IllegalDataValue.
+/// Registers the meter model has but that hold nothing read as 0, see `sunspec_map::VALID_RANGES`.
 fn register_read(
-    registers: &HashMap<u16, u16>,
+    registers: &RegisterBank,
     addr: u16,
     cnt: u16,
+    framing: FramingMode,
 ) -> Result<Vec<u16>, tokio_modbus::ExceptionCode> {
-    let mut response_values = vec![0; cnt.into()];
-    for i in 0..cnt {
-        let reg_addr = addr + i;
-        if let Some(r) = registers.get(&reg_addr) {
-            response_values[i as usize] = *r;
-        } else {
-            println!(
-                "SERVER: Exception::IllegalDataAddress, can't handle read of register {reg_addr}/0x{reg_addr:X}"
+    if cnt > MAX_READ_REGISTERS || (cnt == 0 && framing == FramingMode::Strict) {
+        warn!("SERVER: Exception::IllegalDataValue, can't read {cnt} registers");
+        return Err(tokio_modbus::ExceptionCode::IllegalDataValue);
+    }
+    if let Some(reg_addr) = registers
+        .unset_in(addr, cnt)
+        .find(|reg_addr| !sunspec_map::is_valid_address(*reg_addr))
+    {
+        warn!(
+            "SERVER: Exception::IllegalDataAddress, can't handle read of register {reg_addr}/0x{reg_addr:X}"
+        );
+        return Err(tokio_modbus::ExceptionCode::IllegalDataAddress);
+    }
+    // Reads running past the end of the address space are a bad address, not a wrap around
+    let Some(response_values) = registers.get_range(addr, cnt) else {
+        warn!("SERVER: Exception::IllegalDataAddress, read of {addr}/{cnt} overflows");
+        return Err(tokio_modbus::ExceptionCode::IllegalDataAddress);
+    };
+    // debug!("Register read for addr:{addr} count:{cnt} returns {response_values:?}");
+    Ok(response_values.to_vec())
+}
+
+/// Helper function implementing writing registers to the bank.
+/// Only registers the meter has can be written, and the SunSpec identification block is read only.
+/// Nothing is written unless the whole write is allowed.
+fn register_write(
+    registers: &mut RegisterBank,
+    addr: u16,
+    values: &[u16],
+) -> Result<(), tokio_modbus::ExceptionCode> {
+    if values.is_empty() || values.len() > MAX_WRITE_REGISTERS.into() {
+        warn!(
+            "SERVER: Exception::IllegalDataValue, can't write {} registers",
+            values.len()
+        );
+        return Err(tokio_modbus::ExceptionCode::IllegalDataValue);
+    }
+    for i in 0..values.len() as u16 {
+        let writable = addr
+            .checked_add(i)
+            .filter(|reg_addr| !sunspec_map::READ_ONLY_REGISTERS.contains(reg_addr))
+            .is_some_and(|reg_addr| {
+                registers.contains(reg_addr) || sunspec_map::is_valid_address(reg_addr)
+            });
+        if !writable {
+            warn!(
+                "SERVER: Exception::IllegalDataAddress, can't write {addr}/{}",
+                values.len()
             );
             return Err(tokio_modbus::ExceptionCode::IllegalDataAddress);
         }
     }
-    // println!("Register read for addr:{addr} count:{cnt} returns {response_values:?}");
-    Ok(response_values)
+    for (reg_addr, value) in (addr..).zip(values) {
+        registers.set(reg_addr, *value);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `request` as an inverter sends it, addressed to the meter's default unit
+    fn to_meter(request: Request<'static>) -> SlaveRequest<'static> {
+        SlaveRequest {
+            slave: sunspec_map::DEFAULT_UNIT_ID,
+            request,
+        }
+    }
+
+    #[test]
+    fn test_read_quantity_limits() {
+        let registers =
+            sunspec_map::seed_registers(sunspec_map::DEFAULT_UNIT_ID, MeterTopology::default());
+        for framing in [FramingMode::Strict, FramingMode::Lenient] {
+            assert_eq!(
+                register_read(&registers, 40000, 126, framing),
+                Err(tokio_modbus::ExceptionCode::IllegalDataValue)
+            );
+            assert_eq!(
+                register_read(&registers, 40000, MAX_READ_REGISTERS, framing).map(|r| r.len()),
+                Ok(125)
+            );
+        }
+        assert_eq!(
+            register_read(&registers, 40000, 0, FramingMode::Strict),
+            Err(tokio_modbus::ExceptionCode::IllegalDataValue)
+        );
+        assert_eq!(
+            register_read(&registers, 40000, 0, FramingMode::Lenient),
+            Ok(vec![])
+        );
+    }
+
+    #[test]
+    fn test_empty_registers_in_the_model_read_as_zero() {
+        let registers = sunspec_map::seed_registers(
+            sunspec_map::DEFAULT_UNIT_ID,
+            sunspec_map::MeterTopology::default(),
+        );
+        // The reactive energy counters aren't seeded, but are part of the meter model
+        assert!(!registers.contains(40161));
+        assert_eq!(
+            register_read(&registers, 40159, 4, FramingMode::Strict),
+            Ok(vec![0; 4])
+        );
+        // Past the end marker there is nothing
+        assert_eq!(
+            register_read(&registers, 40195, 3, FramingMode::Strict),
+            Err(tokio_modbus::ExceptionCode::IllegalDataAddress)
+        );
+        assert_eq!(
+            register_read(&registers, 20000, 1, FramingMode::Strict),
+            Err(tokio_modbus::ExceptionCode::IllegalDataAddress)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_current_readings() {
+        let (emulator, update_handle) = SmartMeterEmulator::new();
+        update_handle
+            .send(Readings::Batch(vec![
+                Readings::TotalRealPower(-1234.5),
+                Readings::PhaseAVoltage(231.25),
+            ]))
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(10)).await;
+        let readings = emulator.current_readings().await;
+        assert_eq!(readings["TotalRealPower"], -1234.5);
+        assert_eq!(readings["PhaseAVoltage"], 231.3);
+        assert_eq!(readings["PhaseBVoltage"], 230.0);
+        assert_eq!(readings["ApparentPower"], 0.0);
+        assert_eq!(readings.len(), sunspec_map::MEASUREMENT_FIELDS.len());
+    }
+
+    #[tokio::test]
+    async fn test_floats_follow_the_layout() {
+        use tokio_modbus::server::Service;
+        for layout in FloatLayout::ALL {
+            let (emulator, update_handle) = SmartMeterEmulator::with_options(EmulatorOptions {
+                float_layout: layout,
+                ..Default::default()
+            });
+            update_handle
+                .send(Readings::TotalRealPower(1234.5678))
+                .await
+                .unwrap();
+            while !emulator.has_readings.load(Ordering::Relaxed) {
+                tokio::task::yield_now().await;
+            }
+            let response = emulator
+                .call(to_meter(Request::ReadHoldingRegisters(
+                    sunspec_map::TOTAL_REAL_POWER.address,
+                    2,
+                )))
+                .await;
+            let Ok(Some(Response::ReadHoldingRegisters(words))) = response else {
+                panic!("{layout:?}: {response:?}");
+            };
+            assert_eq!(words, layout.encode(1234.5678), "{layout:?}");
+            assert_eq!(layout.decode([words[0], words[1]]), 1234.5678);
+            // Read back as the inverter would
+            assert_eq!(emulator.snapshot().await.total_real_power, 1234.5678);
+            assert_eq!(emulator.snapshot().await.frequency, 50.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nominal_values_before_readings() {
+        let (emulator, _update_handle) = SmartMeterEmulator::new();
+        let snapshot = emulator.snapshot().await;
+        assert_eq!(snapshot.frequency, 50.0);
+        assert_eq!(snapshot.average_phase_voltage, 230.0);
+        assert_eq!(snapshot.phase_voltages, [230.0; 3]);
+        assert_eq!(snapshot.total_real_power, 0.0);
+
+        let (emulator, update_handle) = SmartMeterEmulator::with_options(EmulatorOptions {
+            nominal_voltage: 120.0,
+            nominal_frequency: 60.0,
+            ..Default::default()
+        });
+        let snapshot = emulator.snapshot().await;
+        assert_eq!(snapshot.frequency, 60.0);
+        assert_eq!(snapshot.phase_voltages, [120.0; 3]);
+        // Measured values replace them
+        update_handle
+            .send(Readings::Frequency(59.98))
+            .await
+            .unwrap();
+        while !emulator.has_readings.load(Ordering::Relaxed) {
+            tokio::task::yield_now().await;
+        }
+        let snapshot = emulator.snapshot().await;
+        assert_eq!(snapshot.frequency, 59.98);
+        assert_eq!(snapshot.phase_voltages, [120.0; 3]);
+    }
+
+    #[tokio::test]
+    async fn test_map_is_valid() {
+        let (emulator, _update_handle) = SmartMeterEmulator::new();
+        assert_eq!(emulator.validate_map().await, Ok(()));
+        for topology in MeterTopology::ALL {
+            let (emulator, _update_handle) = SmartMeterEmulator::builder()
+                .serial_number("a serial number that is far too long")
+                .options(EmulatorOptions {
+                    topology,
+                    ..Default::default()
+                })
+                .build();
+            assert_eq!(emulator.validate_map().await, Ok(()), "{topology:?}");
+        }
+
+        let (emulator, _update_handle) = SmartMeterEmulator::new();
+        emulator
+            .holding_registers
+            .write()
+            .await
+            .set(sunspec_map::METER_MODEL_REGISTER + 1, 125);
+        assert!(matches!(
+            emulator.validate_map().await,
+            Err(MapError::BadModelLength { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_snapshot() {
+        let (emulator, update_handle) = SmartMeterEmulator::new();
+        assert_eq!(emulator.snapshot().await.total_real_power, 0.0);
+        update_handle
+            .send(Readings::Batch(vec![
+                Readings::TotalRealPower(-1500.0),
+                Readings::PhaseAWatts(-400.0),
+                Readings::PhaseBWatts(-500.0),
+                Readings::PhaseCWatts(-600.0),
+                Readings::PhaseBVoltage(229.5),
+                Readings::PhaseCAVoltage(398.0),
+                Readings::Frequency(50.02),
+            ]))
+            .await
+            .unwrap();
+        while !emulator.has_readings.load(Ordering::Relaxed) {
+            tokio::task::yield_now().await;
+        }
+        let snapshot = emulator.snapshot().await;
+        assert_eq!(snapshot.total_real_power, -1500.0);
+        assert_eq!(snapshot.phase_watts, [-400.0, -500.0, -600.0]);
+        assert_eq!(snapshot.phase_voltages, [230.0, 229.5, 230.0]);
+        assert_eq!(snapshot.line_voltages, [0.0, 0.0, 398.0]);
+        assert_eq!(snapshot.frequency, 50.02);
+        assert_eq!(snapshot.apparent_power, 0.0);
+        // The same values as the by-name readings
+        let readings = emulator.current_readings().await;
+        assert_eq!(snapshot.total_real_power, readings["TotalRealPower"]);
+        assert_eq!(snapshot.phase_watts[2], readings["PhaseCWatts"]);
+    }
+
+    #[test]
+    fn test_describe_read() {
+        // 1024.0 is 0x44800000
+        assert_eq!(
+            describe_read(40097, &[0x4480, 0x0000], FloatLayout::Abcd),
+            "TotalRealPower=1024"
+        );
+        assert_eq!(
+            describe_read(40095, &[0, 0, 0x4480, 0, 0], FloatLayout::Abcd),
+            "Frequency=0, TotalRealPower=1024, Meter readings"
+        );
+        assert_eq!(
+            describe_read(40000, &[0x5375, 0x6e53, 1, 65], FloatLayout::Abcd),
+            "SunSpec marker, Common model header"
+        );
+        assert_eq!(describe_read(40161, &[0, 0], FloatLayout::Abcd), "unmapped");
+    }
+
+    #[test]
+    fn test_read_past_end_of_address_space() {
+        // Register 0 exists, so a read that wrapped around would succeed
+        let registers = RegisterBank::from_iter([(u16::MAX, 1), (0, 2)]);
+        assert_eq!(
+            register_read(&registers, u16::MAX, 1, FramingMode::Strict),
+            Ok(vec![1])
+        );
+        assert_eq!(
+            register_read(&registers, u16::MAX, 2, FramingMode::Strict),
+            Err(tokio_modbus::ExceptionCode::IllegalDataAddress)
+        );
+    }
+
+    #[test]
+    fn test_parse_framing_mode() {
+        assert_eq!("Strict".parse(), Ok(FramingMode::Strict));
+        assert_eq!(" lenient".parse(), Ok(FramingMode::Lenient));
+        assert!("loose".parse::<FramingMode>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_builder_sets_the_identity() {
+        use tokio_modbus::server::Service;
+        let (emulator, update_handle) = SmartMeterEmulator::builder()
+            .manufacturer("Acme")
+            .serial_number("SN-2024-0042")
+            .channel_capacity(4)
+            .options(EmulatorOptions {
+                unit_id: 1,
+                ..Default::default()
+            })
+            .build();
+        assert_eq!(update_handle.max_capacity(), 4);
+        let text_at = |start| {
+            let response = emulator.call(to_meter(Request::ReadHoldingRegisters(
+                start,
+                sunspec_map::TEXT_FIELD_LEN,
+            )));
+            async move {
+                let Ok(Some(Response::ReadHoldingRegisters(words))) = response.await else {
+                    panic!("The text registers should be readable");
+                };
+                let bytes: Vec<u8> = words.iter().map(|&word| word as u8).collect();
+                String::from_utf8(bytes).unwrap()
+            }
+        };
+        assert_eq!(
+            text_at(sunspec_map::SERIAL_NUMBER_REGISTER).await,
+            "SN-2024-0042\0\0\0\0"
+        );
+        assert_eq!(
+            text_at(sunspec_map::MANUFACTURER_REGISTER).await,
+            format!("Acme{}", "\0".repeat(12))
+        );
+        // Unset fields keep the Fronius defaults, and the options still apply
+        assert!(text_at(sunspec_map::MODEL_REGISTER)
+            .await
+            .starts_with("Smart Meter 63A"));
+        let registers = emulator.holding_registers.read().await;
+        assert_eq!(registers.get(sunspec_map::MODBUS_ADDRESS_REGISTER), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_readings_are_rounded() {
+        let (emulator, update_handle) = SmartMeterEmulator::with_options(EmulatorOptions {
+            precision: Precision {
+                voltage_decimals: 0,
+                current_decimals: 1,
+                frequency_decimals: 3,
+            },
+            ..Default::default()
+        });
+        update_handle
+            .send(Readings::Batch(vec![
+                Readings::PhaseAVoltage(231.46),
+                Readings::PhaseACurrent(4.26),
+                Readings::Frequency(49.98765),
+                Readings::TotalRealPower(980.123),
+            ]))
+            .await
+            .unwrap();
+        // Wait for the handler task to apply the batch
+        while !emulator.has_readings.load(Ordering::Relaxed) {
+            tokio::task::yield_now().await;
+        }
+        let registers = emulator.holding_registers.read().await;
+        let read = |field: FieldInfo| {
+            let words = register_read(&registers, field.address, 2, FramingMode::Strict).unwrap();
+            f32::from_bits((words[0] as u32) << 16 | words[1] as u32)
+        };
+        assert_eq!(read(sunspec_map::PHASE_A_VOLTAGE), 231.0);
+        assert_eq!(read(sunspec_map::PHASE_A_CURRENT), 4.3);
+        assert_eq!(read(sunspec_map::FREQUENCY), 49.988);
+        assert_eq!(read(sunspec_map::TOTAL_REAL_POWER), 980.123);
+    }
+
+    #[tokio::test]
+    async fn test_readings_are_scaled() {
+        let (emulator, update_handle) = SmartMeterEmulator::with_options(EmulatorOptions {
+            scale_factors: parse_scale_factors("TotalRealPower=0.001, PhaseACurrent=10").unwrap(),
+            ..Default::default()
+        });
+        update_handle
+            .send(Readings::Batch(vec![
+                Readings::TotalRealPower(2500.0),
+                Readings::PhaseACurrent(1.5),
+                Readings::PhaseAWatts(800.0),
+            ]))
+            .await
+            .unwrap();
+        while !emulator.has_readings.load(Ordering::Relaxed) {
+            tokio::task::yield_now().await;
+        }
+        let registers = emulator.holding_registers.read().await;
+        let words = |field: FieldInfo| {
+            register_read(&registers, field.address, 2, FramingMode::Strict).unwrap()
+        };
+        // 2.5 is 0x40200000
+        assert_eq!(words(sunspec_map::TOTAL_REAL_POWER), vec![0x4020, 0x0000]);
+        // 15.0 is 0x41700000
+        assert_eq!(words(sunspec_map::PHASE_A_CURRENT), vec![0x4170, 0x0000]);
+        // Unscaled, 800.0 is 0x44480000
+        assert_eq!(words(sunspec_map::PHASE_A_WATTS), vec![0x4448, 0x0000]);
+    }
+
+    #[tokio::test]
+    async fn test_phase_c_va_register() {
+        let (emulator, update_handle) = SmartMeterEmulator::new();
+        let untouched = emulator.holding_registers.read().await.get(4011);
+        update_handle
+            .send(Readings::PhaseCVA(1234.0))
+            .await
+            .unwrap();
+        while !emulator.has_readings.load(Ordering::Relaxed) {
+            tokio::task::yield_now().await;
+        }
+        let registers = emulator.holding_registers.read().await;
+        let words = register_read(&registers, 40111, 2, FramingMode::Strict).unwrap();
+        assert_eq!(
+            f32::from_bits((words[0] as u32) << 16 | words[1] as u32),
+            1234.0
+        );
+        assert_eq!(registers.get(4011), untouched);
+    }
+
+    #[tokio::test]
+    async fn test_readings_create_unseeded_registers() {
+        let (emulator, update_handle) = SmartMeterEmulator::new();
+        let field = sunspec_map::PHASE_C_PF;
+        {
+            let mut registers = emulator.holding_registers.write().await;
+            registers.remove(field.address);
+            registers.remove(field.address + 1);
+            assert!(!registers.contains(field.address));
+        }
+        update_handle.send(Readings::PhaseCPF(0.5)).await.unwrap();
+        while !emulator.has_readings.load(Ordering::Relaxed) {
+            tokio::task::yield_now().await;
+        }
+        let registers = emulator.holding_registers.read().await;
+        // 0.5 is 0x3F000000
+        assert_eq!(
+            register_read(&registers, field.address, 2, FramingMode::Strict),
+            Ok(vec![0x3F00, 0x0000])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_meter_handle_outlives_its_sender() {
+        let (emulator, sender) = SmartMeterEmulator::with_options(EmulatorOptions {
+            update_timeout: None,
+            ..Default::default()
+        });
+        let handle = MeterHandle::from(sender);
+        handle.set_phase_voltage(Phase::B, 239.5).await.unwrap();
+        handle.set_frequency(49.5).await.unwrap();
+        drop(handle);
+        // The handler stops once the channel closes, rather than taking the process with it
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let registers = emulator.holding_registers.read().await;
+        let read_f32 = |field: FieldInfo| {
+            let words = register_read(&registers, field.address, 2, FramingMode::Strict).unwrap();
+            f32::from_bits((words[0] as u32) << 16 | words[1] as u32)
+        };
+        assert_eq!(read_f32(sunspec_map::PHASE_B_VOLTAGE), 239.5);
+        assert_eq!(read_f32(sunspec_map::FREQUENCY), 49.5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_energy_is_accumulated() {
+        let (emulator, update_handle) = SmartMeterEmulator::with_options(EmulatorOptions {
+            update_timeout: None,
+            ..Default::default()
+        });
+        let half_hour = Duration::from_secs(1800);
+        update_handle
+            .send(Readings::TotalRealPower(1200.0))
+            .await
+            .unwrap();
+        sleep(half_hour).await;
+        update_handle
+            .send(Readings::Batch(vec![
+                Readings::NetACCurrent(-2.6),
+                Readings::TotalRealPower(-600.0),
+            ]))
+            .await
+            .unwrap();
+        sleep(2 * half_hour).await;
+        // Other readings don't count
+        update_handle.send(Readings::Frequency(50.0)).await.unwrap();
+        sleep(half_hour).await;
+        update_handle
+            .send(Readings::TotalRealPower(0.0))
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(1)).await;
+
+        let registers = emulator.holding_registers.read().await;
+        let read_f32 = |field: FieldInfo| {
+            let words = register_read(&registers, field.address, 2, FramingMode::Strict).unwrap();
+            f32::from_bits((words[0] as u32) << 16 | words[1] as u32)
+        };
+        assert_eq!(read_f32(sunspec_map::TOTAL_WH_IMPORTED), 600.0);
+        assert_eq!(read_f32(sunspec_map::TOTAL_WH_EXPORTED), 900.0);
+    }
+
+    #[test]
+    fn test_parse_scale_factors() {
+        assert_eq!(
+            parse_scale_factors("TotalRealPower=0.001,PhaseAWatts = 2"),
+            Ok(BTreeMap::from([
+                ("PhaseAWatts".to_string(), 2.0),
+                ("TotalRealPower".to_string(), 0.001)
+            ]))
+        );
+        assert_eq!(parse_scale_factors(""), Ok(BTreeMap::new()));
+        assert!(parse_scale_factors("TotalPower=0.001").is_err());
+        assert!(parse_scale_factors("TotalRealPower").is_err());
+        assert!(parse_scale_factors("TotalRealPower=kW").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unimplemented_function_response() {
+        use tokio_modbus::server::Service;
+        for (unimplemented, expected) in [
+            (
+                UnimplementedResponse::IllegalFunction,
+                Err(tokio_modbus::ExceptionCode::IllegalFunction),
+            ),
+            (
+                UnimplementedResponse::IllegalDataAddress,
+                Err(tokio_modbus::ExceptionCode::IllegalDataAddress),
+            ),
+            (UnimplementedResponse::NoResponse, Ok(None)),
+        ] {
+            let (emulator, _update_handle) = SmartMeterEmulator::with_options(EmulatorOptions {
+                unimplemented,
+                ..Default::default()
+            });
+            let response = emulator.call(to_meter(Request::ReadCoils(0, 1))).await;
+            assert_eq!(response, expected, "{unimplemented:?}");
+            // Implemented functions are unaffected
+            let response = emulator
+                .call(to_meter(Request::ReadHoldingRegisters(40000, 1)))
+                .await;
+            assert_eq!(
+                response,
+                Ok(Some(Response::ReadHoldingRegisters(vec![0x5375])))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_register_writes() {
+        use tokio_modbus::server::Service;
+        let (emulator, _tx) = SmartMeterEmulator::new();
+        let response = emulator
+            .call(to_meter(Request::WriteMultipleRegisters(
+                50000,
+                vec![1, 2].into(),
+            )))
+            .await;
+        assert_eq!(
+            response,
+            Ok(Some(Response::WriteMultipleRegisters(50000, 2)))
+        );
+        let response = emulator
+            .call(to_meter(Request::ReadHoldingRegisters(50000, 2)))
+            .await;
+        assert_eq!(
+            response,
+            Ok(Some(Response::ReadHoldingRegisters(vec![1, 2])))
+        );
+
+        // The SunSpec identification block can't be changed, even as part of a larger write
+        for request in [
+            Request::WriteSingleRegister(40000, 1),
+            Request::WriteSingleRegister(40070, 1),
+            Request::WriteMultipleRegisters(40069, vec![1, 2, 3].into()),
+        ] {
+            let response = emulator.call(to_meter(request)).await;
+            assert_eq!(
+                response,
+                Err(tokio_modbus::ExceptionCode::IllegalDataAddress)
+            );
+        }
+        // Nor can registers the meter doesn't have
+        let response = emulator
+            .call(to_meter(Request::WriteSingleRegister(50002, 1)))
+            .await;
+        assert_eq!(
+            response,
+            Err(tokio_modbus::ExceptionCode::IllegalDataAddress)
+        );
+
+        let response = emulator
+            .call(to_meter(Request::ReadHoldingRegisters(40069, 3)))
+            .await;
+        assert_eq!(
+            response,
+            Ok(Some(Response::ReadHoldingRegisters(vec![213, 124, 0])))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fault_injection() {
+        use tokio_modbus::server::Service;
+        let (emulator, _update_handle) = SmartMeterEmulator::new();
+        let faults = FaultInjection {
+            delay: Duration::from_millis(30),
+            jitter: Duration::from_millis(10),
+            drop_probability: 0.0,
+        };
+        let delayed = emulator.clone().with_fault_injection(faults);
+        let started = Instant::now();
+        let response = delayed
+            .call(to_meter(Request::ReadHoldingRegisters(40000, 1)))
+            .await;
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        assert_eq!(
+            response,
+            Ok(Some(Response::ReadHoldingRegisters(vec![0x5375])))
+        );
+
+        let dropping = emulator.with_fault_injection(FaultInjection {
+            delay: Duration::ZERO,
+            jitter: Duration::ZERO,
+            drop_probability: 1.0,
+        });
+        let response = dropping
+            .call(to_meter(Request::ReadHoldingRegisters(40000, 1)))
+            .await;
+        assert_eq!(response, Ok(None));
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_until_the_next_reading() {
+        use tokio_modbus::server::Service;
+        let (emulator, update_handle) = SmartMeterEmulator::new();
+        let read_power = || emulator.call(to_meter(Request::ReadHoldingRegisters(40097, 2)));
+        update_handle
+            .send(Readings::TotalRealPower(1000.0))
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(10)).await;
+        assert!(read_power().await.is_ok());
+
+        update_handle.send(Readings::Unavailable).await.unwrap();
+        sleep(Duration::from_millis(10)).await;
+        assert_eq!(
+            read_power().await,
+            Err(tokio_modbus::ExceptionCode::ServerDeviceFailure)
+        );
+
+        update_handle
+            .send(Readings::TotalRealPower(500.0))
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(10)).await;
+        let words = [0x43FA, 0x0000];
+        assert_eq!(
+            read_power().await,
+            Ok(Some(Response::ReadHoldingRegisters(words.to_vec())))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delay_serving_without_a_usable_deadline() {
+        let (emulator, update_handle) = SmartMeterEmulator::new();
+        assert!(emulator.is_ready_to_serve());
+        let emulator = emulator.delay_serving_until_ready(Duration::MAX);
+        assert!(!emulator.is_ready_to_serve());
+        update_handle
+            .send(Readings::TotalRealPower(1000.0))
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(10)).await;
+        assert!(emulator.is_ready_to_serve());
+        let (emulator, _update_handle) = SmartMeterEmulator::new();
+        assert!(emulator
+            .delay_serving_until_ready(Duration::ZERO)
+            .is_ready_to_serve());
+    }
+
+    #[tokio::test]
+    async fn test_input_registers_mirror_the_sunspec_map() {
+        use tokio_modbus::server::Service;
+        let (emulator, update_handle) = SmartMeterEmulator::new();
+        update_handle
+            .send(Readings::TotalRealPower(500.0))
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(10)).await;
+        for (addr, cnt) in [(40097, 2), (40000, 4), (40071, MAX_READ_REGISTERS)] {
+            let input = emulator
+                .call(to_meter(Request::ReadInputRegisters(addr, cnt)))
+                .await;
+            let holding = emulator
+                .call(to_meter(Request::ReadHoldingRegisters(addr, cnt)))
+                .await;
+            let (
+                Ok(Some(Response::ReadInputRegisters(input))),
+                Ok(Some(Response::ReadHoldingRegisters(holding))),
+            ) = (input, holding)
+            else {
+                panic!("Both reads of {addr}/{cnt} should succeed");
+            };
+            assert_eq!(input, holding, "{addr}/{cnt}");
+        }
+        // 500.0 is 0x43FA0000
+        let response = emulator
+            .call(to_meter(Request::ReadInputRegisters(40097, 2)))
+            .await;
+        assert_eq!(
+            response,
+            Ok(Some(Response::ReadInputRegisters(vec![0x43FA, 0x0000])))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reads_share_the_registers() {
+        use tokio_modbus::server::Service;
+        let (emulator, _update_handle) = SmartMeterEmulator::new();
+        // A read that is still going doesn't hold up any of the others
+        let _in_progress = emulator.holding_registers.read().await;
+        let mut reads = tokio::task::JoinSet::new();
+        for _ in 0..100 {
+            let emulator = emulator.clone();
+            reads.spawn(async move {
+                emulator
+                    .call(to_meter(Request::ReadHoldingRegisters(40000, 2)))
+                    .await
+            });
+        }
+        let responses = timeout(Duration::from_secs(1), reads.join_all())
+            .await
+            .expect("Concurrent reads shouldn't wait on each other");
+        assert!(responses
+            .iter()
+            .all(|response| response
+                == &Ok(Some(Response::ReadHoldingRegisters(vec![0x5375, 0x6E53])))));
+    }
+
+    #[tokio::test]
+    async fn test_answer_only_unit_id() {
+        use tokio_modbus::server::Service;
+        let (emulator, _update_handle) = SmartMeterEmulator::new();
+        let other_unit = SlaveRequest {
+            slave: 1,
+            request: Request::ReadHoldingRegisters(40000, 1),
+        };
+        // Any unit is answered by default
+        let response = emulator.call(other_unit.clone()).await;
+        assert_eq!(
+            response,
+            Ok(Some(Response::ReadHoldingRegisters(vec![0x5375])))
+        );
+
+        let filtered = emulator.answer_only_unit_id(sunspec_map::DEFAULT_UNIT_ID);
+        assert_eq!(filtered.call(other_unit).await, Ok(None));
+        let response = filtered
+            .call(to_meter(Request::ReadHoldingRegisters(40000, 1)))
+            .await;
+        assert_eq!(
+            response,
+            Ok(Some(Response::ReadHoldingRegisters(vec![0x5375])))
+        );
+    }
+
+    #[test]
+    fn test_parse_unimplemented_response() {
+        assert_eq!(
+            "illegal_function".parse(),
+            Ok(UnimplementedResponse::IllegalFunction)
+        );
+        assert_eq!(
+            "Illegal_Data_Address".parse(),
+            Ok(UnimplementedResponse::IllegalDataAddress)
+        );
+        assert_eq!("none".parse(), Ok(UnimplementedResponse::NoResponse));
+        assert!("silent".parse::<UnimplementedResponse>().is_err());
+    }
 }
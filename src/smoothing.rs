@@ -0,0 +1,219 @@
+use std::{collections::VecDeque, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::rolling_average::RollingAverage;
+
+// Filters applied to the HA offset before it is combined
+
+/// Takes a stream of samples and returns the filtered value after each one
+pub trait Smoother: Send {
+    fn add(&mut self, value: f32) -> f32;
+}
+
+impl Smoother for RollingAverage {
+    fn add(&mut self, value: f32) -> f32 {
+        RollingAverage::add(self, value)
+    }
+}
+
+/// How the offset is smoothed when smoothing is turned on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SmoothingMode {
+    /// Rolling average, which smears spikes across the window
+    Mean,
+    /// Rolling median, which rejects isolated spikes outright
+    Median,
+    /// Median to reject spikes, feeding the rolling average to smooth what's left
+    MedianMean,
+    /// Exponential moving average, which follows steps faster than the rolling average
+    Ema,
+}
+
+impl SmoothingMode {
+    pub fn uses_median(self) -> bool {
+        matches!(self, Self::Median | Self::MedianMean)
+    }
+
+    pub fn uses_mean(self) -> bool {
+        matches!(self, Self::Mean | Self::MedianMean)
+    }
+
+    pub fn uses_ema(self) -> bool {
+        self == Self::Ema
+    }
+}
+
+impl FromStr for SmoothingMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "mean" | "rolling" => Ok(Self::Mean),
+            "median" => Ok(Self::Median),
+            "median_mean" => Ok(Self::MedianMean),
+            "ema" => Ok(Self::Ema),
+            other => Err(format!("Unknown smoothing mode `{other}`")),
+        }
+    }
+}
+
+/// The median of the last `window` samples.
+/// Unlike the average this reports as soon as it has a sample, using what it has so far.
+#[derive(Debug, Clone)]
+pub struct Median {
+    window: usize,
+    samples: VecDeque<f32>,
+}
+
+impl Median {
+    pub fn new(window: usize) -> Self {
+        let window = window.max(1);
+        Self {
+            window,
+            samples: VecDeque::with_capacity(window),
+        }
+    }
+}
+
+impl Smoother for Median {
+    fn add(&mut self, value: f32) -> f32 {
+        if self.samples.len() == self.window {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(value);
+        let mut sorted: Vec<f32> = self.samples.iter().copied().collect();
+        sorted.sort_by(f32::total_cmp);
+        let middle = sorted.len() / 2;
+        if sorted.len().is_multiple_of(2) {
+            (sorted[middle - 1] + sorted[middle]) / 2.0
+        } else {
+            sorted[middle]
+        }
+    }
+}
+
+/// Weights each new sample by `alpha` against everything before it, so old samples fade away
+/// rather than dropping out of a window all at once.
+/// Like the median it reports from the first sample, which it starts from.
+#[derive(Debug, Clone)]
+pub struct ExponentialMovingAverage {
+    alpha: f32,
+    current: Option<f32>,
+}
+
+impl ExponentialMovingAverage {
+    /// `alpha` is between 0 and 1, where 1 follows the input exactly.
+    /// Anything that can't be used is treated as 1, turning the smoothing off.
+    pub fn new(alpha: f32) -> Self {
+        let alpha = if alpha > 0.0 { alpha.min(1.0) } else { 1.0 };
+        Self {
+            alpha,
+            current: None,
+        }
+    }
+}
+
+impl Smoother for ExponentialMovingAverage {
+    fn add(&mut self, value: f32) -> f32 {
+        let next = match self.current {
+            Some(current) => current + self.alpha * (value - current),
+            None => value,
+        };
+        self.current = Some(next);
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_median_rejects_single_outlier() {
+        let mut median = Median::new(5);
+        for _ in 0..5 {
+            assert_eq!(median.add(200.0), 200.0);
+        }
+        assert_eq!(median.add(9000.0), 200.0);
+        assert_eq!(median.add(200.0), 200.0);
+        assert_eq!(median.add(-9000.0), 200.0);
+    }
+
+    #[test]
+    fn test_median_follows_a_step() {
+        let mut median = Median::new(3);
+        median.add(100.0);
+        median.add(100.0);
+        assert_eq!(median.add(500.0), 100.0);
+        assert_eq!(median.add(500.0), 500.0);
+    }
+
+    #[test]
+    fn test_median_of_partial_window() {
+        let mut median = Median::new(5);
+        assert_eq!(median.add(10.0), 10.0);
+        assert_eq!(median.add(20.0), 15.0);
+        assert_eq!(median.add(0.0), 10.0);
+    }
+
+    #[test]
+    fn test_median_prefilter_keeps_spike_out_of_mean() {
+        let mut median = Median::new(3);
+        let mut mean = RollingAverage::new();
+        let mut smoothed = 0.0;
+        for step in 0..20 {
+            let value = if step == 12 { 10_000.0 } else { 300.0 };
+            smoothed = Smoother::add(&mut mean, median.add(value));
+        }
+        assert_eq!(smoothed, 300.0);
+    }
+
+    #[test]
+    fn test_ema_converges_on_constant_input() {
+        let mut ema = ExponentialMovingAverage::new(0.3);
+        assert_eq!(ema.add(0.0), 0.0);
+        let mut previous_error = f32::INFINITY;
+        for _ in 0..50 {
+            let error = 1000.0 - ema.add(1000.0);
+            assert!(error >= 0.0 && error <= previous_error);
+            previous_error = error;
+        }
+        assert!(previous_error < 0.1);
+    }
+
+    #[test]
+    fn test_ema_follows_a_step_faster_than_the_mean() {
+        let mut ema = ExponentialMovingAverage::new(0.3);
+        let mut mean = RollingAverage::new();
+        for _ in 0..mean.capacity() {
+            ema.add(0.0);
+            Smoother::add(&mut mean, 0.0);
+        }
+        // Count how long each takes to get 90% of the way to the new value
+        let settle = |smoother: &mut dyn Smoother| (1..).find(|_| smoother.add(100.0) >= 90.0);
+        let ema_steps = settle(&mut ema).unwrap();
+        let mean_steps = settle(&mut mean).unwrap();
+        assert!(ema_steps < mean_steps, "{ema_steps} vs {mean_steps}");
+    }
+
+    #[test]
+    fn test_ema_unusable_alpha_passes_through() {
+        for alpha in [0.0, -1.0, f32::NAN, 2.0] {
+            let mut ema = ExponentialMovingAverage::new(alpha);
+            ema.add(0.0);
+            assert_eq!(ema.add(500.0), 500.0, "alpha {alpha}");
+        }
+    }
+
+    #[test]
+    fn test_parse_smoothing_mode() {
+        assert_eq!("mean".parse(), Ok(SmoothingMode::Mean));
+        assert_eq!(" Median".parse(), Ok(SmoothingMode::Median));
+        assert_eq!("median_mean".parse(), Ok(SmoothingMode::MedianMean));
+        assert_eq!("EMA".parse(), Ok(SmoothingMode::Ema));
+        assert_eq!("rolling".parse(), Ok(SmoothingMode::Mean));
+        assert!("boxcar".parse::<SmoothingMode>().is_err());
+    }
+}
@@ -0,0 +1,773 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::register_bank::RegisterBank;
+
+// The register layout of the emulated Fronius Smart Meter, described as data.
+// The static values are seeded at startup, and the measurement fields describe where each
+// `Readings` value lands. SunSpec model 213 (three phase wye, float) follows the common model.
+
+/// How the registers of a block are filled when seeded
+#[derive(Debug, Clone, Copy)]
+pub enum BlockContents {
+    Values(&'static [u16]),
+    /// One ASCII character per register, zero padded to the block length
+    Text(&'static str),
+    Zeros,
+}
+
+/// A contiguous run of registers seeded at startup
+#[derive(Debug, Clone, Copy)]
+pub struct RegisterBlock {
+    pub name: &'static str,
+    pub start: u16,
+    pub len: u16,
+    pub contents: BlockContents,
+}
+
+impl RegisterBlock {
+    /// Returns the seeded value of each register in the block, in address order
+    pub fn values(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        (0..self.len).map(move |offset| {
+            let value = match self.contents {
+                BlockContents::Values(values) => values.get(offset as usize).copied(),
+                BlockContents::Text(text) => {
+                    text.as_bytes().get(offset as usize).map(|&c| c as u16)
+                }
+                BlockContents::Zeros => None,
+            };
+            (self.start + offset, value.unwrap_or_default())
+        })
+    }
+}
+
+/// Starts the map, identifying it as SunSpec
+pub const SUNSPEC_MARKER: [u16; 2] = [0x5375, 0x6e53];
+/// Where the SunSpec marker is, with the common model's header following it
+pub const MARKER_REGISTER: u16 = 40000;
+/// Follows the last model
+pub const END_MARKER: [u16; 2] = [0xFFFF, 0];
+/// The model id of the SunSpec common model
+const COMMON_MODEL_ID: u16 = 1;
+/// Where the common model reports who made the meter
+pub const MANUFACTURER_REGISTER: u16 = 40004;
+/// Where the common model reports what the meter is
+pub const MODEL_REGISTER: u16 = 40020;
+/// Where the common model reports the meter's serial number
+pub const SERIAL_NUMBER_REGISTER: u16 = 40052;
+/// How many characters the manufacturer, model and serial number each hold
+pub const TEXT_FIELD_LEN: u16 = 16;
+/// Where the common model reports the meter's Modbus address
+pub const MODBUS_ADDRESS_REGISTER: u16 = 40068;
+/// Where the meter model header reports which meter model follows
+pub const METER_MODEL_REGISTER: u16 = 40069;
+/// The address a real Fronius meter uses, and the one the seed table reports
+pub const DEFAULT_UNIT_ID: u8 = 240;
+/// The SunSpec marker, common model and meter model header, which identify the meter and can't be written
+pub const READ_ONLY_REGISTERS: std::ops::RangeInclusive<u16> = 40000..=40070;
+
+const fn block(name: &'static str, start: u16, len: u16, contents: BlockContents) -> RegisterBlock {
+    RegisterBlock {
+        name,
+        start,
+        len,
+        contents,
+    }
+}
+
+pub const SEED_BLOCKS: &[RegisterBlock] = &[
+    // Well-known value. Uniquely identifies this as a SunSpec Modbus Map
+    block(
+        "SunSpec marker",
+        MARKER_REGISTER,
+        2,
+        BlockContents::Values(&SUNSPEC_MARKER),
+    ),
+    block(
+        "Common model header",
+        40002,
+        2,
+        BlockContents::Values(&[1, 65]),
+    ),
+    block(
+        "Manufacturer",
+        MANUFACTURER_REGISTER,
+        TEXT_FIELD_LEN,
+        BlockContents::Text(DEFAULT_MANUFACTURER),
+    ),
+    block(
+        "Model",
+        MODEL_REGISTER,
+        TEXT_FIELD_LEN,
+        BlockContents::Text(DEFAULT_MODEL),
+    ),
+    block("Options", 40036, 8, BlockContents::Zeros),
+    block("Version", 40044, 8, BlockContents::Zeros),
+    block(
+        "Serial number",
+        SERIAL_NUMBER_REGISTER,
+        TEXT_FIELD_LEN,
+        BlockContents::Text(DEFAULT_SERIAL_NUMBER),
+    ),
+    block(
+        "Modbus address",
+        MODBUS_ADDRESS_REGISTER,
+        1,
+        BlockContents::Values(&[DEFAULT_UNIT_ID as u16]),
+    ),
+    // Y connected 3 phase (ABCN), 124 registers long
+    block(
+        "Meter model header",
+        METER_MODEL_REGISTER,
+        2,
+        BlockContents::Values(&[213, 124]),
+    ),
+    // 0 fill the "readings" address space
+    block("Meter readings", 40071, 90, BlockContents::Zeros),
+    block("Meter model tail", 40193, 2, BlockContents::Zeros),
+    // Terminates the readings blocks
+    block("End marker", 40195, 2, BlockContents::Values(&END_MARKER)),
+    // Probed by the inverter while looking for a SunSpec device
+    block("Sunspec model common", 0, 2, BlockContents::Values(&[1, 0])),
+    block("Probe 11", 11, 2, BlockContents::Zeros),
+    // Not SunSpec, so return 0 to mark us as SunSpec
+    block("Probe 768", 768, 1, BlockContents::Zeros),
+    block("Probe 1706", 1706, 1, BlockContents::Zeros),
+    block("Probe 50000", 50000, 2, BlockContents::Zeros),
+];
+
+/// The registers a real meter answers, whether or not anything is stored in them.
+/// Reads of these give 0 where nothing has been seeded or written, as a real meter's do;
+/// anything else is an illegal address.
+pub const VALID_RANGES: &[std::ops::RangeInclusive<u16>] = &[
+    // The probes an inverter makes while looking for a SunSpec device
+    0..=1,
+    11..=12,
+    768..=768,
+    1706..=1706,
+    // SunSpec marker, common model, meter model and the end marker
+    40000..=40196,
+    50000..=50001,
+];
+
+/// The seeded block holding `address`, if any
+pub fn block_at(address: u16) -> Option<&'static RegisterBlock> {
+    SEED_BLOCKS
+        .iter()
+        .find(|block| (block.start..block.start + block.len).contains(&address))
+}
+
+/// Whether `address` is in one of the `VALID_RANGES`
+pub fn is_valid_address(address: u16) -> bool {
+    VALID_RANGES.iter().any(|range| range.contains(&address))
+}
+
+/// Builds the register contents the meter starts with, reporting `unit_id` as its address
+/// and presenting as the meter model for `topology`
+pub fn seed_registers(unit_id: u8, topology: MeterTopology) -> RegisterBank {
+    let mut registers: RegisterBank = SEED_BLOCKS.iter().flat_map(RegisterBlock::values).collect();
+    registers.set(MODBUS_ADDRESS_REGISTER, unit_id.into());
+    registers.set(METER_MODEL_REGISTER, topology.model_id());
+    registers
+}
+
+const DEFAULT_MANUFACTURER: &str = "Fronius";
+const DEFAULT_MODEL: &str = "Smart Meter 63A";
+const DEFAULT_SERIAL_NUMBER: &str = "00000001";
+
+/// What the common model reports the meter as. The defaults are what the seed table holds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MeterIdentity {
+    pub manufacturer: String,
+    pub model: String,
+    pub serial_number: String,
+}
+
+impl Default for MeterIdentity {
+    fn default() -> Self {
+        Self {
+            manufacturer: DEFAULT_MANUFACTURER.to_string(),
+            model: DEFAULT_MODEL.to_string(),
+            serial_number: DEFAULT_SERIAL_NUMBER.to_string(),
+        }
+    }
+}
+
+impl MeterIdentity {
+    /// Writes each field one ASCII character per register, as the seed table does, cut to
+    /// `TEXT_FIELD_LEN` characters and zero padded
+    pub fn write_to(&self, registers: &mut RegisterBank) {
+        for (start, text) in [
+            (MANUFACTURER_REGISTER, &self.manufacturer),
+            (MODEL_REGISTER, &self.model),
+            (SERIAL_NUMBER_REGISTER, &self.serial_number),
+        ] {
+            let mut characters = text.bytes().map(u16::from);
+            for address in start..start + TEXT_FIELD_LEN {
+                registers.set(address, characters.next().unwrap_or_default());
+            }
+        }
+    }
+
+    /// The fields too long to fit in their registers, by name
+    pub fn overlong_fields(&self) -> impl Iterator<Item = &'static str> + '_ {
+        [
+            ("manufacturer", &self.manufacturer),
+            ("model", &self.model),
+            ("serial number", &self.serial_number),
+        ]
+        .into_iter()
+        .filter(|(_, text)| text.len() > usize::from(TEXT_FIELD_LEN))
+        .map(|(name, _)| name)
+    }
+}
+
+/// Why a register map isn't one an inverter could make sense of
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MapError {
+    /// The map doesn't start with the SunSpec marker
+    BadMarker([u16; 2]),
+    /// The model at `address` isn't the one that should be there
+    UnexpectedModel { address: u16, found: u16 },
+    /// The length in the model header at `address` doesn't lead to the next model
+    BadModelLength { address: u16, length: u16 },
+    /// A field lies outside the meter model, so the inverter never reads it
+    FieldOutsideModel { field: &'static str, address: u16 },
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMarker([high, low]) => write!(
+                f,
+                "The SunSpec marker reads {high:#06x} {low:#06x} rather than 0x5375 0x6e53"
+            ),
+            Self::UnexpectedModel { address, found } => {
+                write!(f, "Unexpected model {found} at {address}")
+            }
+            Self::BadModelLength { address, length } => write!(
+                f,
+                "The model at {address} is {length} registers long, which doesn't lead to the next model"
+            ),
+            Self::FieldOutsideModel { field, address } => {
+                write!(f, "{field} at {address} is outside the meter model")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MapError {}
+
+/// Checks `registers` hold a map an inverter can walk: the SunSpec marker, the common model,
+/// a float meter model whose length leads to the end marker, and every one of `fields` inside
+/// the meter model
+pub fn validate(registers: &RegisterBank, fields: &[FieldInfo]) -> Result<(), MapError> {
+    let word = |address: u16| registers.get(address).unwrap_or_default();
+    let marker = [word(MARKER_REGISTER), word(MARKER_REGISTER + 1)];
+    if marker != SUNSPEC_MARKER {
+        return Err(MapError::BadMarker(marker));
+    }
+    // Each model header is its id then its length, with the next header straight after
+    let next_header = |address: u16| {
+        let length = word(address + 1);
+        address
+            .checked_add(2)
+            .and_then(|body| body.checked_add(length))
+            .ok_or(MapError::BadModelLength { address, length })
+    };
+    let common = MARKER_REGISTER + 2;
+    if word(common) != COMMON_MODEL_ID {
+        return Err(MapError::UnexpectedModel {
+            address: common,
+            found: word(common),
+        });
+    }
+    let meter = next_header(common)?;
+    let meter_model_ids = MeterTopology::ALL.map(MeterTopology::model_id);
+    if !meter_model_ids.contains(&word(meter)) {
+        return Err(MapError::BadModelLength {
+            address: common,
+            length: word(common + 1),
+        });
+    }
+    let end = next_header(meter)?;
+    if end.checked_add(1).map(|last| [word(end), word(last)]) != Some(END_MARKER) {
+        return Err(MapError::BadModelLength {
+            address: meter,
+            length: word(meter + 1),
+        });
+    }
+    let body = u32::from(meter) + 2..=u32::from(end);
+    for field in fields {
+        let start = u32::from(field.address);
+        if !body.contains(&start) || !body.contains(&(start + u32::from(field.len))) {
+            return Err(MapError::FieldOutsideModel {
+                field: field.name,
+                address: field.address,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// How the meter is wired, which picks the SunSpec float meter model it presents as.
+/// The models share a layout, so only the advertised model number differs; the inverter
+/// ignores the phases a model doesn't have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MeterTopology {
+    SinglePhase,
+    SplitPhase,
+    /// Y connected, ABCN. What the real Fronius meter reports.
+    #[default]
+    ThreePhaseWye,
+    ThreePhaseDelta,
+}
+
+impl MeterTopology {
+    pub const ALL: [Self; 4] = [
+        Self::SinglePhase,
+        Self::SplitPhase,
+        Self::ThreePhaseWye,
+        Self::ThreePhaseDelta,
+    ];
+
+    pub fn model_id(self) -> u16 {
+        match self {
+            Self::SinglePhase => 211,
+            Self::SplitPhase => 212,
+            Self::ThreePhaseWye => 213,
+            Self::ThreePhaseDelta => 214,
+        }
+    }
+}
+
+impl FromStr for MeterTopology {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "single_phase" => Ok(Self::SinglePhase),
+            "split_phase" => Ok(Self::SplitPhase),
+            "three_phase_wye" => Ok(Self::ThreePhaseWye),
+            "three_phase_delta" => Ok(Self::ThreePhaseDelta),
+            other => Err(format!("Unknown meter topology `{other}`")),
+        }
+    }
+}
+
+/// The kind of value a measurement field holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantity {
+    Current,
+    Voltage,
+    Frequency,
+    Power,
+    PowerFactor,
+    /// Accumulated Wh
+    Energy,
+}
+
+/// A measurement in the meter model, stored as an f32 across two registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldInfo {
+    pub name: &'static str,
+    pub address: u16,
+    pub len: u16,
+    pub quantity: Quantity,
+}
+
+impl FieldInfo {
+    /// Returns true if a read of `cnt` registers from `addr` includes every register of the field
+    pub fn is_within(&self, addr: u16, cnt: u16) -> bool {
+        let end = addr as u32 + cnt as u32;
+        addr <= self.address && self.address as u32 + self.len as u32 <= end
+    }
+}
+
+const fn field(name: &'static str, address: u16, quantity: Quantity) -> FieldInfo {
+    FieldInfo {
+        name,
+        address,
+        len: 2,
+        quantity,
+    }
+}
+
+pub const NET_AC_CURRENT: FieldInfo = field("NetACCurrent", 40071, Quantity::Current);
+pub const PHASE_A_CURRENT: FieldInfo = field("PhaseACurrent", 40073, Quantity::Current);
+pub const PHASE_B_CURRENT: FieldInfo = field("PhaseBCurrent", 40075, Quantity::Current);
+pub const PHASE_C_CURRENT: FieldInfo = field("PhaseCCurrent", 40077, Quantity::Current);
+pub const AVERAGE_PHASE_VOLTAGE: FieldInfo = field("AveragePhaseVoltage", 40079, Quantity::Voltage);
+pub const PHASE_A_VOLTAGE: FieldInfo = field("PhaseAVoltage", 40081, Quantity::Voltage);
+pub const PHASE_B_VOLTAGE: FieldInfo = field("PhaseBVoltage", 40083, Quantity::Voltage);
+pub const PHASE_C_VOLTAGE: FieldInfo = field("PhaseCVoltage", 40085, Quantity::Voltage);
+pub const AVERAGE_LL_VOLTAGE: FieldInfo = field("AverageLLVoltage", 40087, Quantity::Voltage);
+pub const PHASE_AB_VOLTAGE: FieldInfo = field("PhaseABVoltage", 40089, Quantity::Voltage);
+pub const PHASE_BC_VOLTAGE: FieldInfo = field("PhaseBCVoltage", 40091, Quantity::Voltage);
+pub const PHASE_CA_VOLTAGE: FieldInfo = field("PhaseCAVoltage", 40093, Quantity::Voltage);
+pub const FREQUENCY: FieldInfo = field("Frequency", 40095, Quantity::Frequency);
+pub const TOTAL_REAL_POWER: FieldInfo = field("TotalRealPower", 40097, Quantity::Power);
+pub const PHASE_A_WATTS: FieldInfo = field("PhaseAWatts", 40099, Quantity::Power);
+pub const PHASE_B_WATTS: FieldInfo = field("PhaseBWatts", 40101, Quantity::Power);
+pub const PHASE_C_WATTS: FieldInfo = field("PhaseCWatts", 40103, Quantity::Power);
+pub const APPARENT_POWER: FieldInfo = field("ApparentPower", 40105, Quantity::Power);
+pub const PHASE_A_VA: FieldInfo = field("PhaseAVA", 40107, Quantity::Power);
+pub const PHASE_B_VA: FieldInfo = field("PhaseBVA", 40109, Quantity::Power);
+pub const PHASE_C_VA: FieldInfo = field("PhaseCVA", 40111, Quantity::Power);
+pub const REACTIVE_POWER: FieldInfo = field("ReactivePower", 40113, Quantity::Power);
+pub const PHASE_A_VAR: FieldInfo = field("PhaseAVAR", 40115, Quantity::Power);
+pub const PHASE_B_VAR: FieldInfo = field("PhaseBVAR", 40117, Quantity::Power);
+pub const PHASE_C_VAR: FieldInfo = field("PhaseCVAR", 40119, Quantity::Power);
+pub const POWER_FACTOR_TOTAL: FieldInfo = field("PowerFactorTotal", 40121, Quantity::PowerFactor);
+pub const PHASE_A_PF: FieldInfo = field("PhaseAPF", 40123, Quantity::PowerFactor);
+pub const PHASE_B_PF: FieldInfo = field("PhaseBPF", 40125, Quantity::PowerFactor);
+pub const PHASE_C_PF: FieldInfo = field("PhaseCPF", 40127, Quantity::PowerFactor);
+pub const TOTAL_WH_EXPORTED: FieldInfo = field("TotalWhExported", 40129, Quantity::Energy);
+pub const PHASE_A_WH_EXPORTED: FieldInfo = field("PhaseAWhExported", 40131, Quantity::Energy);
+pub const PHASE_B_WH_EXPORTED: FieldInfo = field("PhaseBWhExported", 40133, Quantity::Energy);
+pub const PHASE_C_WH_EXPORTED: FieldInfo = field("PhaseCWhExported", 40135, Quantity::Energy);
+pub const TOTAL_WH_IMPORTED: FieldInfo = field("TotalWhImported", 40137, Quantity::Energy);
+pub const PHASE_A_WH_IMPORTED: FieldInfo = field("PhaseAWhImported", 40139, Quantity::Energy);
+pub const PHASE_B_WH_IMPORTED: FieldInfo = field("PhaseBWhImported", 40141, Quantity::Energy);
+pub const PHASE_C_WH_IMPORTED: FieldInfo = field("PhaseCWhImported", 40143, Quantity::Energy);
+// Model 213 has no separate import and export power, so these take the last two reactive energy
+// counters (phase B and C, quadrant 4), which the emulator doesn't count and Fronius doesn't read
+pub const GRID_IMPORT_POWER: FieldInfo = field("GridImportPower", 40189, Quantity::Power);
+pub const GRID_EXPORT_POWER: FieldInfo = field("GridExportPower", 40191, Quantity::Power);
+
+/// Every measurement field, in address order
+pub const MEASUREMENT_FIELDS: &[FieldInfo] = &[
+    NET_AC_CURRENT,
+    PHASE_A_CURRENT,
+    PHASE_B_CURRENT,
+    PHASE_C_CURRENT,
+    AVERAGE_PHASE_VOLTAGE,
+    PHASE_A_VOLTAGE,
+    PHASE_B_VOLTAGE,
+    PHASE_C_VOLTAGE,
+    AVERAGE_LL_VOLTAGE,
+    PHASE_AB_VOLTAGE,
+    PHASE_BC_VOLTAGE,
+    PHASE_CA_VOLTAGE,
+    FREQUENCY,
+    TOTAL_REAL_POWER,
+    PHASE_A_WATTS,
+    PHASE_B_WATTS,
+    PHASE_C_WATTS,
+    APPARENT_POWER,
+    PHASE_A_VA,
+    PHASE_B_VA,
+    PHASE_C_VA,
+    REACTIVE_POWER,
+    PHASE_A_VAR,
+    PHASE_B_VAR,
+    PHASE_C_VAR,
+    POWER_FACTOR_TOTAL,
+    PHASE_A_PF,
+    PHASE_B_PF,
+    PHASE_C_PF,
+    TOTAL_WH_EXPORTED,
+    PHASE_A_WH_EXPORTED,
+    PHASE_B_WH_EXPORTED,
+    PHASE_C_WH_EXPORTED,
+    TOTAL_WH_IMPORTED,
+    PHASE_A_WH_IMPORTED,
+    PHASE_B_WH_IMPORTED,
+    PHASE_C_WH_IMPORTED,
+    GRID_IMPORT_POWER,
+    GRID_EXPORT_POWER,
+];
+
+/// Returns the measurement fields that a read of `cnt` registers from `addr` touches,
+/// including those it only partially covers. Use `FieldInfo::is_within` to tell them apart.
+pub fn sunspec_fields_in_range(addr: u16, cnt: u16) -> Vec<FieldInfo> {
+    let start = addr as u32;
+    let end = start + cnt as u32;
+    MEASUREMENT_FIELDS
+        .iter()
+        .filter(|field| {
+            let field_start = field.address as u32;
+            field_start < end && start < field_start + field.len as u32
+        })
+        .copied()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    // The imperative seeding this table replaced, kept to prove the layout is unchanged
+    fn legacy_seed_registers() -> HashMap<u16, u16> {
+        let mut holding_registers = HashMap::new();
+        let sun_spec_values: [u16; 101] = [
+            0x5375, 0x6e53, // Sun Spec marker
+            1, 65, // Num registers
+            70, 114, 111, 110, 105, 117, 115, 0, 0, 0, 0, 0, 0, 0, 0, 0, 83, 109, 97, 114, 116, 32,
+            77, 101, 116, 101, 114, 32, 54, 51, 65, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 48, 48, 48, 48, 48, 48, 48, 49, 0, 0, 0, 0, 0, 0, 0, 0,   //Block2
+            240, // Modbus address
+            213, // Y connected 3 phase (ABCN)
+            124, //End of static values
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            0,
+        ];
+        for (index, item) in sun_spec_values.iter().enumerate() {
+            holding_registers.insert(40000 + index as u16, *item);
+        }
+        let sun_spec_values_2: [u16; 16] = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        for (index, item) in sun_spec_values_2.iter().enumerate() {
+            holding_registers.insert(40129 + index as u16, *item);
+        }
+        for index in 40071..40161 {
+            holding_registers.insert(index as u16, 0);
+        }
+        holding_registers.insert(40193, 0);
+        holding_registers.insert(40194, 0);
+        holding_registers.insert(40195, 0xFFFF);
+        holding_registers.insert(40196, 0);
+        holding_registers.insert(0, 1);
+        holding_registers.insert(1, 0);
+        holding_registers.insert(11, 0);
+        holding_registers.insert(12, 0);
+        holding_registers.insert(768, 0);
+        holding_registers.insert(1706, 0);
+        holding_registers.insert(50000, 0);
+        holding_registers.insert(50001, 0);
+        holding_registers
+    }
+
+    #[test]
+    fn test_measurement_fields_are_contiguous() {
+        // The meter model packs its f32 fields back to back, so a typo in any address shows up
+        let (counted, split) = MEASUREMENT_FIELDS.split_at(MEASUREMENT_FIELDS.len() - 2);
+        for (index, field) in counted.iter().enumerate() {
+            assert_eq!(
+                field.address,
+                NET_AC_CURRENT.address + 2 * index as u16,
+                "{}",
+                field.name
+            );
+        }
+        // The import and export power end the reactive energy counters, just before the tail
+        assert_eq!(split, [GRID_IMPORT_POWER, GRID_EXPORT_POWER]);
+        assert_eq!(GRID_IMPORT_POWER.address + 2, GRID_EXPORT_POWER.address);
+        assert_eq!(GRID_EXPORT_POWER.address + 2, 40193);
+    }
+
+    #[test]
+    fn test_table_matches_legacy_seed() {
+        assert_eq!(
+            seed_registers(DEFAULT_UNIT_ID, MeterTopology::default()),
+            legacy_seed_registers().into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn test_default_identity_matches_the_seed() {
+        let seeded = seed_registers(DEFAULT_UNIT_ID, MeterTopology::default());
+        let mut registers = seeded.clone();
+        MeterIdentity::default().write_to(&mut registers);
+        assert_eq!(registers, seeded);
+        assert_eq!(MeterIdentity::default().overlong_fields().count(), 0);
+    }
+
+    #[test]
+    fn test_identity_is_cut_to_fit() {
+        let identity = MeterIdentity {
+            model: "Smart Meter TS 65A-3 with a long name".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            identity.overlong_fields().collect::<Vec<_>>(),
+            vec!["model"]
+        );
+        let mut registers = seed_registers(DEFAULT_UNIT_ID, MeterTopology::default());
+        identity.write_to(&mut registers);
+        let model: String = (MODEL_REGISTER..MODEL_REGISTER + TEXT_FIELD_LEN)
+            .map(|address| registers.get(address).unwrap() as u8 as char)
+            .collect();
+        assert_eq!(model, "Smart Meter TS 6");
+    }
+
+    #[test]
+    fn test_seeded_map_is_valid() {
+        for topology in MeterTopology::ALL {
+            let registers = seed_registers(DEFAULT_UNIT_ID, topology);
+            assert_eq!(
+                validate(&registers, MEASUREMENT_FIELDS),
+                Ok(()),
+                "{topology:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_corrupted_maps_are_caught() {
+        let seeded = seed_registers(DEFAULT_UNIT_ID, MeterTopology::default());
+        let corrupted = |address, value| {
+            let mut registers = seeded.clone();
+            registers.set(address, value);
+            validate(&registers, MEASUREMENT_FIELDS)
+        };
+        assert_eq!(
+            corrupted(40001, 0x6e54),
+            Err(MapError::BadMarker([0x5375, 0x6e54]))
+        );
+        assert_eq!(
+            corrupted(40002, 2),
+            Err(MapError::UnexpectedModel {
+                address: 40002,
+                found: 2
+            })
+        );
+        assert_eq!(
+            corrupted(40003, 66),
+            Err(MapError::BadModelLength {
+                address: 40002,
+                length: 66
+            })
+        );
+        assert_eq!(
+            corrupted(METER_MODEL_REGISTER + 1, 105),
+            Err(MapError::BadModelLength {
+                address: METER_MODEL_REGISTER,
+                length: 105
+            })
+        );
+        assert_eq!(
+            corrupted(METER_MODEL_REGISTER + 1, u16::MAX),
+            Err(MapError::BadModelLength {
+                address: METER_MODEL_REGISTER,
+                length: u16::MAX
+            })
+        );
+        assert!(corrupted(40195, 0).is_err());
+
+        // A typo in a field's address, such as PhaseCVA's 4011 for 40111
+        let typo = FieldInfo {
+            address: 4011,
+            ..PHASE_C_VA
+        };
+        assert_eq!(
+            validate(&seeded, &[TOTAL_REAL_POWER, typo]),
+            Err(MapError::FieldOutsideModel {
+                field: "PhaseCVA",
+                address: 4011
+            })
+        );
+        // Straddling the end of the model
+        let straddling = FieldInfo {
+            address: 40194,
+            ..PHASE_C_VA
+        };
+        assert!(validate(&seeded, &[straddling]).is_err());
+    }
+
+    #[test]
+    fn test_topology_sets_the_model() {
+        for (topology, model) in MeterTopology::ALL.into_iter().zip([211, 212, 213, 214]) {
+            let registers = seed_registers(DEFAULT_UNIT_ID, topology);
+            assert_eq!(
+                registers.get(METER_MODEL_REGISTER),
+                Some(model),
+                "{topology:?}"
+            );
+            // Every float meter model is the same length
+            assert_eq!(
+                registers.get(METER_MODEL_REGISTER + 1),
+                Some(124),
+                "{topology:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_topology() {
+        assert_eq!("single_phase".parse(), Ok(MeterTopology::SinglePhase));
+        assert_eq!(
+            " Three_Phase_Delta".parse(),
+            Ok(MeterTopology::ThreePhaseDelta)
+        );
+        assert!("two_phase".parse::<MeterTopology>().is_err());
+    }
+
+    #[test]
+    fn test_blocks_do_not_overlap() {
+        let mut seen = HashMap::new();
+        for block in SEED_BLOCKS {
+            for (address, _) in block.values() {
+                if let Some(other) = seen.insert(address, block.name) {
+                    panic!("{} overlaps {other} at {address}", block.name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_seeded_blocks_are_valid() {
+        for block in SEED_BLOCKS {
+            for (address, _) in block.values() {
+                assert!(is_valid_address(address), "{} at {address}", block.name);
+            }
+        }
+        // The reactive energy counters, which aren't seeded
+        assert!(is_valid_address(40161));
+        assert!(!is_valid_address(40197));
+        assert!(!is_valid_address(2));
+    }
+
+    #[test]
+    fn test_text_blocks_fit() {
+        for block in SEED_BLOCKS {
+            if let BlockContents::Text(text) = block.contents {
+                assert!(text.len() <= block.len as usize, "{} too long", block.name);
+            }
+            if let BlockContents::Values(values) = block.contents {
+                assert_eq!(values.len(), block.len as usize, "{} length", block.name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_fields_fully_covered() {
+        let fields = sunspec_fields_in_range(40097, 8);
+        assert_eq!(
+            fields,
+            vec![
+                TOTAL_REAL_POWER,
+                PHASE_A_WATTS,
+                PHASE_B_WATTS,
+                PHASE_C_WATTS
+            ]
+        );
+        assert!(fields.iter().all(|field| field.is_within(40097, 8)));
+    }
+
+    #[test]
+    fn test_fields_partially_covered() {
+        // Only the high word of the total power
+        let fields = sunspec_fields_in_range(40097, 1);
+        assert_eq!(fields, vec![TOTAL_REAL_POWER]);
+        assert!(!fields[0].is_within(40097, 1));
+
+        // Straddles the end of the frequency and the start of the phase A watts
+        let fields = sunspec_fields_in_range(40096, 4);
+        assert_eq!(fields, vec![FREQUENCY, TOTAL_REAL_POWER, PHASE_A_WATTS]);
+        let partial: Vec<_> = fields
+            .iter()
+            .filter(|field| !field.is_within(40096, 4))
+            .collect();
+        assert_eq!(partial, vec![&FREQUENCY, &PHASE_A_WATTS]);
+    }
+
+    #[test]
+    fn test_fields_missed() {
+        assert!(sunspec_fields_in_range(40000, 71).is_empty());
+        assert!(sunspec_fields_in_range(40097, 0).is_empty());
+        assert!(sunspec_fields_in_range(u16::MAX, 1).is_empty());
+    }
+}
@@ -0,0 +1,156 @@
+// Shared helpers for tests that need a device on the other end of a socket
+
+use std::{
+    collections::HashMap,
+    future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use tokio::net::TcpListener;
+use tokio_modbus::{
+    prelude::*,
+    server::{rtu_over_tcp, tcp},
+};
+
+/// A fake Shelly that answers input register reads with fixed float values,
+/// encoded low word first the same way the real device does.
+#[derive(Clone)]
+pub struct MockShelly {
+    input_registers: Arc<HashMap<u16, u16>>,
+    /// Answer with one register fewer than asked for
+    short_reads: bool,
+    /// Only answer requests for this unit, like a device behind a gateway
+    unit_id: Option<u8>,
+    /// How many of the first connections are accepted and then never answered
+    hung_connections: Arc<AtomicUsize>,
+}
+
+impl MockShelly {
+    /// Starts the mock on an ephemeral local port and returns its address
+    pub async fn start(readings: &[(u16, f32)]) -> SocketAddr {
+        Self::with_readings(readings).serve(false).await
+    }
+
+    /// Starts the mock speaking RTU framing over TCP, like a serial gateway
+    pub async fn start_rtu_over_tcp(readings: &[(u16, f32)]) -> SocketAddr {
+        Self::with_readings(readings).serve(true).await
+    }
+
+    /// Starts a mock that leaves the last register off every response
+    pub async fn start_with_short_reads(readings: &[(u16, f32)]) -> SocketAddr {
+        Self {
+            short_reads: true,
+            ..Self::with_readings(readings)
+        }
+        .serve(false)
+        .await
+    }
+
+    /// Starts a mock that answers requests for any other unit with GatewayTargetDevice
+    pub async fn start_as_unit(readings: &[(u16, f32)], unit_id: u8) -> SocketAddr {
+        Self {
+            unit_id: Some(unit_id),
+            ..Self::with_readings(readings)
+        }
+        .serve(false)
+        .await
+    }
+
+    /// Starts a mock that accepts its first `count` connections but never answers on them,
+    /// like a half-open connection, and serves normally after that
+    pub async fn start_hanging_first(readings: &[(u16, f32)], count: usize) -> SocketAddr {
+        Self {
+            hung_connections: Arc::new(AtomicUsize::new(count)),
+            ..Self::with_readings(readings)
+        }
+        .serve(false)
+        .await
+    }
+
+    fn with_readings(readings: &[(u16, f32)]) -> Self {
+        let mut input_registers = HashMap::new();
+        for (register, value) in readings {
+            let bits = value.to_bits();
+            input_registers.insert(*register, (bits & 0xFFFF) as u16);
+            input_registers.insert(register + 1, (bits >> 16) as u16);
+        }
+        Self {
+            input_registers: Arc::new(input_registers),
+            short_reads: false,
+            unit_id: None,
+            hung_connections: Arc::default(),
+        }
+    }
+
+    async fn serve(self, rtu: bool) -> SocketAddr {
+        let hung_connections = self.hung_connections.clone();
+        let mock = self;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let new_service = |_socket_addr| Ok(Some(mock.clone()));
+            if rtu {
+                let server = rtu_over_tcp::Server::new(listener);
+                let on_connected = |stream, socket_addr| async move {
+                    rtu_over_tcp::accept_tcp_connection(stream, socket_addr, new_service)
+                };
+                server.serve(&on_connected, |_err| {}).await.unwrap();
+            } else {
+                let server = tcp::Server::new(listener);
+                let on_connected = |stream, socket_addr| {
+                    let hang = hung_connections
+                        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                        .is_ok();
+                    async move {
+                        if hang {
+                            // Held open without ever reading from it
+                            tokio::spawn(async move {
+                                let _stream = stream;
+                                future::pending::<()>().await
+                            });
+                            return Ok(None);
+                        }
+                        tcp::accept_tcp_connection(stream, socket_addr, new_service)
+                    }
+                };
+                server.serve(&on_connected, |_err| {}).await.unwrap();
+            }
+        });
+        socket_addr
+    }
+}
+
+impl tokio_modbus::server::Service for MockShelly {
+    type Request = SlaveRequest<'static>;
+    type Response = Response;
+    type Exception = tokio_modbus::ExceptionCode;
+    type Future =
+        Pin<Box<dyn future::Future<Output = Result<Self::Response, Self::Exception>> + Send>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        if self.unit_id.is_some_and(|unit_id| unit_id != req.slave) {
+            return Box::pin(future::ready(Err(
+                tokio_modbus::ExceptionCode::GatewayTargetDevice,
+            )));
+        }
+        let result = match req.request {
+            Request::ReadInputRegisters(addr, cnt) => (addr..addr + cnt)
+                .map(|register| self.input_registers.get(&register).copied())
+                .collect::<Option<Vec<u16>>>()
+                .map(|mut words| {
+                    if self.short_reads {
+                        words.pop();
+                    }
+                    Response::ReadInputRegisters(words)
+                })
+                .ok_or(tokio_modbus::ExceptionCode::IllegalDataAddress),
+            _ => Err(tokio_modbus::ExceptionCode::IllegalFunction),
+        };
+        Box::pin(future::ready(result))
+    }
+}